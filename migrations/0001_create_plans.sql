@@ -0,0 +1,67 @@
+-- 企画
+CREATE TABLE IF NOT EXISTS plans (
+    id TEXT PRIMARY KEY,
+    type TEXT NOT NULL CHECK (type IN ('booth', 'general', 'stage', 'labo')),
+    organization_name TEXT NOT NULL,
+    plan_name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    is_child_friendly INTEGER NOT NULL,
+    is_recommended INTEGER NOT NULL,
+    -- 研究室企画のみ
+    is_lab_tour INTEGER,
+    latitude REAL,
+    longitude REAL
+);
+
+CREATE INDEX IF NOT EXISTS plans_type ON plans (type);
+CREATE INDEX IF NOT EXISTS plans_is_recommended ON plans (is_recommended);
+CREATE INDEX IF NOT EXISTS plans_is_child_friendly ON plans (is_child_friendly);
+
+-- 模擬店・一般企画のカテゴリー
+CREATE TABLE IF NOT EXISTS plan_categories (
+    plan_id TEXT NOT NULL REFERENCES plans (id),
+    position INTEGER NOT NULL,
+    category TEXT NOT NULL,
+    PRIMARY KEY (plan_id, position)
+);
+
+-- 開催時間（day は 1 または 2）
+CREATE TABLE IF NOT EXISTS plan_schedules (
+    plan_id TEXT NOT NULL REFERENCES plans (id),
+    day INTEGER NOT NULL CHECK (day IN (1, 2)),
+    position INTEGER NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time TEXT NOT NULL,
+    -- 開催時間ごとの場所（JSON）
+    location TEXT,
+    PRIMARY KEY (plan_id, day, position)
+);
+
+-- 開催場所
+CREATE TABLE IF NOT EXISTS plan_locations (
+    plan_id TEXT NOT NULL REFERENCES plans (id),
+    position INTEGER NOT NULL,
+    type TEXT NOT NULL CHECK (type IN ('indoor', 'outdoor')),
+    building TEXT,
+    room TEXT,
+    name TEXT,
+    PRIMARY KEY (plan_id, position)
+);
+
+-- 企画詳細（KVと同様に、企画より先に登録できるよう plans は参照しない）
+CREATE TABLE IF NOT EXISTS plan_details (
+    plan_id TEXT PRIMARY KEY,
+    has_product INTEGER NOT NULL,
+    product_description TEXT,
+    additional_info TEXT
+);
+
+-- 販売物（options は JSON）
+CREATE TABLE IF NOT EXISTS plan_products (
+    plan_id TEXT NOT NULL REFERENCES plan_details (plan_id),
+    position INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    price REAL,
+    options TEXT NOT NULL,
+    PRIMARY KEY (plan_id, position)
+);
@@ -0,0 +1,546 @@
+pub mod permission;
+
+use crate::auth::permission::{Permission, RolePermissions};
+use crate::models::api_key::{ApiKey, ApiKeyError};
+use crate::storage::ApiKeyRepository;
+use crate::util::now_secs;
+use jwt_simple::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use thiserror::Error;
+use worker::{console_error, Env, Fetch, Request, Url};
+
+/// JWKSをキャッシュする秒数
+const JWKS_TTL_SECS: u64 = 600;
+/// 未知のkidを受け取った際にJWKSを再取得できる最短間隔（秒）
+const JWKS_MIN_REFRESH_INTERVAL_SECS: u64 = 60;
+/// トークンの時刻検証で許容する時計のずれ（秒）
+const CLOCK_TOLERANCE_SECS: u64 = 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(default, rename = "use", skip_serializing_if = "Option::is_none")]
+    pub key_use: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// Keycloakが発行するアクセストークンの独自クレーム
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct KeycloakClaims {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub realm_access: Option<RoleClaim>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub resource_access: HashMap<String, RoleClaim>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoleClaim {
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+impl KeycloakClaims {
+    /// realmロールとclientロールを`RolePermissions`の形式で列挙する
+    pub fn roles(&self) -> Vec<String> {
+        let mut roles = vec![];
+        if let Some(realm_access) = &self.realm_access {
+            roles.extend(realm_access.roles.iter().cloned());
+        }
+        for (client, access) in &self.resource_access {
+            roles.extend(
+                access
+                    .roles
+                    .iter()
+                    .map(|role| format!("{}:{}", client, role)),
+            );
+        }
+        roles
+    }
+}
+
+/// 認証済みのリクエスト主体
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub subject: String,
+    pub claims: KeycloakClaims,
+    pub permissions: HashSet<Permission>,
+}
+
+impl Principal {
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+}
+
+pub struct AuthConfig {
+    pub jwks_url: String,
+    pub issuer: String,
+    pub audiences: HashSet<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Missing bearer token")]
+    MissingToken,
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+    #[error("Unknown signing key")]
+    UnknownKey,
+    #[error("Token is not issued for this API")]
+    AudienceMismatch,
+    #[error("Invalid API key")]
+    InvalidApiKey,
+    #[error(transparent)]
+    ApiKeyError(ApiKeyError),
+    #[error("Failed to fetch JWKS")]
+    JwksUnavailable,
+    #[error("Auth is misconfigured: {0}")]
+    Misconfigured(String),
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+}
+
+impl AuthConfig {
+    /// 環境変数から設定を読み込む
+    ///
+    /// `JWT_ISSUER`が未設定の場合は`JWKS_URL`のrealm部分から導出する
+    pub fn from_env(env: &Env) -> Result<Self, AuthError> {
+        let jwks_url = env
+            .var("JWKS_URL")
+            .map_err(|_| AuthError::Misconfigured("JWKS_URL is not set".into()))?
+            .to_string();
+        let issuer = match env.var("JWT_ISSUER") {
+            Ok(issuer) => issuer.to_string(),
+            Err(_) => jwks_url
+                .strip_suffix("/protocol/openid-connect/certs")
+                .ok_or_else(|| AuthError::Misconfigured("JWT_ISSUER is not set".into()))?
+                .to_string(),
+        };
+        let audiences = env
+            .var("JWT_AUDIENCE")
+            .map_err(|_| AuthError::Misconfigured("JWT_AUDIENCE is not set".into()))?
+            .to_string()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect::<HashSet<String>>();
+        if audiences.is_empty() {
+            return Err(AuthError::Misconfigured("JWT_AUDIENCE is empty".into()));
+        }
+
+        Ok(Self {
+            jwks_url,
+            issuer,
+            audiences,
+        })
+    }
+}
+
+impl JwkSet {
+    /// トークンの署名・issuer・audience・有効期限を検証する
+    pub fn verify(&self, token: &str, config: &AuthConfig) -> Result<Principal, AuthError> {
+        let metadata =
+            Token::decode_metadata(token).map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+        let alg = metadata.algorithm();
+
+        let jwk = self
+            .keys
+            .iter()
+            .filter(|jwk| jwk.kty == "RSA" && jwk.key_use.as_deref() != Some("enc"))
+            .find(|jwk| match (metadata.key_id(), jwk.kid.as_deref()) {
+                (Some(kid), Some(jwk_kid)) => kid == jwk_kid,
+                (None, _) => jwk.alg.as_deref().is_none_or(|jwk_alg| jwk_alg == alg),
+                (Some(_), None) => false,
+            })
+            .ok_or(AuthError::UnknownKey)?;
+        if jwk.alg.as_deref().is_some_and(|jwk_alg| jwk_alg != alg) {
+            return Err(AuthError::InvalidToken("algorithm mismatch".into()));
+        }
+
+        let (Some(n), Some(e)) = (&jwk.n, &jwk.e) else {
+            return Err(AuthError::UnknownKey);
+        };
+        let n = Base64UrlSafeNoPadding::decode_to_vec(n, None)
+            .map_err(|_| AuthError::InvalidToken("malformed jwk".into()))?;
+        let e = Base64UrlSafeNoPadding::decode_to_vec(e, None)
+            .map_err(|_| AuthError::InvalidToken("malformed jwk".into()))?;
+
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from([config.issuer.clone()])),
+            time_tolerance: Some(Duration::from_secs(CLOCK_TOLERANCE_SECS)),
+            ..Default::default()
+        };
+        let result = match alg {
+            "RS256" => RS256PublicKey::from_components(&n, &e)
+                .and_then(|key| key.verify_token::<KeycloakClaims>(token, Some(options))),
+            "RS384" => RS384PublicKey::from_components(&n, &e)
+                .and_then(|key| key.verify_token::<KeycloakClaims>(token, Some(options))),
+            "RS512" => RS512PublicKey::from_components(&n, &e)
+                .and_then(|key| key.verify_token::<KeycloakClaims>(token, Some(options))),
+            _ => return Err(AuthError::InvalidToken("unsupported algorithm".into())),
+        };
+        let claims = result.map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+
+        if claims.expires_at.is_none() {
+            return Err(AuthError::InvalidToken("exp is missing".into()));
+        }
+
+        // Keycloakはclient向けのaudienceを付与しない場合があるため、azpでも照合する
+        let audience_allowed = claims
+            .audiences
+            .as_ref()
+            .is_some_and(|aud| aud.contains(&config.audiences))
+            || claims
+                .custom
+                .azp
+                .as_ref()
+                .is_some_and(|azp| config.audiences.contains(azp));
+        if !audience_allowed {
+            return Err(AuthError::AudienceMismatch);
+        }
+
+        let Some(subject) = claims.subject else {
+            return Err(AuthError::InvalidToken("sub is missing".into()));
+        };
+
+        Ok(Principal {
+            subject,
+            claims: claims.custom,
+            permissions: HashSet::new(),
+        })
+    }
+}
+
+struct CachedJwks {
+    jwks: JwkSet,
+    fetched_at: u64,
+}
+
+thread_local! {
+    static JWKS_CACHE: RefCell<Option<CachedJwks>> = const { RefCell::new(None) };
+}
+
+/// JWKSを取得する
+///
+/// isolate内でキャッシュし、`force_refresh`の場合でも最短間隔内であればキャッシュを返す
+async fn load_jwks(url: &str, force_refresh: bool) -> Result<JwkSet, AuthError> {
+    let now = now_secs();
+    let cached = JWKS_CACHE.with(|cache| {
+        cache.borrow().as_ref().and_then(|cached| {
+            let age = now.saturating_sub(cached.fetched_at);
+            let fresh = if force_refresh {
+                age < JWKS_MIN_REFRESH_INTERVAL_SECS
+            } else {
+                age < JWKS_TTL_SECS
+            };
+            fresh.then(|| cached.jwks.clone())
+        })
+    });
+    if let Some(jwks) = cached {
+        return Ok(jwks);
+    }
+
+    let url = Url::parse(url).map_err(|e| AuthError::Misconfigured(e.to_string()))?;
+    let mut response = Fetch::Url(url).send().await?;
+    if !(200..300).contains(&response.status_code()) {
+        console_error!("failed to fetch jwks: status {}", response.status_code());
+        return Err(AuthError::JwksUnavailable);
+    }
+    let jwks = response.json::<JwkSet>().await?;
+
+    JWKS_CACHE.with(|cache| {
+        *cache.borrow_mut() = Some(CachedJwks {
+            jwks: jwks.clone(),
+            fetched_at: now,
+        });
+    });
+
+    Ok(jwks)
+}
+
+/// `Authorization: Bearer`ヘッダーからトークンを取り出す
+pub fn bearer_token(req: &Request) -> Result<String, AuthError> {
+    let header = req
+        .headers()
+        .get("Authorization")?
+        .ok_or(AuthError::MissingToken)?;
+    match header.split_once(' ') {
+        Some((scheme, token))
+            if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() =>
+        {
+            Ok(token.trim().to_string())
+        }
+        _ => Err(AuthError::MissingToken),
+    }
+}
+
+/// APIキーを検証し、キーのスコープを権限とするリクエスト主体を返す
+async fn authenticate_api_key(
+    api_key: &str,
+    api_keys: &dyn ApiKeyRepository,
+) -> Result<Principal, AuthError> {
+    let key = match ApiKey::verify(api_keys, api_key).await {
+        Ok(key) => key,
+        Err(ApiKeyError::Invalid) | Err(ApiKeyError::NotFound) => {
+            return Err(AuthError::InvalidApiKey)
+        }
+        Err(err) => return Err(AuthError::ApiKeyError(err)),
+    };
+
+    Ok(Principal {
+        subject: format!("apikey:{}", key.id),
+        claims: KeycloakClaims {
+            preferred_username: Some(key.name),
+            ..Default::default()
+        },
+        permissions: key
+            .scopes
+            .iter()
+            .filter_map(|scope| Permission::parse(scope))
+            .collect(),
+    })
+}
+
+/// リクエストのアクセストークン（または`X-API-Key`のAPIキー）を検証し、権限を解決したリクエスト主体を返す
+pub async fn authenticate(
+    req: &Request,
+    env: &Env,
+    api_keys: &dyn ApiKeyRepository,
+) -> Result<Principal, AuthError> {
+    if let Some(api_key) = req.headers().get("X-API-Key")? {
+        return authenticate_api_key(api_key.trim(), api_keys).await;
+    }
+
+    let token = bearer_token(req)?;
+    let config = AuthConfig::from_env(env)?;
+
+    let jwks = load_jwks(&config.jwks_url, false).await?;
+    let mut principal = match jwks.verify(&token, &config) {
+        Err(AuthError::UnknownKey) => {
+            // 鍵のローテーションに追従するため再取得して再検証する
+            let jwks = load_jwks(&config.jwks_url, true).await?;
+            jwks.verify(&token, &config)?
+        }
+        result => result?,
+    };
+    principal.permissions = RolePermissions::from_env(env).resolve(&principal.claims.roles());
+
+    Ok(principal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::api_key::ApiKeyCreate;
+    use crate::storage::memory::storage;
+    use futures::executor::block_on;
+    use std::sync::OnceLock;
+
+    const ISSUER: &str = "https://auth.example.com/realms/koudaisai";
+    const AUDIENCE: &str = "plans-info-api";
+
+    fn key_pair() -> &'static RS256KeyPair {
+        static KEY_PAIR: OnceLock<RS256KeyPair> = OnceLock::new();
+        KEY_PAIR.get_or_init(|| RS256KeyPair::generate(2048).unwrap().with_key_id("key-1"))
+    }
+
+    fn jwk(kid: &str, alg: &str) -> Jwk {
+        let components = key_pair().public_key().to_components();
+        Jwk {
+            kty: "RSA".into(),
+            kid: Some(kid.into()),
+            alg: Some(alg.into()),
+            key_use: Some("sig".into()),
+            n: Some(Base64UrlSafeNoPadding::encode_to_string(components.n).unwrap()),
+            e: Some(Base64UrlSafeNoPadding::encode_to_string(components.e).unwrap()),
+        }
+    }
+
+    fn jwks() -> JwkSet {
+        JwkSet {
+            keys: vec![jwk("key-1", "RS256")],
+        }
+    }
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            jwks_url: format!("{}/protocol/openid-connect/certs", ISSUER),
+            issuer: ISSUER.into(),
+            audiences: HashSet::from([AUDIENCE.to_string()]),
+        }
+    }
+
+    fn claims() -> JWTClaims<KeycloakClaims> {
+        Claims::with_custom_claims(KeycloakClaims::default(), Duration::from_mins(5))
+            .with_issuer(ISSUER)
+            .with_audience(AUDIENCE)
+            .with_subject("user-1")
+    }
+
+    fn sign(claims: JWTClaims<KeycloakClaims>) -> String {
+        key_pair().sign(claims).unwrap()
+    }
+
+    #[test]
+    fn verify_accepts_valid_token() {
+        let principal = jwks().verify(&sign(claims()), &config()).unwrap();
+        assert_eq!(principal.subject, "user-1");
+    }
+
+    #[test]
+    fn verify_accepts_matching_azp_without_audience() {
+        let mut claims = claims();
+        claims.audiences = None;
+        claims.custom.azp = Some(AUDIENCE.into());
+        assert!(jwks().verify(&sign(claims), &config()).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_wrong_issuer() {
+        let token = sign(claims().with_issuer("https://evil.example.com/realms/koudaisai"));
+        assert!(matches!(
+            jwks().verify(&token, &config()),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_wrong_audience() {
+        let token = sign(claims().with_audience("another-client"));
+        assert!(matches!(
+            jwks().verify(&token, &config()),
+            Err(AuthError::AudienceMismatch)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_wrong_azp() {
+        let mut claims = claims();
+        claims.audiences = None;
+        claims.custom.azp = Some("another-client".into());
+        assert!(matches!(
+            jwks().verify(&sign(claims), &config()),
+            Err(AuthError::AudienceMismatch)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_expired_token() {
+        let mut claims = claims();
+        let past = Clock::now_since_epoch() - Duration::from_hours(1);
+        claims.issued_at = Some(past - Duration::from_mins(5));
+        claims.invalid_before = claims.issued_at;
+        claims.expires_at = Some(past);
+        assert!(matches!(
+            jwks().verify(&sign(claims), &config()),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_token_without_exp() {
+        let mut claims = claims();
+        claims.expires_at = None;
+        assert!(matches!(
+            jwks().verify(&sign(claims), &config()),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_unknown_kid() {
+        let jwks = JwkSet {
+            keys: vec![jwk("key-2", "RS256")],
+        };
+        assert!(matches!(
+            jwks.verify(&sign(claims()), &config()),
+            Err(AuthError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_algorithm_mismatch() {
+        let jwks = JwkSet {
+            keys: vec![jwk("key-1", "RS512")],
+        };
+        assert!(matches!(
+            jwks.verify(&sign(claims()), &config()),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_token_signed_by_another_key() {
+        let other = RS256KeyPair::generate(2048).unwrap().with_key_id("key-1");
+        let token = other.sign(claims()).unwrap();
+        assert!(matches!(
+            jwks().verify(&token, &config()),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn roles_include_realm_and_client_roles() {
+        let claims = KeycloakClaims {
+            realm_access: Some(RoleClaim {
+                roles: vec!["staff".into()],
+            }),
+            resource_access: HashMap::from([(
+                AUDIENCE.to_string(),
+                RoleClaim {
+                    roles: vec!["editor".into()],
+                },
+            )]),
+            ..Default::default()
+        };
+        let mut roles = claims.roles();
+        roles.sort();
+        assert_eq!(roles, vec!["plans-info-api:editor", "staff"]);
+    }
+
+    #[test]
+    fn api_key_principal_has_only_the_key_scopes() {
+        let storage = storage();
+        block_on(async {
+            let create = serde_json::from_value::<ApiKeyCreate>(
+                serde_json::json!({"name": "自動化", "scopes": ["icons:write", "removed:scope"]}),
+            )
+            .unwrap();
+            let (key, token) = create.create(&*storage.api_keys, "admin").await.unwrap();
+
+            let principal = authenticate_api_key(&token, &*storage.api_keys)
+                .await
+                .unwrap();
+            assert_eq!(principal.subject, format!("apikey:{}", key.id));
+            assert_eq!(
+                principal.permissions,
+                HashSet::from([Permission::IconsWrite])
+            );
+
+            ApiKey::revoke(&*storage.api_keys, &key.id).await.unwrap();
+            assert!(matches!(
+                authenticate_api_key(&token, &*storage.api_keys).await,
+                Err(AuthError::InvalidApiKey)
+            ));
+        });
+    }
+}
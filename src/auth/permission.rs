@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use worker::{console_error, Env};
+
+/// 管理APIの操作権限
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Permission {
+    PlansWrite,
+    PlansDelete,
+    DetailsRead,
+    DetailsWrite,
+    IconsWrite,
+    BulkWrite,
+    OwnersRead,
+    OwnersWrite,
+    ApiKeysManage,
+    AuditRead,
+    Maintenance,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 11] = [
+        Permission::PlansWrite,
+        Permission::PlansDelete,
+        Permission::DetailsRead,
+        Permission::DetailsWrite,
+        Permission::IconsWrite,
+        Permission::BulkWrite,
+        Permission::OwnersRead,
+        Permission::OwnersWrite,
+        Permission::ApiKeysManage,
+        Permission::AuditRead,
+        Permission::Maintenance,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::PlansWrite => "plans:write",
+            Permission::PlansDelete => "plans:delete",
+            Permission::DetailsRead => "details:read",
+            Permission::DetailsWrite => "details:write",
+            Permission::IconsWrite => "icons:write",
+            Permission::BulkWrite => "bulk:write",
+            Permission::OwnersRead => "owners:read",
+            Permission::OwnersWrite => "owners:write",
+            Permission::ApiKeysManage => "apikeys:manage",
+            Permission::AuditRead => "audit:read",
+            Permission::Maintenance => "maintenance",
+        }
+    }
+
+    /// 企画の所有者であれば、この権限を持たなくても自分の企画に対して操作できるか
+    pub fn is_granted_to_owner(&self) -> bool {
+        matches!(
+            self,
+            Permission::PlansWrite
+                | Permission::DetailsRead
+                | Permission::DetailsWrite
+                | Permission::IconsWrite
+        )
+    }
+
+    pub fn parse(s: &str) -> Option<Permission> {
+        Self::ALL.into_iter().find(|p| p.as_str() == s)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// ロールから権限への対応表
+///
+/// 環境変数`ROLE_PERMISSIONS`に`{"ロール名": ["plans:write", ...]}`の形式で設定する。
+/// realmロールはそのままの名前、clientロールは`<client_id>:<ロール名>`で指定し、
+/// 権限に`*`を指定すると全ての権限を付与する
+#[derive(Default)]
+pub struct RolePermissions(HashMap<String, Vec<String>>);
+
+impl RolePermissions {
+    pub fn from_env(env: &Env) -> Self {
+        if let Ok(map) = env.object_var::<HashMap<String, Vec<String>>>("ROLE_PERMISSIONS") {
+            return Self(map);
+        }
+
+        let Ok(var) = env.var("ROLE_PERMISSIONS") else {
+            return Self::default();
+        };
+        match serde_json::from_str::<HashMap<String, Vec<String>>>(&var.to_string()) {
+            Ok(map) => Self(map),
+            Err(err) => {
+                console_error!("invalid ROLE_PERMISSIONS: {}", err);
+                Self::default()
+            }
+        }
+    }
+
+    /// ロール一覧から付与される権限を求める
+    pub fn resolve(&self, roles: &[String]) -> HashSet<Permission> {
+        let mut permissions = HashSet::new();
+        for role in roles {
+            let Some(grants) = self.0.get(role) else {
+                continue;
+            };
+            for grant in grants {
+                if grant == "*" {
+                    permissions.extend(Permission::ALL);
+                    continue;
+                }
+                match Permission::parse(grant) {
+                    Some(permission) => {
+                        permissions.insert(permission);
+                    }
+                    None => console_error!("unknown permission in ROLE_PERMISSIONS: {}", grant),
+                }
+            }
+        }
+        permissions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role_permissions(json: &str) -> RolePermissions {
+        RolePermissions(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn permission_round_trips_through_str() {
+        for permission in Permission::ALL {
+            assert_eq!(Permission::parse(permission.as_str()), Some(permission));
+        }
+        assert_eq!(Permission::parse("plans:read"), None);
+    }
+
+    #[test]
+    fn resolve_maps_realm_and_client_roles() {
+        let role_permissions = role_permissions(
+            r#"{
+                "staff": ["plans:write", "details:read"],
+                "plans-info-api:auditor": ["audit:read"],
+                "other": ["plans:delete"]
+            }"#,
+        );
+        let permissions =
+            role_permissions.resolve(&["staff".into(), "plans-info-api:auditor".into()]);
+        assert_eq!(
+            permissions,
+            HashSet::from([
+                Permission::PlansWrite,
+                Permission::DetailsRead,
+                Permission::AuditRead
+            ])
+        );
+    }
+
+    #[test]
+    fn resolve_grants_all_permissions_for_wildcard() {
+        let role_permissions = role_permissions(r#"{"admin": ["*"]}"#);
+        assert_eq!(
+            role_permissions.resolve(&["admin".into()]),
+            HashSet::from(Permission::ALL)
+        );
+    }
+
+    #[test]
+    fn resolve_ignores_unmapped_roles() {
+        let role_permissions = role_permissions(r#"{"admin": ["*"]}"#);
+        assert!(role_permissions
+            .resolve(&["staff".into(), "plans-info-api:admin".into()])
+            .is_empty());
+    }
+}
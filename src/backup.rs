@@ -0,0 +1,200 @@
+//! R2へのスナップショットの定期バックアップ
+//!
+//! Cron Triggerから呼ばれ、企画・企画詳細のスナップショット（`crate::snapshot`の形式）を
+//! `backups/<id>.jsonl`のキーで保存する。IDは作成日時（UTC）の`YYYYMMDDTHHMMSSZ`
+
+use crate::snapshot::{export_to_string, SnapshotError, SNAPSHOT_CONTENT_TYPE};
+use crate::storage::Storage;
+use crate::util::now;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use thiserror::Error;
+use worker::{Bucket, Env, HttpMetadata, Include};
+
+const BACKUP_KEY_PREFIX: &str = "backups/";
+const BACKUP_KEY_SUFFIX: &str = ".jsonl";
+
+/// 保持する毎時のバックアップの数の既定値
+const DEFAULT_KEEP_HOURLY: usize = 24;
+/// 保持する毎日のバックアップの数の既定値
+const DEFAULT_KEEP_DAILY: usize = 14;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+    #[error(transparent)]
+    SnapshotError(#[from] SnapshotError),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BackupInfo {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    /// スナップショットのバイト数
+    pub size: u64,
+    /// 企画の数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plans: Option<usize>,
+}
+
+/// バックアップの保持方針
+///
+/// 直近の`hourly`時間と`daily`日について、それぞれの時間・日の最新のバックアップを保持する。
+/// 最新のバックアップは常に保持する
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub hourly: usize,
+    pub daily: usize,
+}
+
+impl RetentionPolicy {
+    /// 環境変数`BACKUP_KEEP_HOURLY`・`BACKUP_KEEP_DAILY`から読み込む
+    pub fn from_env(env: &Env) -> Self {
+        let var = |name: &str, default: usize| {
+            env.var(name)
+                .ok()
+                .and_then(|var| var.to_string().parse().ok())
+                .unwrap_or(default)
+        };
+        RetentionPolicy {
+            hourly: var("BACKUP_KEEP_HOURLY", DEFAULT_KEEP_HOURLY),
+            daily: var("BACKUP_KEEP_DAILY", DEFAULT_KEEP_DAILY),
+        }
+    }
+
+    /// 削除するバックアップのIDを返す（`backups`は新しい順に並んでいること）
+    fn expired(&self, backups: &[BackupInfo]) -> Vec<String> {
+        let mut keep = BTreeSet::new();
+        for (limit, period) in [(self.hourly, "%Y%m%d%H"), (self.daily, "%Y%m%d")] {
+            let mut periods = BTreeSet::new();
+            for backup in backups {
+                if periods.len() >= limit {
+                    break;
+                }
+                if periods.insert(backup.created_at.format(period).to_string()) {
+                    keep.insert(backup.id.as_str());
+                }
+            }
+        }
+        if let Some(latest) = backups.first() {
+            keep.insert(latest.id.as_str());
+        }
+
+        backups
+            .iter()
+            .filter(|backup| !keep.contains(backup.id.as_str()))
+            .map(|backup| backup.id.clone())
+            .collect()
+    }
+}
+
+fn backup_key(id: &str) -> String {
+    format!("{}{}{}", BACKUP_KEY_PREFIX, id, BACKUP_KEY_SUFFIX)
+}
+
+/// バックアップのIDとして正しい形式かどうか
+pub fn is_backup_id(id: &str) -> bool {
+    id.len() == 16
+        && id.chars().enumerate().all(|(i, c)| match i {
+            8 => c == 'T',
+            15 => c == 'Z',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+/// 現在の企画・企画詳細のスナップショットを保存する
+///
+/// アイコンの画像は元々R2に保存されているため、メタデータのみを含める。
+/// R2への保存には長さの分かる本文が必要なため、ストリームではなく1つの文字列にまとめてから保存する
+pub async fn create(bucket: &Bucket, storage: &Storage) -> Result<BackupInfo, BackupError> {
+    let created_at = now();
+    let id = created_at.format("%Y%m%dT%H%M%SZ").to_string();
+    let snapshot = export_to_string(storage, false).await?;
+    let plans = snapshot.lines().count().saturating_sub(1);
+    let size = snapshot.len() as u64;
+
+    bucket
+        .put(backup_key(&id), snapshot)
+        .http_metadata(HttpMetadata {
+            content_type: Some(SNAPSHOT_CONTENT_TYPE.to_string()),
+            ..Default::default()
+        })
+        .custom_metadata(HashMap::from([("plans".to_string(), plans.to_string())]))
+        .execute()
+        .await?;
+
+    Ok(BackupInfo {
+        id,
+        created_at,
+        size,
+        plans: Some(plans),
+    })
+}
+
+/// 保存されているバックアップを新しい順に取得する
+pub async fn list(bucket: &Bucket) -> Result<Vec<BackupInfo>, BackupError> {
+    let mut backups = vec![];
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut list = bucket
+            .list()
+            .prefix(BACKUP_KEY_PREFIX)
+            .include(vec![Include::CustomMetadata]);
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let objects = list.execute().await?;
+
+        for object in objects.objects() {
+            let key = object.key();
+            let Some(id) = key
+                .strip_prefix(BACKUP_KEY_PREFIX)
+                .and_then(|key| key.strip_suffix(BACKUP_KEY_SUFFIX))
+                .filter(|id| is_backup_id(id))
+            else {
+                continue;
+            };
+            backups.push(BackupInfo {
+                id: id.to_string(),
+                created_at: DateTime::from_timestamp_millis(object.uploaded().as_millis() as i64)
+                    .unwrap_or(DateTime::<Utc>::UNIX_EPOCH),
+                size: object.size(),
+                plans: object
+                    .custom_metadata()
+                    .ok()
+                    .and_then(|metadata| metadata.get("plans")?.parse().ok()),
+            });
+        }
+
+        match objects.cursor() {
+            Some(next) if objects.truncated() => cursor = Some(next),
+            _ => break,
+        }
+    }
+
+    // IDは作成日時のため、文字列の降順が新しい順になる
+    backups.sort_by(|a, b| b.id.cmp(&a.id));
+    Ok(backups)
+}
+
+/// バックアップのスナップショットを取得する
+pub async fn read(bucket: &Bucket, id: &str) -> Result<Option<String>, BackupError> {
+    let Some(object) = bucket.get(backup_key(id)).execute().await? else {
+        return Ok(None);
+    };
+    match object.body() {
+        Some(body) => Ok(Some(body.text().await?)),
+        None => Ok(None),
+    }
+}
+
+/// 保持方針に従って古いバックアップを削除し、削除したIDを返す
+pub async fn prune(bucket: &Bucket, policy: RetentionPolicy) -> Result<Vec<String>, BackupError> {
+    let expired = policy.expired(&list(bucket).await?);
+    for id in &expired {
+        bucket.delete(backup_key(id)).await?;
+    }
+    Ok(expired)
+}
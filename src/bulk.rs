@@ -0,0 +1,413 @@
+//! 企画の一括作成・一括更新のall-or-nothing実行（`?atomic=true`）
+//!
+//! 全てのエントリーを検証してから書き込み、1件でも検証に失敗した場合は何も書き込まない。
+//! 書き込み中に失敗した場合は、書き込んだ分を元に戻す（KVにはトランザクションが無いため、補償処理で取り消す）
+
+use crate::models::keys::is_valid_plan_id;
+use crate::models::plan::{
+    PlanBulkUpdate, PlanCreate, PlanCreateError, PlanReadError, PlanUpdate, PlanUpdateError,
+};
+use crate::storage::Storage;
+use crate::util::if_match;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use worker::console_error;
+
+/// 他のエントリーが失敗したために書き込まなかった（または取り消した）エントリーのステータス
+const FAILED_DEPENDENCY: u16 = 424;
+
+/// エントリーごとの結果。`post_plans_bulk`の207レスポンスのエラーと同じ形で返す
+#[derive(Serialize, Clone, Debug)]
+pub struct EntryResult {
+    pub plan_id: String,
+    pub code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_etag: Option<String>,
+}
+
+impl EntryResult {
+    fn ok(plan_id: &str, code: u16) -> Self {
+        EntryResult {
+            plan_id: plan_id.to_string(),
+            code,
+            message: None,
+            current_etag: None,
+        }
+    }
+
+    fn error(plan_id: &str, code: u16, message: String) -> Self {
+        EntryResult {
+            plan_id: plan_id.to_string(),
+            code,
+            message: Some(message),
+            current_etag: None,
+        }
+    }
+}
+
+/// 書き込んだ企画の変更前と変更後の値（変更履歴・監査記録用）
+#[derive(Clone, Debug)]
+pub struct BulkChange {
+    pub id: String,
+    /// 作成した場合は`None`
+    pub before: Option<Value>,
+    pub after: Value,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AtomicReport {
+    /// 全てのエントリーを書き込んだかどうか
+    pub applied: bool,
+    /// 企画ID順の全てのエントリーの結果
+    pub results: Vec<EntryResult>,
+    /// 書き込み中の失敗により、書き込みを取り消した企画
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rolled_back: Vec<String>,
+    /// 取り消しに失敗した企画（書き込んだ値が残っているため、確認が必要）
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rollback_failed: Vec<String>,
+    /// レスポンスのステータス
+    #[serde(skip)]
+    pub status: u16,
+    /// 書き込みが確定した変更（`applied`が`true`の場合のみ）
+    #[serde(skip)]
+    pub changes: Vec<BulkChange>,
+    /// 書き込んだ後に取り消したものを含む、書き込みを行った企画（キャッシュの無効化用）
+    #[serde(skip)]
+    pub touched: Vec<String>,
+}
+
+/// 検証済みのエントリー
+enum Operation {
+    Create(PlanCreate),
+    Update {
+        update: PlanUpdate,
+        /// 検証時の値のETag。書き込むまでに他の操作で更新された場合は失敗させる
+        etag: String,
+    },
+}
+
+async fn validate_create(
+    storage: &Storage,
+    id: &str,
+    value: Value,
+) -> Result<Operation, EntryResult> {
+    if !is_valid_plan_id(id) {
+        return Err(EntryResult::error(
+            id,
+            400,
+            format!("「{}」は企画IDとして使用できません", id),
+        ));
+    }
+    let plan = serde_json::from_value::<PlanCreate>(value)
+        .map_err(|err| EntryResult::error(id, 400, err.to_string()))?;
+    plan.clone()
+        .into_read(id)
+        .check_rules()
+        .map_err(|message| EntryResult::error(id, 422, message))?;
+
+    match storage.plans.read_value(id).await {
+        Ok(None) => Ok(Operation::Create(plan)),
+        Ok(Some(_)) => Err(EntryResult::error(
+            id,
+            409,
+            format!("指定されたID「{}」の企画が既に存在します", id),
+        )),
+        Err(err) => Err(internal_error(id, "作成", format!("{:?}", err))),
+    }
+}
+
+async fn validate_update(
+    storage: &Storage,
+    id: &str,
+    value: Value,
+) -> Result<Operation, EntryResult> {
+    let entry = serde_json::from_value::<PlanBulkUpdate>(value)
+        .map_err(|err| EntryResult::error(id, 400, err.to_string()))?;
+
+    let (before, etag) = match storage.plans.read_with_etag(id).await {
+        Ok(read) => read,
+        Err(PlanReadError::NotFound) => {
+            return Err(EntryResult::error(
+                id,
+                404,
+                format!("指定されたID「{}」の企画が見つかりません", id),
+            ));
+        }
+        Err(err) => {
+            return Err(internal_error(id, "更新", format!("{:?}", err)));
+        }
+    };
+    if !if_match(entry.if_match.as_deref(), Some(&etag)) {
+        return Err(precondition_failed(id, etag));
+    }
+
+    // 更新後の値がスキーマ・条件を満たすか確認する
+    let mut after =
+        serde_json::to_value(&before).map_err(|err| internal_error(id, "更新", err.to_string()))?;
+    entry
+        .update
+        .clone()
+        .apply(&mut after)
+        .map_err(|err| match err {
+            PlanUpdateError::Invalid(message) => EntryResult::error(id, 422, message),
+            err => EntryResult::error(id, 400, err.to_string()),
+        })?;
+
+    Ok(Operation::Update {
+        update: entry.update,
+        etag,
+    })
+}
+
+fn internal_error(id: &str, operation: &str, err: String) -> EntryResult {
+    console_error!("bulk {} failed for {}: {}", operation, id, err);
+    EntryResult::error(
+        id,
+        500,
+        format!(
+            "ID「{}」の企画{}中に内部エラーが発生しました",
+            id, operation
+        ),
+    )
+}
+
+fn precondition_failed(id: &str, current_etag: String) -> EntryResult {
+    EntryResult {
+        current_etag: Some(current_etag),
+        ..EntryResult::error(
+            id,
+            412,
+            format!("ID「{}」の企画は他の操作によって更新されています", id),
+        )
+    }
+}
+
+async fn write(
+    storage: &Storage,
+    id: &str,
+    operation: Operation,
+) -> Result<BulkChange, EntryResult> {
+    match operation {
+        Operation::Create(plan) => match storage.plans.create(id, plan).await {
+            Ok(plan) => Ok(BulkChange {
+                id: id.to_string(),
+                before: None,
+                after: serde_json::to_value(plan)
+                    .map_err(|err| internal_error(id, "作成", err.to_string()))?,
+            }),
+            Err(PlanCreateError::Conflict) => Err(EntryResult::error(
+                id,
+                409,
+                format!("指定されたID「{}」の企画が既に存在します", id),
+            )),
+            Err(err) => Err(internal_error(id, "作成", err.to_string())),
+        },
+        Operation::Update { update, etag } => {
+            match storage.plans.update(id, update, Some(&etag)).await {
+                Ok((before, after)) => Ok(BulkChange {
+                    id: id.to_string(),
+                    before: Some(before),
+                    after,
+                }),
+                Err(PlanUpdateError::NotFound) => Err(EntryResult::error(
+                    id,
+                    404,
+                    format!("指定されたID「{}」の企画が見つかりません", id),
+                )),
+                Err(PlanUpdateError::PreconditionFailed(current_etag)) => {
+                    Err(precondition_failed(id, current_etag))
+                }
+                Err(err) => Err(internal_error(id, "更新", err.to_string())),
+            }
+        }
+    }
+}
+
+/// 書き込んだ変更を元に戻す
+async fn rollback(storage: &Storage, change: &BulkChange) -> Result<(), String> {
+    match &change.before {
+        None => storage
+            .plans
+            .delete(&change.id)
+            .await
+            .map_err(|err| err.to_string()),
+        Some(before) => storage
+            .plans
+            .put_value(&change.id, before)
+            .await
+            .map_err(|err| err.to_string()),
+    }
+}
+
+/// 一括作成（`create`が`true`）または一括更新をall-or-nothingで実行する
+///
+/// 成功した場合のエントリーのステータスは、作成では201、更新では200とする
+pub async fn apply_atomic(
+    storage: &Storage,
+    entries: BTreeMap<String, Value>,
+    create: bool,
+) -> AtomicReport {
+    let mut report = AtomicReport {
+        applied: false,
+        results: vec![],
+        rolled_back: vec![],
+        rollback_failed: vec![],
+        status: 422,
+        changes: vec![],
+        touched: vec![],
+    };
+
+    // 全てのエントリーを検証する
+    let mut operations = vec![];
+    let mut valid = true;
+    for (id, value) in entries {
+        let result = if create {
+            validate_create(storage, &id, value).await
+        } else {
+            validate_update(storage, &id, value).await
+        };
+        match result {
+            Ok(operation) => operations.push((id, Some(operation))),
+            Err(error) => {
+                valid = false;
+                report.results.push(error);
+                operations.push((id, None));
+            }
+        }
+    }
+    if !valid {
+        for (id, _) in operations
+            .iter()
+            .filter(|(_, operation)| operation.is_some())
+        {
+            report.results.push(EntryResult::error(
+                id,
+                FAILED_DEPENDENCY,
+                "他の企画の検証に失敗したため、書き込みませんでした".into(),
+            ));
+        }
+        report.results.sort_by(|a, b| a.plan_id.cmp(&b.plan_id));
+        return report;
+    }
+
+    // 書き込み
+    let mut failure = None;
+    let mut pending = vec![];
+    for (id, operation) in operations {
+        let Some(operation) = operation else {
+            continue;
+        };
+        if failure.is_some() {
+            pending.push(id);
+            continue;
+        }
+        match write(storage, &id, operation).await {
+            Ok(change) => {
+                report.touched.push(id);
+                report.changes.push(change);
+            }
+            Err(error) => failure = Some(error),
+        }
+    }
+
+    let Some(failure) = failure else {
+        report.applied = true;
+        report.status = if create { 201 } else { 200 };
+        report.results = report
+            .changes
+            .iter()
+            .map(|change| EntryResult::ok(&change.id, report.status))
+            .collect();
+        return report;
+    };
+
+    // 書き込んだ分を後に書き込んだものから順に取り消す
+    report.status = failure.code;
+    for change in report.changes.drain(..).rev() {
+        match rollback(storage, &change).await {
+            Ok(()) => {
+                report.results.push(EntryResult::error(
+                    &change.id,
+                    FAILED_DEPENDENCY,
+                    "他の企画の書き込みに失敗したため、書き込みを取り消しました".into(),
+                ));
+                report.rolled_back.push(change.id);
+            }
+            Err(err) => {
+                console_error!("failed to roll back {}: {}", change.id, err);
+                report.results.push(EntryResult::error(
+                    &change.id,
+                    500,
+                    format!(
+                        "他の企画の書き込みに失敗しましたが、ID「{}」の企画の書き込みを取り消せませんでした",
+                        change.id
+                    ),
+                ));
+                report.rollback_failed.push(change.id);
+            }
+        }
+    }
+    report.results.push(failure);
+    for id in pending {
+        report.results.push(EntryResult::error(
+            &id,
+            FAILED_DEPENDENCY,
+            "他の企画の書き込みに失敗したため、書き込みませんでした".into(),
+        ));
+    }
+    report.rolled_back.sort();
+    report.results.sort_by(|a, b| a.plan_id.cmp(&b.plan_id));
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::{seed, storage};
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    #[test]
+    fn atomic_update_writes_nothing_when_an_entry_breaks_rules() {
+        let storage = storage();
+        block_on(async {
+            seed(&storage, "plan-1").await;
+            seed(&storage, "plan-2").await;
+            let entries = BTreeMap::from([
+                ("plan-1".to_string(), json!({"plan_name": "変更後"})),
+                ("plan-2".to_string(), json!({"plan_name": " "})),
+            ]);
+
+            let report = apply_atomic(&storage, entries, false).await;
+            assert!(!report.applied);
+            let codes = report
+                .results
+                .iter()
+                .map(|result| (result.plan_id.as_str(), result.code))
+                .collect::<Vec<_>>();
+            assert_eq!(codes, [("plan-1", FAILED_DEPENDENCY), ("plan-2", 422)]);
+            let (plan, _) = storage.plans.read_with_etag("plan-1").await.unwrap();
+            assert_eq!(plan.plan_name, "テスト企画");
+        });
+    }
+
+    #[test]
+    fn update_rejects_plans_that_break_rules() {
+        let storage = storage();
+        block_on(async {
+            seed(&storage, "plan-1").await;
+            let update = serde_json::from_value::<PlanUpdate>(json!({
+                "schedule": {"day1": [{"start_time": "12:00", "end_time": "10:00"}]}
+            }))
+            .unwrap();
+            assert!(matches!(
+                storage.plans.update("plan-1", update, None).await,
+                Err(PlanUpdateError::Invalid(_))
+            ));
+        });
+    }
+}
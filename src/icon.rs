@@ -1,6 +1,7 @@
 use crate::service::discord::Discord;
+use crate::storage::IconStore;
 use thiserror::Error;
-use worker::{console_error, Bucket, Data, HttpMetadata};
+use worker::console_error;
 
 #[derive(Debug, Error)]
 pub enum WriteIconError {
@@ -8,28 +9,20 @@ pub enum WriteIconError {
     WorkerError(#[from] worker::Error),
 }
 
-/// アイコンをr2 bucketに保存する
+/// アイコンを保存する
 ///
 /// # params
-/// * `bucket` - r2 bucket
+/// * `icons` - アイコンの保存先
 /// * `
 pub async fn write_icon(
-    bucket: Bucket,
+    icons: &dyn IconStore,
     plan_id: &str,
-    bytes: impl Into<Data> + Into<Vec<u8>> + Clone,
+    bytes: Vec<u8>,
     content_type: String,
     discord: Discord,
 ) -> Result<(), WriteIconError> {
     // オリジナルを保存
-    let key_original = format!("{}/original", plan_id);
-    bucket
-        .put(&key_original, bytes.clone())
-        .http_metadata(HttpMetadata {
-            content_type: Some(content_type.clone()),
-            ..Default::default()
-        })
-        .execute()
-        .await?;
+    icons.put(plan_id, bytes.clone(), &content_type).await?;
 
     // discordに通知
     match discord
@@ -1,36 +1,68 @@
+#![allow(clippy::enum_variant_names)]
+
+mod auth;
+mod backup;
+mod bulk;
 mod icon;
 mod models;
 mod routes;
 mod service;
+mod snapshot;
+mod storage;
 mod util;
 
+use crate::routes::admin::api_keys::{delete_api_key, get_api_keys, post_api_key};
+use crate::routes::admin::audit::get_audit;
+use crate::routes::admin::backups::{get_backups, post_backup};
+use crate::routes::admin::keys::{get_keys_check, post_keys_rebuild};
+use crate::routes::admin::migration::{get_d1_migration, post_d1_migration};
+use crate::routes::admin::orphans::{get_orphans, post_orphans_purge};
 use crate::routes::admin::plans::details::{get_details_admin, put_details};
 use crate::routes::admin::plans::icon::{post_icon_import, put_icon};
+use crate::routes::admin::plans::owners::{get_owners, put_owners};
+use crate::routes::admin::plans::revisions::{
+    get_details_revision, get_details_revisions, get_plan_revision, get_plan_revisions,
+    post_details_revision, post_plan_revision,
+};
 use crate::routes::admin::plans::{
-    delete_plan, patch_plan, patch_plans_bulk, post_plans_bulk, put_plan,
+    delete_plan, patch_plan, patch_plans_bulk, post_plan_action, post_plans_bulk, put_plan,
 };
+use crate::routes::admin::schema::get_schema_versions;
+use crate::routes::admin::snapshot::{get_export, post_import};
+use crate::routes::admin::trash::{get_trash, post_trash_purge};
 use crate::routes::plans::details::get_details;
 use crate::routes::plans::icon::get_icon;
-use crate::routes::plans::{get_plan, get_plans};
+use crate::routes::plans::{
+    get_plan, get_plans, get_plans_geojson, get_plans_nearby, get_plans_now,
+};
 use worker::*;
 
 const KV_PLANS: &str = "PLANS";
 const KV_PLAN_DETAILS: &str = "PLAN_DETAILS";
+const KV_PLAN_HISTORY: &str = "PLAN_HISTORY";
 const R2_PLAN_IMAGES: &str = "plan_icons";
+const D1_PLANS: &str = "DB";
+const R2_BACKUPS: &str = "plan_backups";
 
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
-    // Router設定
-    let router = Router::new();
+    // Router設定（保存先は各ハンドラーに`ctx.data`として渡す）
+    let router = Router::with_data(storage::Storage::from_env(&env)?);
 
     router
         .get_async("/v1/plans", get_plans)
+        .get_async("/v1/plans.geojson", get_plans_geojson)
+        // 静的なセグメントは`:plan_id`より優先される
+        .get_async("/v1/plans/now", get_plans_now)
+        .get_async("/v1/plans/nearby", get_plans_nearby)
         .get_async("/v1/plans/:plan_id", get_plan)
         .put_async("/v1/admin/plans/:plan_id", put_plan)
         .patch_async("/v1/admin/plans/:plan_id", patch_plan)
         .delete_async("/v1/admin/plans/:plan_id", delete_plan)
+        // `:plan_id:restore`・`:plan_id:rename`はルーターでは1つのパラメーターになるため、ハンドラー側で分解する
+        .post_async("/v1/admin/plans/:plan_id", post_plan_action)
         .post_async("/v1/admin/plans:bulk", post_plans_bulk)
         .patch_async("/v1/admin/plans:bulk", patch_plans_bulk)
         .put_async("/v1/admin/plans/:plan_id/icon", put_icon)
@@ -39,6 +71,87 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         .get_async("/v1/plans/:plan_id/details", get_details)
         .get_async("/v1/admin/plans/:plan_id/details", get_details_admin)
         .put_async("/v1/admin/plans/:plan_id/details", put_details)
+        .get_async("/v1/admin/plans/:plan_id/owners", get_owners)
+        .put_async("/v1/admin/plans/:plan_id/owners", put_owners)
+        .get_async("/v1/admin/plans/:plan_id/revisions", get_plan_revisions)
+        .get_async("/v1/admin/plans/:plan_id/revisions/:rev", get_plan_revision)
+        // `:rev:restore`はルーターでは1つのパラメーターになるため、ハンドラー側で分解する
+        .post_async(
+            "/v1/admin/plans/:plan_id/revisions/:rev",
+            post_plan_revision,
+        )
+        .get_async(
+            "/v1/admin/plans/:plan_id/details/revisions",
+            get_details_revisions,
+        )
+        .get_async(
+            "/v1/admin/plans/:plan_id/details/revisions/:rev",
+            get_details_revision,
+        )
+        .post_async(
+            "/v1/admin/plans/:plan_id/details/revisions/:rev",
+            post_details_revision,
+        )
+        .post_async("/v1/admin/apikeys", post_api_key)
+        .get_async("/v1/admin/apikeys", get_api_keys)
+        .delete_async("/v1/admin/apikeys/:key_id", delete_api_key)
+        .get_async("/v1/admin/audit", get_audit)
+        .get_async("/v1/admin/keys", get_keys_check)
+        .post_async("/v1/admin/keys:rebuild", post_keys_rebuild)
+        .get_async("/v1/admin/migrations/d1", get_d1_migration)
+        .post_async("/v1/admin/migrations/d1", post_d1_migration)
+        .get_async("/v1/admin/schema", get_schema_versions)
+        .get_async("/v1/admin/export", get_export)
+        .post_async("/v1/admin/import", post_import)
+        .get_async("/v1/admin/trash", get_trash)
+        .post_async("/v1/admin/trash:purge", post_trash_purge)
+        .get_async("/v1/admin/orphans", get_orphans)
+        .post_async("/v1/admin/orphans:purge", post_orphans_purge)
+        .get_async("/v1/admin/backups", get_backups)
+        // `:id:restore`はルーターでは1つのパラメーターになるため、ハンドラー側で分解する
+        .post_async("/v1/admin/backups/:backup_id", post_backup)
         .run(req, env)
         .await
 }
+
+/// Cron Triggerから呼ばれ、企画・企画詳細のスナップショットをR2にバックアップし、
+/// 保持期間を過ぎたゴミ箱の企画を完全に削除する
+#[event(scheduled)]
+async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+
+    let storage = match storage::Storage::from_env(&env) {
+        Ok(storage) => storage,
+        Err(err) => {
+            console_error!("failed to open storage: {:?}", err);
+            return;
+        }
+    };
+
+    let result = async {
+        let bucket = env.bucket(R2_BACKUPS)?;
+        let created = backup::create(&bucket, &storage).await?;
+        let pruned = backup::prune(&bucket, backup::RetentionPolicy::from_env(&env)).await?;
+        Ok::<_, backup::BackupError>((created, pruned))
+    }
+    .await;
+    match result {
+        Ok((created, pruned)) => console_log!(
+            "backup {} created ({} plans), {} expired backups deleted",
+            created.id,
+            created.plans.unwrap_or_default(),
+            pruned.len()
+        ),
+        Err(err) => console_error!("failed to back up: {:?}", err),
+    }
+
+    let result = async {
+        let retention = models::trash::retention(&env);
+        models::trash::purge_expired(&*storage.trash, &*storage.icons, retention).await
+    }
+    .await;
+    match result {
+        Ok(purged) => console_log!("{} expired plans purged from trash", purged.len()),
+        Err(err) => console_error!("failed to purge trash: {:?}", err),
+    }
+}
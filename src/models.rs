@@ -1,7 +1,20 @@
+pub mod alias;
+pub mod api_key;
+pub mod audit;
 pub mod base;
+pub mod cache;
 pub mod details;
+pub mod festival;
+pub mod geo;
+pub mod geojson;
 pub mod keys;
+pub mod listing;
+pub mod migration;
+pub mod owners;
 pub mod plan;
 pub mod plan_type;
 pub mod products;
+pub mod revision;
 pub mod schedule;
+pub mod schema;
+pub mod trash;
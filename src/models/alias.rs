@@ -0,0 +1,63 @@
+use crate::util::now;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use worker::kv::{KvError, KvStore};
+use worker::Env;
+
+pub const ALIASES_KEY_PREFIX: &str = "aliases:";
+
+/// 転送を残す日数の既定値
+const DEFAULT_TTL_DAYS: i64 = 90;
+
+fn alias_key(plan_id: &str) -> String {
+    format!("{}{}", ALIASES_KEY_PREFIX, plan_id)
+}
+
+/// 変更前の企画IDから変更後の企画IDへの転送
+///
+/// 企画と同じnamespaceに`aliases:<変更前の企画ID>`のキーで保存し、保持期間を過ぎるとKVから自動で削除される。
+/// 変更を繰り返した場合は転送を1つずつ辿る（変更後の企画IDへの転送は変更時に削除するため、循環しない）
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlanAlias {
+    /// 変更後の企画ID
+    pub plan_id: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// 転送の保持期間。環境変数`PLAN_ALIAS_TTL_DAYS`で指定する
+pub fn ttl(env: &Env) -> Duration {
+    let days = env
+        .var("PLAN_ALIAS_TTL_DAYS")
+        .ok()
+        .and_then(|var| var.to_string().parse().ok())
+        .unwrap_or(DEFAULT_TTL_DAYS);
+    Duration::days(days)
+}
+
+impl PlanAlias {
+    pub fn new(plan_id: &str) -> Self {
+        PlanAlias {
+            plan_id: plan_id.to_string(),
+            created_at: now(),
+        }
+    }
+
+    pub async fn read(kv: &KvStore, old_id: &str) -> Result<Option<PlanAlias>, KvError> {
+        kv.get(&alias_key(old_id)).json::<PlanAlias>().await
+    }
+
+    pub async fn write(&self, kv: &KvStore, old_id: &str, ttl: Duration) -> Result<(), KvError> {
+        kv.put(
+            &alias_key(old_id),
+            serde_json::to_string(self).map_err(KvError::Serialization)?,
+        )?
+        // KVの有効期限は60秒以上である必要がある
+        .expiration_ttl(ttl.num_seconds().max(60) as u64)
+        .execute()
+        .await
+    }
+
+    pub async fn delete(kv: &KvStore, old_id: &str) -> Result<(), KvError> {
+        kv.delete(&alias_key(old_id)).await
+    }
+}
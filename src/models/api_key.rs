@@ -0,0 +1,373 @@
+use crate::auth::permission::Permission;
+use crate::auth::Principal;
+use crate::storage::ApiKeyRepository;
+use crate::util::{now, sha256_hex};
+use chrono::{DateTime, Utc};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder};
+use jwt_simple::reexports::rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use worker::kv::{KvError, KvStore};
+
+pub const API_KEYS_KEY_PREFIX: &str = "apikeys:";
+/// 発行するAPIキーの接頭辞（`kpi_<id>_<secret>`）
+const API_KEY_TOKEN_PREFIX: &str = "kpi_";
+/// `last_used_at`を更新する最短間隔（秒）
+const LAST_USED_UPDATE_INTERVAL_SECS: i64 = 300;
+
+/// 自動化用のAPIキー
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// 付与する権限（`Permission`の文字列表現）
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// 保存する形式。シークレットはハッシュのみを保持する
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredApiKey {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub secret_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKeyCreate {
+    pub name: String,
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Error, Debug)]
+pub enum ApiKeyError {
+    #[error("Not found")]
+    NotFound,
+    #[error("Invalid API key")]
+    Invalid,
+    #[error(transparent)]
+    KvError(#[from] KvError),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+}
+
+/// 発行できないスコープ
+#[derive(Error, Debug, PartialEq)]
+pub enum ApiKeyScopeError {
+    #[error("不明な権限です: {0}")]
+    Unknown(String),
+    /// 発行者が持たない権限
+    #[error("この操作には権限「{0}」が必要です")]
+    NotGranted(Permission),
+}
+
+fn api_key_key(id: &str) -> String {
+    format!("{}{}", API_KEYS_KEY_PREFIX, id)
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+impl ApiKeyCreate {
+    /// 発行者自身が持たない権限はAPIキーに付与できない
+    pub fn check_scopes(&self, issuer: &Principal) -> Result<(), ApiKeyScopeError> {
+        for scope in &self.scopes {
+            match Permission::parse(scope) {
+                Some(permission) if issuer.has(permission) => {}
+                Some(permission) => return Err(ApiKeyScopeError::NotGranted(permission)),
+                None => return Err(ApiKeyScopeError::Unknown(scope.clone())),
+            }
+        }
+        Ok(())
+    }
+
+    /// APIキーを発行する
+    ///
+    /// 平文のキーはこの戻り値でのみ取得でき、保存先にはハッシュのみを保存する
+    pub async fn create(
+        self,
+        keys: &dyn ApiKeyRepository,
+        created_by: &str,
+    ) -> Result<(ApiKey, String), ApiKeyError> {
+        let id = random_bytes::<8>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let secret = Base64UrlSafeNoPadding::encode_to_string(random_bytes::<32>())
+            .map_err(|_| ApiKeyError::Invalid)?;
+
+        let key = ApiKey {
+            id: id.clone(),
+            name: self.name,
+            scopes: self.scopes,
+            created_at: now(),
+            created_by: Some(created_by.to_string()),
+            last_used_at: None,
+            expires_at: self.expires_at,
+            revoked_at: None,
+        };
+        let stored = StoredApiKey {
+            key: key.clone(),
+            secret_hash: sha256_hex(&secret),
+        };
+        keys.put(&stored).await?;
+
+        Ok((key, format!("{}{}_{}", API_KEY_TOKEN_PREFIX, id, secret)))
+    }
+}
+
+impl ApiKey {
+    pub fn is_active(&self, at: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|expires_at| at < expires_at)
+    }
+
+    pub async fn list(keys: &dyn ApiKeyRepository) -> Result<Vec<ApiKey>, ApiKeyError> {
+        let mut api_keys = keys
+            .list()
+            .await?
+            .into_iter()
+            .map(|stored| stored.key)
+            .collect::<Vec<_>>();
+        api_keys.sort_by_key(|key| key.created_at);
+        Ok(api_keys)
+    }
+
+    pub async fn revoke(keys: &dyn ApiKeyRepository, id: &str) -> Result<ApiKey, ApiKeyError> {
+        let Some(mut stored) = keys.get(id).await? else {
+            return Err(ApiKeyError::NotFound);
+        };
+        if stored.key.revoked_at.is_none() {
+            stored.key.revoked_at = Some(now());
+            keys.put(&stored).await?;
+        }
+        Ok(stored.key)
+    }
+
+    /// 平文のAPIキーを検証し、有効であればそのキーの情報を返す
+    pub async fn verify(keys: &dyn ApiKeyRepository, token: &str) -> Result<ApiKey, ApiKeyError> {
+        let Some((id, secret)) = token
+            .strip_prefix(API_KEY_TOKEN_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+        else {
+            return Err(ApiKeyError::Invalid);
+        };
+
+        let Some(mut stored) = keys.get(id).await? else {
+            return Err(ApiKeyError::Invalid);
+        };
+        let hash = sha256_hex(secret);
+        let matches = hash.len() == stored.secret_hash.len()
+            && hash
+                .bytes()
+                .zip(stored.secret_hash.bytes())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0;
+        let now = now();
+        if !matches || !stored.key.is_active(now) {
+            return Err(ApiKeyError::Invalid);
+        }
+
+        // 毎回書き込むとKVの書き込み制限に当たるため、一定間隔でのみ更新する
+        let stale = stored.key.last_used_at.is_none_or(|last_used_at| {
+            (now - last_used_at).num_seconds() >= LAST_USED_UPDATE_INTERVAL_SECS
+        });
+        if stale {
+            stored.key.last_used_at = Some(now);
+            keys.put(&stored).await?;
+        }
+
+        Ok(stored.key)
+    }
+}
+
+impl StoredApiKey {
+    /// 企画と同じnamespaceに`apikeys:<id>`のキーで保存する
+    pub async fn read(kv: &KvStore, id: &str) -> Result<Option<StoredApiKey>, ApiKeyError> {
+        Ok(kv.get(&api_key_key(id)).json::<StoredApiKey>().await?)
+    }
+
+    pub async fn write(&self, kv: &KvStore) -> Result<(), ApiKeyError> {
+        kv.put(&api_key_key(&self.key.id), serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list(kv: &KvStore) -> Result<Vec<StoredApiKey>, ApiKeyError> {
+        let mut keys = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = kv.list().prefix(API_KEYS_KEY_PREFIX.into());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let list = list.execute().await?;
+            for name in list.keys.into_iter().map(|key| key.name) {
+                if let Some(stored) = kv.get(&name).json::<StoredApiKey>().await? {
+                    keys.push(stored);
+                }
+            }
+            match list.cursor {
+                Some(next) if !list.list_complete => cursor = Some(next),
+                _ => break,
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::KeycloakClaims;
+    use crate::storage::memory::storage;
+    use chrono::Duration;
+    use futures::executor::block_on;
+
+    fn create(scopes: &[&str], expires_at: Option<DateTime<Utc>>) -> ApiKeyCreate {
+        ApiKeyCreate {
+            name: "自動化".into(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires_at,
+        }
+    }
+
+    fn issuer(permissions: &[Permission]) -> Principal {
+        Principal {
+            subject: "admin".into(),
+            claims: KeycloakClaims::default(),
+            permissions: permissions.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn issued_key_verifies_and_records_last_use() {
+        let storage = storage();
+        block_on(async {
+            let (key, token) = create(&["plans:write"], None)
+                .create(&*storage.api_keys, "admin")
+                .await
+                .unwrap();
+            let secret = token.strip_prefix(&format!("kpi_{}_", key.id)).unwrap();
+            // 平文のシークレットは保存しない
+            let stored = storage.api_keys.get(&key.id).await.unwrap().unwrap();
+            assert_eq!(stored.secret_hash, sha256_hex(secret));
+
+            let verified = ApiKey::verify(&*storage.api_keys, &token).await.unwrap();
+            assert_eq!(verified.id, key.id);
+            assert_eq!(verified.scopes, ["plans:write"]);
+            let stored = storage.api_keys.get(&key.id).await.unwrap().unwrap();
+            assert!(stored.key.last_used_at.is_some());
+        });
+    }
+
+    #[test]
+    fn wrong_secret_and_malformed_tokens_are_invalid() {
+        let storage = storage();
+        block_on(async {
+            let (key, token) = create(&["plans:write"], None)
+                .create(&*storage.api_keys, "admin")
+                .await
+                .unwrap();
+            let secret = token.strip_prefix(&format!("kpi_{}_", key.id)).unwrap();
+            for invalid in [
+                format!("kpi_{}_{}x", key.id, secret),
+                format!("kpi_{}_", key.id),
+                format!("kpi_{}", key.id),
+                format!("kpi_0000000000000000_{}", secret),
+                format!("{}_{}", key.id, secret),
+                format!("KPI_{}_{}", key.id, secret),
+                "kpi_".into(),
+                String::new(),
+            ] {
+                assert!(
+                    matches!(
+                        ApiKey::verify(&*storage.api_keys, &invalid).await,
+                        Err(ApiKeyError::Invalid)
+                    ),
+                    "{}",
+                    invalid
+                );
+            }
+            // 検証に失敗した場合は最終使用日時を更新しない
+            let stored = storage.api_keys.get(&key.id).await.unwrap().unwrap();
+            assert!(stored.key.last_used_at.is_none());
+        });
+    }
+
+    #[test]
+    fn revoked_and_expired_keys_are_invalid() {
+        let storage = storage();
+        block_on(async {
+            let (key, token) = create(&["plans:write"], None)
+                .create(&*storage.api_keys, "admin")
+                .await
+                .unwrap();
+            let revoked = ApiKey::revoke(&*storage.api_keys, &key.id).await.unwrap();
+            assert!(revoked.revoked_at.is_some());
+            assert!(matches!(
+                ApiKey::verify(&*storage.api_keys, &token).await,
+                Err(ApiKeyError::Invalid)
+            ));
+            assert!(matches!(
+                ApiKey::revoke(&*storage.api_keys, "missing").await,
+                Err(ApiKeyError::NotFound)
+            ));
+
+            let (_, expired) = create(&["plans:write"], Some(now() - Duration::minutes(1)))
+                .create(&*storage.api_keys, "admin")
+                .await
+                .unwrap();
+            assert!(matches!(
+                ApiKey::verify(&*storage.api_keys, &expired).await,
+                Err(ApiKeyError::Invalid)
+            ));
+        });
+
+        // 有効期限の日時ちょうどから無効になる
+        let expires_at = now();
+        let key = ApiKey {
+            id: "id".into(),
+            name: "自動化".into(),
+            scopes: vec![],
+            created_at: expires_at - Duration::days(1),
+            created_by: None,
+            last_used_at: None,
+            expires_at: Some(expires_at),
+            revoked_at: None,
+        };
+        assert!(key.is_active(expires_at - Duration::seconds(1)));
+        assert!(!key.is_active(expires_at));
+    }
+
+    #[test]
+    fn scopes_must_be_a_subset_of_the_issuer_permissions() {
+        let editor = issuer(&[Permission::PlansWrite, Permission::ApiKeysManage]);
+        assert_eq!(create(&["plans:write"], None).check_scopes(&editor), Ok(()));
+        assert_eq!(
+            create(&["plans:write", "plans:delete"], None).check_scopes(&editor),
+            Err(ApiKeyScopeError::NotGranted(Permission::PlansDelete))
+        );
+        assert_eq!(
+            create(&["plans:read"], None).check_scopes(&editor),
+            Err(ApiKeyScopeError::Unknown("plans:read".into()))
+        );
+        assert_eq!(
+            create(&["*"], None).check_scopes(&issuer(&Permission::ALL)),
+            Err(ApiKeyScopeError::Unknown("*".into()))
+        );
+    }
+}
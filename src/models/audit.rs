@@ -0,0 +1,217 @@
+use crate::auth::Principal;
+use crate::util::now;
+use chrono::{DateTime, Utc};
+use jwt_simple::reexports::rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use worker::kv::{KvError, KvStore};
+
+const AUDIT_KEY_PREFIX: &str = "audit:";
+/// キーを新しい順に並べるための時刻の反転基準（ミリ秒）
+const INVERTED_TIMESTAMP_BASE: i64 = 9_999_999_999_999;
+
+/// 管理APIによる変更の監査記録
+///
+/// 企画とは別の`PLAN_HISTORY` namespaceに、新しい順に並ぶキーで保存する
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    /// 操作したトークンの`sub`（APIキーの場合は`apikey:<id>`）
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor_name: Option<String>,
+    /// 呼び出されたルート（例: `PATCH /v1/admin/plans/:plan_id`）
+    pub route: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_hash: Option<String>,
+}
+
+/// 一覧時に値を取得せずに絞り込むため、KVのメタデータに保存する項目
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AuditMetadata {
+    ts: i64,
+    actor: String,
+    #[serde(default)]
+    plan_id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct AuditQuery {
+    pub plan_id: Option<String>,
+    pub actor: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: usize,
+    pub cursor: Option<String>,
+}
+
+pub struct AuditPage {
+    pub records: Vec<AuditRecord>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("Invalid cursor")]
+    InvalidCursor,
+    #[error(transparent)]
+    KvError(#[from] KvError),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+}
+
+impl AuditRecord {
+    pub fn new(actor: &Principal, route: &str, plan_id: Option<&str>) -> Self {
+        let mut random = [0u8; 4];
+        thread_rng().fill_bytes(&mut random);
+        let timestamp = now();
+        Self {
+            id: format!(
+                "{:013}-{}",
+                timestamp.timestamp_millis(),
+                random
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            ),
+            timestamp,
+            actor: actor.subject.clone(),
+            actor_name: actor.claims.preferred_username.clone(),
+            route: route.to_string(),
+            plan_id: plan_id.map(Into::into),
+            before: None,
+            after: None,
+            icon_hash: None,
+        }
+    }
+
+    pub fn with_before<T: Serialize>(mut self, before: &T) -> Self {
+        self.before = serde_json::to_value(before).ok();
+        self
+    }
+
+    pub fn with_after<T: Serialize>(mut self, after: &T) -> Self {
+        self.after = serde_json::to_value(after).ok();
+        self
+    }
+
+    pub fn with_icon_hash(mut self, icon_hash: String) -> Self {
+        self.icon_hash = Some(icon_hash);
+        self
+    }
+
+    fn key(&self) -> String {
+        let (_, random) = self.id.split_once('-').unwrap_or_default();
+        format!(
+            "{}{:013}:{}",
+            AUDIT_KEY_PREFIX,
+            INVERTED_TIMESTAMP_BASE - self.timestamp.timestamp_millis(),
+            random
+        )
+    }
+
+    /// 監査記録を追記する
+    pub async fn append(&self, kv: &KvStore) -> Result<(), AuditError> {
+        kv.put(&self.key(), serde_json::to_string(self)?)?
+            .metadata(AuditMetadata {
+                ts: self.timestamp.timestamp_millis(),
+                actor: self.actor.clone(),
+                plan_id: self.plan_id.clone(),
+            })?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// 監査記録を新しい順に取得する
+    ///
+    /// カーソルは`<ページ内の位置>:<KVのカーソル>`の形式で、KVの1ページの途中から再開できるようにしている
+    pub async fn list(kv: &KvStore, query: &AuditQuery) -> Result<AuditPage, AuditError> {
+        let (mut offset, mut kv_cursor) = match &query.cursor {
+            None => (0, None),
+            Some(cursor) => {
+                let (offset, kv_cursor) =
+                    cursor.split_once(':').ok_or(AuditError::InvalidCursor)?;
+                let offset = offset
+                    .parse::<usize>()
+                    .map_err(|_| AuditError::InvalidCursor)?;
+                let kv_cursor = (!kv_cursor.is_empty()).then(|| kv_cursor.to_string());
+                (offset, kv_cursor)
+            }
+        };
+        let from = query.from.map(|from| from.timestamp_millis());
+        let to = query.to.map(|to| to.timestamp_millis());
+
+        let mut records = vec![];
+        loop {
+            let mut list = kv.list().prefix(AUDIT_KEY_PREFIX.into());
+            if let Some(cursor) = &kv_cursor {
+                list = list.cursor(cursor.clone());
+            }
+            let list = list.execute().await?;
+
+            for (index, key) in list.keys.iter().enumerate().skip(offset) {
+                if records.len() >= query.limit {
+                    return Ok(AuditPage {
+                        records,
+                        next_cursor: Some(format!(
+                            "{}:{}",
+                            index,
+                            kv_cursor.as_deref().unwrap_or_default()
+                        )),
+                    });
+                }
+
+                let Some(metadata) = key
+                    .metadata
+                    .clone()
+                    .and_then(|metadata| serde_json::from_value::<AuditMetadata>(metadata).ok())
+                else {
+                    continue;
+                };
+                // 新しい順に並んでいるため、fromより古くなったら以降は全て範囲外
+                if from.is_some_and(|from| metadata.ts < from) {
+                    return Ok(AuditPage {
+                        records,
+                        next_cursor: None,
+                    });
+                }
+                if to.is_some_and(|to| metadata.ts > to)
+                    || query
+                        .actor
+                        .as_ref()
+                        .is_some_and(|actor| actor != &metadata.actor)
+                    || query
+                        .plan_id
+                        .as_ref()
+                        .is_some_and(|plan_id| Some(plan_id) != metadata.plan_id.as_ref())
+                {
+                    continue;
+                }
+
+                if let Some(record) = kv.get(&key.name).json::<AuditRecord>().await? {
+                    records.push(record);
+                }
+            }
+
+            offset = 0;
+            match list.cursor {
+                Some(next) if !list.list_complete => kv_cursor = Some(next),
+                _ => {
+                    return Ok(AuditPage {
+                        records,
+                        next_cursor: None,
+                    })
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::util::now;
+use chrono::{DateTime, Utc};
+use worker::kv::{KvError, KvStore};
+
+pub const CACHE_KEY_PREFIX: &str = "cache:";
+
+/// 公開APIのレスポンスキャッシュの無効化単位
+///
+/// Cache APIの削除はそのデータセンターにしか効かないため、キャッシュキーに世代を含め、
+/// 書き込み時に世代を進めることで全てのデータセンターの古いエントリーを参照できなくする
+#[derive(Clone, Copy, Debug)]
+pub enum CacheScope<'a> {
+    /// `GET /v1/plans`
+    List,
+    /// `GET /v1/plans/:plan_id`とその詳細情報・アイコン
+    Plan(&'a str),
+}
+
+impl CacheScope<'_> {
+    /// 世代を保存するキー
+    pub fn key(&self) -> String {
+        match self {
+            CacheScope::List => format!("{}list", CACHE_KEY_PREFIX),
+            CacheScope::Plan(plan_id) => format!("{}plan:{}", CACHE_KEY_PREFIX, plan_id),
+        }
+    }
+
+    /// 現在の世代を取得する。一度も書き込まれていない場合は`0`
+    pub async fn generation(&self, kv: &KvStore) -> Result<String, KvError> {
+        Ok(kv
+            .get(&self.key())
+            .text()
+            .await?
+            .unwrap_or_else(|| "0".to_string()))
+    }
+
+    /// 世代を進めた日時（そのスコープの値が最後に変更された日時）。一度も書き込まれていない場合は`None`
+    pub fn modified_at(generation: &str) -> Option<DateTime<Utc>> {
+        generation
+            .parse::<i64>()
+            .ok()
+            .filter(|millis| *millis > 0)
+            .and_then(DateTime::from_timestamp_millis)
+    }
+
+    /// 世代を進め、それまでのキャッシュを参照できなくする
+    pub async fn bump(&self, kv: &KvStore) -> Result<(), KvError> {
+        kv.put(&self.key(), now().timestamp_millis().to_string())?
+            .execute()
+            .await
+    }
+}
@@ -1,8 +1,10 @@
+use crate::util::etag;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use worker::kv::{KvError, KvStore};
 
 use super::products::{ProductsCreate, ProductsRead};
+use super::schema::{read_kv, write_kv, Document};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CreatePlanDetails {
@@ -20,27 +22,43 @@ pub struct ReadPlanDetails {
     pub additional_info: Option<String>,
 }
 
+impl From<CreatePlanDetails> for ReadPlanDetails {
+    fn from(val: CreatePlanDetails) -> Self {
+        ReadPlanDetails {
+            product: val.product.map(Into::into),
+            additional_info: val.additional_info,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum PlanDetailsCreateError {
     #[error(transparent)]
     KvError(#[from] KvError),
     #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+    #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
 }
 
 impl CreatePlanDetails {
-    pub async fn create(self, kv: KvStore, id: &str) -> Result<(), PlanDetailsCreateError> {
+    pub async fn create(
+        self,
+        kv: KvStore,
+        id: &str,
+    ) -> Result<ReadPlanDetails, PlanDetailsCreateError> {
         // Overwrite (upsert) semantics for PUT
-        let plan_details = ReadPlanDetails {
-            product: self.product.map(Into::into),
-            additional_info: self.additional_info,
-        };
+        let plan_details = ReadPlanDetails::from(self);
 
-        kv.put(id, serde_json::to_string(&plan_details)?)?
-            .execute()
-            .await?;
+        write_kv(
+            &kv,
+            Document::Details,
+            id,
+            &serde_json::to_value(&plan_details)?,
+        )
+        .await?;
 
-        Ok(())
+        Ok(plan_details)
     }
 }
 
@@ -50,12 +68,23 @@ pub enum PlanDetailsReadError {
     NotFound,
     #[error(transparent)]
     KvError(#[from] KvError),
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
 }
 
 impl ReadPlanDetails {
-    pub async fn read(kv: KvStore, id: &str) -> Result<ReadPlanDetails, PlanDetailsReadError> {
-        match kv.get(id).json::<ReadPlanDetails>().await? {
-            Some(plan_details) => Ok(plan_details),
+    /// 企画詳細と、保存されている値のETagを取得する
+    pub async fn read_with_etag(
+        kv: KvStore,
+        id: &str,
+    ) -> Result<(ReadPlanDetails, String), PlanDetailsReadError> {
+        match read_kv(&kv, Document::Details, id).await? {
+            Some(value) => {
+                let etag = etag(&value);
+                Ok((serde_json::from_value(value)?, etag))
+            }
             None => Err(PlanDetailsReadError::NotFound),
         }
     }
@@ -0,0 +1,95 @@
+use super::schedule::Time;
+use chrono::{DateTime, FixedOffset, NaiveDate, Timelike, Utc};
+use worker::Env;
+
+/// 日本標準時（UTC+9）のオフセット（秒）
+const JST_OFFSET_SECS: i32 = 9 * 60 * 60;
+
+/// 開催日（1日目・2日目の順）。環境変数`FESTIVAL_DATES`に`YYYY-MM-DD`をカンマ区切りで指定する
+pub fn dates(env: &Env) -> Vec<NaiveDate> {
+    env.var("FESTIVAL_DATES")
+        .map(|var| {
+            var.to_string()
+                .split(',')
+                .filter_map(|date| date.trim().parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 指定した時刻が開催日であれば、何日目か（1日目は1、2日目は2）と日本時間の時刻を返す
+pub fn resolve(dates: &[NaiveDate], now: DateTime<Utc>) -> Option<(u8, Time)> {
+    let jst = now.with_timezone(&FixedOffset::east_opt(JST_OFFSET_SECS)?);
+    let day = dates
+        .iter()
+        .take(2)
+        .position(|date| *date == jst.date_naive())?;
+    let time = Time::new(jst.hour() as u8, jst.minute() as u8)?;
+    Some((day as u8 + 1, time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn dates() -> Vec<NaiveDate> {
+        vec![
+            NaiveDate::from_ymd_opt(2026, 11, 21).unwrap(),
+            NaiveDate::from_ymd_opt(2026, 11, 22).unwrap(),
+        ]
+    }
+
+    /// 日本時間の日時をUTCで返す
+    fn jst(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        FixedOffset::east_opt(JST_OFFSET_SECS)
+            .unwrap()
+            .with_ymd_and_hms(2026, 11, day, hour, minute, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn resolves_festival_day_and_time_in_jst() {
+        let dates = dates();
+        assert_eq!(
+            resolve(&dates, jst(21, 0, 0)),
+            Some((1, Time::new(0, 0).unwrap()))
+        );
+        assert_eq!(
+            resolve(&dates, jst(21, 23, 59)),
+            Some((1, Time::new(23, 59).unwrap()))
+        );
+        assert_eq!(
+            resolve(&dates, jst(22, 10, 30)),
+            Some((2, Time::new(10, 30).unwrap()))
+        );
+        // UTCでは前日の15時が、日本時間の1日目の0時
+        assert_eq!(
+            resolve(
+                &dates,
+                Utc.with_ymd_and_hms(2026, 11, 20, 15, 0, 0).unwrap()
+            ),
+            Some((1, Time::new(0, 0).unwrap()))
+        );
+    }
+
+    #[test]
+    fn outside_festival_days_resolve_to_none() {
+        let dates = dates();
+        assert_eq!(resolve(&dates, jst(20, 23, 59)), None);
+        assert_eq!(resolve(&dates, jst(23, 0, 0)), None);
+        assert_eq!(resolve(&[], jst(21, 12, 0)), None);
+
+        // 開催日が連続していない場合、間の日は開催日ではない
+        let apart = vec![
+            NaiveDate::from_ymd_opt(2026, 11, 21).unwrap(),
+            NaiveDate::from_ymd_opt(2026, 11, 23).unwrap(),
+        ];
+        assert_eq!(resolve(&apart, jst(22, 12, 0)), None);
+        assert_eq!(
+            resolve(&apart, jst(23, 12, 0)),
+            Some((2, Time::new(12, 0).unwrap()))
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! 企画の座標（`Coordinates`）を使った範囲・距離の計算
+
+use super::base::Coordinates;
+use super::plan::PlanRead;
+use thiserror::Error;
+
+/// 地球の平均半径（メートル）
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+/// `radius_m`を省略した場合の半径（メートル）
+pub const DEFAULT_RADIUS_M: f64 = 500.0;
+/// `radius_m`の最大値（メートル）
+pub const MAX_RADIUS_M: f64 = 10_000.0;
+
+/// 公開APIのエラーのため、メッセージは他の公開APIに合わせて英語とする
+#[derive(Error, Debug)]
+pub enum GeoQueryError {
+    #[error("Invalid bbox: must be min_lng,min_lat,max_lng,max_lat")]
+    InvalidBbox,
+    #[error("Invalid {0}: must be a number between {1} and {2}")]
+    OutOfRange(&'static str, f64, f64),
+    #[error("{0} is required")]
+    Missing(&'static str),
+}
+
+/// 2点間の大円距離（メートル、haversine公式）
+pub fn distance_m(a: &Coordinates, b: &Coordinates) -> f64 {
+    let (lat1, lat2) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (b.longitude - a.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// 数値のクエリパラメーターを範囲を確認して読み込む
+fn parse_number(key: &'static str, value: &str, min: f64, max: f64) -> Result<f64, GeoQueryError> {
+    match value.trim().parse::<f64>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(number),
+        _ => Err(GeoQueryError::OutOfRange(key, min, max)),
+    }
+}
+
+/// 緯度・経度の範囲（`bbox`）。経度180度をまたぐ範囲は扱わない
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub min_lng: f64,
+    pub min_lat: f64,
+    pub max_lng: f64,
+    pub max_lat: f64,
+}
+
+impl BoundingBox {
+    /// GeoJSONと同じ`min_lng,min_lat,max_lng,max_lat`の順で読み込む
+    pub fn parse(value: &str) -> Result<Self, GeoQueryError> {
+        let numbers = value
+            .split(',')
+            .map(|number| number.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| GeoQueryError::InvalidBbox)?;
+        let [min_lng, min_lat, max_lng, max_lat] = numbers[..] else {
+            return Err(GeoQueryError::InvalidBbox);
+        };
+        let valid = (-180.0..=180.0).contains(&min_lng)
+            && (-180.0..=180.0).contains(&max_lng)
+            && (-90.0..=90.0).contains(&min_lat)
+            && (-90.0..=90.0).contains(&max_lat)
+            && min_lng <= max_lng
+            && min_lat <= max_lat;
+        if !valid {
+            return Err(GeoQueryError::InvalidBbox);
+        }
+        Ok(BoundingBox {
+            min_lng,
+            min_lat,
+            max_lng,
+            max_lat,
+        })
+    }
+
+    pub fn contains(&self, coordinates: &Coordinates) -> bool {
+        (self.min_lng..=self.max_lng).contains(&coordinates.longitude)
+            && (self.min_lat..=self.max_lat).contains(&coordinates.latitude)
+    }
+}
+
+/// 近くの企画の検索（`GET /v1/plans/nearby`）の中心と半径
+pub struct NearbyQuery {
+    pub center: Coordinates,
+    pub radius_m: f64,
+}
+
+impl NearbyQuery {
+    /// `lat`・`lng`（必須）と`radius_m`（省略した場合は`DEFAULT_RADIUS_M`）を読み込む
+    pub fn parse(
+        lat: Option<&str>,
+        lng: Option<&str>,
+        radius_m: Option<&str>,
+    ) -> Result<Self, GeoQueryError> {
+        let latitude = parse_number(
+            "lat",
+            lat.ok_or(GeoQueryError::Missing("lat"))?,
+            -90.0,
+            90.0,
+        )?;
+        let longitude = parse_number(
+            "lng",
+            lng.ok_or(GeoQueryError::Missing("lng"))?,
+            -180.0,
+            180.0,
+        )?;
+        let radius_m = radius_m
+            .map(|radius_m| parse_number("radius_m", radius_m, 0.0, MAX_RADIUS_M))
+            .transpose()?
+            .unwrap_or(DEFAULT_RADIUS_M);
+        Ok(NearbyQuery {
+            center: Coordinates {
+                latitude,
+                longitude,
+            },
+            radius_m,
+        })
+    }
+
+    /// 座標のある企画のうち半径以内の企画を、距離の近い順（同じ距離の企画は企画ID順）に距離と一緒に返す
+    pub fn select(&self, plans: Vec<PlanRead>) -> Vec<(f64, PlanRead)> {
+        let mut nearby = plans
+            .into_iter()
+            .filter_map(|plan| {
+                let distance = distance_m(&self.center, plan.coordinates.as_ref()?);
+                (distance <= self.radius_m).then_some((distance, plan))
+            })
+            .collect::<Vec<_>>();
+        nearby.sort_by(|(a, a_plan), (b, b_plan)| {
+            a.total_cmp(b).then_with(|| a_plan.id.cmp(&b_plan.id))
+        });
+        nearby
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::plan;
+    use serde_json::{json, Value};
+
+    fn point(latitude: f64, longitude: f64) -> Coordinates {
+        Coordinates {
+            latitude,
+            longitude,
+        }
+    }
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn haversine_matches_known_distances() {
+        // 経線上の1度・赤道上の1度は、地球の平均半径の円周の1/360
+        let degree = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+        assert_near(
+            distance_m(&point(35.0, 139.0), &point(36.0, 139.0)),
+            degree,
+            0.01,
+        );
+        assert_near(distance_m(&point(0.0, 0.0), &point(0.0, 1.0)), degree, 0.01);
+        assert_near(
+            distance_m(&point(0.0, 0.0), &point(0.0, 180.0)),
+            180.0 * degree,
+            0.01,
+        );
+        assert_eq!(distance_m(&point(35.5, 139.5), &point(35.5, 139.5)), 0.0);
+        // 東京駅から大阪駅まで約403km、パリからロンドンまで約343.5km
+        assert_near(
+            distance_m(&point(35.681236, 139.767125), &point(34.702485, 135.495951)),
+            403_000.0,
+            1_000.0,
+        );
+        assert_near(
+            distance_m(&point(48.8566, 2.3522), &point(51.5074, -0.1278)),
+            343_500.0,
+            1_000.0,
+        );
+    }
+
+    #[test]
+    fn nearby_query_requires_center_and_checks_ranges() {
+        let query = NearbyQuery::parse(Some("35.6"), Some(" 139.7 "), None).unwrap();
+        assert_eq!(query.center.latitude, 35.6);
+        assert_eq!(query.center.longitude, 139.7);
+        assert_eq!(query.radius_m, DEFAULT_RADIUS_M);
+        let edge = NearbyQuery::parse(Some("-90"), Some("180"), Some("10000")).unwrap();
+        assert_eq!(edge.radius_m, MAX_RADIUS_M);
+
+        assert!(matches!(
+            NearbyQuery::parse(None, Some("139.7"), None),
+            Err(GeoQueryError::Missing("lat"))
+        ));
+        assert!(matches!(
+            NearbyQuery::parse(Some("35.6"), None, None),
+            Err(GeoQueryError::Missing("lng"))
+        ));
+        for (lat, lng, radius_m, key) in [
+            ("90.1", "139.7", None, "lat"),
+            ("north", "139.7", None, "lat"),
+            ("NaN", "139.7", None, "lat"),
+            ("35.6", "-180.5", None, "lng"),
+            ("35.6", "inf", None, "lng"),
+            ("35.6", "139.7", Some("-1"), "radius_m"),
+            ("35.6", "139.7", Some("10000.1"), "radius_m"),
+        ] {
+            assert!(
+                matches!(
+                    NearbyQuery::parse(Some(lat), Some(lng), radius_m),
+                    Err(GeoQueryError::OutOfRange(invalid, _, _)) if invalid == key
+                ),
+                "{} {} {:?}",
+                lat,
+                lng,
+                radius_m
+            );
+        }
+    }
+
+    #[test]
+    fn bbox_is_parsed_in_geojson_order() {
+        assert_eq!(
+            BoundingBox::parse("139.5, 35.5,139.8,35.8").unwrap(),
+            BoundingBox {
+                min_lng: 139.5,
+                min_lat: 35.5,
+                max_lng: 139.8,
+                max_lat: 35.8
+            }
+        );
+        // 1点だけの範囲
+        assert!(BoundingBox::parse("139.5,35.5,139.5,35.5").is_ok());
+        for invalid in [
+            "139.8,35.5,139.5,35.8",
+            "139.5,35.8,139.8,35.5",
+            "139.5,35.5,139.8",
+            "139.5,35.5,139.8,35.8,1",
+            "139.5,35.5,139.8,north",
+            "-181,35.5,139.8,35.8",
+            "139.5,-91,139.8,35.8",
+        ] {
+            assert!(
+                matches!(BoundingBox::parse(invalid), Err(GeoQueryError::InvalidBbox)),
+                "{}",
+                invalid
+            );
+        }
+
+        let bbox = BoundingBox::parse("139.5,35.5,139.8,35.8").unwrap();
+        assert!(bbox.contains(&point(35.5, 139.8)));
+        assert!(!bbox.contains(&point(35.49, 139.6)));
+    }
+
+    #[test]
+    fn nearby_excludes_plans_without_coordinates_or_out_of_radius() {
+        let at = |id: &str, coordinates: Value| plan(id, json!({ "coordinates": coordinates }));
+        let plans = vec![
+            at("far", json!({"latitude": 35.01, "longitude": 139.0})),
+            at("none", Value::Null),
+            at("b-near", json!({"latitude": 35.001, "longitude": 139.0})),
+            at("a-near", json!({"latitude": 35.001, "longitude": 139.0})),
+            at("center", json!({"latitude": 35.0, "longitude": 139.0})),
+        ];
+        let query = NearbyQuery::parse(Some("35.0"), Some("139.0"), Some("500")).unwrap();
+
+        let nearby = query.select(plans);
+        let ids = nearby
+            .iter()
+            .map(|(_, plan)| plan.id.as_str())
+            .collect::<Vec<_>>();
+        // 同じ距離の企画は企画ID順
+        assert_eq!(ids, ["center", "a-near", "b-near"]);
+        assert_eq!(nearby[0].0, 0.0);
+        assert_near(nearby[1].0, 111.2, 0.1);
+    }
+}
@@ -0,0 +1,90 @@
+//! 企画一覧のGeoJSON（`GET /v1/plans.geojson`）への変換
+
+use super::plan::PlanRead;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use worker::Url;
+
+/// GeoJSONのContent-Type
+pub const CONTENT_TYPE: &str = "application/geo+json";
+
+/// 座標のある企画をPointのFeatureにし、座標の無い企画の企画IDは`unlocated`にまとめる
+///
+/// `unlocated`はGeoJSONの仕様外のメンバーのため、地図ライブラリからは無視される。
+/// `icon_url`は`icon_ids`に含まれる（アイコンが保存されている）企画のみに設定し、それ以外は`null`とする
+pub fn feature_collection(plans: &[PlanRead], icon_ids: &HashSet<String>, base: &Url) -> Value {
+    let mut features = vec![];
+    let mut unlocated = vec![];
+    for plan in plans {
+        match &plan.coordinates {
+            Some(coordinates) => features.push(json!({
+                "type": "Feature",
+                "id": plan.id,
+                "geometry": {
+                    "type": "Point",
+                    // GeoJSONの座標は経度・緯度の順
+                    "coordinates": [coordinates.longitude, coordinates.latitude]
+                },
+                "properties": properties(plan, icon_ids.contains(&plan.id), base)
+            })),
+            None => unlocated.push(plan.id.clone()),
+        }
+    }
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+        "unlocated": unlocated
+    })
+}
+
+fn properties(plan: &PlanRead, has_icon: bool, base: &Url) -> Value {
+    let icon_url = has_icon
+        .then(|| base.join(&format!("/v1/plans/{}/icon", plan.id)).ok())
+        .flatten()
+        .map(|url| url.to_string());
+    json!({
+        "id": plan.id,
+        "type": plan.r#type.name(),
+        "categories": plan.r#type.category_names(),
+        "plan_name": plan.plan_name,
+        "organization_name": plan.organization_name,
+        "is_recommended": plan.is_recommended,
+        "is_child_friendly": plan.is_child_friendly,
+        "icon_url": icon_url
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::base::Coordinates;
+    use crate::storage::memory::{seed, storage};
+    use futures::executor::block_on;
+
+    #[test]
+    fn icon_url_is_set_only_for_plans_with_icons() {
+        block_on(async {
+            let storage = storage();
+            let mut plans = vec![
+                seed(&storage, "with-icon").await,
+                seed(&storage, "no-icon").await,
+            ];
+            for plan in &mut plans {
+                plan.coordinates = Some(Coordinates {
+                    latitude: 35.0,
+                    longitude: 139.0,
+                });
+            }
+            let icon_ids = HashSet::from(["with-icon".to_string()]);
+            let base = Url::parse("https://example.com/v1/plans.geojson").unwrap();
+
+            let body = feature_collection(&plans, &icon_ids, &base);
+            let features = body["features"].as_array().unwrap();
+            assert_eq!(
+                features[0]["properties"]["icon_url"],
+                "https://example.com/v1/plans/with-icon/icon"
+            );
+            assert!(features[1]["properties"]["icon_url"].is_null());
+        });
+    }
+}
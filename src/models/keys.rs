@@ -1,6 +1,46 @@
+use super::alias::ALIASES_KEY_PREFIX;
+use super::api_key::API_KEYS_KEY_PREFIX;
+use super::cache::CACHE_KEY_PREFIX;
+use super::migration::MIGRATIONS_KEY_PREFIX;
+use super::owners::OWNERS_KEY_PREFIX;
+use super::trash::TRASH_KEY_PREFIX;
+use serde::Serialize;
+use std::future::Future;
 use thiserror::Error;
 use worker::kv::{KvError, KvStore};
 
+/// 企画以外の用途で使用しているキーの接頭辞
+const RESERVED_KEY_PREFIXES: [&str; 7] = [
+    "keys:",
+    OWNERS_KEY_PREFIX,
+    API_KEYS_KEY_PREFIX,
+    CACHE_KEY_PREFIX,
+    MIGRATIONS_KEY_PREFIX,
+    TRASH_KEY_PREFIX,
+    ALIASES_KEY_PREFIX,
+];
+
+/// 企画のキーかどうか
+pub fn is_plan_key(key: &str) -> bool {
+    !RESERVED_KEY_PREFIXES
+        .iter()
+        .any(|prefix| key.starts_with(prefix))
+}
+
+/// 公開APIのパスで企画IDの代わりに使うため、企画IDとして使えない値
+const RESERVED_PLAN_IDS: [&str; 2] = ["now", "nearby"];
+
+/// 企画IDとして使えるか（KVのキー・R2のキー・ルーターのパラメーターとして扱えること）
+pub fn is_valid_plan_id(id: &str) -> bool {
+    !id.is_empty()
+        && is_plan_key(id)
+        && !id.contains(['/', ':', '?', '#'])
+        && !RESERVED_PLAN_IDS.contains(&id)
+}
+
+/// 企画IDの一覧（インデックス）を保存するキー
+const KEYS_INDEX_KEY: &str = "keys:all";
+
 #[derive(Error, Debug)]
 pub enum PutKeysError {
     #[error(transparent)]
@@ -11,29 +51,91 @@ pub enum PutKeysError {
     SerdeError(#[from] serde_json::Error),
 }
 
-pub async fn put_keys(kv: &KvStore) -> Result<(), PutKeysError> {
+/// キー一覧の1ページ分
+struct KeysPage {
+    keys: Vec<String>,
+    cursor: Option<String>,
+    list_complete: bool,
+}
+
+/// namespaceを列挙して企画のキーを全て取得する
+pub async fn list_plan_keys(kv: &KvStore) -> Result<Vec<String>, KvError> {
+    collect_plan_keys(|cursor| async move {
+        let mut list = kv.list();
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let list = list.execute().await?;
+        Ok(KeysPage {
+            keys: list.keys.into_iter().map(|key| key.name).collect(),
+            cursor: list.cursor,
+            list_complete: list.list_complete,
+        })
+    })
+    .await
+}
+
+/// `fetch`でページを順に取得し、企画のキーだけを集める
+async fn collect_plan_keys<F, Fut, E>(mut fetch: F) -> Result<Vec<String>, E>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<KeysPage, E>>,
+{
     let mut keys = vec![];
+    let mut cursor: Option<String> = None;
     loop {
-        let list = kv.list().execute().await?;
-        for key in list.keys {
-            if key.name.starts_with("keys:") {
-                continue;
-            }
-            keys.push(key.name);
-        }
-        if list.list_complete {
-            break;
+        let page = fetch(cursor.take()).await?;
+        keys.extend(page.keys.into_iter().filter(|name| is_plan_key(name)));
+        match page.cursor {
+            Some(next) if !page.list_complete => cursor = Some(next),
+            _ => break,
         }
     }
 
     keys.sort();
-    kv.put("keys:all", serde_json::to_string(&keys)?)?
+    Ok(keys)
+}
+
+async fn write_keys(kv: &KvStore, keys: &[String]) -> Result<(), PutKeysError> {
+    kv.put(KEYS_INDEX_KEY, serde_json::to_string(keys)?)?
         .execute()
         .await?;
-
     Ok(())
 }
 
+/// namespaceを列挙してインデックスを作り直し、その内容を返す
+pub async fn put_keys(kv: &KvStore) -> Result<Vec<String>, PutKeysError> {
+    let keys = list_plan_keys(kv).await?;
+    write_keys(kv, &keys).await?;
+    Ok(keys)
+}
+
+/// インデックスに企画IDを追加・削除する
+///
+/// インデックスが無い場合は作り直す（作り直した時点でKVの内容が反映されている）
+pub async fn update_keys(
+    kv: &KvStore,
+    added: &[String],
+    removed: &[String],
+) -> Result<(), PutKeysError> {
+    let Some(keys_json) = kv.get(KEYS_INDEX_KEY).text().await? else {
+        put_keys(kv).await?;
+        return Ok(());
+    };
+
+    let keys = merge_keys(serde_json::from_str(&keys_json)?, added, removed);
+    write_keys(kv, &keys).await
+}
+
+/// インデックスの内容に追加・削除を反映する。同じ追加・削除を繰り返しても結果は変わらない
+fn merge_keys(mut keys: Vec<String>, added: &[String], removed: &[String]) -> Vec<String> {
+    keys.retain(|key| !removed.contains(key));
+    keys.extend(added.iter().filter(|key| is_plan_key(key)).cloned());
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
 #[derive(Error, Debug)]
 pub enum GetKeysError {
     #[error(transparent)]
@@ -45,16 +147,151 @@ pub enum GetKeysError {
 }
 
 pub async fn get_keys(kv: &KvStore) -> Result<Vec<String>, GetKeysError> {
-    loop {
-        match kv.get("keys:all").text().await? {
-            Some(keys_json) => {
-                let keys: Vec<String> = serde_json::from_str(&keys_json)?;
-                return Ok(keys);
-            }
-            None => {
-                // Cache miss - generate cache using put_keys
-                put_keys(kv).await?;
-            }
+    match kv.get(KEYS_INDEX_KEY).text().await? {
+        Some(keys_json) => Ok(serde_json::from_str(&keys_json)?),
+        // Cache miss - generate cache using put_keys
+        None => Ok(put_keys(kv).await?),
+    }
+}
+
+/// インデックスとKVの内容の差分
+#[derive(Serialize, Clone, Debug)]
+pub struct KeysCheck {
+    pub consistent: bool,
+    /// インデックスに含まれる企画IDの数
+    pub indexed: usize,
+    /// KVに保存されている企画の数
+    pub stored: usize,
+    /// KVに存在するがインデックスに含まれていない企画ID
+    pub missing_from_index: Vec<String>,
+    /// インデックスに含まれるがKVに存在しない企画ID
+    pub missing_from_kv: Vec<String>,
+}
+
+/// インデックスとKVの内容が一致しているか確認する
+pub async fn check_keys(kv: &KvStore) -> Result<KeysCheck, GetKeysError> {
+    let indexed = match kv.get(KEYS_INDEX_KEY).text().await? {
+        Some(keys_json) => serde_json::from_str::<Vec<String>>(&keys_json)?,
+        None => vec![],
+    };
+    let stored = list_plan_keys(kv).await?;
+    Ok(KeysCheck::new(&indexed, &stored))
+}
+
+impl KeysCheck {
+    fn new(indexed: &[String], stored: &[String]) -> KeysCheck {
+        let missing_from_index = stored
+            .iter()
+            .filter(|key| !indexed.contains(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        let missing_from_kv = indexed
+            .iter()
+            .filter(|key| !stored.contains(key))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        KeysCheck {
+            consistent: missing_from_index.is_empty() && missing_from_kv.is_empty(),
+            indexed: indexed.len(),
+            stored: stored.len(),
+            missing_from_index,
+            missing_from_kv,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn page(names: &[&str], cursor: Option<&str>, list_complete: bool) -> KeysPage {
+        KeysPage {
+            keys: keys(names),
+            cursor: cursor.map(Into::into),
+            list_complete,
+        }
+    }
+
+    fn collect(pages: Vec<KeysPage>) -> (Vec<String>, Vec<Option<String>>) {
+        let mut pages = pages.into_iter();
+        let mut cursors = vec![];
+        let keys = block_on(collect_plan_keys(|cursor| {
+            cursors.push(cursor);
+            let page = pages.next().expect("requested a page past the end");
+            async move { Ok::<_, ()>(page) }
+        }))
+        .unwrap();
+        (keys, cursors)
+    }
+
+    #[test]
+    fn listing_follows_cursor_until_complete() {
+        let (listed, cursors) = collect(vec![
+            page(&["b", "keys:all"], Some("c1"), false),
+            page(&["owners:b", "a"], Some("c2"), false),
+            page(&["c"], None, true),
+        ]);
+        assert_eq!(listed, keys(&["a", "b", "c"]));
+        assert_eq!(cursors, vec![None, Some("c1".into()), Some("c2".into())]);
+    }
+
+    #[test]
+    fn listing_stops_on_complete_page_even_with_cursor() {
+        let (listed, cursors) = collect(vec![page(&["a"], Some("c1"), true)]);
+        assert_eq!(listed, keys(&["a"]));
+        assert_eq!(cursors, vec![None]);
+
+        let (listed, _) = collect(vec![page(&[], None, false)]);
+        assert!(listed.is_empty());
+    }
+
+    #[test]
+    fn merging_keys_is_idempotent() {
+        let index = keys(&["a", "c"]);
+        let added = keys(&["b", "cache:x"]);
+        let removed = keys(&["c", "missing"]);
+
+        let once = merge_keys(index, &added, &removed);
+        assert_eq!(once, keys(&["a", "b"]));
+        assert_eq!(merge_keys(once.clone(), &added, &removed), once);
+        assert_eq!(merge_keys(once.clone(), &keys(&["a"]), &[]), once);
+        assert_eq!(merge_keys(once.clone(), &[], &keys(&["z"])), once);
+    }
+
+    #[test]
+    fn key_added_and_removed_together_stays_indexed() {
+        assert_eq!(
+            merge_keys(keys(&["a"]), &keys(&["a"]), &keys(&["a"])),
+            keys(&["a"])
+        );
+    }
+
+    #[test]
+    fn check_reports_differences_in_both_directions() {
+        let check = KeysCheck::new(&keys(&["a", "b"]), &keys(&["b", "c"]));
+        assert!(!check.consistent);
+        assert_eq!((check.indexed, check.stored), (2, 2));
+        assert_eq!(check.missing_from_index, keys(&["c"]));
+        assert_eq!(check.missing_from_kv, keys(&["a"]));
+
+        assert!(KeysCheck::new(&keys(&["a"]), &keys(&["a"])).consistent);
+        assert!(KeysCheck::new(&[], &[]).consistent);
+    }
+
+    #[test]
+    fn plan_ids_exclude_routes_and_reserved_keys() {
+        assert!(is_valid_plan_id("booth-1"));
+        for id in ["", "now", "nearby", "a/b", "a:b", "a?b", "a#b"] {
+            assert!(!is_valid_plan_id(id), "{:?}", id);
+        }
+        for prefix in RESERVED_KEY_PREFIXES {
+            assert!(!is_valid_plan_id(&format!("{}1", prefix)), "{:?}", prefix);
         }
     }
 }
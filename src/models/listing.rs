@@ -0,0 +1,421 @@
+//! 企画一覧（`GET /v1/plans`）の並び替え・ページ分割・フィールドの選択
+
+use super::plan::PlanRead;
+use super::schedule::ScheduleRead;
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use thiserror::Error;
+
+/// `limit`の最大値
+pub const MAX_LIMIT: usize = 500;
+/// `cursor`のみを指定した場合の件数
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// `fields`で選択できるフィールド（`id`は常に含める）
+pub const PLAN_FIELDS: [&str; 12] = [
+    "id",
+    "type",
+    "categories",
+    "is_lab_tour",
+    "organization_name",
+    "plan_name",
+    "description",
+    "is_child_friendly",
+    "is_recommended",
+    "schedule",
+    "location",
+    "coordinates",
+];
+
+/// 公開APIのエラーのため、メッセージは他の公開APIに合わせて英語とする
+#[derive(Error, Debug)]
+pub enum ListQueryError {
+    #[error("Invalid sort: {0}")]
+    InvalidSort(String),
+    #[error("Invalid order: {0}")]
+    InvalidOrder(String),
+    #[error("Invalid limit: must be between 1 and {MAX_LIMIT}")]
+    InvalidLimit,
+    #[error("Invalid cursor.")]
+    InvalidCursor,
+    #[error("Unknown field: {0}")]
+    UnknownField(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Id,
+    PlanName,
+    OrganizationName,
+    /// 最初の実施日時（1日目・2日目の順）。実施日時の無い企画は昇順・降順ともに最後にする
+    StartTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// 並び替えに使う値
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+enum SortKey {
+    Text(String),
+    /// 1日目の0時からの分数
+    Minutes(u32),
+    Missing,
+}
+
+/// 企画一覧の並び順。値が同じ企画は企画ID順にする
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PlanSort {
+    pub field: SortField,
+    pub order: SortOrder,
+}
+
+impl PlanSort {
+    /// `sort`（`id`・`plan_name`・`organization_name`・`start_time`）と`order`（`asc`・`desc`）を読み込む
+    pub fn parse(sort: Option<&str>, order: Option<&str>) -> Result<Self, ListQueryError> {
+        let field = match sort {
+            None => SortField::default(),
+            Some(sort) => serde_json::from_value(Value::String(sort.to_string()))
+                .map_err(|_| ListQueryError::InvalidSort(sort.to_string()))?,
+        };
+        let order = match order {
+            None => SortOrder::default(),
+            Some(order) => serde_json::from_value(Value::String(order.to_string()))
+                .map_err(|_| ListQueryError::InvalidOrder(order.to_string()))?,
+        };
+        Ok(PlanSort { field, order })
+    }
+
+    fn key(&self, plan: &PlanRead) -> SortKey {
+        match self.field {
+            SortField::Id => SortKey::Text(plan.id.clone()),
+            SortField::PlanName => SortKey::Text(plan.plan_name.clone()),
+            SortField::OrganizationName => SortKey::Text(plan.organization_name.clone()),
+            SortField::StartTime => {
+                start_minutes(&plan.schedule).map_or(SortKey::Missing, SortKey::Minutes)
+            }
+        }
+    }
+
+    fn compare(&self, (a, a_id): (&SortKey, &str), (b, b_id): (&SortKey, &str)) -> Ordering {
+        let ordering = match (a, b) {
+            (SortKey::Missing, SortKey::Missing) => Ordering::Equal,
+            (SortKey::Missing, _) => Ordering::Greater,
+            (_, SortKey::Missing) => Ordering::Less,
+            (SortKey::Text(a), SortKey::Text(b)) => self.directed(a.cmp(b)),
+            (SortKey::Minutes(a), SortKey::Minutes(b)) => self.directed(a.cmp(b)),
+            // 同じ並び順では同じ種類の値のみを比較する
+            _ => Ordering::Equal,
+        };
+        ordering.then_with(|| a_id.cmp(b_id))
+    }
+
+    fn directed(&self, ordering: Ordering) -> Ordering {
+        match self.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+}
+
+/// 最初の実施日時の、1日目の0時からの分数
+fn start_minutes(schedule: &ScheduleRead) -> Option<u32> {
+    let ScheduleRead::NotCombined { day1, day2 } = schedule.uncombine() else {
+        return None;
+    };
+    [(0, day1), (24 * 60, day2)]
+        .into_iter()
+        .find_map(|(offset, day)| {
+            day.iter()
+                .map(|schedule| schedule.start_time.minutes())
+                .min()
+                .map(|minutes| offset + minutes as u32)
+        })
+}
+
+/// 次のページの位置。最後の企画の並び替えの値と企画IDを保持する
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cursor {
+    sort: SortField,
+    order: SortOrder,
+    key: SortKey,
+    id: String,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        Base64UrlSafeNoPadding::encode_to_string(json).unwrap_or_default()
+    }
+
+    /// 読み込んだカーソルが並び順と一致しない場合はエラーとする
+    pub fn decode(cursor: &str, sort: &PlanSort) -> Result<Self, ListQueryError> {
+        let json = Base64UrlSafeNoPadding::decode_to_vec(cursor, None)
+            .map_err(|_| ListQueryError::InvalidCursor)?;
+        let cursor =
+            serde_json::from_slice::<Cursor>(&json).map_err(|_| ListQueryError::InvalidCursor)?;
+        if cursor.sort != sort.field || cursor.order != sort.order {
+            return Err(ListQueryError::InvalidCursor);
+        }
+        Ok(cursor)
+    }
+}
+
+/// 企画一覧の並び順・ページ分割・フィールドの指定
+#[derive(Debug, Default)]
+pub struct ListQuery {
+    pub sort: PlanSort,
+    pub cursor: Option<Cursor>,
+    /// 指定されない場合はページ分割しない
+    pub limit: Option<usize>,
+    /// 指定されない場合は全てのフィールドを返す
+    pub fields: Option<Vec<String>>,
+}
+
+impl ListQuery {
+    pub fn parse(
+        sort: Option<&str>,
+        order: Option<&str>,
+        limit: Option<&str>,
+        cursor: Option<&str>,
+        fields: Option<&str>,
+    ) -> Result<Self, ListQueryError> {
+        let sort = PlanSort::parse(sort, order)?;
+        let cursor = cursor
+            .map(|cursor| Cursor::decode(cursor, &sort))
+            .transpose()?;
+        let limit = match limit {
+            Some(limit) => Some(parse_limit(limit)?),
+            None => cursor.as_ref().map(|_| DEFAULT_LIMIT),
+        };
+        let fields = fields.map(parse_fields).transpose()?;
+        Ok(ListQuery {
+            sort,
+            cursor,
+            limit,
+            fields,
+        })
+    }
+}
+
+/// `limit`を読み込む
+fn parse_limit(limit: &str) -> Result<usize, ListQueryError> {
+    match limit.parse::<usize>() {
+        Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+        _ => Err(ListQueryError::InvalidLimit),
+    }
+}
+
+/// 企画を並び替え、`limit`が指定された場合は`cursor`の次から`limit`件を返す
+///
+/// 続きがある場合は次のページのカーソルも返す
+pub fn paginate(
+    plans: Vec<PlanRead>,
+    sort: &PlanSort,
+    limit: Option<usize>,
+    cursor: Option<&Cursor>,
+) -> (Vec<PlanRead>, Option<Cursor>) {
+    let mut keyed = plans
+        .into_iter()
+        .map(|plan| (sort.key(&plan), plan))
+        .collect::<Vec<_>>();
+    keyed.sort_by(|(a, a_plan), (b, b_plan)| sort.compare((a, &a_plan.id), (b, &b_plan.id)));
+
+    if let Some(cursor) = cursor {
+        keyed.retain(|(key, plan)| {
+            sort.compare((key, &plan.id), (&cursor.key, &cursor.id)) == Ordering::Greater
+        });
+    }
+
+    let Some(limit) = limit else {
+        return (keyed.into_iter().map(|(_, plan)| plan).collect(), None);
+    };
+    let has_more = keyed.len() > limit;
+    keyed.truncate(limit);
+    let next = match keyed.last() {
+        Some((key, plan)) if has_more => Some(Cursor {
+            sort: sort.field,
+            order: sort.order,
+            key: key.clone(),
+            id: plan.id.clone(),
+        }),
+        _ => None,
+    };
+    (keyed.into_iter().map(|(_, plan)| plan).collect(), next)
+}
+
+/// `fields`（カンマ区切り）を読み込む。`id`は指定しなくても含める
+fn parse_fields(fields: &str) -> Result<Vec<String>, ListQueryError> {
+    let mut selected = vec!["id".to_string()];
+    for field in fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+    {
+        if !PLAN_FIELDS.contains(&field) {
+            return Err(ListQueryError::UnknownField(field.to_string()));
+        }
+        if !selected.iter().any(|selected| selected == field) {
+            selected.push(field.to_string());
+        }
+    }
+    Ok(selected)
+}
+
+/// 企画の値から指定されたフィールドのみを残す（企画の種類に無いフィールドは含めない）
+pub fn select_fields(value: Value, fields: &[String]) -> Value {
+    match value {
+        Value::Object(mut map) => {
+            map.retain(|key, _| fields.iter().any(|field| field == key));
+            Value::Object(map)
+        }
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::plan;
+    use serde_json::json;
+
+    fn ids(plans: &[PlanRead]) -> Vec<&str> {
+        plans.iter().map(|plan| plan.id.as_str()).collect()
+    }
+
+    /// 同じ企画名の企画を含む一覧
+    fn plans() -> Vec<PlanRead> {
+        vec![
+            plan("d", json!({"plan_name": "B"})),
+            plan("b", json!({"plan_name": "A"})),
+            plan("c", json!({"plan_name": "B"})),
+            plan("a", json!({"plan_name": "B"})),
+        ]
+    }
+
+    /// カーソルをたどって全てのページを取得する
+    fn pages(sort: &PlanSort, limit: usize) -> Vec<Vec<String>> {
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next) = paginate(plans(), sort, Some(limit), cursor.as_ref());
+            pages.push(page.into_iter().map(|plan| plan.id).collect());
+            match next {
+                // 公開APIと同じく、文字列を経由して次のページを求める
+                Some(next) => cursor = Some(Cursor::decode(&next.encode(), sort).unwrap()),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn ties_are_ordered_by_id_in_both_directions() {
+        let asc = PlanSort::parse(Some("plan_name"), None).unwrap();
+        let (sorted, next) = paginate(plans(), &asc, None, None);
+        assert_eq!(ids(&sorted), ["b", "a", "c", "d"]);
+        assert!(next.is_none());
+
+        let desc = PlanSort::parse(Some("plan_name"), Some("desc")).unwrap();
+        let (sorted, _) = paginate(plans(), &desc, None, None);
+        assert_eq!(ids(&sorted), ["a", "c", "d", "b"]);
+    }
+
+    #[test]
+    fn cursor_pages_through_ties_without_gaps_or_duplicates() {
+        let sort = PlanSort::parse(Some("plan_name"), None).unwrap();
+        assert_eq!(pages(&sort, 1), [["b"], ["a"], ["c"], ["d"]]);
+        assert_eq!(pages(&sort, 3), [vec!["b", "a", "c"], vec!["d"]]);
+        // ちょうど割り切れる場合は最後のページにカーソルを付けない
+        assert_eq!(pages(&sort, 2), [["b", "a"], ["c", "d"]]);
+    }
+
+    #[test]
+    fn plans_without_schedule_come_last_in_both_orders() {
+        let slot = |start: &str| json!({"schedule": {"day1": [], "day2": [{"start_time": start, "end_time": "23:00"}]}});
+        let plans = || {
+            vec![
+                plan("none", json!({})),
+                plan("late", slot("12:00")),
+                plan("early", slot("09:00")),
+            ]
+        };
+        let asc = PlanSort::parse(Some("start_time"), None).unwrap();
+        assert_eq!(
+            ids(&paginate(plans(), &asc, None, None).0),
+            ["early", "late", "none"]
+        );
+        let desc = PlanSort::parse(Some("start_time"), Some("desc")).unwrap();
+        assert_eq!(
+            ids(&paginate(plans(), &desc, None, None).0),
+            ["late", "early", "none"]
+        );
+    }
+
+    #[test]
+    fn tampered_or_mismatched_cursors_are_rejected() {
+        let sort = PlanSort::parse(Some("plan_name"), None).unwrap();
+        let (_, next) = paginate(plans(), &sort, Some(1), None);
+        let cursor = next.unwrap().encode();
+
+        assert!(ListQuery::parse(Some("plan_name"), None, None, Some(&cursor), None).is_ok());
+        for invalid in [
+            "not base64!",
+            &Base64UrlSafeNoPadding::encode_to_string(r#"{"id":"a"}"#).unwrap(),
+            &cursor[..cursor.len() - 2],
+        ] {
+            assert!(matches!(
+                ListQuery::parse(Some("plan_name"), None, None, Some(invalid), None),
+                Err(ListQueryError::InvalidCursor)
+            ));
+        }
+        // 別の並び順のカーソル
+        assert!(matches!(
+            ListQuery::parse(Some("plan_name"), Some("desc"), None, Some(&cursor), None),
+            Err(ListQueryError::InvalidCursor)
+        ));
+    }
+
+    #[test]
+    fn cursor_alone_uses_default_limit() {
+        let sort = PlanSort::default();
+        let (_, next) = paginate(plans(), &sort, Some(1), None);
+        let query =
+            ListQuery::parse(None, None, None, Some(&next.unwrap().encode()), None).unwrap();
+        assert_eq!(query.limit, Some(DEFAULT_LIMIT));
+        assert!(matches!(
+            ListQuery::parse(None, None, Some("0"), None, None),
+            Err(ListQueryError::InvalidLimit)
+        ));
+        assert!(matches!(
+            ListQuery::parse(None, None, Some(&(MAX_LIMIT + 1).to_string()), None, None),
+            Err(ListQueryError::InvalidLimit)
+        ));
+    }
+
+    #[test]
+    fn fields_always_include_id_and_drop_unselected_members() {
+        let fields = parse_fields("plan_name, is_lab_tour,plan_name,").unwrap();
+        assert_eq!(fields, ["id", "plan_name", "is_lab_tour"]);
+        assert!(matches!(
+            parse_fields("plan_name,secret"),
+            Err(ListQueryError::UnknownField(field)) if field == "secret"
+        ));
+
+        let value = serde_json::to_value(plan("a", json!({}))).unwrap();
+        // 一般企画には`is_lab_tour`が無いため含めない
+        assert_eq!(
+            select_fields(value, &fields),
+            json!({"id": "a", "plan_name": "テスト企画"})
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use crate::util::now;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use worker::kv::{KvError, KvStore};
+
+pub const MIGRATIONS_KEY_PREFIX: &str = "migrations:";
+
+/// 移行の対象
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationPhase {
+    Plans,
+    Details,
+    Completed,
+}
+
+/// 移行できなかった値
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MigrationFailure {
+    pub phase: MigrationPhase,
+    pub id: String,
+    pub message: String,
+}
+
+/// KVからD1への移行の進捗
+///
+/// 企画と同じnamespaceに`migrations:d1`のキーで保存し、途中で中断しても続きから再開できるようにする
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MigrationState {
+    pub phase: MigrationPhase,
+    /// 現在の対象で最後に処理したキー
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    pub plans_migrated: usize,
+    pub details_migrated: usize,
+    #[serde(default)]
+    pub failures: Vec<MigrationFailure>,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Error, Debug)]
+pub enum MigrationStateError {
+    #[error(transparent)]
+    KvError(#[from] KvError),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+}
+
+const D1_MIGRATION_KEY: &str = "migrations:d1";
+
+impl MigrationState {
+    pub fn start() -> Self {
+        let timestamp = now();
+        MigrationState {
+            phase: MigrationPhase::Plans,
+            cursor: None,
+            plans_migrated: 0,
+            details_migrated: 0,
+            failures: vec![],
+            started_at: timestamp,
+            updated_at: timestamp,
+        }
+    }
+
+    /// 進捗を取得する。開始していない場合は`None`を返す
+    pub async fn read(kv: &KvStore) -> Result<Option<MigrationState>, MigrationStateError> {
+        Ok(kv.get(D1_MIGRATION_KEY).json::<MigrationState>().await?)
+    }
+
+    pub async fn write(&self, kv: &KvStore) -> Result<(), MigrationStateError> {
+        kv.put(D1_MIGRATION_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// 失敗を記録する。同じ値の以前の失敗は置き換える
+    pub fn record_failure(&mut self, failure: MigrationFailure) {
+        self.failures
+            .retain(|f| !(f.phase == failure.phase && f.id == failure.id));
+        self.failures.push(failure);
+    }
+
+    /// 移行できた値の以前の失敗を取り除く
+    pub fn clear_failure(&mut self, phase: MigrationPhase, id: &str) {
+        self.failures.retain(|f| !(f.phase == phase && f.id == id));
+    }
+}
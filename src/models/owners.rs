@@ -0,0 +1,88 @@
+use crate::auth::Principal;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use worker::kv::{KvError, KvStore};
+
+/// 企画の所有者（出展団体の代表者）
+///
+/// 企画と同じnamespaceに`owners:<plan_id>`のキーで保存する
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PlanOwners {
+    /// 所有者として扱うトークンの`sub`
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    /// 所有者として扱うトークンの`groups`クレームの値
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum PlanOwnersError {
+    #[error(transparent)]
+    KvError(#[from] KvError),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+}
+
+pub const OWNERS_KEY_PREFIX: &str = "owners:";
+
+fn owners_key(plan_id: &str) -> String {
+    format!("{}{}", OWNERS_KEY_PREFIX, plan_id)
+}
+
+impl PlanOwners {
+    /// 所有者を取得する。未設定の場合は空の所有者を返す
+    pub async fn read(kv: &KvStore, plan_id: &str) -> Result<PlanOwners, PlanOwnersError> {
+        Ok(kv
+            .get(&owners_key(plan_id))
+            .json::<PlanOwners>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn write(&self, kv: &KvStore, plan_id: &str) -> Result<(), PlanOwnersError> {
+        kv.put(&owners_key(plan_id), serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(kv: &KvStore, plan_id: &str) -> Result<(), PlanOwnersError> {
+        kv.delete(&owners_key(plan_id)).await?;
+        Ok(())
+    }
+
+    /// 所有者が設定されている企画IDを全て取得する
+    pub async fn ids(kv: &KvStore) -> Result<Vec<String>, PlanOwnersError> {
+        let mut ids = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = kv.list().prefix(OWNERS_KEY_PREFIX.into());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let list = list.execute().await?;
+            ids.extend(
+                list.keys
+                    .into_iter()
+                    .filter_map(|key| key.name.strip_prefix(OWNERS_KEY_PREFIX).map(String::from)),
+            );
+            match list.cursor {
+                Some(next) if !list.list_complete => cursor = Some(next),
+                _ => break,
+            }
+        }
+
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn allows(&self, principal: &Principal) -> bool {
+        self.subjects.contains(&principal.subject)
+            || principal
+                .claims
+                .groups
+                .iter()
+                .any(|group| self.groups.contains(group))
+    }
+}
@@ -1,13 +1,15 @@
-use crate::util::{deep_merge, kv_bulk_get_values};
+use crate::util::{deep_merge, etag, if_match, kv_bulk_get_values};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
 use worker::kv::{KvError, KvStore};
 
 use super::base::{Coordinates, Location};
-use super::keys::{get_keys, GetKeysError};
-use super::plan_type::{PlanTypeCreate, PlanTypeRead, PlanTypeUpdate};
-use super::schedule::{ScheduleCreate, ScheduleRead, ScheduleUpdate};
+use super::geo::{BoundingBox, GeoQueryError};
+use super::keys::{get_keys, is_plan_key, GetKeysError};
+use super::plan_type::{is_plan_category, PlanTypeCreate, PlanTypeRead, PlanTypeUpdate};
+use super::schedule::{ScheduleCreate, ScheduleRead, ScheduleUpdate, ScheduleWindow};
+use super::schema::{read_kv, unstamp, write_back, write_kv, Document};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PlanCreate {
@@ -62,50 +64,210 @@ pub struct PlanUpdate {
     pub coordinates: Option<Option<Coordinates>>,
 }
 
+/// 一括更新の1件分。`if_match`で更新前の値のETagを指定できる
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PlanBulkUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_match: Option<String>,
+    #[serde(flatten)]
+    pub update: PlanUpdate,
+}
+
+/// 企画一覧の絞り込み条件
+#[derive(Clone, Debug, Default)]
+pub struct PlanFilter {
+    /// 企画の種類（`booth`、`general`、`stage`、`labo`）
+    pub types: Option<Vec<String>>,
+    pub recommended: Option<bool>,
+    pub child_friendly: Option<bool>,
+    /// 研究室企画のみに適用し、それ以外の種類の企画は常に含める
+    pub lab_tour: Option<bool>,
+    /// 模擬店・一般企画のカテゴリー。いずれかのカテゴリーを持つ企画に絞り込む
+    pub categories: Option<Vec<String>>,
+    /// 屋内の場所の建物
+    pub buildings: Option<Vec<String>>,
+    /// 屋内の場所の部屋
+    pub rooms: Option<Vec<String>>,
+    /// 屋外の場所の名前
+    pub outdoor: Option<Vec<String>>,
+    /// 実施日時の時間帯
+    pub schedule: Option<ScheduleWindow>,
+    /// 座標の範囲。座標の無い企画は含めない
+    pub bbox: Option<BoundingBox>,
+}
+
+/// 公開APIのエラーのため、メッセージは他の公開APIに合わせて英語とする
+#[derive(Error, Debug)]
+pub enum PlanFilterError {
+    #[error("Unknown category: {0}")]
+    UnknownCategory(String),
+    #[error("Invalid {0}: values must not be empty")]
+    EmptyValue(String),
+    #[error(transparent)]
+    Geo(#[from] GeoQueryError),
+}
+
+/// カンマ区切りの値を読み込む。空の値はエラーとする
+fn parse_list(key: &str, value: &str) -> Result<Vec<String>, PlanFilterError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .map(|value| match value {
+            "" => Err(PlanFilterError::EmptyValue(key.to_string())),
+            value => Ok(value.to_string()),
+        })
+        .collect()
+}
+
+impl PlanFilter {
+    /// 絞り込み条件のクエリパラメーターを読み込む。絞り込み条件以外のパラメーターの場合は`false`を返す
+    ///
+    /// `category`・場所・`bbox`の値が不正な場合はエラーを返す
+    pub fn parse_param(&mut self, key: &str, value: &str) -> Result<bool, PlanFilterError> {
+        match key {
+            // 以前からある条件は互換性のため値を検証しない（不明な種類には一致せず、真偽値以外は指定しないものとする）
+            "type" => self.types = Some(value.split(',').map(str::to_string).collect()),
+            "recommended" => self.recommended = value.parse().ok(),
+            "child_friendly" => self.child_friendly = value.parse().ok(),
+            "lab_tour" => self.lab_tour = value.parse().ok(),
+            "category" => {
+                let categories = parse_list(key, value)?;
+                if let Some(unknown) = categories.iter().find(|c| !is_plan_category(c)) {
+                    return Err(PlanFilterError::UnknownCategory(unknown.clone()));
+                }
+                self.categories = Some(categories);
+            }
+            "building" => self.buildings = Some(parse_list(key, value)?),
+            "room" => self.rooms = Some(parse_list(key, value)?),
+            "outdoor" => self.outdoor = Some(parse_list(key, value)?),
+            "bbox" => self.bbox = Some(BoundingBox::parse(value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// 場所の絞り込み条件があるかどうか
+    pub fn has_location(&self) -> bool {
+        self.buildings.is_some() || self.rooms.is_some() || self.outdoor.is_some()
+    }
+
+    /// 場所が絞り込み条件に合うかどうか
+    ///
+    /// `building`・`room`は同じ屋内の場所で両方を満たす必要があり、`outdoor`とはいずれかを満たせばよい
+    pub fn matches_location(&self, location: &Location) -> bool {
+        let contains = |values: &Option<Vec<String>>, value: &String| {
+            values.as_ref().is_none_or(|values| values.contains(value))
+        };
+        match location {
+            Location::IndoorLocation { building, room } => {
+                (self.buildings.is_some() || self.rooms.is_some())
+                    && contains(&self.buildings, building)
+                    && contains(&self.rooms, room)
+            }
+            Location::OutdoorLocation { name } => self
+                .outdoor
+                .as_ref()
+                .is_some_and(|outdoor| outdoor.contains(name)),
+        }
+    }
+
+    pub fn matches(&self, plan: &PlanRead) -> bool {
+        let mut flag = (self.recommended == Some(plan.is_recommended)
+            || self.recommended.is_none())
+            && (self.child_friendly == Some(plan.is_child_friendly)
+                || self.child_friendly.is_none());
+
+        if let PlanTypeRead::Labo { is_lab_tour } = plan.r#type {
+            flag = flag && (self.lab_tour == Some(is_lab_tour) || self.lab_tour.is_none());
+        }
+
+        if let Some(categories) = &self.categories {
+            let names = plan.r#type.category_names();
+            flag = flag && categories.iter().any(|category| names.contains(category));
+        }
+
+        // 企画の場所と、実施日時ごとの場所のいずれかが合えばよい
+        if self.has_location() {
+            let mut locations = plan.location.clone();
+            if let ScheduleRead::NotCombined { day1, day2 } = plan.schedule.uncombine() {
+                locations.extend(
+                    day1.iter()
+                        .chain(day2.iter())
+                        .filter_map(|schedule| schedule.location().cloned()),
+                );
+            }
+            flag = flag && locations.iter().any(|l| self.matches_location(l));
+        }
+
+        if let Some(window) = &self.schedule {
+            flag = flag && window.matches(&plan.schedule);
+        }
+
+        if let Some(bbox) = &self.bbox {
+            flag = flag && plan.coordinates.as_ref().is_some_and(|c| bbox.contains(c));
+        }
+
+        let Some(types) = &self.types else {
+            return flag;
+        };
+        flag && types.iter().any(|t| t == plan.r#type.name())
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum PlanCreateError {
     #[error("Conflict")]
     Conflict,
     #[error(transparent)]
     KvError(#[from] KvError),
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
 }
 
 impl PlanCreate {
-    pub async fn create(self, kv: KvStore, id: &str) -> Result<(), PlanCreateError> {
+    /// 作成する企画を、指定したIDの`PlanRead`に変換する
+    pub fn into_read(self, id: &str) -> PlanRead {
+        PlanRead {
+            id: id.to_string(),
+            r#type: self.r#type.into(),
+            organization_name: self.organization_name,
+            plan_name: self.plan_name,
+            description: self.description,
+            is_child_friendly: self.is_child_friendly,
+            is_recommended: self.is_recommended,
+            schedule: self.schedule.into(),
+            location: self.location,
+            coordinates: self.coordinates,
+        }
+    }
+
+    pub async fn create(self, kv: KvStore, id: &str) -> Result<PlanRead, PlanCreateError> {
         // conflict check
-        if kv.get(id).text().await? != None {
+        if kv.get(id).text().await?.is_some() {
             return Err(PlanCreateError::Conflict);
         }
 
         // create
-        kv.put(
+        let plan = self.into_read(id);
+        write_kv(
+            &kv,
+            Document::Plan,
             id,
-            serde_json::to_string(&PlanRead {
-                id: id.parse().unwrap(),
-                r#type: self.r#type.into(),
-                organization_name: self.organization_name,
-                plan_name: self.plan_name,
-                description: self.description,
-                is_child_friendly: self.is_child_friendly,
-                is_recommended: self.is_recommended,
-                schedule: self.schedule.into(),
-                location: self.location,
-                coordinates: self.coordinates,
-            })
-            .unwrap(),
-        )?
-        .execute()
+            &serde_json::to_value(&plan).unwrap(),
+        )
         .await?;
 
-        Ok(())
+        Ok(plan)
     }
 }
 
+#[derive(Debug)]
 pub enum PlanReadError {
     NotFound,
     KvError(KvError),
     WorkerError(worker::Error),
     GetKeysError(GetKeysError),
+    SerdeError(serde_json::Error),
 }
 
 impl From<KvError> for PlanReadError {
@@ -126,10 +288,44 @@ impl From<GetKeysError> for PlanReadError {
     }
 }
 
+impl From<serde_json::Error> for PlanReadError {
+    fn from(e: serde_json::Error) -> Self {
+        PlanReadError::SerdeError(e)
+    }
+}
+
 impl PlanRead {
-    pub async fn read(kv: KvStore, id: &str) -> Result<PlanRead, PlanReadError> {
-        match kv.get(id).json::<PlanRead>().await? {
-            Some(plan) => Ok(plan),
+    /// 企画の内容についての検証（スキーマとして正しいことに加えて満たすべき条件）
+    pub fn check_rules(&self) -> Result<(), String> {
+        if self.plan_name.trim().is_empty() {
+            return Err("企画名が空です".into());
+        }
+        if self.organization_name.trim().is_empty() {
+            return Err("団体名が空です".into());
+        }
+        if let ScheduleRead::NotCombined { day1, day2 } = self.schedule.uncombine() {
+            for (day, schedules) in [("1日目", day1), ("2日目", day2)] {
+                if schedules
+                    .iter()
+                    .any(|schedule| schedule.start_time >= schedule.end_time)
+                {
+                    return Err(format!("{}の開始時刻が終了時刻以降になっています", day));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 企画と、保存されている値のETagを取得する
+    pub async fn read_with_etag(
+        kv: KvStore,
+        id: &str,
+    ) -> Result<(PlanRead, String), PlanReadError> {
+        match read_kv(&kv, Document::Plan, id).await? {
+            Some(value) => {
+                let etag = etag(&value);
+                Ok((serde_json::from_value(value)?, etag))
+            }
             None => Err(PlanReadError::NotFound),
         }
     }
@@ -143,20 +339,22 @@ impl PlanRead {
         // Filter out cache keys and process in chunks of 100
         let plan_keys: Vec<String> = all_keys
             .into_iter()
-            .filter(|key| !key.starts_with("keys:"))
+            .filter(|key| is_plan_key(key))
             .collect();
 
         // bulk_getの最大数が100なのでkeyを100ごとに分割する
         for chunk in plan_keys.chunks(100) {
-            let values_chunk = kv_bulk_get_values::<PlanRead>(kv, chunk, "json")
-                .await?
-                .into_values()
-                .collect::<Vec<Option<PlanRead>>>();
-            let mut values_chunk = values_chunk
+            let values_chunk = kv_bulk_get_values::<Value>(kv, chunk, "json").await?;
+            for (id, mut value) in values_chunk
                 .into_iter()
-                .filter_map(|value| value)
-                .collect::<Vec<PlanRead>>();
-            values.append(&mut values_chunk);
+                .filter_map(|(id, value)| Some((id, value?)))
+            {
+                if Document::Plan.upgrade(&mut value) {
+                    write_back(kv, &id, &value).await;
+                }
+                unstamp(&mut value);
+                values.push(serde_json::from_value::<PlanRead>(value)?);
+            }
         }
 
         values.sort_by(|a, b| a.id.cmp(&b.id));
@@ -169,25 +367,195 @@ impl PlanRead {
 pub enum PlanUpdateError {
     #[error("Not found")]
     NotFound,
+    /// `If-Match`が現在の値のETagと一致しない
+    #[error("Precondition failed")]
+    PreconditionFailed(String),
     #[error(transparent)]
     KvError(#[from] KvError),
     #[error(transparent)]
     WorkerError(#[from] worker::Error),
     #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
+    /// 更新後の企画が条件を満たさない
+    #[error("{0}")]
+    Invalid(String),
 }
 
 impl PlanUpdate {
-    pub async fn update(self, kv: KvStore, id: &str) -> Result<(), PlanUpdateError> {
-        let Some(mut plan) = kv.get(id).json::<Value>().await? else {
+    /// 保存されている企画の値に更新内容をマージする
+    ///
+    /// 更新後の値が企画として読み込めない場合や、`PlanRead::check_rules`を満たさない場合は`Invalid`を返す
+    pub fn apply(self, plan: &mut Value) -> Result<(), PlanUpdateError> {
+        deep_merge(plan, serde_json::to_value(self)?);
+        serde_json::from_value::<PlanRead>(plan.clone())
+            .map_err(|err| PlanUpdateError::Invalid(err.to_string()))?
+            .check_rules()
+            .map_err(PlanUpdateError::Invalid)
+    }
+
+    /// 企画を更新し、更新前と更新後の値を返す
+    ///
+    /// `expected_etag`を指定した場合は、現在の値のETagと一致しなければ更新しない
+    pub async fn update(
+        self,
+        kv: KvStore,
+        id: &str,
+        expected_etag: Option<&str>,
+    ) -> Result<(Value, Value), PlanUpdateError> {
+        let Some(mut plan) = read_kv(&kv, Document::Plan, id).await? else {
             return Err(PlanUpdateError::NotFound);
         };
+        let current_etag = etag(&plan);
+        if !if_match(expected_etag, Some(&current_etag)) {
+            return Err(PlanUpdateError::PreconditionFailed(current_etag));
+        }
+        let before = plan.clone();
+        self.apply(&mut plan)?;
+
+        write_kv(&kv, Document::Plan, id, &plan).await?;
+
+        Ok((before, plan))
+    }
+}
 
-        let patch = serde_json::to_value(self.clone())?;
-        deep_merge(&mut plan, patch);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::plan;
+    use serde_json::json;
 
-        kv.put(id, serde_json::to_string(&plan)?)?.execute().await?;
+    fn parse(params: &[(&str, &str)]) -> PlanFilter {
+        let mut filter = PlanFilter::default();
+        for (key, value) in params {
+            assert!(filter.parse_param(key, value).unwrap());
+        }
+        filter
+    }
 
-        Ok(())
+    fn labo(id: &str, is_lab_tour: bool) -> PlanRead {
+        plan(id, json!({"type": "labo", "is_lab_tour": is_lab_tour}))
+    }
+
+    #[test]
+    fn existing_params_are_parsed_leniently() {
+        let filter = parse(&[
+            ("type", "labo,unknown"),
+            ("recommended", "yes"),
+            ("child_friendly", "1"),
+        ]);
+        assert_eq!(
+            filter.types.as_deref(),
+            Some(&["labo".into(), "unknown".into()][..])
+        );
+        assert_eq!(filter.recommended, None);
+        assert_eq!(filter.child_friendly, None);
+
+        let general = plan("general", json!({}));
+        assert!(!filter.matches(&general));
+        assert!(filter.matches(&labo("labo", false)));
+        assert!(!parse(&[("type", "")]).matches(&general));
+    }
+
+    #[test]
+    fn new_params_reject_invalid_values() {
+        let mut filter = PlanFilter::default();
+        assert!(matches!(
+            filter.parse_param("category", "play,unknown"),
+            Err(PlanFilterError::UnknownCategory(category)) if category == "unknown"
+        ));
+        assert!(matches!(
+            filter.parse_param("building", "A,"),
+            Err(PlanFilterError::EmptyValue(key)) if key == "building"
+        ));
+        assert!(matches!(
+            filter.parse_param("bbox", "1,2,3"),
+            Err(PlanFilterError::Geo(_))
+        ));
+        assert!(!filter.parse_param("sort", "id").unwrap());
+    }
+
+    #[test]
+    fn lab_tour_applies_only_to_labo_plans() {
+        let general = plan("general", json!({}));
+        let tour = labo("tour", true);
+        let no_tour = labo("no-tour", false);
+
+        let only_tours = parse(&[("lab_tour", "true")]);
+        assert!(only_tours.matches(&general));
+        assert!(only_tours.matches(&tour));
+        assert!(!only_tours.matches(&no_tour));
+
+        let no_tours = parse(&[("lab_tour", "false")]);
+        assert!(no_tours.matches(&general));
+        assert!(!no_tours.matches(&tour));
+        assert!(no_tours.matches(&no_tour));
+    }
+
+    #[test]
+    fn categories_match_any_and_exclude_plans_without_categories() {
+        let booth = plan(
+            "booth",
+            json!({"type": "booth", "categories": ["main_rice"]}),
+        );
+        let stage = plan("stage", json!({"type": "stage"}));
+        let filter = parse(&[("category", "play,main_rice")]);
+        assert!(filter.matches(&plan("general", json!({}))));
+        assert!(filter.matches(&booth));
+        assert!(!filter.matches(&stage));
+    }
+
+    #[test]
+    fn building_and_room_must_match_the_same_location() {
+        let indoor = |building: &str, room: &str| Location::IndoorLocation {
+            building: building.into(),
+            room: room.into(),
+        };
+        let outdoor = Location::OutdoorLocation {
+            name: "中庭".into(),
+        };
+
+        let filter = parse(&[("building", "A"), ("room", "101")]);
+        assert!(filter.matches_location(&indoor("A", "101")));
+        assert!(!filter.matches_location(&indoor("A", "102")));
+        assert!(!filter.matches_location(&indoor("B", "101")));
+        assert!(!filter.matches_location(&outdoor));
+
+        // 屋内の条件と屋外の条件は、いずれかを満たせばよい
+        let either = parse(&[("room", "101"), ("outdoor", "中庭")]);
+        assert!(either.matches_location(&indoor("B", "101")));
+        assert!(either.matches_location(&outdoor));
+        assert!(!parse(&[("outdoor", "中庭")]).matches_location(&indoor("A", "101")));
+
+        // 企画の場所と合わなくても、実施日時ごとの場所が合えば含める
+        let moved = plan(
+            "moved",
+            json!({
+                "location": [{"type": "indoor", "building": "C", "room": "301"}],
+                "schedule": {
+                    "day1": [{
+                        "start_time": "10:00",
+                        "end_time": "11:00",
+                        "location": {"type": "indoor", "building": "A", "room": "101"}
+                    }],
+                    "day2": []
+                }
+            }),
+        );
+        assert!(filter.matches(&moved));
+        assert!(!parse(&[("building", "B")]).matches(&moved));
+    }
+
+    #[test]
+    fn bbox_excludes_plans_without_coordinates() {
+        let filter = parse(&[("bbox", "139,35,140,36")]);
+        assert!(!filter.matches(&plan("none", json!({}))));
+        assert!(filter.matches(&plan(
+            "inside",
+            json!({"coordinates": {"latitude": 35.5, "longitude": 139.5}})
+        )));
+        assert!(!filter.matches(&plan(
+            "outside",
+            json!({"coordinates": {"latitude": 36.5, "longitude": 139.5}})
+        )));
     }
 }
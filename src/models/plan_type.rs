@@ -1,4 +1,12 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// `booth`・`general`のいずれかのカテゴリーの値かどうか
+pub fn is_plan_category(category: &str) -> bool {
+    let value = Value::String(category.to_string());
+    serde_json::from_value::<BoothPlanCategory>(value.clone()).is_ok()
+        || serde_json::from_value::<GeneralPlanCategory>(value).is_ok()
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
@@ -82,9 +90,37 @@ pub enum PlanTypeUpdate {
     },
 }
 
-impl Into<PlanTypeRead> for PlanTypeCreate {
-    fn into(self) -> PlanTypeRead {
+impl PlanTypeRead {
+    /// 企画の種類を表す`type`の値
+    pub fn name(&self) -> &'static str {
         match self {
+            PlanTypeRead::Booth { .. } => "booth",
+            PlanTypeRead::General { .. } => "general",
+            PlanTypeRead::Stage {} => "stage",
+            PlanTypeRead::Labo { .. } => "labo",
+        }
+    }
+
+    /// カテゴリーの値（`booth`・`general`以外の企画は空）
+    pub fn category_names(&self) -> Vec<String> {
+        let categories = match self {
+            PlanTypeRead::Booth { categories } => serde_json::to_value(categories),
+            PlanTypeRead::General { categories } => serde_json::to_value(categories),
+            _ => return vec![],
+        };
+        match categories {
+            Ok(Value::Array(categories)) => categories
+                .into_iter()
+                .filter_map(|category| category.as_str().map(str::to_string))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl From<PlanTypeCreate> for PlanTypeRead {
+    fn from(val: PlanTypeCreate) -> Self {
+        match val {
             PlanTypeCreate::Booth { categories } => PlanTypeRead::Booth { categories },
             PlanTypeCreate::General { categories } => PlanTypeRead::General { categories },
             PlanTypeCreate::Stage {} => PlanTypeRead::Stage {},
@@ -25,11 +25,11 @@ pub struct ProductsRead {
     pub description: String,
 }
 
-impl Into<ProductsRead> for ProductsCreate {
-    fn into(self) -> ProductsRead {
+impl From<ProductsCreate> for ProductsRead {
+    fn from(val: ProductsCreate) -> Self {
         ProductsRead {
-            items: self.items,
-            description: self.description,
+            items: val.items,
+            description: val.description,
         }
     }
 }
@@ -0,0 +1,323 @@
+use crate::models::schema::Document;
+use crate::storage::RevisionRepository;
+use crate::util::now;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use worker::kv::{KvError, KvStore};
+
+const REVISIONS_KEY_PREFIX: &str = "revisions:";
+/// 1つの企画あたりに保持するリビジョン数
+const MAX_REVISIONS: usize = 20;
+
+/// 企画（または企画詳細）のある時点の値
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Revision {
+    pub rev: u64,
+    pub timestamp: DateTime<Utc>,
+    /// 変更したトークンの`sub`。履歴を取り始める前の値の場合は`None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    pub value: Value,
+}
+
+/// 企画（または企画詳細）の変更履歴
+///
+/// `PLAN_HISTORY` namespaceに`revisions:<plan|details>:<plan_id>`のキーで、古い順に直近`MAX_REVISIONS`件を保存する
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RevisionHistory {
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
+}
+
+/// リビジョンと現在の値とのフィールド単位の差分
+#[derive(Serialize, Clone, Debug)]
+pub struct FieldChange {
+    /// JSON Pointer形式のフィールドのパス（例: `/schedule/day1`）
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<Value>,
+}
+
+#[derive(Error, Debug)]
+pub enum RevisionError {
+    #[error(transparent)]
+    KvError(#[from] KvError),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+}
+
+fn revisions_key(document: Document, plan_id: &str) -> String {
+    let document = match document {
+        Document::Plan => "plan",
+        Document::Details => "details",
+    };
+    format!("{}{}:{}", REVISIONS_KEY_PREFIX, document, plan_id)
+}
+
+impl RevisionHistory {
+    /// 変更履歴を取得する。未記録の場合は空の履歴を返す
+    pub async fn read(
+        kv: &KvStore,
+        document: Document,
+        plan_id: &str,
+    ) -> Result<RevisionHistory, RevisionError> {
+        Ok(kv
+            .get(&revisions_key(document, plan_id))
+            .json::<RevisionHistory>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn write(
+        &self,
+        kv: &KvStore,
+        document: Document,
+        plan_id: &str,
+    ) -> Result<(), RevisionError> {
+        kv.put(
+            &revisions_key(document, plan_id),
+            serde_json::to_string(self)?,
+        )?
+        .execute()
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete(
+        kv: &KvStore,
+        document: Document,
+        plan_id: &str,
+    ) -> Result<(), RevisionError> {
+        kv.delete(&revisions_key(document, plan_id)).await?;
+        Ok(())
+    }
+
+    /// 企画IDの変更に合わせて、企画のリビジョンの値の`id`を書き換える
+    pub fn rename(&mut self, new_id: &str) {
+        for revision in &mut self.revisions {
+            if let Value::Object(map) = &mut revision.value {
+                if map.contains_key("id") {
+                    map.insert("id".into(), Value::String(new_id.to_string()));
+                }
+            }
+        }
+    }
+
+    /// 変更後の値をリビジョンとして追記し、そのリビジョン番号を返す
+    ///
+    /// 履歴が空の場合は、変更前の値も最初のリビジョンとして残す
+    pub fn push(&mut self, before: Option<&Value>, after: &Value, actor: &str) -> u64 {
+        let timestamp = now();
+
+        if self.revisions.is_empty() {
+            if let Some(before) = before {
+                self.revisions.push(Revision {
+                    rev: 1,
+                    timestamp,
+                    actor: None,
+                    value: before.clone(),
+                });
+            }
+        }
+
+        let rev = self.latest().map_or(1, |latest| latest.rev + 1);
+        self.revisions.push(Revision {
+            rev,
+            timestamp,
+            actor: Some(actor.to_string()),
+            value: after.clone(),
+        });
+        if self.revisions.len() > MAX_REVISIONS {
+            let overflow = self.revisions.len() - MAX_REVISIONS;
+            self.revisions.drain(..overflow);
+        }
+        rev
+    }
+
+    /// 保存されている変更履歴に変更後の値を追記し、そのリビジョン番号を返す
+    pub async fn record(
+        revisions: &dyn RevisionRepository,
+        document: Document,
+        plan_id: &str,
+        before: Option<&Value>,
+        after: &Value,
+        actor: &str,
+    ) -> Result<u64, RevisionError> {
+        let mut history = revisions.read(document, plan_id).await?;
+        let rev = history.push(before, after, actor);
+        revisions.write(document, plan_id, &history).await?;
+        Ok(rev)
+    }
+
+    pub fn get(&self, rev: u64) -> Option<&Revision> {
+        self.revisions.iter().find(|revision| revision.rev == rev)
+    }
+
+    pub fn latest(&self) -> Option<&Revision> {
+        self.revisions.last()
+    }
+}
+
+/// 現在の値からリビジョンの値への差分をフィールド単位で求める
+///
+/// オブジェクトは再帰的に比較し、配列やそれ以外の値は値全体を1つのフィールドとして扱う
+pub fn diff(current: &Value, revision: &Value) -> Vec<FieldChange> {
+    let mut changes = vec![];
+    diff_at("", Some(current), Some(revision), &mut changes);
+    changes
+}
+
+fn diff_at(
+    path: &str,
+    current: Option<&Value>,
+    revision: Option<&Value>,
+    changes: &mut Vec<FieldChange>,
+) {
+    match (current, revision) {
+        (Some(Value::Object(current)), Some(Value::Object(revision))) => {
+            let mut keys = current.keys().chain(revision.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let escaped = key.replace('~', "~0").replace('/', "~1");
+                diff_at(
+                    &format!("{}/{}", path, escaped),
+                    current.get(key),
+                    revision.get(key),
+                    changes,
+                );
+            }
+        }
+        (current, revision) if current != revision => changes.push(FieldChange {
+            path: path.to_string(),
+            current: current.cloned(),
+            revision: revision.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn revs(history: &RevisionHistory) -> Vec<u64> {
+        history
+            .revisions
+            .iter()
+            .map(|revision| revision.rev)
+            .collect()
+    }
+
+    #[test]
+    fn first_push_keeps_before_as_rev_1() {
+        let mut history = RevisionHistory::default();
+        let rev = history.push(Some(&json!({"v": 0})), &json!({"v": 1}), "editor");
+        assert_eq!(rev, 2);
+        assert_eq!(revs(&history), [1, 2]);
+        let first = history.get(1).unwrap();
+        assert_eq!(first.value, json!({"v": 0}));
+        assert!(first.actor.is_none());
+        assert_eq!(history.latest().unwrap().actor.as_deref(), Some("editor"));
+
+        // 2回目以降は変更前の値を追記しない
+        assert_eq!(
+            history.push(Some(&json!({"v": 1})), &json!({"v": 2}), "editor"),
+            3
+        );
+        assert_eq!(revs(&history), [1, 2, 3]);
+
+        // 作成時は変更前の値が無いため、作成後の値がrev 1になる
+        let mut created = RevisionHistory::default();
+        assert_eq!(created.push(None, &json!({"v": 1}), "editor"), 1);
+        assert_eq!(revs(&created), [1]);
+    }
+
+    #[test]
+    fn truncation_keeps_latest_revisions_with_monotonic_numbers() {
+        let mut history = RevisionHistory::default();
+        let mut before = json!({"v": 0});
+        for v in 1..=MAX_REVISIONS as u64 + 5 {
+            let after = json!({ "v": v });
+            history.push(Some(&before), &after, "editor");
+            before = after;
+        }
+
+        assert_eq!(history.revisions.len(), MAX_REVISIONS);
+        let expected = (7..=MAX_REVISIONS as u64 + 6).collect::<Vec<_>>();
+        assert_eq!(revs(&history), expected);
+        assert!(history.get(6).is_none());
+        // 切り詰めた後も、番号は最新のリビジョンの次から振る
+        let rev = history.push(Some(&before), &json!({"v": "next"}), "editor");
+        assert_eq!(rev, MAX_REVISIONS as u64 + 7);
+        assert_eq!(history.latest().unwrap().value, json!({"v": "next"}));
+    }
+
+    #[test]
+    fn rename_rewrites_plan_ids_only() {
+        let mut history = RevisionHistory::default();
+        history.push(
+            Some(&json!({"id": "old", "plan_name": "A"})),
+            &json!({"id": "old", "plan_name": "B"}),
+            "editor",
+        );
+        let mut details = RevisionHistory::default();
+        details.push(None, &json!({"additional_info": "予約制"}), "editor");
+
+        history.rename("new");
+        details.rename("new");
+        assert!(history
+            .revisions
+            .iter()
+            .all(|revision| revision.value["id"] == "new"));
+        assert_eq!(history.get(1).unwrap().value["plan_name"], "A");
+        assert_eq!(revs(&history), [1, 2]);
+        assert_eq!(
+            details.latest().unwrap().value,
+            json!({"additional_info": "予約制"})
+        );
+    }
+
+    #[test]
+    fn diff_reports_nested_fields_as_escaped_json_pointers() {
+        let current = json!({
+            "plan_name": "A",
+            "schedule": {"day1": [1], "day2": []},
+            "a/b": 1,
+            "m~n": {"x": 1},
+            "removed": true
+        });
+        let revision = json!({
+            "plan_name": "A",
+            "schedule": {"day1": [2], "day2": []},
+            "a/b": 2,
+            "m~n": {"x": 2},
+            "added": null
+        });
+
+        let changes = diff(&current, &revision)
+            .into_iter()
+            .map(|change| (change.path, change.current, change.revision))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                ("/a~1b".into(), Some(json!(1)), Some(json!(2))),
+                ("/added".into(), None, Some(Value::Null)),
+                ("/m~0n/x".into(), Some(json!(1)), Some(json!(2))),
+                ("/removed".into(), Some(json!(true)), None),
+                ("/schedule/day1".into(), Some(json!([1])), Some(json!([2]))),
+            ]
+        );
+        assert!(diff(&current, &current).is_empty());
+        // オブジェクト以外の値は値全体を比較する
+        assert_eq!(diff(&json!(1), &json!(2))[0].path, "");
+    }
+}
@@ -1,5 +1,11 @@
 use crate::models::base::Location;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// 1日の分数
+const MINUTES_PER_DAY: u16 = 24 * 60;
 
 #[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Copy)]
 pub struct Time(u16);
@@ -12,6 +18,17 @@ impl Time {
             None
         }
     }
+
+    /// 0時からの分数
+    pub fn minutes(&self) -> u16 {
+        self.0
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
 }
 
 impl Serialize for Time {
@@ -19,7 +36,21 @@ impl Serialize for Time {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&format!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Time {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((hour, minute)) = s.split_once(':') else {
+            return Err("invalid time format");
+        };
+        let (Ok(hour), Ok(minute)) = (hour.parse::<u8>(), minute.parse::<u8>()) else {
+            return Err("invalid HH:mm format");
+        };
+        Self::new(hour, minute).ok_or("invalid HH:mm format")
     }
 }
 
@@ -29,13 +60,7 @@ impl<'a> Deserialize<'a> for Time {
         D: serde::Deserializer<'a>,
     {
         let s = String::deserialize(deserializer)?;
-        let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() != 2 {
-            return Err(serde::de::Error::custom("invalid time format"));
-        }
-        let hour = parts[0].parse::<u8>().unwrap();
-        let minute = parts[1].parse::<u8>().unwrap();
-        Self::new(hour, minute).ok_or(serde::de::Error::custom("invalid HH:mm format"))
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -47,6 +72,13 @@ pub struct DaySchedule {
     location: Option<Location>,
 }
 
+impl DaySchedule {
+    /// 実施日時ごとの場所（企画の場所と異なる場合のみ指定される）
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ScheduleCreate {
     pub day1: Vec<DaySchedule>,
@@ -98,19 +130,19 @@ impl ScheduleRead {
             },
         }
     }
-    fn combine_schedule(day: &Vec<DaySchedule>) -> Option<DaySchedule> {
+    fn combine_schedule(day: &[DaySchedule]) -> Option<DaySchedule> {
         if day.is_empty() {
             return None;
         }
 
-        let mut start_time = day[0].start_time.clone();
-        let mut end_time = day[0].end_time.clone();
+        let mut start_time = day[0].start_time;
+        let mut end_time = day[0].end_time;
         for schedule in day.iter().skip(1) {
             if schedule.start_time < start_time {
-                start_time = schedule.start_time.clone();
+                start_time = schedule.start_time;
             }
             if schedule.end_time > end_time {
-                end_time = schedule.end_time.clone();
+                end_time = schedule.end_time;
             }
         }
         Some(DaySchedule {
@@ -129,11 +161,202 @@ pub struct ScheduleUpdate {
     pub day2: Option<Option<Vec<DaySchedule>>>,
 }
 
-impl Into<ScheduleRead> for ScheduleCreate {
-    fn into(self) -> ScheduleRead {
+impl From<ScheduleCreate> for ScheduleRead {
+    fn from(val: ScheduleCreate) -> Self {
+        ScheduleRead::NotCombined {
+            day1: val.day1,
+            day2: val.day2,
+        }
+    }
+}
+
+/// 公開APIのエラーのため、メッセージは他の公開APIに合わせて英語とする
+#[derive(Error, Debug)]
+pub enum ScheduleWindowError {
+    #[error("Invalid day: must be 1 or 2")]
+    InvalidDay,
+    #[error("Invalid {0}: must be HH:mm")]
+    InvalidTime(&'static str),
+    #[error("day is required when at, from or to is specified")]
+    MissingDay,
+    #[error("at cannot be combined with from or to")]
+    AtWithRange,
+    #[error("Invalid time window: from must be earlier than to")]
+    EmptyWindow,
+}
+
+/// 実施日時の絞り込み条件。指定した日の時間帯に、実施日時の枠が重なる企画に絞り込む
+///
+/// 結合した範囲ではなく、保存されている実施日時の枠ごとに判定する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScheduleWindow {
+    /// 1日目は1、2日目は2
+    pub day: u8,
+    /// 時間帯の始まり（0時からの分数、この時刻を含む）
+    pub start: u16,
+    /// 時間帯の終わり（0時からの分数、この時刻を含まない）
+    pub end: u16,
+}
+
+impl ScheduleWindow {
+    /// `day`と、`at`（その時刻）または`from`・`to`（時間帯。省略した側はその日の始まり・終わりまで）を読み込む
+    pub fn parse(
+        day: Option<&str>,
+        at: Option<&str>,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<Option<Self>, ScheduleWindowError> {
+        let Some(day) = day else {
+            if at.is_some() || from.is_some() || to.is_some() {
+                return Err(ScheduleWindowError::MissingDay);
+            }
+            return Ok(None);
+        };
+        let day = match day {
+            "1" => 1,
+            "2" => 2,
+            _ => return Err(ScheduleWindowError::InvalidDay),
+        };
+        let time = |key: &'static str, value: Option<&str>| {
+            value
+                .map(|value| value.parse::<Time>())
+                .transpose()
+                .map_err(|_| ScheduleWindowError::InvalidTime(key))
+        };
+
+        let (start, end) = match (time("at", at)?, time("from", from)?, time("to", to)?) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                return Err(ScheduleWindowError::AtWithRange);
+            }
+            (Some(at), None, None) => (at.minutes(), at.minutes() + 1),
+            (None, from, to) => (
+                from.map_or(0, |from| from.minutes()),
+                to.map_or(MINUTES_PER_DAY, |to| to.minutes()),
+            ),
+        };
+        if start >= end {
+            return Err(ScheduleWindowError::EmptyWindow);
+        }
+        Ok(Some(ScheduleWindow { day, start, end }))
+    }
+
+    pub fn matches(&self, schedule: &ScheduleRead) -> bool {
+        let ScheduleRead::NotCombined { day1, day2 } = schedule.uncombine() else {
+            return false;
+        };
+        let schedules = if self.day == 1 { day1 } else { day2 };
+        schedules.iter().any(|schedule| {
+            schedule.start_time.minutes() < self.end && schedule.end_time.minutes() > self.start
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(
+        day: Option<&str>,
+        at: Option<&str>,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<Option<ScheduleWindow>, ScheduleWindowError> {
+        ScheduleWindow::parse(day, at, from, to)
+    }
+
+    fn schedule(day1: &[(&str, &str)]) -> ScheduleRead {
         ScheduleRead::NotCombined {
-            day1: self.day1,
-            day2: self.day2,
+            day1: day1
+                .iter()
+                .map(|(start, end)| DaySchedule {
+                    start_time: start.parse().unwrap(),
+                    end_time: end.parse().unwrap(),
+                    location: None,
+                })
+                .collect(),
+            day2: vec![],
         }
     }
+
+    #[test]
+    fn window_covers_the_minute_of_at_and_defaults_to_the_whole_day() {
+        assert_eq!(window(None, None, None, None).unwrap(), None);
+        assert_eq!(
+            window(Some("1"), Some("10:30"), None, None).unwrap(),
+            Some(ScheduleWindow {
+                day: 1,
+                start: 630,
+                end: 631
+            })
+        );
+        assert_eq!(
+            window(Some("2"), None, None, None).unwrap(),
+            Some(ScheduleWindow {
+                day: 2,
+                start: 0,
+                end: MINUTES_PER_DAY
+            })
+        );
+        assert_eq!(
+            window(Some("1"), None, Some("23:59"), None).unwrap(),
+            Some(ScheduleWindow {
+                day: 1,
+                start: 1439,
+                end: MINUTES_PER_DAY
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_windows_are_rejected() {
+        assert!(matches!(
+            window(None, Some("10:00"), None, None),
+            Err(ScheduleWindowError::MissingDay)
+        ));
+        assert!(matches!(
+            window(Some("3"), None, None, None),
+            Err(ScheduleWindowError::InvalidDay)
+        ));
+        // その日の終わりまでは`to`を省略して指定する
+        assert!(matches!(
+            window(Some("1"), None, None, Some("24:00")),
+            Err(ScheduleWindowError::InvalidTime("to"))
+        ));
+        assert!(matches!(
+            window(Some("1"), Some("9:60"), None, None),
+            Err(ScheduleWindowError::InvalidTime("at"))
+        ));
+        assert!(matches!(
+            window(Some("1"), Some("10:00"), Some("09:00"), None),
+            Err(ScheduleWindowError::AtWithRange)
+        ));
+        assert!(matches!(
+            window(Some("1"), None, Some("12:00"), Some("12:00")),
+            Err(ScheduleWindowError::EmptyWindow)
+        ));
+    }
+
+    #[test]
+    fn window_matches_overlapping_slots_with_exclusive_ends() {
+        let schedule = schedule(&[("10:00", "11:00"), ("13:00", "14:00")]);
+        let at = |time| window(Some("1"), Some(time), None, None).unwrap().unwrap();
+        assert!(at("10:00").matches(&schedule));
+        assert!(at("10:59").matches(&schedule));
+        assert!(!at("11:00").matches(&schedule));
+        assert!(!at("12:00").matches(&schedule));
+        assert!(at("13:30").matches(&schedule));
+
+        let range = |from, to| {
+            window(Some("1"), None, Some(from), Some(to))
+                .unwrap()
+                .unwrap()
+        };
+        assert!(!range("11:00", "13:00").matches(&schedule));
+        assert!(range("11:00", "13:01").matches(&schedule));
+
+        let day2 = window(Some("2"), Some("10:00"), None, None)
+            .unwrap()
+            .unwrap();
+        assert!(!day2.matches(&schedule));
+    }
 }
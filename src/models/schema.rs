@@ -0,0 +1,290 @@
+use super::keys::list_plan_keys;
+use crate::util::kv_bulk_get_values;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use worker::console_error;
+use worker::kv::{KvError, KvStore};
+
+/// 保存する文書のスキーマのバージョンを表すフィールド
+///
+/// 保存先でのみ使用し、APIのレスポンスやETagの計算には含めない
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+/// 文書をあるバージョンから次のバージョンに変換する関数
+type Upgrade = fn(&mut Map<String, Value>);
+
+/// 企画の文書の変換。`PLAN_UPGRADES[n]`はバージョン`n`から`n + 1`に変換する
+///
+/// 変換は同じ文書に繰り返し適用しても結果が変わらないようにする
+/// （バージョンの無い変更履歴の値を復元する際に、現在の形式の文書にも適用されるため）
+const PLAN_UPGRADES: [Upgrade; 1] = [plan_v0_to_v1];
+
+/// 企画詳細の文書の変換。`DETAILS_UPGRADES[n]`はバージョン`n`から`n + 1`に変換する
+const DETAILS_UPGRADES: [Upgrade; 1] = [details_v0_to_v1];
+
+/// バージョン0（`schema_version`の無い文書）からバージョン1への変換
+///
+/// * `schedule`の各日を、1つにまとめた形式（`Combined`）から配列の形式に揃える
+/// * 模擬店・一般企画の`categories`が無い場合は空の配列にする
+/// * `coordinates`が無い場合は`null`にする
+fn plan_v0_to_v1(plan: &mut Map<String, Value>) {
+    if let Some(Value::Object(schedule)) = plan.get_mut("schedule") {
+        for day in ["day1", "day2"] {
+            let entries = match schedule.remove(day) {
+                Some(Value::Array(entries)) => entries,
+                Some(Value::Object(entry)) => vec![Value::Object(entry)],
+                _ => vec![],
+            };
+            schedule.insert(day.into(), Value::Array(entries));
+        }
+    }
+
+    let has_categories = matches!(
+        plan.get("type").and_then(Value::as_str),
+        Some("booth") | Some("general")
+    );
+    if has_categories && !plan.contains_key("categories") {
+        plan.insert("categories".into(), Value::Array(vec![]));
+    }
+
+    plan.entry("coordinates").or_insert(Value::Null);
+}
+
+/// バージョン0（`schema_version`の無い文書）からバージョン1への変換
+///
+/// 形式は変わらないため、バージョンのみを付与する
+fn details_v0_to_v1(_details: &mut Map<String, Value>) {}
+
+/// 文書のバージョン。`schema_version`が無い場合は0とする
+pub fn schema_version(value: &Value) -> u32 {
+    value
+        .get(SCHEMA_VERSION_FIELD)
+        .and_then(Value::as_u64)
+        .map_or(0, |version| version as u32)
+}
+
+fn upgrade(value: &mut Value, upgrades: &[Upgrade]) -> bool {
+    let version = schema_version(value) as usize;
+    let Value::Object(map) = value else {
+        return false;
+    };
+    // 新しいバージョンで保存された文書はそのまま扱う
+    if version >= upgrades.len() {
+        return false;
+    }
+
+    for upgrade in &upgrades[version..] {
+        upgrade(map);
+    }
+    map.insert(SCHEMA_VERSION_FIELD.into(), Value::from(upgrades.len()));
+    true
+}
+
+/// 保存する文書の種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Document {
+    Plan,
+    Details,
+}
+
+impl Document {
+    fn upgrades(self) -> &'static [Upgrade] {
+        match self {
+            Document::Plan => &PLAN_UPGRADES,
+            Document::Details => &DETAILS_UPGRADES,
+        }
+    }
+
+    /// 現在のバージョン
+    pub fn version(self) -> u32 {
+        self.upgrades().len() as u32
+    }
+
+    /// 文書を現在のバージョンに変換する。変換した場合は`true`を返す
+    pub fn upgrade(self, value: &mut Value) -> bool {
+        upgrade(value, self.upgrades())
+    }
+}
+
+/// 保存する文書に現在のバージョンを付与する
+///
+/// 変換は繰り返し適用できるため、バージョンの無い文書は古い形式として変換してから付与する
+pub fn stamp(document: Document, value: &mut Value) {
+    document.upgrade(value);
+    if let Value::Object(map) = value {
+        map.insert(SCHEMA_VERSION_FIELD.into(), Value::from(document.version()));
+    }
+}
+
+/// 保存されていた文書からバージョンを取り除く
+pub fn unstamp(value: &mut Value) {
+    if let Value::Object(map) = value {
+        map.remove(SCHEMA_VERSION_FIELD);
+    }
+}
+
+/// KVに保存されている文書を現在のバージョンに変換して取得する
+///
+/// 変換した場合は変換後の文書を書き戻す。書き戻しに失敗しても、変換後の文書を返す
+pub async fn read_kv(kv: &KvStore, document: Document, id: &str) -> Result<Option<Value>, KvError> {
+    let Some(mut value) = kv.get(id).json::<Value>().await? else {
+        return Ok(None);
+    };
+    if document.upgrade(&mut value) {
+        write_back(kv, id, &value).await;
+    }
+    unstamp(&mut value);
+    Ok(Some(value))
+}
+
+async fn put_json(kv: &KvStore, id: &str, value: &Value) -> Result<(), KvError> {
+    kv.put(
+        id,
+        serde_json::to_string(value).map_err(KvError::Serialization)?,
+    )?
+    .execute()
+    .await
+}
+
+/// 変換した文書を書き戻す（失敗しても次に読み込んだ際に再度変換する）
+pub async fn write_back(kv: &KvStore, id: &str, value: &Value) {
+    if let Err(err) = put_json(kv, id, value).await {
+        console_error!("failed to write back upgraded document {}: {:?}", id, err);
+    }
+}
+
+/// 文書に現在のバージョンを付与してKVに保存する
+pub async fn write_kv(
+    kv: &KvStore,
+    document: Document,
+    id: &str,
+    value: &Value,
+) -> Result<(), KvError> {
+    let mut value = value.clone();
+    stamp(document, &mut value);
+    put_json(kv, id, &value).await
+}
+
+/// バージョンごとの文書の数
+pub type VersionCounts = BTreeMap<u32, usize>;
+
+/// KVに保存されている文書のバージョンを数える（変換や書き戻しは行わない）
+pub async fn count_kv_versions(kv: &KvStore) -> Result<VersionCounts, worker::Error> {
+    let mut counts = VersionCounts::new();
+    let keys = list_plan_keys(kv).await?;
+    // bulk_getの最大数が100なのでkeyを100ごとに分割する
+    for chunk in keys.chunks(100) {
+        for value in kv_bulk_get_values::<Value>(kv, chunk, "json")
+            .await?
+            .into_values()
+            .flatten()
+        {
+            *counts.entry(schema_version(&value)).or_default() += 1;
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::plan::PlanRead;
+    use serde_json::json;
+
+    fn v0_plan() -> Value {
+        json!({
+            "id": "booth-1",
+            "type": "booth",
+            "organization_name": "模擬店の団体",
+            "plan_name": "おにぎり",
+            "description": "",
+            "is_child_friendly": false,
+            "is_recommended": false,
+            "schedule": {
+                "day1": {"start_time": "10:00", "end_time": "12:00"},
+                "day2": null
+            },
+            "location": [{"type": "indoor", "building": "A", "room": "101"}]
+        })
+    }
+
+    #[test]
+    fn v0_plan_is_upgraded_to_current_version() {
+        let mut plan = v0_plan();
+        assert_eq!(schema_version(&plan), 0);
+        assert!(Document::Plan.upgrade(&mut plan));
+
+        assert_eq!(schema_version(&plan), Document::Plan.version());
+        assert_eq!(
+            plan["schedule"],
+            json!({
+                "day1": [{"start_time": "10:00", "end_time": "12:00"}],
+                "day2": []
+            })
+        );
+        assert_eq!(plan["categories"], json!([]));
+        assert_eq!(plan["coordinates"], Value::Null);
+
+        unstamp(&mut plan);
+        serde_json::from_value::<PlanRead>(plan).unwrap();
+    }
+
+    #[test]
+    fn v0_upgrade_keeps_existing_values() {
+        let mut plan = v0_plan();
+        plan["categories"] = json!(["main_rice"]);
+        plan["coordinates"] = json!({"latitude": 35.6, "longitude": 139.6});
+        plan["schedule"] = json!({
+            "day1": [{"start_time": "10:00", "end_time": "11:00"}],
+            "day2": [{"start_time": "13:00", "end_time": "14:00"}]
+        });
+        let expected = plan.clone();
+
+        assert!(Document::Plan.upgrade(&mut plan));
+        unstamp(&mut plan);
+        assert_eq!(plan, expected);
+    }
+
+    #[test]
+    fn categories_are_added_only_to_types_that_have_them() {
+        let mut plan = v0_plan();
+        plan["type"] = json!("labo");
+        Document::Plan.upgrade(&mut plan);
+        assert!(plan.get("categories").is_none());
+    }
+
+    #[test]
+    fn v0_upgrade_is_idempotent() {
+        let mut once = v0_plan();
+        Document::Plan.upgrade(&mut once);
+
+        let mut twice = once.clone();
+        unstamp(&mut twice);
+        Document::Plan.upgrade(&mut twice);
+        assert_eq!(twice, once);
+
+        // 現在のバージョンの文書は変換しない
+        assert!(!Document::Plan.upgrade(&mut twice));
+        assert_eq!(twice, once);
+    }
+
+    #[test]
+    fn documents_from_future_versions_are_left_as_is() {
+        let mut plan = v0_plan();
+        plan[SCHEMA_VERSION_FIELD] = json!(Document::Plan.version() + 1);
+        let expected = plan.clone();
+
+        assert!(!Document::Plan.upgrade(&mut plan));
+        assert_eq!(plan, expected);
+    }
+
+    #[test]
+    fn details_upgrade_only_adds_version() {
+        let mut details = json!({"additional_info": "予約制"});
+        assert!(Document::Details.upgrade(&mut details));
+        assert_eq!(
+            details,
+            json!({"additional_info": "予約制", "schema_version": Document::Details.version()})
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use super::owners::PlanOwners;
+use super::plan::PlanRead;
+use crate::auth::Principal;
+use crate::storage::{IconStore, Storage, TrashRepository};
+use crate::util::{kv_bulk_get_values, now};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Reverse;
+use thiserror::Error;
+use worker::kv::{KvError, KvStore};
+use worker::{console_error, Env};
+
+pub const TRASH_KEY_PREFIX: &str = "trash:";
+
+/// ゴミ箱に残す日数の既定値
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+fn trash_key(plan_id: &str) -> String {
+    format!("{}{}", TRASH_KEY_PREFIX, plan_id)
+}
+
+/// 削除した企画
+///
+/// 企画と同じnamespaceに`trash:<plan_id>`のキーで保存し、保持期間を過ぎるまでは復元できる。
+/// アイコンは保存先のゴミ箱の領域に移動する。
+/// 企画IDごとに1つのみ保持するため、同じIDの企画がゴミ箱にある間は削除できない
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrashEntry {
+    pub plan_id: String,
+    pub plan: Value,
+    #[serde(default)]
+    pub details: Option<Value>,
+    #[serde(default)]
+    pub owners: Option<PlanOwners>,
+    /// アイコンをゴミ箱に移動したかどうか
+    #[serde(default)]
+    pub icon: bool,
+    pub deleted_at: DateTime<Utc>,
+    /// 削除したトークンの`sub`
+    pub deleted_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_by_name: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum TrashError {
+    #[error(transparent)]
+    KvError(#[from] KvError),
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+    #[error("{0}")]
+    StoreError(String),
+}
+
+/// ゴミ箱の保持期間。環境変数`TRASH_RETENTION_DAYS`で指定する
+pub fn retention(env: &Env) -> Duration {
+    let days = env
+        .var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|var| var.to_string().parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    Duration::days(days)
+}
+
+impl TrashEntry {
+    pub fn new(principal: &Principal, plan_id: &str, plan: Value) -> Self {
+        TrashEntry {
+            plan_id: plan_id.to_string(),
+            plan,
+            details: None,
+            owners: None,
+            icon: false,
+            deleted_at: now(),
+            deleted_by: principal.subject.clone(),
+            deleted_by_name: principal.claims.preferred_username.clone(),
+        }
+    }
+
+    pub fn expires_at(&self, retention: Duration) -> DateTime<Utc> {
+        self.deleted_at + retention
+    }
+
+    pub async fn read(kv: &KvStore, plan_id: &str) -> Result<Option<TrashEntry>, TrashError> {
+        Ok(kv.get(&trash_key(plan_id)).json::<TrashEntry>().await?)
+    }
+
+    pub async fn write(&self, kv: &KvStore) -> Result<(), TrashError> {
+        kv.put(&trash_key(&self.plan_id), serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(kv: &KvStore, plan_id: &str) -> Result<(), TrashError> {
+        kv.delete(&trash_key(plan_id)).await?;
+        Ok(())
+    }
+
+    /// ゴミ箱の企画を削除日時の新しい順に取得する
+    pub async fn list(kv: &KvStore) -> Result<Vec<TrashEntry>, TrashError> {
+        let mut keys = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = kv.list().prefix(TRASH_KEY_PREFIX.into());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let list = list.execute().await?;
+            keys.extend(list.keys.into_iter().map(|key| key.name));
+            match list.cursor {
+                Some(next) if !list.list_complete => cursor = Some(next),
+                _ => break,
+            }
+        }
+
+        let mut entries = vec![];
+        // bulk_getの最大数が100なのでkeyを100ごとに分割する
+        for chunk in keys.chunks(100) {
+            entries.extend(
+                kv_bulk_get_values::<TrashEntry>(kv, chunk, "json")
+                    .await?
+                    .into_values()
+                    .flatten(),
+            );
+        }
+        entries.sort_by_key(|entry| Reverse(entry.deleted_at));
+        Ok(entries)
+    }
+}
+
+/// 削除する企画をゴミ箱に保存し、アイコンをゴミ箱の領域に移動する
+///
+/// 同じIDの以前に削除した企画がゴミ箱にある場合は、完全に削除してから置き換える
+async fn trash_entry(
+    storage: &Storage,
+    principal: &Principal,
+    plan_id: &str,
+    plan: &PlanRead,
+) -> Result<TrashEntry, TrashError> {
+    // 以前の企画のアイコンが新しい企画と一緒に復元されないよう、アイコンも先に削除する
+    if let Some(previous) = storage.trash.read(plan_id).await? {
+        purge(&*storage.trash, &*storage.icons, &previous).await?;
+    }
+    let mut entry = TrashEntry::new(principal, plan_id, serde_json::to_value(plan)?);
+    entry.details = storage
+        .details
+        .read_value(plan_id)
+        .await
+        .map_err(|err| TrashError::StoreError(err.to_string()))?;
+    entry.owners = Some(
+        storage
+            .owners
+            .read(plan_id)
+            .await
+            .map_err(|err| TrashError::StoreError(err.to_string()))?,
+    );
+    entry.icon = storage.icons.move_to_trash(plan_id).await?;
+
+    if let Err(err) = storage.trash.write(&entry).await {
+        if entry.icon {
+            if let Err(err) = storage.icons.restore_from_trash(plan_id).await {
+                console_error!("Failed to restore icon from trash: {:?}", err);
+            }
+        }
+        return Err(err);
+    }
+    Ok(entry)
+}
+
+/// 企画を企画詳細・所有者・アイコンと一緒にゴミ箱に移動し、企画を削除する
+///
+/// 企画を削除できなかった場合はゴミ箱への移動を取り消す
+pub async fn move_to_trash(
+    storage: &Storage,
+    principal: &Principal,
+    plan_id: &str,
+    plan: &PlanRead,
+) -> Result<TrashEntry, TrashError> {
+    let entry = trash_entry(storage, principal, plan_id, plan).await?;
+
+    if let Err(err) = storage.plans.delete(plan_id).await {
+        if entry.icon {
+            if let Err(err) = storage.icons.restore_from_trash(plan_id).await {
+                console_error!("Failed to restore icon from trash: {:?}", err);
+            }
+        }
+        if let Err(err) = storage.trash.delete(plan_id).await {
+            console_error!("Failed to delete trash entry: {:?}", err);
+        }
+        return Err(TrashError::StoreError(format!("{:?}", err)));
+    }
+
+    // 企画詳細・所有者情報も削除（ゴミ箱から復元できる）
+    if entry.details.is_some() {
+        if let Err(err) = storage.details.delete(plan_id).await {
+            console_error!("Failed to delete plan details: {:?}", err);
+        }
+    }
+    if let Err(err) = storage.owners.delete(plan_id).await {
+        console_error!("Failed to delete plan owners: {:?}", err);
+    }
+
+    // Update keys cache
+    if let Err(err) = storage.plans.update_index(&[], &[plan_id.into()]).await {
+        console_error!("Failed to update keys cache: {:?}", err);
+    }
+
+    Ok(entry)
+}
+
+/// ゴミ箱の企画を完全に削除する
+pub async fn purge(
+    trash: &dyn TrashRepository,
+    icons: &dyn IconStore,
+    entry: &TrashEntry,
+) -> Result<(), TrashError> {
+    if entry.icon {
+        icons.purge_trash(&entry.plan_id).await?;
+    }
+    trash.delete(&entry.plan_id).await
+}
+
+/// 保持期間を過ぎたゴミ箱の企画を完全に削除し、削除した企画IDを返す
+pub async fn purge_expired(
+    trash: &dyn TrashRepository,
+    icons: &dyn IconStore,
+    retention: Duration,
+) -> Result<Vec<String>, TrashError> {
+    let now = now();
+    let mut purged = vec![];
+    for entry in trash.list().await? {
+        if entry.expires_at(retention) <= now {
+            purge(trash, icons, &entry).await?;
+            purged.push(entry.plan_id);
+        }
+    }
+    Ok(purged)
+}
@@ -1 +1,311 @@
+pub mod api_keys;
+pub mod audit;
+pub mod backups;
+pub mod keys;
+pub mod migration;
+pub mod orphans;
 pub mod plans;
+pub mod schema;
+pub mod snapshot;
+pub mod trash;
+
+use crate::auth::permission::Permission;
+use crate::auth::{authenticate, AuthError, Principal};
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::owners::PlanOwnersError;
+use crate::models::revision::RevisionHistory;
+use crate::models::schema::Document;
+use crate::storage::Storage;
+use serde::Serialize;
+use worker::{console_error, console_log, Error, Request, Response, RouteContext};
+
+/// 管理APIのリクエストを認証し、`permission`を持つか確認する
+///
+/// 認証・認可に失敗した場合は、そのままハンドラーから返せるエラーレスポンスを`Err`で返す
+pub async fn authorize(
+    req: &Request,
+    ctx: &RouteContext<Storage>,
+    permission: Permission,
+) -> Result<Result<Principal, Response>, Error> {
+    let principal = match authenticate(req, &ctx.env, &*ctx.data.api_keys).await {
+        Ok(principal) => principal,
+        Err(err) => return Ok(Err(auth_error_response(err)?)),
+    };
+    if !principal.has(permission) {
+        return Ok(Err(forbidden_response(permission)?));
+    }
+
+    log_admin_request(req, &principal);
+    Ok(Ok(principal))
+}
+
+/// 特定の企画に対する管理APIのリクエストを認証・認可する
+///
+/// `permission`を持たない場合でも、その企画の所有者であり`permission`が所有者に許可された操作であれば通す
+pub async fn authorize_plan(
+    req: &Request,
+    ctx: &RouteContext<Storage>,
+    permission: Permission,
+    plan_id: &str,
+) -> Result<Result<Principal, Response>, Error> {
+    let principal = match authenticate(req, &ctx.env, &*ctx.data.api_keys).await {
+        Ok(principal) => principal,
+        Err(err) => return Ok(Err(auth_error_response(err)?)),
+    };
+    match can_access_plan(&ctx.data, &principal, permission, plan_id).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(Err(forbidden_response(permission)?)),
+        Err(err) => {
+            console_error!("failed to read plan owners: {:?}", err);
+            return Ok(Err(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500)));
+        }
+    }
+
+    log_admin_request(req, &principal);
+    Ok(Ok(principal))
+}
+
+/// `principal`が企画`plan_id`に対して`permission`の操作をできるか
+///
+/// 所有者の読み込みは`permission`を持たない場合にのみ行う
+pub async fn can_access_plan(
+    storage: &Storage,
+    principal: &Principal,
+    permission: Permission,
+    plan_id: &str,
+) -> Result<bool, PlanOwnersError> {
+    if principal.has(permission) {
+        return Ok(true);
+    }
+    if !permission.is_granted_to_owner() {
+        return Ok(false);
+    }
+    Ok(storage.owners.read(plan_id).await?.allows(principal))
+}
+
+fn log_admin_request(req: &Request, principal: &Principal) {
+    console_log!(
+        "admin request: {:?} {} by {} ({})",
+        req.method(),
+        req.path(),
+        principal.subject,
+        principal
+            .claims
+            .preferred_username
+            .as_deref()
+            .unwrap_or("-")
+    );
+}
+
+fn auth_error_response(err: AuthError) -> Result<Response, Error> {
+    let (code, message, challenge) = match err {
+        AuthError::MissingToken => (401, "認証が必要です", Some("Bearer")),
+        AuthError::InvalidToken(_) | AuthError::UnknownKey => (
+            401,
+            "トークンが無効です",
+            Some("Bearer error=\"invalid_token\""),
+        ),
+        AuthError::InvalidApiKey => (401, "APIキーが無効です", None),
+        AuthError::AudienceMismatch => (403, "このAPIへのアクセスは許可されていません", None),
+        AuthError::JwksUnavailable
+        | AuthError::Misconfigured(_)
+        | AuthError::ApiKeyError(_)
+        | AuthError::WorkerError(_) => {
+            console_error!("auth error: {}", err);
+            (500, "内部エラーが発生しました", None)
+        }
+    };
+
+    let mut response = Response::from_json(&serde_json::json!({
+        "code": code,
+        "message": message
+    }))?
+    .with_status(code);
+    if let Some(challenge) = challenge {
+        response.headers_mut().set("WWW-Authenticate", challenge)?;
+    }
+
+    Ok(response)
+}
+
+pub fn forbidden_response(permission: Permission) -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 403,
+        "message": format!("この操作には権限「{}」が必要です", permission),
+        "missing_permission": permission.as_str()
+    }))?
+    .with_status(403))
+}
+
+/// `If-Match`が現在の値と一致しない場合のレスポンス
+///
+/// クライアントが最新の値を取得し直せるよう、現在のETagを返す
+pub fn precondition_failed_response(current_etag: Option<&str>) -> Result<Response, Error> {
+    let mut response = Response::from_json(&serde_json::json!({
+        "code": 412,
+        "message": "他の操作によって更新されています。最新の内容を取得してから再度実行してください",
+        "current_etag": current_etag
+    }))?
+    .with_status(412);
+    if let Some(current_etag) = current_etag {
+        response.headers_mut().set("ETag", current_etag)?;
+    }
+
+    Ok(response)
+}
+
+/// 監査記録を追記する
+///
+/// 変更自体は既に成功しているため、追記に失敗してもエラーはログに残すのみとする
+pub async fn record_audit(storage: &Storage, record: AuditRecord) {
+    if let Err(err) = storage.audit.append(&record).await {
+        console_error!("failed to append audit record: {:?}", err);
+    }
+}
+
+/// 変更後の値を変更履歴に追記する
+///
+/// 監査記録と同様に、追記に失敗してもエラーはログに残すのみとする
+pub async fn record_revision<B: Serialize, A: Serialize>(
+    storage: &Storage,
+    document: Document,
+    plan_id: &str,
+    before: Option<&B>,
+    after: &A,
+    principal: &Principal,
+) {
+    let result = async {
+        let before = before.map(serde_json::to_value).transpose()?;
+        let after = serde_json::to_value(after)?;
+        RevisionHistory::record(
+            &*storage.revisions,
+            document,
+            plan_id,
+            before.as_ref(),
+            &after,
+            &principal.subject,
+        )
+        .await
+    }
+    .await;
+    if let Err(err) = result {
+        console_error!("failed to record revision: {:?}", err);
+    }
+}
+
+/// 公開APIのキャッシュを無効化する
+///
+/// 変更自体は既に成功しているため、失敗してもエラーはログに残すのみとする
+pub async fn invalidate_cache(storage: &Storage, scopes: &[CacheScope<'_>]) {
+    for scope in scopes {
+        if let Err(err) = storage.cache.bump(*scope).await {
+            console_error!("failed to invalidate cache {:?}: {:?}", scope, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::KeycloakClaims;
+    use crate::models::owners::PlanOwners;
+    use crate::storage::memory::{seed, storage};
+    use futures::executor::block_on;
+    use std::collections::HashSet;
+
+    fn principal(subject: &str, groups: &[&str], permissions: &[Permission]) -> Principal {
+        Principal {
+            subject: subject.into(),
+            claims: KeycloakClaims {
+                groups: groups.iter().map(|group| group.to_string()).collect(),
+                ..Default::default()
+            },
+            permissions: permissions.iter().copied().collect::<HashSet<_>>(),
+        }
+    }
+
+    #[test]
+    fn owner_without_permission_can_write_only_own_plan() {
+        let storage = storage();
+        block_on(seed(&storage, "mine"));
+        block_on(seed(&storage, "other"));
+        block_on(storage.owners.write("other", &PlanOwners::default())).unwrap();
+        let owner = principal("owner", &[], &[]);
+
+        let access = |plan_id| {
+            block_on(can_access_plan(
+                &storage,
+                &owner,
+                Permission::PlansWrite,
+                plan_id,
+            ))
+            .unwrap()
+        };
+        assert!(access("mine"));
+        assert!(!access("other"));
+        assert!(!access("missing"));
+    }
+
+    #[test]
+    fn group_members_are_treated_as_owners() {
+        let storage = storage();
+        block_on(seed(&storage, "plan"));
+        let owners = PlanOwners {
+            subjects: vec![],
+            groups: vec!["/exhibitors/lab".into()],
+        };
+        block_on(storage.owners.write("plan", &owners)).unwrap();
+
+        let member = principal("member", &["/exhibitors/lab"], &[]);
+        let outsider = principal("outsider", &["/exhibitors/club"], &[]);
+        let access = |principal| {
+            block_on(can_access_plan(
+                &storage,
+                principal,
+                Permission::IconsWrite,
+                "plan",
+            ))
+            .unwrap()
+        };
+        assert!(access(&member));
+        assert!(!access(&outsider));
+    }
+
+    #[test]
+    fn owners_cannot_use_permissions_not_granted_to_owners() {
+        let storage = storage();
+        block_on(seed(&storage, "plan"));
+        let owner = principal("owner", &[], &[]);
+
+        for permission in [Permission::PlansDelete, Permission::OwnersWrite] {
+            assert!(!block_on(can_access_plan(&storage, &owner, permission, "plan")).unwrap());
+        }
+    }
+
+    #[test]
+    fn global_permission_allows_any_plan() {
+        let storage = storage();
+        block_on(seed(&storage, "plan"));
+        let editor = principal("editor", &[], &[Permission::PlansWrite]);
+
+        assert!(block_on(can_access_plan(
+            &storage,
+            &editor,
+            Permission::PlansWrite,
+            "plan"
+        ))
+        .unwrap());
+        assert!(block_on(can_access_plan(
+            &storage,
+            &editor,
+            Permission::PlansWrite,
+            "missing"
+        ))
+        .unwrap());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::auth::permission::Permission;
+use crate::models::api_key::{ApiKey, ApiKeyCreate, ApiKeyError, ApiKeyScopeError};
+use crate::models::audit::AuditRecord;
+use crate::routes::admin::{authorize, forbidden_response, record_audit};
+use crate::storage::Storage;
+use crate::util::now;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+pub async fn post_api_key(mut req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::ApiKeysManage).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let api_key_create = match req.json::<ApiKeyCreate>().await {
+        Ok(api_key_create) => api_key_create,
+        Err(e) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": e.to_string()
+            }))?
+            .with_status(400));
+        }
+    };
+
+    if api_key_create.name.trim().is_empty() || api_key_create.scopes.is_empty() {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 400,
+            "message": "nameとscopesは必須です"
+        }))?
+        .with_status(400));
+    }
+    if api_key_create
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now())
+    {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 400,
+            "message": "expires_atには未来の日時を指定してください"
+        }))?
+        .with_status(400));
+    }
+
+    match api_key_create.check_scopes(&principal) {
+        Ok(()) => {}
+        Err(ApiKeyScopeError::NotGranted(permission)) => return forbidden_response(permission),
+        Err(err @ ApiKeyScopeError::Unknown(_)) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": err.to_string()
+            }))?
+            .with_status(400));
+        }
+    }
+
+    match api_key_create
+        .create(&*ctx.data.api_keys, &principal.subject)
+        .await
+    {
+        Ok((api_key, secret)) => {
+            record_audit(
+                &ctx.data,
+                AuditRecord::new(&principal, "POST /v1/admin/apikeys", None).with_after(&api_key),
+            )
+            .await;
+            Ok(Response::from_json(&serde_json::json!({
+                "api_key": api_key,
+                "key": secret
+            }))?
+            .with_status(201))
+        }
+        Err(err) => {
+            console_error!("failed to create api key: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
+
+pub async fn get_api_keys(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::ApiKeysManage).await? {
+        return Ok(response);
+    }
+
+    match ApiKey::list(&*ctx.data.api_keys).await {
+        Ok(api_keys) => Ok(Response::from_json(&serde_json::json!({
+            "api_keys": api_keys
+        }))?
+        .with_status(200)),
+        Err(err) => {
+            console_error!("failed to list api keys: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
+
+pub async fn delete_api_key(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::ApiKeysManage).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let key_id = ctx.param("key_id").map_or("", |v| v);
+
+    match ApiKey::revoke(&*ctx.data.api_keys, key_id).await {
+        Ok(api_key) => {
+            record_audit(
+                &ctx.data,
+                AuditRecord::new(&principal, "DELETE /v1/admin/apikeys/:key_id", None)
+                    .with_after(&api_key),
+            )
+            .await;
+            Ok(Response::empty()?.with_status(204))
+        }
+        Err(ApiKeyError::NotFound) => Ok(Response::from_json(&serde_json::json!({
+            "code": 404,
+            "message": "APIキーが見つかりません"
+        }))?
+        .with_status(404)),
+        Err(err) => {
+            console_error!("failed to revoke api key: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
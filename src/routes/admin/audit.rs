@@ -0,0 +1,79 @@
+use crate::auth::permission::Permission;
+use crate::models::audit::{AuditError, AuditQuery};
+use crate::routes::admin::authorize;
+use crate::storage::Storage;
+use chrono::{DateTime, Utc};
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+/// 1ページあたりの件数の既定値
+const DEFAULT_LIMIT: usize = 50;
+/// 1ページあたりの件数の上限
+const MAX_LIMIT: usize = 200;
+
+pub async fn get_audit(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::AuditRead).await? {
+        return Ok(response);
+    }
+
+    let url = req.url()?;
+    let query_params = url.query_pairs();
+
+    // クエリパラメータの解析
+    let mut query = AuditQuery {
+        limit: DEFAULT_LIMIT,
+        ..Default::default()
+    };
+    for (key, value) in query_params {
+        match key.as_ref() {
+            "plan_id" => query.plan_id = Some(value.into()),
+            "actor" => query.actor = Some(value.into()),
+            "from" | "to" => {
+                let Ok(time) = value.parse::<DateTime<Utc>>() else {
+                    return Ok(Response::from_json(&serde_json::json!({
+                        "code": 400,
+                        "message": format!("{}はRFC 3339形式で指定してください", key)
+                    }))?
+                    .with_status(400));
+                };
+                if key == "from" {
+                    query.from = Some(time);
+                } else {
+                    query.to = Some(time);
+                }
+            }
+            "limit" => match value.parse::<usize>() {
+                Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => query.limit = limit,
+                _ => {
+                    return Ok(Response::from_json(&serde_json::json!({
+                        "code": 400,
+                        "message": format!("limitは1から{}の間で指定してください", MAX_LIMIT)
+                    }))?
+                    .with_status(400));
+                }
+            },
+            "cursor" => query.cursor = Some(value.into()),
+            _ => {}
+        }
+    }
+
+    match ctx.data.audit.list(&query).await {
+        Ok(page) => Ok(Response::from_json(&serde_json::json!({
+            "records": page.records,
+            "next_cursor": page.next_cursor
+        }))?
+        .with_status(200)),
+        Err(AuditError::InvalidCursor) => Ok(Response::from_json(&serde_json::json!({
+            "code": 400,
+            "message": "cursorが無効です"
+        }))?
+        .with_status(400)),
+        Err(err) => {
+            console_error!("failed to list audit records: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::auth::permission::Permission;
+use crate::backup::{is_backup_id, list, read, RetentionPolicy};
+use crate::models::audit::AuditRecord;
+use crate::routes::admin::snapshot::apply_import_side_effects;
+use crate::routes::admin::{authorize, record_audit};
+use crate::snapshot::{import, ImportMode, SnapshotError};
+use crate::storage::Storage;
+use crate::R2_BACKUPS;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+fn bucket_not_configured() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 503,
+        "message": "バックアップ用のR2バケットが設定されていません"
+    }))?
+    .with_status(503))
+}
+
+fn internal_error() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 500,
+        "message": "内部エラーが発生しました"
+    }))?
+    .with_status(500))
+}
+
+fn not_found() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 404,
+        "message": "バックアップが見つかりません"
+    }))?
+    .with_status(404))
+}
+
+/// R2に保存されているバックアップを新しい順に取得する
+pub async fn get_backups(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::Maintenance).await? {
+        return Ok(response);
+    }
+
+    let Ok(bucket) = ctx.env.bucket(R2_BACKUPS) else {
+        return bucket_not_configured();
+    };
+
+    match list(&bucket).await {
+        Ok(backups) => Ok(Response::from_json(&serde_json::json!({
+            "backups": backups,
+            "retention": RetentionPolicy::from_env(&ctx.env),
+        }))?
+        .with_status(200)),
+        Err(err) => {
+            console_error!("failed to list backups: {:?}", err);
+            internal_error()
+        }
+    }
+}
+
+/// バックアップから企画・企画詳細を復元する（`POST /v1/admin/backups/:id:restore`）
+///
+/// `mode`は`POST /v1/admin/import`と同じで、既定は`merge`（バックアップ後に追加した企画を残す）。
+/// バックアップはアイコンの画像を含まないため、現在のアイコンはそのまま残す
+pub async fn post_backup(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    // `:id:restore`はルーター上では1つのパラメーターとして扱われるため、ここで分解する
+    let Some(backup_id) = ctx
+        .param("backup_id")
+        .and_then(|id| id.strip_suffix(":restore"))
+        .filter(|id| is_backup_id(id))
+        .map(str::to_string)
+    else {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 404,
+            "message": "Not Found"
+        }))?
+        .with_status(404));
+    };
+
+    let principal = match authorize(&req, &ctx, Permission::Maintenance).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let mode = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "mode")
+        .map(|(_, value)| value.into_owned());
+    let mode = match mode.as_deref().map(ImportMode::parse) {
+        None => ImportMode::Merge,
+        Some(Some(mode)) => mode,
+        Some(None) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": "modeにはmerge、replaceまたはdry-runを指定してください"
+            }))?
+            .with_status(400));
+        }
+    };
+
+    let Ok(bucket) = ctx.env.bucket(R2_BACKUPS) else {
+        return bucket_not_configured();
+    };
+    let snapshot = match read(&bucket, &backup_id).await {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return not_found(),
+        Err(err) => {
+            console_error!("failed to read backup: {:?}", err);
+            return internal_error();
+        }
+    };
+
+    let report = match import(&ctx.data, &snapshot, mode, &principal).await {
+        Ok(report) => report,
+        Err(err @ (SnapshotError::MissingHeader | SnapshotError::UnsupportedFormat(..))) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 422,
+                "message": format!("バックアップを読み込めません: {}", err)
+            }))?
+            .with_status(422));
+        }
+        Err(err) => {
+            console_error!("failed to restore backup: {:?}", err);
+            return internal_error();
+        }
+    };
+
+    if mode != ImportMode::DryRun {
+        apply_import_side_effects(&ctx.data, &report, &principal).await?;
+        record_audit(
+            &ctx.data,
+            AuditRecord::new(&principal, "POST /v1/admin/backups/:id:restore", None).with_after(
+                &serde_json::json!({
+                    "backup_id": backup_id,
+                    "mode": report.mode,
+                    "created": report.created,
+                    "updated": report.updated,
+                    "deleted": report.deleted,
+                    "icons_skipped": report.icons_skipped,
+                    "errors": report.errors.len(),
+                }),
+            ),
+        )
+        .await;
+    }
+
+    // 失敗したエントリーがある場合は207 Multi-Statusでエラー一覧を返す
+    let status = if report.errors.is_empty() { 200 } else { 207 };
+    Ok(Response::from_json(&report)?.with_status(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{KeycloakClaims, Principal};
+    use crate::snapshot::export_to_string;
+    use crate::storage::memory::{seed, storage};
+    use futures::executor::block_on;
+
+    #[test]
+    fn restore_rejects_backup_lines_with_reserved_ids() {
+        let storage = storage();
+        let principal = Principal {
+            subject: "admin".into(),
+            claims: KeycloakClaims::default(),
+            permissions: Default::default(),
+        };
+        block_on(async {
+            seed(&storage, "plan-1").await;
+            // `backup::create`と同じく画像を含まないスナップショットを、R2上で書き換えられたものとして扱う
+            let backup = export_to_string(&storage, false).await.unwrap();
+            let entry = backup.lines().nth(1).unwrap();
+            let tampered = [
+                backup.trim_end(),
+                &entry.replace(r#""id":"plan-1""#, r#""id":"owners:plan-1""#),
+                &entry.replace(r#""id":"plan-1""#, r#""id":"keys:all""#),
+            ]
+            .join("\n");
+            seed(&storage, "plan-2").await;
+
+            let report = import(&storage, &tampered, ImportMode::Replace, &principal)
+                .await
+                .unwrap();
+            let rejected = report
+                .errors
+                .iter()
+                .map(|error| (error.plan_id.as_deref().unwrap(), error.code))
+                .collect::<Vec<_>>();
+            assert_eq!(rejected, [("owners:plan-1", 400), ("keys:all", 400)]);
+            // 検証エラーがあるため、バックアップに無い企画も削除しない
+            assert!(report.deleted.is_empty());
+            assert!(storage.plans.read_value("plan-2").await.unwrap().is_some());
+            assert_eq!(
+                storage.owners.read("plan-1").await.unwrap().subjects,
+                ["owner"]
+            );
+        });
+    }
+}
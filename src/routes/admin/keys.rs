@@ -0,0 +1,61 @@
+use crate::auth::permission::Permission;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::routes::admin::{authorize, invalidate_cache, record_audit};
+use crate::storage::Storage;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+/// 企画IDのインデックス（`keys:all`）とKVの内容が一致しているか確認する
+pub async fn get_keys_check(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::Maintenance).await? {
+        return Ok(response);
+    }
+
+    match ctx.data.plans.check_index().await {
+        Ok(check) => Ok(Response::from_json(&check)?.with_status(200)),
+        Err(err) => {
+            console_error!("failed to check keys: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
+
+/// KVを列挙して企画IDのインデックスを作り直す
+pub async fn post_keys_rebuild(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::Maintenance).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    match ctx.data.plans.rebuild_index().await {
+        Ok(keys) => {
+            record_audit(
+                &ctx.data,
+                AuditRecord::new(&principal, "POST /v1/admin/keys:rebuild", None)
+                    .with_after(&serde_json::json!({ "indexed": keys.len() })),
+            )
+            .await;
+            invalidate_cache(&ctx.data, &[CacheScope::List]).await;
+
+            Ok(Response::from_json(&serde_json::json!({
+                "indexed": keys.len()
+            }))?
+            .with_status(200))
+        }
+        Err(err) => {
+            console_error!("failed to rebuild keys: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
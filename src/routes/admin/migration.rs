@@ -0,0 +1,199 @@
+use crate::auth::permission::Permission;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::migration::{MigrationPhase, MigrationState};
+use crate::routes::admin::{authorize, invalidate_cache, record_audit};
+use crate::storage::migration::{dry_run, run_batch, source_and_target, verify};
+use crate::storage::Storage;
+use serde::Deserialize;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+/// 1回のリクエストで移行する件数の既定値
+const DEFAULT_LIMIT: usize = 20;
+/// 1回のリクエストで移行する件数の上限
+const MAX_LIMIT: usize = 100;
+
+#[derive(Deserialize, Default)]
+struct MigrationRequest {
+    /// 書き込まずに、移行できない値の一覧のみを返す
+    #[serde(default)]
+    dry_run: bool,
+    /// 進捗を破棄して最初から移行し直す
+    #[serde(default)]
+    restart: bool,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+fn d1_not_configured() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 503,
+        "message": "D1データベースが設定されていません"
+    }))?
+    .with_status(503))
+}
+
+fn internal_error() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 500,
+        "message": "内部エラーが発生しました"
+    }))?
+    .with_status(500))
+}
+
+/// KVからD1への移行の進捗を取得する。`verify=true`の場合は移行元と移行先も比較する
+pub async fn get_d1_migration(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::Maintenance).await? {
+        return Ok(response);
+    }
+
+    let verify_requested = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "verify" && value == "true");
+
+    let (source, target) = source_and_target(&ctx.env)?;
+    let state = match ctx.data.migration.read().await {
+        Ok(state) => state,
+        Err(err) => {
+            console_error!("failed to read migration state: {:?}", err);
+            return internal_error();
+        }
+    };
+
+    let verification = match (verify_requested, &target) {
+        (false, _) => None,
+        (true, None) => return d1_not_configured(),
+        (true, Some(target)) => match verify(&source, target).await {
+            Ok(verification) => Some(verification),
+            Err(err) => {
+                console_error!("failed to verify migration: {:?}", err);
+                return internal_error();
+            }
+        },
+    };
+
+    Ok(Response::from_json(&serde_json::json!({
+        "state": state,
+        "verification": verification,
+    }))?
+    .with_status(200))
+}
+
+/// KVの企画・企画詳細をD1に移行する
+///
+/// 1回のリクエストでは`limit`件ずつ移行し、進捗を保存して次のリクエストで続きから再開する。
+/// 全て移行し終えた時点で、移行元と移行先の件数とチェックサムを比較した結果を返す
+pub async fn post_d1_migration(
+    mut req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::Maintenance).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let body = req.text().await?;
+    let request = if body.trim().is_empty() {
+        MigrationRequest::default()
+    } else {
+        match serde_json::from_str::<MigrationRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                return Ok(Response::from_json(&serde_json::json!({
+                    "code": 400,
+                    "message": e.to_string()
+                }))?
+                .with_status(400));
+            }
+        }
+    };
+    let limit = match request.limit {
+        None => DEFAULT_LIMIT,
+        Some(limit) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Some(_) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": format!("limitは1から{}の間で指定してください", MAX_LIMIT)
+            }))?
+            .with_status(400));
+        }
+    };
+
+    let (source, target) = source_and_target(&ctx.env)?;
+
+    if request.dry_run {
+        return match dry_run(&source).await {
+            Ok(report) => Ok(Response::from_json(&serde_json::json!({
+                "dry_run": true,
+                "plans": report.plans,
+                "details": report.details,
+                "failures": report.failures,
+            }))?
+            .with_status(200)),
+            Err(err) => {
+                console_error!("failed to read migration source: {:?}", err);
+                internal_error()
+            }
+        };
+    }
+
+    let Some(target) = target else {
+        return d1_not_configured();
+    };
+
+    let mut state = match ctx.data.migration.read().await {
+        Ok(Some(state)) if !request.restart => state,
+        Ok(_) => MigrationState::start(),
+        Err(err) => {
+            console_error!("failed to read migration state: {:?}", err);
+            return internal_error();
+        }
+    };
+
+    // 途中で失敗しても、それまでに移行した分の進捗は保存する
+    let result = run_batch(&source, &target, &mut state, limit).await;
+    if let Err(err) = ctx.data.migration.write(&state).await {
+        console_error!("failed to write migration state: {:?}", err);
+        return internal_error();
+    }
+    if let Err(err) = result {
+        console_error!("failed to migrate: {:?}", err);
+        return internal_error();
+    }
+
+    record_audit(
+        &ctx.data,
+        AuditRecord::new(&principal, "POST /v1/admin/migrations/d1", None).with_after(
+            &serde_json::json!({
+                "phase": state.phase,
+                "plans_migrated": state.plans_migrated,
+                "details_migrated": state.details_migrated,
+                "failures": state.failures.len(),
+            }),
+        ),
+    )
+    .await;
+
+    let completed = state.phase == MigrationPhase::Completed;
+    let verification = if completed {
+        invalidate_cache(&ctx.data, &[CacheScope::List]).await;
+        match verify(&source, &target).await {
+            Ok(verification) => Some(verification),
+            Err(err) => {
+                console_error!("failed to verify migration: {:?}", err);
+                return internal_error();
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(Response::from_json(&serde_json::json!({
+        "dry_run": false,
+        "completed": completed,
+        "state": state,
+        "verification": verification,
+    }))?
+    .with_status(200))
+}
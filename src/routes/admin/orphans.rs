@@ -0,0 +1,73 @@
+use crate::auth::permission::Permission;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::routes::admin::{authorize, invalidate_cache, record_audit};
+use crate::storage::orphans::{clean, scan};
+use crate::storage::Storage;
+use std::collections::BTreeSet;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+/// 企画が存在しない企画詳細・アイコン・所有者を取得する
+pub async fn get_orphans(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::Maintenance).await? {
+        return Ok(response);
+    }
+
+    match scan(&ctx.data).await {
+        Ok(orphans) => Ok(Response::from_json(&orphans)?.with_status(200)),
+        Err(err) => {
+            console_error!("failed to scan orphans: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
+
+/// 企画が存在しない企画詳細・アイコン・所有者を削除する
+pub async fn post_orphans_purge(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::Maintenance).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    match clean(&ctx.data).await {
+        Ok(cleaned) => {
+            if !cleaned.is_empty() {
+                record_audit(
+                    &ctx.data,
+                    AuditRecord::new(&principal, "POST /v1/admin/orphans:purge", None)
+                        .with_before(&cleaned),
+                )
+                .await;
+
+                // 公開APIが削除した企画詳細・アイコンを返し続けないようにする（所有者は公開APIで返さない）
+                let ids = cleaned
+                    .details
+                    .iter()
+                    .chain(&cleaned.icons)
+                    .collect::<BTreeSet<_>>();
+                let scopes = ids
+                    .iter()
+                    .map(|id| CacheScope::Plan(id.as_str()))
+                    .collect::<Vec<_>>();
+                invalidate_cache(&ctx.data, &scopes).await;
+            }
+
+            Ok(Response::from_json(&cleaned)?.with_status(200))
+        }
+        Err(err) => {
+            console_error!("failed to clean orphans: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
@@ -1,69 +1,196 @@
-use crate::models::keys::put_keys;
+use crate::auth::permission::Permission;
+use crate::auth::Principal;
+use crate::bulk::apply_atomic;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::keys::is_valid_plan_id;
 use crate::models::plan::{
-    PlanCreate, PlanCreateError, PlanRead, PlanReadError, PlanUpdate, PlanUpdateError,
+    PlanBulkUpdate, PlanCreate, PlanCreateError, PlanRead, PlanReadError, PlanUpdate,
+    PlanUpdateError,
+};
+use crate::models::schema::Document;
+use crate::models::trash::move_to_trash;
+use crate::routes::admin::plans::rename::rename_plan;
+use crate::routes::admin::trash::restore_plan;
+use crate::routes::admin::{
+    authorize, authorize_plan, invalidate_cache, precondition_failed_response, record_audit,
+    record_revision,
 };
 use crate::service::discord::Discord;
-use crate::KV_PLANS;
+use crate::storage::Storage;
+use crate::util::{etag, if_match};
+use serde_json::Value;
+use std::collections::BTreeMap;
 use worker::{console_error, Error, Request, Response, RouteContext};
 
 pub mod details;
 pub mod icon;
+pub mod owners;
+pub mod rename;
+pub mod revisions;
+
+pub async fn put_plan(mut req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::PlansWrite).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
 
-pub async fn put_plan(mut req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
     let plan_id = ctx.param("plan_id").map_or("", |v| v);
+    if !is_valid_plan_id(plan_id) {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 400,
+            "message": format!("「{}」は企画IDとして使用できません", plan_id)
+        }))?
+        .with_status(400));
+    }
 
-    match req.json::<PlanCreate>().await {
-        Ok(plan_create) => {
-            let kv = ctx.env.kv(KV_PLANS)?;
-            match plan_create.clone().create(kv, plan_id).await {
-                Ok(_) => {
-                    // Discord通知
-                    let discord = Discord::new_from_env(&ctx.env);
-                    match discord.send_create_plan(plan_id.into(), &plan_create).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            console_error!("Discord webhook error: {}", err)
-                        }
-                    }
+    let plan_create = match req.json::<PlanCreate>().await {
+        Ok(plan_create) => plan_create,
+        Err(e) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": e.to_string()
+            }))?
+            .with_status(400));
+        }
+    };
 
-                    // Update keys cache
-                    let kv_cache = ctx.env.kv(KV_PLANS)?;
-                    if let Err(err) = put_keys(&kv_cache).await {
-                        console_error!("Failed to update keys cache: {:?}", err);
-                    }
+    let plan = match create(&ctx.data, plan_id, plan_create.clone()).await {
+        Ok(plan) => plan,
+        Err(CreateError::RuleViolation(message)) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 422,
+                "message": message
+            }))?
+            .with_status(422));
+        }
+        Err(CreateError::Conflict) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 409,
+                "message": "指定されたIDの企画が既に存在します"
+            }))?
+            .with_status(409));
+        }
+        Err(CreateError::Internal(err)) => {
+            console_error!("failed to create plan: {}", err);
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500));
+        }
+    };
 
-                    // 企画作成成功時は204 No Contentを返す
-                    Ok(Response::empty()?.with_status(204))
-                }
-                Err(PlanCreateError::Conflict) => Ok(Response::from_json(&serde_json::json!({
-                    "code": 409,
-                    "message": "指定されたIDの企画が既に存在します"
-                }))?
-                .with_status(409)),
-                Err(PlanCreateError::KvError(_)) => Ok(Response::from_json(&serde_json::json!({
-                    "code": 500,
-                    "message": "内部エラーが発生しました"
-                }))?
-                .with_status(500)),
-            }
+    record_revision(
+        &ctx.data,
+        Document::Plan,
+        plan_id,
+        None::<&Value>,
+        &plan,
+        &principal,
+    )
+    .await;
+    invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id), CacheScope::List]).await;
+    record_audit(
+        &ctx.data,
+        AuditRecord::new(&principal, "PUT /v1/admin/plans/:plan_id", Some(plan_id))
+            .with_after(&plan),
+    )
+    .await;
+
+    // Discord通知
+    let discord = Discord::new_from_env(&ctx.env);
+    match discord.send_create_plan(plan_id.into(), &plan_create).await {
+        Ok(_) => {}
+        Err(err) => {
+            console_error!("Discord webhook error: {}", err)
         }
-        Err(e) => Ok(Response::from_json(&serde_json::json!({
-            "code": 400,
-            "message": e.to_string()
-        }))?
-        .with_status(400)),
     }
+
+    // 企画作成成功時は204 No Contentを返す
+    let mut response = Response::empty()?.with_status(204);
+    if let Ok(value) = serde_json::to_value(&plan) {
+        response.headers_mut().set("ETag", &etag(&value))?;
+    }
+    Ok(response)
+}
+
+/// 企画を作成できなかった理由
+#[derive(Debug)]
+pub enum CreateError {
+    /// 企画の値が規則に反している。理由を持つ
+    RuleViolation(String),
+    Conflict,
+    Internal(String),
+}
+
+/// 企画を作成し、インデックスに追加する
+pub async fn create(
+    storage: &Storage,
+    plan_id: &str,
+    plan_create: PlanCreate,
+) -> Result<PlanRead, CreateError> {
+    plan_create
+        .clone()
+        .into_read(plan_id)
+        .check_rules()
+        .map_err(CreateError::RuleViolation)?;
+
+    let plan = match storage.plans.create(plan_id, plan_create).await {
+        Ok(plan) => plan,
+        Err(PlanCreateError::Conflict) => return Err(CreateError::Conflict),
+        Err(err) => return Err(CreateError::Internal(format!("{:?}", err))),
+    };
+
+    // Update keys cache
+    if let Err(err) = storage.plans.update_index(&[plan_id.into()], &[]).await {
+        console_error!("Failed to update keys cache: {:?}", err);
+    }
+
+    Ok(plan)
 }
 
-pub async fn patch_plan(mut req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+pub async fn patch_plan(mut req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
     let plan_id = ctx.param("plan_id").map_or("", |v| v);
 
-    let kv = ctx.env.kv(KV_PLANS)?;
+    let principal = match authorize_plan(&req, &ctx, Permission::PlansWrite, plan_id).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let store = &ctx.data.plans;
+    let expected_etag = req.headers().get("If-Match")?;
 
     match req.json::<PlanUpdate>().await {
         Ok(plan_update) => {
-            match plan_update.clone().update(kv, plan_id).await {
-                Ok(_) => {
+            match store
+                .update(plan_id, plan_update.clone(), expected_etag.as_deref())
+                .await
+            {
+                Ok((before, after)) => {
+                    record_revision(
+                        &ctx.data,
+                        Document::Plan,
+                        plan_id,
+                        Some(&before),
+                        &after,
+                        &principal,
+                    )
+                    .await;
+                    invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id), CacheScope::List])
+                        .await;
+                    record_audit(
+                        &ctx.data,
+                        AuditRecord::new(
+                            &principal,
+                            "PATCH /v1/admin/plans/:plan_id",
+                            Some(plan_id),
+                        )
+                        .with_before(&before)
+                        .with_after(&after),
+                    )
+                    .await;
+
                     // discord通知
                     let discord = Discord::new_from_env(&ctx.env);
                     match discord.send_update_plan(plan_id.into(), &plan_update).await {
@@ -73,13 +200,25 @@ pub async fn patch_plan(mut req: Request, ctx: RouteContext<()>) -> Result<Respo
                         }
                     }
                     // 企画更新成功時は204 No Contentを返す
-                    Ok(Response::empty()?.with_status(204))
+                    let mut response = Response::empty()?.with_status(204);
+                    response.headers_mut().set("ETag", &etag(&after))?;
+                    Ok(response)
                 }
                 Err(PlanUpdateError::NotFound) => Ok(Response::from_json(&serde_json::json!({
                     "code": 404,
                     "message": "企画が見つかりません"
                 }))?
                 .with_status(404)),
+                Err(PlanUpdateError::PreconditionFailed(current_etag)) => {
+                    precondition_failed_response(Some(&current_etag))
+                }
+                Err(PlanUpdateError::Invalid(message)) => {
+                    Ok(Response::from_json(&serde_json::json!({
+                        "code": 422,
+                        "message": message
+                    }))?
+                    .with_status(422))
+                }
                 Err(_) => Ok(Response::from_json(&serde_json::json!({
                     "code": 500,
                     "message": "内部エラーが発生しました"
@@ -95,68 +234,317 @@ pub async fn patch_plan(mut req: Request, ctx: RouteContext<()>) -> Result<Respo
     }
 }
 
-pub async fn delete_plan(_req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+/// `POST /v1/admin/plans/:plan_id:<操作>`を操作ごとのハンドラーに振り分ける
+///
+/// `:plan_id:restore`などはルーター上では1つのパラメーターとして扱われるため、接尾辞で判別する
+pub async fn post_plan_action(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    match ctx.param("plan_id").and_then(|id| id.rsplit_once(':')) {
+        Some((_, "restore")) => restore_plan(req, ctx).await,
+        Some((_, "rename")) => rename_plan(req, ctx).await,
+        _ => Ok(Response::from_json(&serde_json::json!({
+            "code": 404,
+            "message": "Not Found"
+        }))?
+        .with_status(404)),
+    }
+}
+
+/// 企画が企画詳細・アイコンを持っているか確認する
+async fn has_dependents(storage: &Storage, plan_id: &str) -> Result<Vec<&'static str>, Error> {
+    let mut dependents = vec![];
+    if storage
+        .details
+        .read_value(plan_id)
+        .await
+        .map_err(|err| Error::RustError(err.to_string()))?
+        .is_some()
+    {
+        dependents.push("企画詳細");
+    }
+    if storage.icons.etag(plan_id).await?.is_some() {
+        dependents.push("アイコン");
+    }
+    Ok(dependents)
+}
+
+/// 企画を企画詳細・所有者・アイコンと一緒にゴミ箱に移動する。
+/// ゴミ箱の企画は`POST /v1/admin/plans/:plan_id:restore`で復元できる
+///
+/// `?cascade=false`を指定した場合は、企画詳細・アイコンが残っていれば削除せずに409を返す。
+/// 同じIDの企画が既にゴミ箱にある場合は、ゴミ箱の企画をこの企画で置き換える
+pub async fn delete_plan(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::PlansDelete).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
     let plan_id = ctx.param("plan_id").map_or("", |v| v);
+    let cascade = !req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "cascade" && value == "false");
 
-    let kv = ctx.env.kv(KV_PLANS)?;
+    let expected_etag = req.headers().get("If-Match")?;
+    let plan = match delete(
+        &ctx.data,
+        &principal,
+        plan_id,
+        expected_etag.as_deref(),
+        cascade,
+    )
+    .await
+    {
+        Ok(plan) => plan,
+        Err(DeleteError::NotFound) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 404,
+                "message": "企画が見つかりません"
+            }))?
+            .with_status(404));
+        }
+        Err(DeleteError::PreconditionFailed(current_etag)) => {
+            return precondition_failed_response(Some(&current_etag));
+        }
+        Err(DeleteError::HasDependents(dependents)) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 409,
+                "message": format!(
+                    "ID「{}」の企画には{}が残っているため削除できません",
+                    plan_id,
+                    dependents.join("・")
+                )
+            }))?
+            .with_status(409));
+        }
+        Err(DeleteError::Internal(err)) => {
+            console_error!("failed to delete plan: {}", err);
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500));
+        }
+    };
 
-    // 企画が存在するか確認
-    match PlanRead::read(kv.clone(), plan_id).await {
-        Ok(_) => {
-            // 削除実行
-            match kv.delete(plan_id).await {
-                Ok(_) => {
-                    // discord通知
-                    let discord = Discord::new_from_env(&ctx.env);
-                    match discord.send_delete_plan(plan_id.into()).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            console_error!("Discord webhook error: {}", err)
-                        }
-                    }
+    record_audit(
+        &ctx.data,
+        AuditRecord::new(&principal, "DELETE /v1/admin/plans/:plan_id", Some(plan_id))
+            .with_before(&plan),
+    )
+    .await;
 
-                    // Update keys cache
-                    let kv_cache = ctx.env.kv(KV_PLANS)?;
-                    if let Err(err) = put_keys(&kv_cache).await {
-                        console_error!("Failed to update keys cache: {:?}", err);
-                    }
+    invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id), CacheScope::List]).await;
 
-                    Ok(Response::empty()?.with_status(204))
-                }
-                Err(_) => Ok(Response::from_json(&serde_json::json!({
-                    "code": 500,
-                    "message": "内部エラーが発生しました"
-                }))?
-                .with_status(500)),
+    // discord通知
+    let discord = Discord::new_from_env(&ctx.env);
+    match discord.send_delete_plan(plan_id.into()).await {
+        Ok(_) => {}
+        Err(err) => {
+            console_error!("Discord webhook error: {}", err)
+        }
+    }
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// 企画を削除できなかった理由
+#[derive(Debug)]
+pub enum DeleteError {
+    NotFound,
+    /// `If-Match`が現在のETagと一致しない。現在のETagを持つ
+    PreconditionFailed(String),
+    /// `cascade`が`false`で、企画に残っているものの名前
+    HasDependents(Vec<&'static str>),
+    Internal(String),
+}
+
+/// 企画を企画詳細・所有者・アイコンと一緒にゴミ箱に移動し、削除した企画を返す
+pub async fn delete(
+    storage: &Storage,
+    principal: &Principal,
+    plan_id: &str,
+    expected_etag: Option<&str>,
+    cascade: bool,
+) -> Result<PlanRead, DeleteError> {
+    let plan = match storage.plans.read_with_etag(plan_id).await {
+        Ok((plan, current_etag)) => {
+            if !if_match(expected_etag, Some(&current_etag)) {
+                return Err(DeleteError::PreconditionFailed(current_etag));
             }
+            plan
+        }
+        Err(PlanReadError::NotFound) => return Err(DeleteError::NotFound),
+        Err(err) => return Err(DeleteError::Internal(format!("{:?}", err))),
+    };
+
+    if !cascade {
+        let dependents = has_dependents(storage, plan_id)
+            .await
+            .map_err(|err| DeleteError::Internal(format!("{:?}", err)))?;
+        if !dependents.is_empty() {
+            return Err(DeleteError::HasDependents(dependents));
         }
-        Err(PlanReadError::NotFound) => Ok(Response::from_json(&serde_json::json!({
-            "code": 404,
-            "message": "企画が見つかりません"
-        }))?
-        .with_status(404)),
-        Err(_) => Ok(Response::from_json(&serde_json::json!({
-            "code": 500,
-            "message": "内部エラーが発生しました"
-        }))?
-        .with_status(500)),
     }
+
+    move_to_trash(storage, principal, plan_id, &plan)
+        .await
+        .map_err(|err| DeleteError::Internal(format!("{:?}", err)))?;
+    Ok(plan)
 }
 
-pub async fn post_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+fn is_atomic(req: &Request) -> Result<bool, Error> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "atomic" && value == "true"))
+}
+
+/// 一括作成・一括更新をall-or-nothingで実行する（`?atomic=true`）
+///
+/// 全てのエントリーの結果を返し、1件でも失敗した場合は何も書き込まない（書き込んだ分は取り消す）
+async fn bulk_atomic(
+    mut req: Request,
+    ctx: RouteContext<Storage>,
+    principal: Principal,
+    create: bool,
+) -> Result<Response, Error> {
+    let entries = match req.json::<BTreeMap<String, Value>>().await {
+        Ok(entries) => entries,
+        Err(e) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": e.to_string()
+            }))?
+            .with_status(400));
+        }
+    };
+    // Discord通知用（書き込んだ場合は全てのエントリーが正しい形であるため、ここで失敗したものは使われない）
+    let updates = entries
+        .iter()
+        .filter_map(|(id, value)| {
+            let entry = serde_json::from_value::<PlanBulkUpdate>(value.clone()).ok()?;
+            Some((id.clone(), entry.update))
+        })
+        .collect::<Vec<_>>();
+
+    let report = apply_atomic(&ctx.data, entries, create).await;
+
+    let route = if create {
+        "POST /v1/admin/plans:bulk"
+    } else {
+        "PATCH /v1/admin/plans:bulk"
+    };
+    for change in &report.changes {
+        record_revision(
+            &ctx.data,
+            Document::Plan,
+            &change.id,
+            change.before.as_ref(),
+            &change.after,
+            &principal,
+        )
+        .await;
+        let mut record =
+            AuditRecord::new(&principal, route, Some(&change.id)).with_after(&change.after);
+        if let Some(before) = &change.before {
+            record = record.with_before(before);
+        }
+        record_audit(&ctx.data, record).await;
+    }
+
+    // 取り消した企画も、書き込んでいた間にキャッシュされた可能性があるため無効化する
+    if !report.touched.is_empty() {
+        let mut scopes = report
+            .touched
+            .iter()
+            .map(|id| CacheScope::Plan(id))
+            .collect::<Vec<_>>();
+        scopes.push(CacheScope::List);
+        invalidate_cache(&ctx.data, &scopes).await;
+    }
+
+    if report.applied {
+        let discord = Discord::new_from_env(&ctx.env);
+        let result = if create {
+            let created = report
+                .changes
+                .iter()
+                .map(|change| change.id.clone())
+                .collect::<Vec<_>>();
+            if let Err(err) = ctx.data.plans.update_index(&created, &[]).await {
+                console_error!("Failed to update keys cache: {:?}", err);
+            }
+            discord.send_bulk_create_plan().await
+        } else {
+            discord.send_bulk_update_plan(updates).await
+        };
+        if let Err(err) = result {
+            console_error!("Discord webhook error: {}", err)
+        }
+    }
+
+    Ok(Response::from_json(&report)?.with_status(report.status))
+}
+
+pub async fn post_plans_bulk(
+    mut req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::BulkWrite).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+    if is_atomic(&req)? {
+        return bulk_atomic(req, ctx, principal, true).await;
+    }
+
     match req
         .json::<std::collections::HashMap<String, PlanCreate>>()
         .await
     {
         Ok(plans_map) => {
-            let kv = ctx.env.kv(KV_PLANS)?;
+            let store = &ctx.data.plans;
             let mut errors = Vec::new();
+            let mut created = Vec::new();
 
             // すべてのエントリーに対して作成を試行
             for (id, plan_create) in plans_map {
-                match plan_create.create(kv.clone(), &id).await {
-                    Ok(_) => {
+                if !is_valid_plan_id(&id) {
+                    errors.push(serde_json::json!({
+                        "plan_id": id,
+                        "code": 400,
+                        "message": format!("「{}」は企画IDとして使用できません", id)
+                    }));
+                    continue;
+                }
+                if let Err(message) = plan_create.clone().into_read(&id).check_rules() {
+                    errors.push(serde_json::json!({
+                        "plan_id": id,
+                        "code": 422,
+                        "message": message
+                    }));
+                    continue;
+                }
+                match store.create(&id, plan_create).await {
+                    Ok(plan) => {
+                        created.push(id.clone());
                         // 企画作成成功
+                        record_revision(
+                            &ctx.data,
+                            Document::Plan,
+                            &id,
+                            None::<&Value>,
+                            &plan,
+                            &principal,
+                        )
+                        .await;
+                        invalidate_cache(&ctx.data, &[CacheScope::Plan(&id)]).await;
+                        record_audit(
+                            &ctx.data,
+                            AuditRecord::new(&principal, "POST /v1/admin/plans:bulk", Some(&id))
+                                .with_after(&plan),
+                        )
+                        .await;
                     }
                     Err(PlanCreateError::Conflict) => {
                         errors.push(serde_json::json!({
@@ -165,7 +553,7 @@ pub async fn post_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result<
                             "message": format!("指定されたID「{}」の企画が既に存在します", id)
                         }));
                     }
-                    Err(PlanCreateError::KvError(_)) => {
+                    Err(PlanCreateError::KvError(_) | PlanCreateError::WorkerError(_)) => {
                         errors.push(serde_json::json!({
                             "plan_id": id,
                             "code": 500,
@@ -175,6 +563,14 @@ pub async fn post_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result<
                 }
             }
 
+            // 一覧のキャッシュはエントリーごとではなく最後に一度だけ無効化する
+            invalidate_cache(&ctx.data, &[CacheScope::List]).await;
+
+            // Update keys cache（一部が失敗した場合も作成できた分は反映する）
+            if let Err(err) = store.update_index(&created, &[]).await {
+                console_error!("Failed to update keys cache: {:?}", err);
+            }
+
             if errors.is_empty() {
                 // discord
                 let discord = Discord::new_from_env(&ctx.env);
@@ -185,12 +581,6 @@ pub async fn post_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result<
                     }
                 }
 
-                // Update keys cache
-                let kv_cache = ctx.env.kv(KV_PLANS)?;
-                if let Err(err) = put_keys(&kv_cache).await {
-                    console_error!("Failed to update keys cache: {:?}", err);
-                }
-
                 // 全て成功した場合は201 Createdで空のレスポンスを返す
                 Ok(Response::empty()?.with_status(201))
             } else {
@@ -209,20 +599,51 @@ pub async fn post_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result<
     }
 }
 
-pub async fn patch_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+pub async fn patch_plans_bulk(
+    mut req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::BulkWrite).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+    if is_atomic(&req)? {
+        return bulk_atomic(req, ctx, principal, false).await;
+    }
+
     match req
-        .json::<std::collections::HashMap<String, PlanUpdate>>()
+        .json::<std::collections::HashMap<String, PlanBulkUpdate>>()
         .await
     {
         Ok(plans_map) => {
-            let kv = ctx.env.kv(KV_PLANS)?;
+            let store = &ctx.data.plans;
             let mut errors = Vec::new();
 
             // すべてのエントリーに対して更新を試行
-            for (id, plan_update) in plans_map.clone() {
-                match plan_update.update(kv.clone(), &id).await {
-                    Ok(_) => {
+            for (id, entry) in plans_map.clone() {
+                match store
+                    .update(&id, entry.update, entry.if_match.as_deref())
+                    .await
+                {
+                    Ok((before, after)) => {
                         // 企画更新成功
+                        record_revision(
+                            &ctx.data,
+                            Document::Plan,
+                            &id,
+                            Some(&before),
+                            &after,
+                            &principal,
+                        )
+                        .await;
+                        invalidate_cache(&ctx.data, &[CacheScope::Plan(&id)]).await;
+                        record_audit(
+                            &ctx.data,
+                            AuditRecord::new(&principal, "PATCH /v1/admin/plans:bulk", Some(&id))
+                                .with_before(&before)
+                                .with_after(&after),
+                        )
+                        .await;
                     }
                     Err(PlanUpdateError::NotFound) => {
                         errors.push(serde_json::json!({
@@ -231,6 +652,21 @@ pub async fn patch_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result
                             "message": format!("指定されたID「{}」の企画が見つかりません", id)
                         }));
                     }
+                    Err(PlanUpdateError::PreconditionFailed(current_etag)) => {
+                        errors.push(serde_json::json!({
+                            "plan_id": id,
+                            "code": 412,
+                            "message": format!("ID「{}」の企画は他の操作によって更新されています", id),
+                            "current_etag": current_etag
+                        }));
+                    }
+                    Err(PlanUpdateError::Invalid(message)) => {
+                        errors.push(serde_json::json!({
+                            "plan_id": id,
+                            "code": 422,
+                            "message": message
+                        }));
+                    }
                     Err(_) => {
                         errors.push(serde_json::json!({
                             "plan_id": id,
@@ -241,6 +677,9 @@ pub async fn patch_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result
                 }
             }
 
+            // 一覧のキャッシュはエントリーごとではなく最後に一度だけ無効化する
+            invalidate_cache(&ctx.data, &[CacheScope::List]).await;
+
             if errors.is_empty() {
                 // discord通知
                 let discord = Discord::new_from_env(&ctx.env);
@@ -248,7 +687,7 @@ pub async fn patch_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result
                     .send_bulk_update_plan(
                         plans_map
                             .iter()
-                            .map(|e| (e.0.clone(), e.1.clone()))
+                            .map(|e| (e.0.clone(), e.1.update.clone()))
                             .collect(),
                     )
                     .await
@@ -274,3 +713,136 @@ pub async fn patch_plans_bulk(mut req: Request, ctx: RouteContext<()>) -> Result
         .with_status(400)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::KeycloakClaims;
+    use crate::storage::memory::{plan_value, seed, storage};
+    use futures::executor::block_on;
+
+    fn principal() -> Principal {
+        Principal {
+            subject: "admin".into(),
+            claims: KeycloakClaims::default(),
+            permissions: Default::default(),
+        }
+    }
+
+    fn plan_create() -> PlanCreate {
+        serde_json::from_value(plan_value()).unwrap()
+    }
+
+    #[test]
+    fn created_plan_can_be_read_and_deleted() {
+        let storage = storage();
+        block_on(async {
+            let created = create(&storage, "plan", plan_create()).await.unwrap();
+            let (read, current_etag) = storage.plans.read_with_etag("plan").await.unwrap();
+            assert_eq!(
+                serde_json::to_value(&read).unwrap(),
+                serde_json::to_value(&created).unwrap()
+            );
+
+            let deleted = delete(&storage, &principal(), "plan", Some(&current_etag), true)
+                .await
+                .unwrap();
+            assert_eq!(deleted.id, "plan");
+            assert!(matches!(
+                storage.plans.read("plan").await,
+                Err(PlanReadError::NotFound)
+            ));
+            assert!(storage.trash.read("plan").await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn create_rejects_conflicts_and_rule_violations() {
+        let storage = storage();
+        block_on(async {
+            create(&storage, "plan", plan_create()).await.unwrap();
+            assert!(matches!(
+                create(&storage, "plan", plan_create()).await,
+                Err(CreateError::Conflict)
+            ));
+
+            let mut value = plan_value();
+            value["plan_name"] = serde_json::json!(" ");
+            let invalid = serde_json::from_value(value).unwrap();
+            assert!(matches!(
+                create(&storage, "other", invalid).await,
+                Err(CreateError::RuleViolation(_))
+            ));
+            assert!(storage.plans.read_value("other").await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn delete_moves_details_owners_and_icon_to_trash() {
+        let storage = storage();
+        block_on(async {
+            seed(&storage, "plan").await;
+            delete(&storage, &principal(), "plan", None, true)
+                .await
+                .unwrap();
+
+            assert!(storage.details.read_value("plan").await.unwrap().is_none());
+            assert!(storage.icons.get("plan").await.unwrap().is_none());
+            assert!(storage
+                .owners
+                .read("plan")
+                .await
+                .unwrap()
+                .subjects
+                .is_empty());
+
+            let entry = storage.trash.read("plan").await.unwrap().unwrap();
+            assert!(entry.details.is_some());
+            assert!(entry.icon);
+        });
+    }
+
+    #[test]
+    fn delete_checks_existence_etag_and_dependents() {
+        let storage = storage();
+        block_on(async {
+            assert!(matches!(
+                delete(&storage, &principal(), "missing", None, true).await,
+                Err(DeleteError::NotFound)
+            ));
+
+            seed(&storage, "plan").await;
+            assert!(matches!(
+                delete(&storage, &principal(), "plan", Some("\"stale\""), true).await,
+                Err(DeleteError::PreconditionFailed(_))
+            ));
+            match delete(&storage, &principal(), "plan", None, false).await {
+                Err(DeleteError::HasDependents(dependents)) => {
+                    assert_eq!(dependents, ["企画詳細", "アイコン"])
+                }
+                other => panic!("unexpected result: {:?}", other.map(|plan| plan.id)),
+            }
+            assert!(storage.plans.read_value("plan").await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn deleting_a_recreated_plan_replaces_it_in_trash() {
+        let storage = storage();
+        block_on(async {
+            seed(&storage, "plan").await;
+            delete(&storage, &principal(), "plan", None, true)
+                .await
+                .unwrap();
+            create(&storage, "plan", plan_create()).await.unwrap();
+            delete(&storage, &principal(), "plan", None, true)
+                .await
+                .unwrap();
+
+            let trashed = storage.trash.list().await.unwrap();
+            assert_eq!(trashed.len(), 1);
+            assert!(!trashed[0].icon);
+            assert!(trashed[0].details.is_none());
+        });
+    }
+}
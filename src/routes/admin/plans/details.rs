@@ -1,20 +1,69 @@
-use crate::models::details::{
-    CreatePlanDetails, PlanDetailsCreateError, PlanDetailsReadError, ReadPlanDetails,
+use crate::auth::permission::Permission;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::details::{CreatePlanDetails, PlanDetailsCreateError, PlanDetailsReadError};
+use crate::models::schema::Document;
+use crate::routes::admin::{
+    authorize_plan, invalidate_cache, precondition_failed_response, record_audit, record_revision,
 };
 use crate::service::discord::Discord;
-use crate::KV_PLAN_DETAILS;
+use crate::storage::Storage;
+use crate::util::{etag, if_match};
 use worker::{Error, Request, Response, RouteContext};
 
-pub async fn put_details(mut req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+pub async fn put_details(mut req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
     let plan_id = ctx.param("plan_id").map_or("", |v| v).to_string();
 
+    let principal = match authorize_plan(&req, &ctx, Permission::DetailsWrite, &plan_id).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let expected_etag = req.headers().get("If-Match")?;
+
     match req.json::<CreatePlanDetails>().await {
         Ok(plan_details_create) => {
-            let kv = ctx.env.kv(KV_PLAN_DETAILS)?;
+            let store = &ctx.data.details;
+            // 監査記録のため更新前の値を取得しておく
+            let (before, current_etag) = match store.read_with_etag(&plan_id).await {
+                Ok((before, current_etag)) => (Some(before), Some(current_etag)),
+                Err(PlanDetailsReadError::NotFound) => (None, None),
+                Err(_) => {
+                    return Ok(Response::from_json(&serde_json::json!({
+                        "code": 500,
+                        "message": "内部エラーが発生しました"
+                    }))?
+                    .with_status(500))
+                }
+            };
+            if !if_match(expected_etag.as_deref(), current_etag.as_deref()) {
+                return precondition_failed_response(current_etag.as_deref());
+            }
             // keep a clone for Discord notification after successful upsert
             let details_for_notify = plan_details_create.clone();
-            match plan_details_create.create(kv, &plan_id).await {
-                Ok(_) => {
+            match store.put(&plan_id, plan_details_create).await {
+                Ok(after) => {
+                    record_revision(
+                        &ctx.data,
+                        Document::Details,
+                        &plan_id,
+                        before.as_ref(),
+                        &after,
+                        &principal,
+                    )
+                    .await;
+                    invalidate_cache(&ctx.data, &[CacheScope::Plan(&plan_id)]).await;
+                    let mut record = AuditRecord::new(
+                        &principal,
+                        "PUT /v1/admin/plans/:plan_id/details",
+                        Some(&plan_id),
+                    )
+                    .with_after(&after);
+                    if let Some(before) = before {
+                        record = record.with_before(&before);
+                    }
+                    record_audit(&ctx.data, record).await;
+
                     // fire-and-forget Discord notification (do not fail the API on error)
                     let discord = Discord::new_from_env(&ctx.env);
                     if let Err(err) = discord
@@ -24,15 +73,19 @@ pub async fn put_details(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
                         worker::console_log!("Failed to send Discord details update: {}", err);
                     }
                     // 詳細情報作成・更新成功時は204 No Contentを返す
-                    Ok(Response::empty()?.with_status(204))
-                }
-                Err(PlanDetailsCreateError::KvError(_)) => {
-                    Ok(Response::from_json(&serde_json::json!({
-                        "code": 500,
-                        "message": "内部エラーが発生しました"
-                    }))?
-                    .with_status(500))
+                    let mut response = Response::empty()?.with_status(204);
+                    if let Ok(value) = serde_json::to_value(&after) {
+                        response.headers_mut().set("ETag", &etag(&value))?;
+                    }
+                    Ok(response)
                 }
+                Err(
+                    PlanDetailsCreateError::KvError(_) | PlanDetailsCreateError::WorkerError(_),
+                ) => Ok(Response::from_json(&serde_json::json!({
+                    "code": 500,
+                    "message": "内部エラーが発生しました"
+                }))?
+                .with_status(500)),
                 Err(PlanDetailsCreateError::SerdeError(_)) => {
                     Ok(Response::from_json(&serde_json::json!({
                         "code": 500,
@@ -50,11 +103,23 @@ pub async fn put_details(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
     }
 }
 
-pub async fn get_details_admin(_req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+pub async fn get_details_admin(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
     let plan_id = ctx.param("plan_id").map_or("", |v| v);
-    let kv = ctx.env.kv(KV_PLAN_DETAILS)?;
-    match ReadPlanDetails::read(kv, plan_id).await {
-        Ok(plan_details) => Ok(Response::from_json(&plan_details)?.with_status(200)),
+
+    if let Err(response) = authorize_plan(&req, &ctx, Permission::DetailsRead, plan_id).await? {
+        return Ok(response);
+    }
+
+    let store = &ctx.data.details;
+    match store.read_with_etag(plan_id).await {
+        Ok((plan_details, etag)) => {
+            let mut response = Response::from_json(&plan_details)?.with_status(200);
+            response.headers_mut().set("ETag", &etag)?;
+            Ok(response)
+        }
         Err(PlanDetailsReadError::NotFound) => Ok(Response::from_json(&serde_json::json!({
             "code": 404,
             "message": "企画詳細が見つかりません"
@@ -1,15 +1,25 @@
+use crate::auth::permission::Permission;
 use crate::icon::{write_icon, WriteIconError};
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::routes::admin::{
+    authorize_plan, invalidate_cache, precondition_failed_response, record_audit,
+};
 use crate::service::discord::Discord;
-use crate::R2_PLAN_IMAGES;
+use crate::storage::Storage;
+use crate::util::{if_match, sha256_hex};
 use worker::{console_error, Request, Response};
 
 pub async fn put_icon(
     mut req: Request,
-    ctx: worker::RouteContext<()>,
+    ctx: worker::RouteContext<Storage>,
 ) -> Result<Response, worker::Error> {
     let plan_id = ctx.param("plan_id").unwrap();
-    let bucket = ctx.env.bucket(R2_PLAN_IMAGES)?;
 
+    let principal = match authorize_plan(&req, &ctx, Permission::IconsWrite, plan_id).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
     // ヘッダー検証
     let ct = req.headers().get("content-type")?.unwrap_or_default();
     if !ct.starts_with("image/") {
@@ -20,10 +30,35 @@ pub async fn put_icon(
         return Response::error("payload too large", 413);
     }
 
+    // 同時編集の検出（ETagはGET /v1/plans/:plan_id/iconが返すR2のもの）
+    let expected_etag = req.headers().get("If-Match")?;
+    if expected_etag.is_some() {
+        let current_etag = ctx.data.icons.etag(plan_id).await?;
+        if !if_match(expected_etag.as_deref(), current_etag.as_deref()) {
+            return precondition_failed_response(current_etag.as_deref());
+        }
+    }
+
     // 保存
     let discord = Discord::new_from_env(&ctx.env);
-    match write_icon(bucket, plan_id, bytes, ct, discord).await {
-        Ok(_) => Ok(Response::empty()?.with_status(204)),
+    let icon_hash = sha256_hex(&bytes);
+    let after = serde_json::json!({ "content_type": ct, "size": bytes.len() });
+    match write_icon(&*ctx.data.icons, plan_id, bytes, ct, discord).await {
+        Ok(_) => {
+            invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id), CacheScope::List]).await;
+            record_audit(
+                &ctx.data,
+                AuditRecord::new(
+                    &principal,
+                    "PUT /v1/admin/plans/:plan_id/icon",
+                    Some(plan_id),
+                )
+                .with_after(&after)
+                .with_icon_hash(icon_hash),
+            )
+            .await;
+            Ok(Response::empty()?.with_status(204))
+        }
         Err(WriteIconError::WorkerError(e)) => Ok(Response::from_json(&serde_json::json!({
             "code": 500,
             "message": format!("Internal error occurred: {}", e.to_string())
@@ -34,10 +69,14 @@ pub async fn put_icon(
 
 pub async fn post_icon_import(
     mut req: Request,
-    ctx: worker::RouteContext<()>,
+    ctx: worker::RouteContext<Storage>,
 ) -> Result<Response, worker::Error> {
     let plan_id = ctx.param("plan_id").unwrap();
-    let bucket = ctx.env.bucket(R2_PLAN_IMAGES)?;
+
+    let principal = match authorize_plan(&req, &ctx, Permission::IconsWrite, plan_id).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
 
     // リクエストボディからURLを取得
     let body: serde_json::Value = match req.json().await {
@@ -120,8 +159,24 @@ pub async fn post_icon_import(
 
     // アイコンを保存
     let discord = Discord::new_from_env(&ctx.env);
-    match write_icon(bucket, plan_id, bytes, ct, discord).await {
-        Ok(_) => Ok(Response::empty()?.with_status(204)),
+    let icon_hash = sha256_hex(&bytes);
+    let after = serde_json::json!({ "content_type": ct, "size": bytes.len(), "source_url": url });
+    match write_icon(&*ctx.data.icons, plan_id, bytes, ct, discord).await {
+        Ok(_) => {
+            invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id), CacheScope::List]).await;
+            record_audit(
+                &ctx.data,
+                AuditRecord::new(
+                    &principal,
+                    "POST /v1/admin/plans/:plan_id/icon:import",
+                    Some(plan_id),
+                )
+                .with_after(&after)
+                .with_icon_hash(icon_hash),
+            )
+            .await;
+            Ok(Response::empty()?.with_status(204))
+        }
         Err(WriteIconError::WorkerError(e)) => Ok(Response::from_json(&serde_json::json!({
             "code": 500,
             "message": format!("Internal error occurred: {}", e.to_string())
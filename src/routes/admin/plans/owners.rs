@@ -0,0 +1,90 @@
+use crate::auth::permission::Permission;
+use crate::models::audit::AuditRecord;
+use crate::models::owners::PlanOwners;
+use crate::models::plan::PlanReadError;
+use crate::routes::admin::{authorize, record_audit};
+use crate::storage::Storage;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+pub async fn get_owners(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::OwnersRead).await? {
+        return Ok(response);
+    }
+
+    let plan_id = ctx.param("plan_id").map_or("", |v| v);
+    match ctx.data.owners.read(plan_id).await {
+        Ok(owners) => Ok(Response::from_json(&owners)?.with_status(200)),
+        Err(err) => {
+            console_error!("failed to read plan owners: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
+
+pub async fn put_owners(mut req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::OwnersWrite).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let plan_id = ctx.param("plan_id").map_or("", |v| v);
+
+    let owners = match req.json::<PlanOwners>().await {
+        Ok(owners) => owners,
+        Err(e) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": e.to_string()
+            }))?
+            .with_status(400));
+        }
+    };
+
+    // 企画が存在するか確認
+    match ctx.data.plans.read(plan_id).await {
+        Ok(_) => {}
+        Err(PlanReadError::NotFound) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 404,
+                "message": "企画が見つかりません"
+            }))?
+            .with_status(404));
+        }
+        Err(_) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500));
+        }
+    }
+
+    let before = ctx.data.owners.read(plan_id).await.ok();
+    match ctx.data.owners.write(plan_id, &owners).await {
+        Ok(_) => {
+            let mut record = AuditRecord::new(
+                &principal,
+                "PUT /v1/admin/plans/:plan_id/owners",
+                Some(plan_id),
+            )
+            .with_after(&owners);
+            if let Some(before) = before {
+                record = record.with_before(&before);
+            }
+            record_audit(&ctx.data, record).await;
+            Ok(Response::empty()?.with_status(204))
+        }
+        Err(err) => {
+            console_error!("failed to write plan owners: {:?}", err);
+            Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500))
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use crate::auth::permission::Permission;
+use crate::models::alias::{ttl, PlanAlias};
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::keys::is_valid_plan_id;
+use crate::models::owners::PlanOwners;
+use crate::models::plan::{PlanRead, PlanReadError};
+use crate::models::schema::Document;
+use crate::routes::admin::{
+    authorize, invalidate_cache, precondition_failed_response, record_audit, record_revision,
+};
+use crate::storage::Storage;
+use crate::util::if_match;
+use chrono::Duration;
+use serde::Deserialize;
+use serde_json::Value;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+#[derive(Deserialize)]
+struct RenameRequest {
+    /// 変更後の企画ID
+    id: String,
+}
+
+fn internal_error() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 500,
+        "message": "内部エラーが発生しました"
+    }))?
+    .with_status(500))
+}
+
+/// 企画IDを変更できなかった理由
+#[derive(Debug)]
+pub enum RenameError {
+    NotFound,
+    /// `If-Match`が現在のETagと一致しない。現在のETagを持つ
+    PreconditionFailed(String),
+    /// 変更後の企画IDに既にあるものの名前
+    Conflict(&'static str),
+    Internal(String),
+}
+
+/// 企画IDを変更した結果
+pub struct Renamed {
+    pub before: PlanRead,
+    pub after: PlanRead,
+    pub details: Option<Value>,
+}
+
+/// 変更後の企画IDに、企画・ゴミ箱の企画・企画詳細・アイコンのいずれかが既にあれば、その名前を返す
+async fn find_conflict(storage: &Storage, id: &str) -> Result<Option<&'static str>, String> {
+    if storage
+        .plans
+        .read_value(id)
+        .await
+        .map_err(|err| format!("{:?}", err))?
+        .is_some()
+    {
+        return Ok(Some("企画"));
+    }
+    if storage
+        .trash
+        .read(id)
+        .await
+        .map_err(|err| err.to_string())?
+        .is_some()
+    {
+        return Ok(Some("ゴミ箱の企画"));
+    }
+    if storage
+        .details
+        .read_value(id)
+        .await
+        .map_err(|err| err.to_string())?
+        .is_some()
+    {
+        return Ok(Some("企画詳細"));
+    }
+    if storage
+        .icons
+        .etag(id)
+        .await
+        .map_err(|err| err.to_string())?
+        .is_some()
+    {
+        return Ok(Some("アイコン"));
+    }
+    Ok(None)
+}
+
+/// 変更後の企画IDに書き込んだもの（取り消し用）
+#[derive(Default)]
+struct Copied {
+    plan: bool,
+    details: bool,
+    icon: bool,
+    /// 移動した所有者
+    owners: Option<PlanOwners>,
+    revisions: Vec<Document>,
+}
+
+/// 企画・企画詳細・所有者・変更履歴を変更後の企画IDに書き込み、アイコンを移動する
+///
+/// 途中で失敗した場合は、書き込んだ分を取り消してからエラーを返す
+async fn copy_to(
+    storage: &Storage,
+    from: &str,
+    to: &str,
+    plan: &Value,
+    details: Option<&Value>,
+) -> Result<Copied, String> {
+    let mut copied = Copied::default();
+    if let Err(err) = copy_each(storage, from, to, plan, details, &mut copied).await {
+        rollback(storage, from, to, &copied).await;
+        return Err(err);
+    }
+    Ok(copied)
+}
+
+async fn copy_each(
+    storage: &Storage,
+    from: &str,
+    to: &str,
+    plan: &Value,
+    details: Option<&Value>,
+    copied: &mut Copied,
+) -> Result<(), String> {
+    storage
+        .plans
+        .put_value(to, plan)
+        .await
+        .map_err(|err| err.to_string())?;
+    copied.plan = true;
+
+    if let Some(details) = details {
+        storage
+            .details
+            .put_value(to, details)
+            .await
+            .map_err(|err| err.to_string())?;
+        copied.details = true;
+    }
+
+    copied.icon = storage
+        .icons
+        .rename(from, to)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let owners = storage
+        .owners
+        .read(from)
+        .await
+        .map_err(|err| err.to_string())?;
+    storage
+        .owners
+        .write(to, &owners)
+        .await
+        .map_err(|err| err.to_string())?;
+    copied.owners = Some(owners);
+
+    for document in [Document::Plan, Document::Details] {
+        let mut history = storage
+            .revisions
+            .read(document, from)
+            .await
+            .map_err(|err| err.to_string())?;
+        if history.revisions.is_empty() {
+            continue;
+        }
+        if document == Document::Plan {
+            history.rename(to);
+        }
+        storage
+            .revisions
+            .write(document, to, &history)
+            .await
+            .map_err(|err| err.to_string())?;
+        copied.revisions.push(document);
+    }
+    Ok(())
+}
+
+/// 変更後の企画IDに書き込んだ企画・企画詳細・所有者・変更履歴を削除し、アイコンを元に戻す
+async fn rollback(storage: &Storage, from: &str, to: &str, copied: &Copied) {
+    if copied.plan {
+        if let Err(err) = storage.plans.delete(to).await {
+            console_error!("Failed to roll back renamed plan: {:?}", err);
+        }
+    }
+    if copied.details {
+        if let Err(err) = storage.details.delete(to).await {
+            console_error!("Failed to roll back renamed plan details: {:?}", err);
+        }
+    }
+    if copied.icon {
+        if let Err(err) = storage.icons.rename(to, from).await {
+            console_error!("Failed to roll back renamed icon: {:?}", err);
+        }
+    }
+    if copied.owners.is_some() {
+        if let Err(err) = storage.owners.delete(to).await {
+            console_error!("Failed to roll back renamed plan owners: {:?}", err);
+        }
+    }
+    for document in &copied.revisions {
+        if let Err(err) = storage.revisions.delete(*document, to).await {
+            console_error!("Failed to roll back renamed revisions: {:?}", err);
+        }
+    }
+}
+
+/// 企画・企画詳細・所有者・アイコン・変更履歴を変更後の企画IDに移動し、変更前の企画IDに転送を残す
+pub async fn rename(
+    storage: &Storage,
+    plan_id: &str,
+    new_id: &str,
+    expected_etag: Option<&str>,
+    alias_ttl: Duration,
+) -> Result<Renamed, RenameError> {
+    let plan = match storage.plans.read_with_etag(plan_id).await {
+        Ok((plan, current_etag)) => {
+            if !if_match(expected_etag, Some(&current_etag)) {
+                return Err(RenameError::PreconditionFailed(current_etag));
+            }
+            plan
+        }
+        Err(PlanReadError::NotFound) => return Err(RenameError::NotFound),
+        Err(err) => return Err(RenameError::Internal(format!("{:?}", err))),
+    };
+
+    // 書き込む前に全ての衝突を確認する
+    if let Some(name) = find_conflict(storage, new_id)
+        .await
+        .map_err(RenameError::Internal)?
+    {
+        return Err(RenameError::Conflict(name));
+    }
+
+    let details = storage
+        .details
+        .read_value(plan_id)
+        .await
+        .map_err(|err| RenameError::Internal(err.to_string()))?;
+    let renamed = PlanRead {
+        id: new_id.to_string(),
+        ..plan.clone()
+    };
+    let value =
+        serde_json::to_value(&renamed).map_err(|err| RenameError::Internal(err.to_string()))?;
+
+    let copied = copy_to(storage, plan_id, new_id, &value, details.as_ref())
+        .await
+        .map_err(RenameError::Internal)?;
+
+    // 変更前の企画IDに所有者が残ると、同じIDで作成した企画の所有者になってしまうため、
+    // 所有者を削除できなかった場合も変更後の企画IDへの書き込みを取り消す
+    if let Err(err) = storage.owners.delete(plan_id).await {
+        rollback(storage, plan_id, new_id, &copied).await;
+        return Err(RenameError::Internal(format!("{:?}", err)));
+    }
+
+    // 変更前の企画を削除できなかった場合は、変更後の企画IDへの書き込みを取り消す
+    if let Err(err) = storage.plans.delete(plan_id).await {
+        if let Some(owners) = &copied.owners {
+            if let Err(err) = storage.owners.write(plan_id, owners).await {
+                console_error!("Failed to roll back plan owners: {:?}", err);
+            }
+        }
+        rollback(storage, plan_id, new_id, &copied).await;
+        return Err(RenameError::Internal(format!("{:?}", err)));
+    }
+
+    // 企画を移動できた後は、残りの処理に失敗してもログに残すのみとする
+    if details.is_some() {
+        if let Err(err) = storage.details.delete(plan_id).await {
+            console_error!("Failed to delete plan details: {:?}", err);
+        }
+    }
+    for document in &copied.revisions {
+        if let Err(err) = storage.revisions.delete(*document, plan_id).await {
+            console_error!("Failed to delete revisions: {:?}", err);
+        }
+    }
+
+    // 変更後の企画IDへの転送は不要になるため削除する（転送が循環しないようにする）
+    if let Err(err) = storage.aliases.delete(new_id).await {
+        console_error!("Failed to delete plan alias: {:?}", err);
+    }
+    if let Err(err) = storage
+        .aliases
+        .write(plan_id, &PlanAlias::new(new_id), alias_ttl)
+        .await
+    {
+        console_error!("Failed to write plan alias: {:?}", err);
+    }
+
+    // Update keys cache
+    if let Err(err) = storage
+        .plans
+        .update_index(&[new_id.into()], &[plan_id.into()])
+        .await
+    {
+        console_error!("Failed to update keys cache: {:?}", err);
+    }
+
+    Ok(Renamed {
+        before: plan,
+        after: renamed,
+        details,
+    })
+}
+
+/// 企画IDを変更する（`POST /v1/admin/plans/:plan_id:rename`）
+///
+/// 企画・企画詳細・所有者・アイコン・変更履歴を新しい企画IDに移動し、変更前の企画IDの公開APIには
+/// 変更後のURLへの301を一定期間（`PLAN_ALIAS_TTL_DAYS`日）返す
+pub async fn rename_plan(mut req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    // `:plan_id:rename`はルーター上では1つのパラメーターとして扱われるため、ここで分解する
+    let Some(plan_id) = ctx
+        .param("plan_id")
+        .and_then(|id| id.strip_suffix(":rename"))
+        .map(str::to_string)
+    else {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 404,
+            "message": "Not Found"
+        }))?
+        .with_status(404));
+    };
+    let plan_id = plan_id.as_str();
+
+    // 変更前の企画IDの企画を削除することになるため、削除の権限を必要とする
+    let principal = match authorize(&req, &ctx, Permission::PlansDelete).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let new_id = match req.json::<RenameRequest>().await {
+        Ok(RenameRequest { id }) if is_valid_plan_id(&id) => id,
+        Ok(RenameRequest { id }) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": format!("「{}」は企画IDとして使用できません", id)
+            }))?
+            .with_status(400));
+        }
+        Err(err) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": format!("Bad Request: {}", err)
+            }))?
+            .with_status(400));
+        }
+    };
+    let new_id = new_id.as_str();
+    if new_id == plan_id {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 400,
+            "message": "変更後の企画IDが現在の企画IDと同じです"
+        }))?
+        .with_status(400));
+    }
+
+    let expected_etag = req.headers().get("If-Match")?;
+    let Renamed {
+        before: plan,
+        after: renamed,
+        details,
+    } = match rename(
+        &ctx.data,
+        plan_id,
+        new_id,
+        expected_etag.as_deref(),
+        ttl(&ctx.env),
+    )
+    .await
+    {
+        Ok(renamed) => renamed,
+        Err(RenameError::NotFound) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 404,
+                "message": "企画が見つかりません"
+            }))?
+            .with_status(404));
+        }
+        Err(RenameError::PreconditionFailed(current_etag)) => {
+            return precondition_failed_response(Some(&current_etag));
+        }
+        Err(RenameError::Conflict(name)) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 409,
+                "message": format!("ID「{}」の{}が既に存在します", new_id, name)
+            }))?
+            .with_status(409));
+        }
+        Err(RenameError::Internal(err)) => {
+            console_error!("failed to rename plan: {}", err);
+            return internal_error();
+        }
+    };
+
+    record_revision(
+        &ctx.data,
+        Document::Plan,
+        new_id,
+        None::<&PlanRead>,
+        &renamed,
+        &principal,
+    )
+    .await;
+    if let Some(details) = &details {
+        record_revision(
+            &ctx.data,
+            Document::Details,
+            new_id,
+            None::<&Value>,
+            details,
+            &principal,
+        )
+        .await;
+    }
+    invalidate_cache(
+        &ctx.data,
+        &[
+            CacheScope::Plan(plan_id),
+            CacheScope::Plan(new_id),
+            CacheScope::List,
+        ],
+    )
+    .await;
+    record_audit(
+        &ctx.data,
+        AuditRecord::new(
+            &principal,
+            "POST /v1/admin/plans/:plan_id:rename",
+            Some(plan_id),
+        )
+        .with_before(&plan)
+        .with_after(&renamed),
+    )
+    .await;
+
+    Ok(Response::from_json(&renamed)?.with_status(200))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{KeycloakClaims, Principal};
+    use crate::models::owners::PlanOwnersError;
+    use crate::models::revision::RevisionHistory;
+    use crate::models::trash::move_to_trash;
+    use crate::storage::memory::{seed, storage, MemoryOwners};
+    use crate::storage::OwnersRepository;
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use std::rc::Rc;
+    use worker::kv::KvError;
+
+    /// 変更前のID「old」の削除に失敗する所有者の保存先
+    #[derive(Default)]
+    struct UndeletableOwners(MemoryOwners);
+
+    #[async_trait(?Send)]
+    impl OwnersRepository for UndeletableOwners {
+        async fn read(&self, plan_id: &str) -> Result<PlanOwners, PlanOwnersError> {
+            self.0.read(plan_id).await
+        }
+
+        async fn write(&self, plan_id: &str, owners: &PlanOwners) -> Result<(), PlanOwnersError> {
+            self.0.write(plan_id, owners).await
+        }
+
+        async fn delete(&self, plan_id: &str) -> Result<(), PlanOwnersError> {
+            if plan_id == "old" {
+                return Err(KvError::InvalidKvStore("owners".into()).into());
+            }
+            self.0.delete(plan_id).await
+        }
+
+        async fn ids(&self) -> Result<Vec<String>, PlanOwnersError> {
+            self.0.ids().await
+        }
+    }
+
+    fn principal() -> Principal {
+        Principal {
+            subject: "admin".into(),
+            claims: KeycloakClaims::default(),
+            permissions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn rename_moves_plan_details_owners_icon_and_revisions() {
+        let storage = storage();
+        block_on(async {
+            let plan = seed(&storage, "old").await;
+            RevisionHistory::record(
+                &*storage.revisions,
+                Document::Plan,
+                "old",
+                None,
+                &serde_json::to_value(&plan).unwrap(),
+                "admin",
+            )
+            .await
+            .unwrap();
+            let renamed = rename(&storage, "old", "new", None, Duration::days(90))
+                .await
+                .unwrap();
+            assert_eq!(renamed.before.id, "old");
+            assert_eq!(renamed.after.id, "new");
+            assert!(renamed.details.is_some());
+
+            assert!(storage.plans.read_value("old").await.unwrap().is_none());
+            assert!(storage.details.read_value("old").await.unwrap().is_none());
+            assert!(storage.icons.etag("old").await.unwrap().is_none());
+            assert!(storage
+                .owners
+                .read("old")
+                .await
+                .unwrap()
+                .subjects
+                .is_empty());
+
+            let (plan, _) = storage.plans.read_with_etag("new").await.unwrap();
+            assert_eq!(plan.id, "new");
+            assert!(storage.details.read_value("new").await.unwrap().is_some());
+            assert!(storage.icons.etag("new").await.unwrap().is_some());
+            assert_eq!(
+                storage.owners.read("new").await.unwrap().subjects,
+                ["owner"]
+            );
+            assert_eq!(storage.plans.ids().await.unwrap(), ["new"]);
+
+            let alias = storage.aliases.read("old").await.unwrap().unwrap();
+            assert_eq!(alias.plan_id, "new");
+
+            let history = storage.revisions.read(Document::Plan, "new").await.unwrap();
+            assert_eq!(history.revisions.len(), 1);
+            assert_eq!(history.revisions[0].value["id"], "new");
+            assert!(storage
+                .revisions
+                .read(Document::Plan, "old")
+                .await
+                .unwrap()
+                .revisions
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn rename_fails_without_changes_when_owners_cannot_be_moved() {
+        let storage = Storage {
+            owners: Rc::new(UndeletableOwners::default()),
+            ..storage()
+        };
+        block_on(async {
+            seed(&storage, "old").await;
+            assert!(matches!(
+                rename(&storage, "old", "new", None, Duration::days(90)).await,
+                Err(RenameError::Internal(_))
+            ));
+
+            assert!(storage.plans.read_value("old").await.unwrap().is_some());
+            assert!(storage.details.read_value("old").await.unwrap().is_some());
+            assert!(storage.icons.etag("old").await.unwrap().is_some());
+            assert_eq!(
+                storage.owners.read("old").await.unwrap().subjects,
+                ["owner"]
+            );
+            assert!(storage.plans.read_value("new").await.unwrap().is_none());
+            assert!(storage.details.read_value("new").await.unwrap().is_none());
+            assert!(storage.icons.etag("new").await.unwrap().is_none());
+            assert!(storage.aliases.read("old").await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn rename_checks_etag_and_conflicts_before_writing() {
+        let storage = storage();
+        block_on(async {
+            seed(&storage, "old").await;
+            let trashed = seed(&storage, "trashed").await;
+            move_to_trash(&storage, &principal(), "trashed", &trashed)
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                rename(
+                    &storage,
+                    "old",
+                    "new",
+                    Some("\"stale\""),
+                    Duration::days(90)
+                )
+                .await,
+                Err(RenameError::PreconditionFailed(_))
+            ));
+            assert!(matches!(
+                rename(&storage, "old", "trashed", None, Duration::days(90)).await,
+                Err(RenameError::Conflict("ゴミ箱の企画"))
+            ));
+            assert!(matches!(
+                rename(&storage, "missing", "new", None, Duration::days(90)).await,
+                Err(RenameError::NotFound)
+            ));
+            assert!(storage.plans.read_value("old").await.unwrap().is_some());
+            assert!(storage.aliases.read("old").await.unwrap().is_none());
+        });
+    }
+}
@@ -0,0 +1,336 @@
+use crate::auth::permission::Permission;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::details::CreatePlanDetails;
+use crate::models::plan::PlanUpdate;
+use crate::models::revision::diff;
+use crate::models::schema::Document;
+use crate::routes::admin::{authorize_plan, invalidate_cache, record_audit, record_revision};
+use crate::service::discord::Discord;
+use crate::storage::Storage;
+use serde_json::Value;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+/// 変更履歴の対象
+#[derive(Clone, Copy)]
+enum Target {
+    Plan,
+    Details,
+}
+
+impl Target {
+    fn document(self) -> Document {
+        match self {
+            Target::Plan => Document::Plan,
+            Target::Details => Document::Details,
+        }
+    }
+
+    /// 現在の値を保存先から取得する
+    async fn read_value(self, storage: &Storage, plan_id: &str) -> Result<Option<Value>, String> {
+        match self {
+            Target::Plan => storage
+                .plans
+                .read_value(plan_id)
+                .await
+                .map_err(|err| format!("{:?}", err)),
+            Target::Details => storage
+                .details
+                .read_value(plan_id)
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    /// 値を保存先にそのまま書き込む
+    async fn put_value(
+        self,
+        storage: &Storage,
+        plan_id: &str,
+        value: &Value,
+    ) -> Result<(), String> {
+        match self {
+            Target::Plan => storage
+                .plans
+                .put_value(plan_id, value)
+                .await
+                .map_err(|err| err.to_string()),
+            Target::Details => storage
+                .details
+                .put_value(plan_id, value)
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    fn read_permission(self) -> Permission {
+        match self {
+            Target::Plan => Permission::PlansWrite,
+            Target::Details => Permission::DetailsRead,
+        }
+    }
+
+    fn write_permission(self) -> Permission {
+        match self {
+            Target::Plan => Permission::PlansWrite,
+            Target::Details => Permission::DetailsWrite,
+        }
+    }
+
+    fn restore_route(self) -> &'static str {
+        match self {
+            Target::Plan => "POST /v1/admin/plans/:plan_id/revisions/:rev:restore",
+            Target::Details => "POST /v1/admin/plans/:plan_id/details/revisions/:rev:restore",
+        }
+    }
+}
+
+pub async fn get_plan_revisions(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    list_revisions(req, ctx, Target::Plan).await
+}
+
+pub async fn get_plan_revision(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    get_revision(req, ctx, Target::Plan).await
+}
+
+pub async fn post_plan_revision(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    post_revision(req, ctx, Target::Plan).await
+}
+
+pub async fn get_details_revisions(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    list_revisions(req, ctx, Target::Details).await
+}
+
+pub async fn get_details_revision(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    get_revision(req, ctx, Target::Details).await
+}
+
+pub async fn post_details_revision(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    post_revision(req, ctx, Target::Details).await
+}
+
+async fn list_revisions(
+    req: Request,
+    ctx: RouteContext<Storage>,
+    target: Target,
+) -> Result<Response, Error> {
+    let plan_id = ctx.param("plan_id").map_or("", |v| v);
+
+    if let Err(response) = authorize_plan(&req, &ctx, target.read_permission(), plan_id).await? {
+        return Ok(response);
+    }
+
+    match ctx.data.revisions.read(target.document(), plan_id).await {
+        Ok(history) => {
+            // 値は個別のリビジョンの取得時に返すため、一覧では新しい順に概要のみを返す
+            let revisions = history
+                .revisions
+                .iter()
+                .rev()
+                .map(|revision| {
+                    serde_json::json!({
+                        "rev": revision.rev,
+                        "timestamp": revision.timestamp,
+                        "actor": revision.actor,
+                    })
+                })
+                .collect::<Vec<_>>();
+            Ok(Response::from_json(&serde_json::json!({
+                "revisions": revisions
+            }))?
+            .with_status(200))
+        }
+        Err(err) => {
+            console_error!("failed to read revisions: {:?}", err);
+            internal_error()
+        }
+    }
+}
+
+async fn get_revision(
+    req: Request,
+    ctx: RouteContext<Storage>,
+    target: Target,
+) -> Result<Response, Error> {
+    let plan_id = ctx.param("plan_id").map_or("", |v| v);
+
+    if let Err(response) = authorize_plan(&req, &ctx, target.read_permission(), plan_id).await? {
+        return Ok(response);
+    }
+
+    let Some(rev) = ctx.param("rev").and_then(|rev| rev.parse::<u64>().ok()) else {
+        return revision_not_found();
+    };
+
+    let history = match ctx.data.revisions.read(target.document(), plan_id).await {
+        Ok(history) => history,
+        Err(err) => {
+            console_error!("failed to read revisions: {:?}", err);
+            return internal_error();
+        }
+    };
+    let Some(revision) = history.get(rev) else {
+        return revision_not_found();
+    };
+
+    let current = match target.read_value(&ctx.data, plan_id).await {
+        Ok(current) => current.unwrap_or(Value::Null),
+        Err(err) => {
+            console_error!("failed to read current value: {:?}", err);
+            return internal_error();
+        }
+    };
+
+    Ok(Response::from_json(&serde_json::json!({
+        "rev": revision.rev,
+        "timestamp": revision.timestamp,
+        "actor": revision.actor,
+        "value": revision.value,
+        "diff": diff(&current, &revision.value),
+    }))?
+    .with_status(200))
+}
+
+async fn post_revision(
+    req: Request,
+    ctx: RouteContext<Storage>,
+    target: Target,
+) -> Result<Response, Error> {
+    let plan_id = ctx.param("plan_id").map_or("", |v| v);
+
+    // `:rev:restore`はルーター上では1つのパラメーターとして扱われるため、ここで分解する
+    let Some(rev) = ctx
+        .param("rev")
+        .and_then(|rev| rev.strip_suffix(":restore"))
+        .and_then(|rev| rev.parse::<u64>().ok())
+    else {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 404,
+            "message": "Not Found"
+        }))?
+        .with_status(404));
+    };
+
+    let principal = match authorize_plan(&req, &ctx, target.write_permission(), plan_id).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let history = match ctx.data.revisions.read(target.document(), plan_id).await {
+        Ok(history) => history,
+        Err(err) => {
+            console_error!("failed to read revisions: {:?}", err);
+            return internal_error();
+        }
+    };
+    let Some(revision) = history.get(rev) else {
+        return revision_not_found();
+    };
+    let value = revision.value.clone();
+
+    let before = match target.read_value(&ctx.data, plan_id).await {
+        Ok(before) => before,
+        Err(err) => {
+            console_error!("failed to read current value: {:?}", err);
+            return internal_error();
+        }
+    };
+
+    if let Err(err) = target.put_value(&ctx.data, plan_id, &value).await {
+        console_error!("failed to restore revision: {:?}", err);
+        return internal_error();
+    }
+
+    record_revision(
+        &ctx.data,
+        target.document(),
+        plan_id,
+        before.as_ref(),
+        &value,
+        &principal,
+    )
+    .await;
+    match target {
+        Target::Plan => {
+            invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id), CacheScope::List]).await
+        }
+        Target::Details => invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id)]).await,
+    }
+
+    let mut record =
+        AuditRecord::new(&principal, target.restore_route(), Some(plan_id)).with_after(&value);
+    if let Some(before) = &before {
+        record = record.with_before(before);
+    }
+    record_audit(&ctx.data, record).await;
+
+    // discord通知
+    let discord = Discord::new_from_env(&ctx.env);
+    match target {
+        Target::Plan => {
+            match serde_json::from_value::<PlanUpdate>(value) {
+                Ok(plan_update) => {
+                    if let Err(err) = discord.send_update_plan(plan_id.into(), &plan_update).await {
+                        console_error!("Discord webhook error: {}", err)
+                    }
+                }
+                Err(err) => console_error!("failed to build Discord notification: {}", err),
+            }
+
+            // 削除済みの企画を復元した場合はキー一覧にも反映する
+            if before.is_none() {
+                let store = &ctx.data.plans;
+                if let Err(err) = store.update_index(&[plan_id.into()], &[]).await {
+                    console_error!("Failed to update keys cache: {:?}", err);
+                }
+            }
+        }
+        Target::Details => match serde_json::from_value::<CreatePlanDetails>(value) {
+            Ok(details) => {
+                if let Err(err) = discord
+                    .send_update_plan_details(plan_id.into(), &details)
+                    .await
+                {
+                    console_error!("Discord webhook error: {}", err)
+                }
+            }
+            Err(err) => console_error!("failed to build Discord notification: {}", err),
+        },
+    }
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+fn revision_not_found() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 404,
+        "message": "リビジョンが見つかりません"
+    }))?
+    .with_status(404))
+}
+
+fn internal_error() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 500,
+        "message": "内部エラーが発生しました"
+    }))?
+    .with_status(500))
+}
@@ -0,0 +1,49 @@
+use crate::auth::permission::Permission;
+use crate::models::schema::{Document, VersionCounts};
+use crate::routes::admin::authorize;
+use crate::storage::Storage;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+fn report(document: Document, versions: &VersionCounts) -> serde_json::Value {
+    serde_json::json!({
+        "current": document.version(),
+        "outdated": versions
+            .range(..document.version())
+            .map(|(_, count)| count)
+            .sum::<usize>(),
+        "versions": versions,
+    })
+}
+
+/// 保存されている企画・企画詳細のスキーマのバージョンごとの数を取得する
+///
+/// 古いバージョンの文書は読み込まれた際に現在のバージョンに変換して書き戻される
+pub async fn get_schema_versions(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::Maintenance).await? {
+        return Ok(response);
+    }
+
+    let versions = match (
+        ctx.data.plans.schema_versions().await,
+        ctx.data.details.schema_versions().await,
+    ) {
+        (Ok(plans), Ok(details)) => (plans, details),
+        (Err(err), _) | (_, Err(err)) => {
+            console_error!("failed to count schema versions: {:?}", err);
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "内部エラーが発生しました"
+            }))?
+            .with_status(500));
+        }
+    };
+
+    Ok(Response::from_json(&serde_json::json!({
+        "plans": report(Document::Plan, &versions.0),
+        "details": report(Document::Details, &versions.1),
+    }))?
+    .with_status(200))
+}
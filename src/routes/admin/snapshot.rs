@@ -0,0 +1,172 @@
+use crate::auth::permission::Permission;
+use crate::auth::Principal;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::schema::Document;
+use crate::routes::admin::{authorize, invalidate_cache, record_audit, record_revision};
+use crate::snapshot::{
+    export, import, ImportMode, ImportReport, SnapshotError, SNAPSHOT_CONTENT_TYPE,
+};
+use crate::storage::Storage;
+use crate::util::now;
+use futures_util::StreamExt;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+fn internal_error() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 500,
+        "message": "内部エラーが発生しました"
+    }))?
+    .with_status(500))
+}
+
+/// 全ての企画・企画詳細・アイコンのメタデータをJSON Linesのスナップショットとして取得する
+///
+/// `icons=true`の場合はアイコンの画像もBase64で含める
+pub async fn get_export(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::Maintenance).await? {
+        return Ok(response);
+    }
+
+    let icon_bytes = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "icons" && value == "true");
+
+    // 企画ごとに読み込んで送るため、途中で失敗した場合はレスポンスが途切れる
+    // （ヘッダーの企画の数と行数が一致しないことで分かる）
+    let lines = export(ctx.data.clone(), icon_bytes).map(|line| {
+        line.map_err(|err| {
+            console_error!("failed to export: {:?}", err);
+            Error::RustError(err.to_string())
+        })
+    });
+    let mut response = Response::from_stream(lines)?;
+    let headers = response.headers_mut();
+    headers.set("Content-Type", SNAPSHOT_CONTENT_TYPE)?;
+    headers.set(
+        "Content-Disposition",
+        &format!(
+            "attachment; filename=\"plans-{}.jsonl\"",
+            now().format("%Y%m%dT%H%M%SZ")
+        ),
+    )?;
+    Ok(response)
+}
+
+/// インポートで変更した企画の変更履歴・キャッシュ・インデックスを更新する
+///
+/// バックアップからの復元でも使用する
+pub async fn apply_import_side_effects(
+    storage: &Storage,
+    report: &ImportReport,
+    principal: &Principal,
+) -> Result<(), Error> {
+    for change in &report.changes {
+        if let Some((before, after)) = &change.plan {
+            record_revision(
+                storage,
+                Document::Plan,
+                &change.id,
+                before.as_ref(),
+                after,
+                principal,
+            )
+            .await;
+        }
+        if let Some((before, after)) = &change.details {
+            record_revision(
+                storage,
+                Document::Details,
+                &change.id,
+                before.as_ref(),
+                after,
+                principal,
+            )
+            .await;
+        }
+        invalidate_cache(storage, &[CacheScope::Plan(&change.id)]).await;
+    }
+    // dry-runの`deleted`は削除される企画の一覧のため、実際に削除した場合のみ反映する
+    let deleted = match report.mode {
+        ImportMode::Replace => report.deleted.clone(),
+        ImportMode::Merge | ImportMode::DryRun => vec![],
+    };
+    // 企画詳細・所有者・アイコンは`import`でゴミ箱に移動している
+    for id in &deleted {
+        invalidate_cache(storage, &[CacheScope::Plan(id)]).await;
+    }
+
+    if !report.changes.is_empty() || !deleted.is_empty() {
+        invalidate_cache(storage, &[CacheScope::List]).await;
+        if let Err(err) = storage.plans.update_index(&report.created, &deleted).await {
+            console_error!("Failed to update keys cache: {:?}", err);
+        }
+    }
+    Ok(())
+}
+
+/// スナップショットを読み込み、企画・企画詳細・アイコンを復元する
+///
+/// `mode`には`merge`（既定）、`replace`または`dry-run`を指定する。
+/// 衝突や検証エラーがあった企画は`post_plans_bulk`と同様に207 Multi-Statusでエラー一覧を返す
+pub async fn post_import(mut req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::Maintenance).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let mode = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "mode")
+        .map(|(_, value)| value.into_owned());
+    let mode = match mode.as_deref().map(ImportMode::parse) {
+        None => ImportMode::Merge,
+        Some(Some(mode)) => mode,
+        Some(None) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": "modeにはmerge、replaceまたはdry-runを指定してください"
+            }))?
+            .with_status(400));
+        }
+    };
+
+    let body = req.text().await?;
+    let report = match import(&ctx.data, &body, mode, &principal).await {
+        Ok(report) => report,
+        Err(err @ (SnapshotError::MissingHeader | SnapshotError::UnsupportedFormat(..))) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": err.to_string()
+            }))?
+            .with_status(400));
+        }
+        Err(err) => {
+            console_error!("failed to import: {:?}", err);
+            return internal_error();
+        }
+    };
+
+    if mode != ImportMode::DryRun {
+        apply_import_side_effects(&ctx.data, &report, &principal).await?;
+        record_audit(
+            &ctx.data,
+            AuditRecord::new(&principal, "POST /v1/admin/import", None).with_after(
+                &serde_json::json!({
+                    "mode": report.mode,
+                    "created": report.created,
+                    "updated": report.updated,
+                    "deleted": report.deleted,
+                    "errors": report.errors.len(),
+                }),
+            ),
+        )
+        .await;
+    }
+
+    // 失敗したエントリーがある場合は207 Multi-Statusでエラー一覧を返す
+    let status = if report.errors.is_empty() { 200 } else { 207 };
+    Ok(Response::from_json(&report)?.with_status(status))
+}
@@ -0,0 +1,375 @@
+use crate::auth::permission::Permission;
+use crate::models::audit::AuditRecord;
+use crate::models::cache::CacheScope;
+use crate::models::plan::PlanRead;
+use crate::models::schema::Document;
+use crate::models::trash::{purge, purge_expired, retention, TrashEntry};
+use crate::routes::admin::{authorize, invalidate_cache, record_audit, record_revision};
+use crate::storage::Storage;
+use worker::{console_error, Error, Request, Response, RouteContext};
+
+fn internal_error() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 500,
+        "message": "内部エラーが発生しました"
+    }))?
+    .with_status(500))
+}
+
+fn not_found() -> Result<Response, Error> {
+    Ok(Response::from_json(&serde_json::json!({
+        "code": 404,
+        "message": "ゴミ箱に企画が見つかりません"
+    }))?
+    .with_status(404))
+}
+
+/// ゴミ箱の企画を削除日時の新しい順に取得する
+pub async fn get_trash(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    if let Err(response) = authorize(&req, &ctx, Permission::PlansDelete).await? {
+        return Ok(response);
+    }
+
+    let retention = retention(&ctx.env);
+    match ctx.data.trash.list().await {
+        Ok(entries) => {
+            let items = entries
+                .iter()
+                .map(|entry| {
+                    serde_json::json!({
+                        "plan_id": entry.plan_id,
+                        "plan": entry.plan,
+                        "has_details": entry.details.is_some(),
+                        "has_icon": entry.icon,
+                        "deleted_at": entry.deleted_at,
+                        "deleted_by": entry.deleted_by,
+                        "deleted_by_name": entry.deleted_by_name,
+                        "expires_at": entry.expires_at(retention),
+                    })
+                })
+                .collect::<Vec<_>>();
+            Ok(Response::from_json(&serde_json::json!({
+                "items": items,
+                "retention_days": retention.num_days(),
+            }))?
+            .with_status(200))
+        }
+        Err(err) => {
+            console_error!("failed to list trash: {:?}", err);
+            internal_error()
+        }
+    }
+}
+
+/// ゴミ箱の企画を復元できなかった理由
+#[derive(Debug)]
+pub enum RestoreError {
+    NotFound,
+    /// 削除後に同じIDで企画が作成されている
+    Conflict,
+    Internal(String),
+}
+
+/// ゴミ箱の企画を企画詳細・所有者・アイコンと一緒に復元し、復元した企画とゴミ箱の内容を返す
+pub async fn restore(
+    storage: &Storage,
+    plan_id: &str,
+) -> Result<(PlanRead, TrashEntry), RestoreError> {
+    let entry = match storage.trash.read(plan_id).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return Err(RestoreError::NotFound),
+        Err(err) => return Err(RestoreError::Internal(err.to_string())),
+    };
+
+    // 削除後に同じIDで作成された企画は上書きしない
+    match storage.plans.read_value(plan_id).await {
+        Ok(None) => {}
+        Ok(Some(_)) => return Err(RestoreError::Conflict),
+        Err(err) => return Err(RestoreError::Internal(format!("{:?}", err))),
+    }
+
+    let plan = serde_json::from_value::<PlanRead>(entry.plan.clone())
+        .map_err(|err| RestoreError::Internal(err.to_string()))?;
+    if let Err(err) = storage.plans.put_value(plan_id, &entry.plan).await {
+        return Err(RestoreError::Internal(err.to_string()));
+    }
+
+    // 企画を復元できた後は、残りの復元に失敗してもログに残すのみとする
+    if let Some(details) = &entry.details {
+        if let Err(err) = storage.details.put_value(plan_id, details).await {
+            console_error!("Failed to restore plan details: {:?}", err);
+        }
+    }
+    if let Some(owners) = &entry.owners {
+        if let Err(err) = storage.owners.write(plan_id, owners).await {
+            console_error!("Failed to restore plan owners: {:?}", err);
+        }
+    }
+    if entry.icon {
+        if let Err(err) = storage.icons.restore_from_trash(plan_id).await {
+            console_error!("Failed to restore icon from trash: {:?}", err);
+        }
+    }
+    if let Err(err) = storage.trash.delete(plan_id).await {
+        console_error!("Failed to delete trash entry: {:?}", err);
+    }
+
+    // Update keys cache
+    if let Err(err) = storage.plans.update_index(&[plan_id.into()], &[]).await {
+        console_error!("Failed to update keys cache: {:?}", err);
+    }
+
+    Ok((plan, entry))
+}
+
+/// ゴミ箱の企画を企画詳細・所有者・アイコンと一緒に復元する（`POST /v1/admin/plans/:plan_id:restore`）
+pub async fn restore_plan(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    // `:plan_id:restore`はルーター上では1つのパラメーターとして扱われるため、ここで分解する
+    let Some(plan_id) = ctx
+        .param("plan_id")
+        .and_then(|id| id.strip_suffix(":restore"))
+        .map(str::to_string)
+    else {
+        return Ok(Response::from_json(&serde_json::json!({
+            "code": 404,
+            "message": "Not Found"
+        }))?
+        .with_status(404));
+    };
+    let plan_id = plan_id.as_str();
+
+    let principal = match authorize(&req, &ctx, Permission::PlansDelete).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let (plan, entry) = match restore(&ctx.data, plan_id).await {
+        Ok(restored) => restored,
+        Err(RestoreError::NotFound) => return not_found(),
+        Err(RestoreError::Conflict) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 409,
+                "message": format!("ID「{}」の企画が既に存在します", plan_id)
+            }))?
+            .with_status(409));
+        }
+        Err(RestoreError::Internal(err)) => {
+            console_error!("failed to restore plan: {}", err);
+            return internal_error();
+        }
+    };
+
+    record_revision(
+        &ctx.data,
+        Document::Plan,
+        plan_id,
+        None::<&PlanRead>,
+        &plan,
+        &principal,
+    )
+    .await;
+    if let Some(details) = &entry.details {
+        record_revision(
+            &ctx.data,
+            Document::Details,
+            plan_id,
+            None::<&serde_json::Value>,
+            details,
+            &principal,
+        )
+        .await;
+    }
+    invalidate_cache(&ctx.data, &[CacheScope::Plan(plan_id), CacheScope::List]).await;
+    record_audit(
+        &ctx.data,
+        AuditRecord::new(
+            &principal,
+            "POST /v1/admin/plans/:plan_id:restore",
+            Some(plan_id),
+        )
+        .with_after(&plan),
+    )
+    .await;
+
+    Ok(Response::from_json(&plan)?.with_status(200))
+}
+
+/// ゴミ箱の企画を完全に削除する
+///
+/// `plan_id`を指定した場合はその企画を、指定しない場合は保持期間を過ぎた企画を全て削除する
+pub async fn post_trash_purge(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let principal = match authorize(&req, &ctx, Permission::Maintenance).await? {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
+    };
+
+    let plan_id = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "plan_id")
+        .map(|(_, value)| value.into_owned());
+
+    let result = match &plan_id {
+        Some(plan_id) => match ctx.data.trash.read(plan_id).await {
+            Ok(Some(entry)) => purge(&*ctx.data.trash, &*ctx.data.icons, &entry)
+                .await
+                .map(|_| vec![entry.plan_id]),
+            Ok(None) => return not_found(),
+            Err(err) => Err(err),
+        },
+        None => purge_expired(&*ctx.data.trash, &*ctx.data.icons, retention(&ctx.env)).await,
+    };
+
+    match result {
+        Ok(purged) => {
+            record_audit(
+                &ctx.data,
+                AuditRecord::new(&principal, "POST /v1/admin/trash:purge", plan_id.as_deref())
+                    .with_after(&serde_json::json!({ "purged": purged })),
+            )
+            .await;
+            Ok(Response::from_json(&serde_json::json!({ "purged": purged }))?.with_status(200))
+        }
+        Err(err) => {
+            console_error!("failed to purge trash: {:?}", err);
+            internal_error()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{KeycloakClaims, Principal};
+    use crate::models::trash::move_to_trash;
+    use crate::storage::memory::{plan_value, seed, storage};
+    use futures::executor::block_on;
+
+    fn principal() -> Principal {
+        Principal {
+            subject: "admin".into(),
+            claims: KeycloakClaims::default(),
+            permissions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn trashed_plan_is_restored_with_details_owners_and_icon() {
+        let storage = storage();
+        block_on(async {
+            let plan = seed(&storage, "plan-1").await;
+            let entry = move_to_trash(&storage, &principal(), "plan-1", &plan)
+                .await
+                .unwrap();
+            assert!(entry.icon);
+            assert!(entry.details.is_some());
+            assert_eq!(entry.owners.unwrap().subjects, ["owner"]);
+            assert!(storage.plans.read_value("plan-1").await.unwrap().is_none());
+            assert!(storage
+                .details
+                .read_value("plan-1")
+                .await
+                .unwrap()
+                .is_none());
+            assert!(storage.icons.etag("plan-1").await.unwrap().is_none());
+            assert!(storage
+                .owners
+                .read("plan-1")
+                .await
+                .unwrap()
+                .subjects
+                .is_empty());
+            assert!(!storage
+                .plans
+                .ids()
+                .await
+                .unwrap()
+                .contains(&"plan-1".into()));
+
+            let (restored, _) = restore(&storage, "plan-1").await.unwrap();
+            assert_eq!(restored.id, "plan-1");
+            assert!(storage.plans.read_value("plan-1").await.unwrap().is_some());
+            assert!(storage
+                .details
+                .read_value("plan-1")
+                .await
+                .unwrap()
+                .is_some());
+            assert!(storage.icons.etag("plan-1").await.unwrap().is_some());
+            assert_eq!(
+                storage.owners.read("plan-1").await.unwrap().subjects,
+                ["owner"]
+            );
+            assert!(storage.trash.list().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn restore_does_not_overwrite_recreated_plan() {
+        let storage = storage();
+        block_on(async {
+            let plan = seed(&storage, "plan-1").await;
+            move_to_trash(&storage, &principal(), "plan-1", &plan)
+                .await
+                .unwrap();
+            seed(&storage, "plan-1").await;
+
+            assert!(matches!(
+                restore(&storage, "plan-1").await,
+                Err(RestoreError::Conflict)
+            ));
+            assert_eq!(storage.trash.list().await.unwrap().len(), 1);
+            assert!(matches!(
+                restore(&storage, "plan-2").await,
+                Err(RestoreError::NotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn plan_already_in_trash_replaces_previous_entry() {
+        let storage = storage();
+        block_on(async {
+            let plan = seed(&storage, "plan-1").await;
+            move_to_trash(&storage, &principal(), "plan-1", &plan)
+                .await
+                .unwrap();
+
+            // アイコンの無い企画で置き換えても、以前の企画のアイコンは残らない
+            let recreated = storage
+                .plans
+                .create("plan-1", serde_json::from_value(plan_value()).unwrap())
+                .await
+                .unwrap();
+            let entry = move_to_trash(&storage, &principal(), "plan-1", &recreated)
+                .await
+                .unwrap();
+            assert!(!entry.icon);
+            assert!(entry.details.is_none());
+            assert!(storage.plans.read_value("plan-1").await.unwrap().is_none());
+            assert!(!storage.icons.restore_from_trash("plan-1").await.unwrap());
+
+            let trashed = storage.trash.list().await.unwrap();
+            assert_eq!(trashed.len(), 1);
+            assert!(trashed[0].details.is_none());
+            assert!(trashed[0].owners.as_ref().unwrap().subjects.is_empty());
+
+            // アイコンのある企画で置き換えた場合は、新しいアイコンを保持する
+            let replaced = seed(&storage, "plan-1").await;
+            storage
+                .icons
+                .put("plan-1", vec![9], "image/png")
+                .await
+                .unwrap();
+            let entry = move_to_trash(&storage, &principal(), "plan-1", &replaced)
+                .await
+                .unwrap();
+            assert!(entry.icon);
+            assert!(storage.icons.restore_from_trash("plan-1").await.unwrap());
+            assert_eq!(
+                storage.icons.get("plan-1").await.unwrap().unwrap().bytes,
+                [9]
+            );
+        });
+    }
+}
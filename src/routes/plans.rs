@@ -1,43 +1,205 @@
 pub mod details;
 pub mod icon;
 
-use crate::models::plan::{PlanRead, PlanReadError};
-use crate::models::plan_type::PlanTypeRead;
-use crate::KV_PLANS;
-use worker::{console_error, Cache, Cors, Error, Method, Request, Response, RouteContext};
+use crate::models::cache::CacheScope;
+use crate::models::festival;
+use crate::models::geo::NearbyQuery;
+use crate::models::geojson;
+use crate::models::listing::{paginate, select_fields, ListQuery};
+use crate::models::plan::{PlanFilter, PlanRead, PlanReadError};
+use crate::models::schedule::ScheduleWindow;
+use crate::storage::Storage;
+use crate::util::{
+    etag, http_date, if_none_match, now, parse_http_date, representation_etag,
+    COMBINED_REPRESENTATION,
+};
+use chrono::{DateTime, Utc};
+use worker::{console_error, Cache, Cors, Error, Method, Request, Response, RouteContext, Url};
 
-pub async fn get_plans(req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+/// キャッシュの世代を含めたキャッシュキーと、その世代に進めた日時（`Last-Modified`に使う）を求める
+///
+/// 書き込み時に世代が進むと、以前のキーで保存したエントリーは参照されなくなる
+pub async fn cache_key(
+    req: &Request,
+    storage: &Storage,
+    scope: CacheScope<'_>,
+) -> Result<(Request, Option<DateTime<Utc>>), Error> {
+    let mut url = req.url()?;
+    let generation = storage.cache.generation(scope).await?;
+    let modified_at = CacheScope::modified_at(&generation);
+    // クエリの順序が異なるだけのリクエストが同じエントリーを使うよう、クエリを並び替える
+    let mut pairs = url.query_pairs().into_owned().collect::<Vec<_>>();
+    pairs.sort();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("_cache_generation", &generation);
+    Ok((Request::new(url.as_str(), Method::Get)?, modified_at))
+}
+
+/// レスポンスをキャッシュに保存する
+///
+/// 404や500などを保存すると、書き込みで世代が進むまで同じエラーを返し続けるため、成功したレスポンスのみ保存する
+pub async fn put_cache(cache: &Cache, key: &Request, response: &mut Response) -> Result<(), Error> {
+    if !(200..300).contains(&response.status_code()) {
+        return Ok(());
+    }
+    cache.put(key, response.cloned()?).await
+}
+
+/// 世代を進めた日時がある場合に`Last-Modified`を設定する
+///
+/// 一度も書き込まれていない場合は、値が最後に変更された日時が分からないため設定しない
+pub fn set_last_modified(
+    response: &mut Response,
+    modified_at: Option<DateTime<Utc>>,
+) -> Result<(), Error> {
+    if let Some(modified_at) = modified_at {
+        response
+            .headers_mut()
+            .set("Last-Modified", &http_date(modified_at))?;
+    }
+    Ok(())
+}
+
+/// 条件付きGETを処理する
+///
+/// `If-None-Match`（指定された場合は優先）または`If-Modified-Since`を満たす場合は、
+/// レスポンスを本文の無い304 Not Modifiedに置き換える
+pub fn conditional(req: &Request, response: Response) -> Result<Response, Error> {
+    if response.status_code() != 200 {
+        return Ok(response);
+    }
+
+    let headers = response.headers();
+    let not_modified = match req.headers().get("If-None-Match")? {
+        Some(if_none_match_header) => headers
+            .get("ETag")?
+            .is_some_and(|etag| if_none_match(&if_none_match_header, &etag)),
+        None => {
+            let since = req
+                .headers()
+                .get("If-Modified-Since")?
+                .and_then(|since| parse_http_date(&since));
+            let last_modified = headers
+                .get("Last-Modified")?
+                .and_then(|last_modified| parse_http_date(&last_modified));
+            matches!((since, last_modified), (Some(since), Some(last_modified)) if last_modified <= since)
+        }
+    };
+    if !not_modified {
+        return Ok(response);
+    }
+
+    let headers = headers.clone();
+    headers.delete("Content-Length")?;
+    headers.delete("Content-Type")?;
+    Ok(Response::empty()?.with_headers(headers).with_status(304))
+}
+
+/// 企画IDが変更されている場合に、変更後の企画IDのURLへの301レスポンスを作る
+///
+/// `plan_id`を含むパスの部分を置き換え、クエリはそのまま引き継ぐ
+pub async fn redirect_alias(
+    req: &Request,
+    storage: &Storage,
+    plan_id: &str,
+) -> Result<Option<Response>, Error> {
+    let alias = match storage.aliases.read(plan_id).await {
+        Ok(Some(alias)) => alias,
+        Ok(None) => return Ok(None),
+        Err(err) => {
+            console_error!("failed to read plan alias: {:?}", err);
+            return Ok(None);
+        }
+    };
+
+    let mut url = req.url()?;
+    let path = url.path().replacen(
+        &format!("/v1/plans/{}", plan_id),
+        &format!("/v1/plans/{}", alias.plan_id),
+        1,
+    );
+    url.set_path(&path);
+
+    let mut response = Response::empty()?
+        .with_cors(&Cors::new().with_origins(vec!["*"]))?
+        .with_status(301);
+    let headers = response.headers_mut();
+    headers.set("Location", url.as_str())?;
+    headers.set("Cache-Control", "public, max-age=3600, s-maxage=3600")?;
+    Ok(Some(response))
+}
+
+pub async fn get_plans(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let url = req.url()?;
+    list_plans(&req, &url, &ctx.data, "public, max-age=3600, s-maxage=3600").await
+}
+
+/// `url`のクエリで企画一覧を作り、`req`のURLをキーとして`cache_control`の期間だけキャッシュする
+///
+/// `GET /v1/plans/now`は現在時刻を`day`・`at`にした`url`で呼び出し、`/now`のキーで短い期間だけキャッシュする
+async fn list_plans(
+    req: &Request,
+    url: &Url,
+    storage: &Storage,
+    cache_control: &str,
+) -> Result<Response, Error> {
     // cacheからの復元
-    let cache_key = Request::new(req.url()?.as_str(), Method::Get)?;
+    let (cache_key, modified_at) = cache_key(req, storage, CacheScope::List).await?;
     let cache = Cache::default();
     if let Some(response) = cache.get(&cache_key, false).await? {
-        return Ok(response);
+        return conditional(req, response);
     }
 
-    let url = req.url()?;
     let query_params = url.query_pairs();
 
-    // クエリパラメータの解析
-    let mut plan_types: Option<Vec<String>> = None;
-    let mut recommended: Option<bool> = None;
-    let mut child_friendly: Option<bool> = None;
-    let mut lab_tour: Option<bool> = None;
+    // クエリパラメータの解析（追加した絞り込み条件の値が不正な場合は無視せずにエラーとする）
+    let filter = match parse_filter(url) {
+        Ok(filter) => filter,
+        Err(message) => return bad_request(message),
+    };
     let mut combine_schedule: bool = true;
+    let mut sort = None;
+    let mut order = None;
+    let mut limit = None;
+    let mut cursor = None;
+    let mut fields = None;
 
     for (key, value) in query_params {
         match key.as_ref() {
-            "type" => plan_types = Some(value.split(",").map(|s| s.into()).collect()),
-            "recommended" => recommended = value.parse().ok(),
-            "child_friendly" => child_friendly = value.parse().ok(),
-            "lab_tour" => lab_tour = value.parse().ok(),
             "combine_schedule" => combine_schedule = value.parse().ok().unwrap_or(true),
+            "sort" => sort = Some(value.into_owned()),
+            "order" => order = Some(value.into_owned()),
+            "limit" => limit = Some(value.into_owned()),
+            "cursor" => cursor = Some(value.into_owned()),
+            "fields" => fields = Some(value.into_owned()),
             _ => {}
         }
     }
 
-    // 企画を全て取得
-    let kv = ctx.env.kv(KV_PLANS)?;
-    let mut plans: Vec<PlanRead> = match PlanRead::read_all(&kv).await {
+    // 並び順・ページ分割・フィールドの指定の検証
+    let query = match ListQuery::parse(
+        sort.as_deref(),
+        order.as_deref(),
+        limit.as_deref(),
+        cursor.as_deref(),
+        fields.as_deref(),
+    ) {
+        Ok(query) => query,
+        Err(err) => {
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 400,
+                "message": err.to_string()
+            }))?
+            .with_cors(&Cors::new().with_origins(vec!["*"]))?
+            .with_status(400));
+        }
+    };
+
+    // 条件に合う企画を取得（D1の場合はSQLで絞り込む）
+    let store = &storage.plans;
+    let mut plans: Vec<PlanRead> = match store.read_all(&filter).await {
         Ok(plans) => plans,
         Err(PlanReadError::NotFound) => {
             return Ok(Response::from_json(&serde_json::json!({
@@ -65,6 +227,15 @@ pub async fn get_plans(req: Request, ctx: RouteContext<()>) -> Result<Response,
             .with_cors(&Cors::new().with_origins(vec!["*"]))?
             .with_status(500));
         }
+        Err(PlanReadError::SerdeError(e)) => {
+            console_error!("serdeerror: {:?}", e);
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "Internal error occurred."
+            }))?
+            .with_cors(&Cors::new().with_origins(vec!["*"]))?
+            .with_status(500));
+        }
         Err(PlanReadError::GetKeysError(e)) => {
             console_error!("error occurred while retrieving keys: {:?}", e);
             return Ok(Response::from_json(&serde_json::json!({
@@ -76,28 +247,8 @@ pub async fn get_plans(req: Request, ctx: RouteContext<()>) -> Result<Response,
         }
     };
 
-    // フィルター
-    plans.retain(|plan| {
-        let mut flag = (recommended == Some(plan.is_recommended) || recommended == None)
-            && (child_friendly == Some(plan.is_child_friendly) || child_friendly == None);
-
-        if let PlanTypeRead::Labo { is_lab_tour } = plan.r#type {
-            flag = flag && (lab_tour == Some(is_lab_tour) || lab_tour == None);
-        }
-
-        if plan_types.is_none() {
-            return flag;
-        }
-        let plan_types = plan_types.as_ref().unwrap();
-        flag = flag
-            && match plan.r#type {
-                PlanTypeRead::Booth { .. } => plan_types.contains(&"booth".into()),
-                PlanTypeRead::General { .. } => plan_types.contains(&"general".into()),
-                PlanTypeRead::Stage {} => plan_types.contains(&"stage".into()),
-                PlanTypeRead::Labo { .. } => plan_types.contains(&"labo".into()),
-            };
-        flag
-    });
+    let (page, next_cursor) = paginate(plans, &query.sort, query.limit, query.cursor.as_ref());
+    plans = page;
 
     // combine
     if combine_schedule {
@@ -118,67 +269,316 @@ pub async fn get_plans(req: Request, ctx: RouteContext<()>) -> Result<Response,
             .collect();
     }
 
-    let mut response = Response::from_json(&serde_json::json!({
+    let plans = match &query.fields {
+        Some(fields) => plans
+            .into_iter()
+            .map(|plan| Ok(select_fields(serde_json::to_value(plan)?, fields)))
+            .collect::<Result<Vec<_>, serde_json::Error>>()?,
+        None => plans
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let mut body = serde_json::json!({
         "plans": plans
-    }))?;
+    });
+    // ページ分割した場合のみ、次のページのカーソルを含める（最後のページでは`null`）
+    if query.limit.is_some() {
+        body["next_cursor"] = serde_json::json!(next_cursor.map(|cursor| cursor.encode()));
+    }
+    let mut response = Response::from_json(&body)?;
 
     response = response.with_cors(&Cors::new().with_origins(vec!["*"]))?;
 
     if 200 <= response.status_code() && response.status_code() < 300 {
         let headers = response.headers_mut();
-        headers.set("Cache-Control", "public, max-age=3600, s-maxage=3600")?;
+        headers.set("Cache-Control", cache_control)?;
+        headers.set("ETag", &etag(&body))?;
+        // 企画ごとの更新日時は保持していないため、一覧のキャッシュの世代を進めた日時を最終更新日時とする
+        set_last_modified(&mut response, modified_at)?;
+
+        put_cache(&cache, &cache_key, &mut response).await?;
+    }
 
-        cache.put(&cache_key, response.cloned()?).await?;
+    conditional(req, response)
+}
+
+/// クエリパラメーターから絞り込み条件（実施日時の時間帯を含む）を読み込む
+///
+/// 絞り込み条件以外のパラメーターは無視する。`PlanFilter::parse_param`・`ScheduleWindow::parse`で値が不正な場合はエラーメッセージを返す
+fn parse_filter(url: &Url) -> Result<PlanFilter, String> {
+    let mut filter = PlanFilter::default();
+    let mut day = None;
+    let mut at = None;
+    let mut from = None;
+    let mut to = None;
+
+    for (key, value) in url.query_pairs() {
+        if filter
+            .parse_param(&key, &value)
+            .map_err(|err| err.to_string())?
+        {
+            continue;
+        }
+        match key.as_ref() {
+            "day" => day = Some(value.into_owned()),
+            "at" => at = Some(value.into_owned()),
+            "from" => from = Some(value.into_owned()),
+            "to" => to = Some(value.into_owned()),
+            _ => {}
+        }
     }
 
-    Ok(response)
+    filter.schedule = ScheduleWindow::parse(
+        day.as_deref(),
+        at.as_deref(),
+        from.as_deref(),
+        to.as_deref(),
+    )
+    .map_err(|err| err.to_string())?;
+    Ok(filter)
 }
 
-pub async fn get_plan(req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
+fn bad_request(message: String) -> Result<Response, Error> {
+    Response::from_json(&serde_json::json!({
+        "code": 400,
+        "message": message
+    }))?
+    .with_cors(&Cors::new().with_origins(vec!["*"]))
+    .map(|response| response.with_status(400))
+}
+
+/// 指定した地点の近くの企画を取得する（`GET /v1/plans/nearby`）
+///
+/// 座標のある企画のうち、`lat`・`lng`から`radius_m`以内の企画を距離の近い順（同じ距離の企画は企画ID順）に、
+/// 距離（`distance_m`）を付けて返す。`GET /v1/plans`と同じ絞り込み条件も指定できる
+pub async fn get_plans_nearby(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
     // cacheからの復元
-    let cache_key = Request::new(req.url()?.as_str(), Method::Get)?;
+    let (cache_key, modified_at) = cache_key(&req, &ctx.data, CacheScope::List).await?;
     let cache = Cache::default();
     if let Some(response) = cache.get(&cache_key, false).await? {
-        return Ok(response);
+        return conditional(&req, response);
     }
 
-    let plan_id = ctx.param("plan_id").map_or("", |v| v);
-
     let url = req.url()?;
-    let query_params = url.query_pairs();
 
     // クエリパラメータの解析
+    let filter = match parse_filter(&url) {
+        Ok(filter) => filter,
+        Err(message) => return bad_request(message),
+    };
     let mut combine_schedule: bool = true;
+    let mut lat = None;
+    let mut lng = None;
+    let mut radius_m = None;
 
-    for (key, value) in query_params {
+    for (key, value) in url.query_pairs() {
         match key.as_ref() {
+            "lat" => lat = Some(value.into_owned()),
+            "lng" => lng = Some(value.into_owned()),
+            "radius_m" => radius_m = Some(value.into_owned()),
             "combine_schedule" => combine_schedule = value.parse().ok().unwrap_or(true),
             _ => {}
         }
     }
+    let query = match NearbyQuery::parse(lat.as_deref(), lng.as_deref(), radius_m.as_deref()) {
+        Ok(query) => query,
+        Err(err) => return bad_request(err.to_string()),
+    };
+
+    let plans = match ctx.data.plans.read_all(&filter).await {
+        Ok(plans) => plans,
+        Err(PlanReadError::NotFound) => vec![],
+        Err(err) => {
+            console_error!("failed to read plans: {:?}", err);
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "Internal error occurred."
+            }))?
+            .with_cors(&Cors::new().with_origins(vec!["*"]))?
+            .with_status(500));
+        }
+    };
+
+    let plans = query
+        .select(plans)
+        .into_iter()
+        .map(|(distance, plan)| {
+            let schedule = if combine_schedule {
+                plan.schedule.combine()
+            } else {
+                plan.schedule.uncombine()
+            };
+            let mut value = serde_json::to_value(PlanRead { schedule, ..plan })?;
+            // 距離は0.1m単位に丸める
+            value["distance_m"] = serde_json::json!((distance * 10.0).round() / 10.0);
+            Ok(value)
+        })
+        .collect::<Result<Vec<_>, serde_json::Error>>()?;
+    let body = serde_json::json!({
+        "plans": plans
+    });
+
+    let mut response =
+        Response::from_json(&body)?.with_cors(&Cors::new().with_origins(vec!["*"]))?;
+    let headers = response.headers_mut();
+    headers.set("Cache-Control", "public, max-age=3600, s-maxage=3600")?;
+    headers.set("ETag", &etag(&body))?;
+    set_last_modified(&mut response, modified_at)?;
+    put_cache(&cache, &cache_key, &mut response).await?;
+
+    conditional(&req, response)
+}
+
+/// 企画一覧をGeoJSONのFeatureCollectionで取得する（`GET /v1/plans.geojson`）
+///
+/// `GET /v1/plans`と同じ絞り込み条件を指定できる。座標の無い企画はFeatureにせず、企画IDを`unlocated`に含める
+pub async fn get_plans_geojson(
+    req: Request,
+    ctx: RouteContext<Storage>,
+) -> Result<Response, Error> {
+    // cacheからの復元
+    let (cache_key, modified_at) = cache_key(&req, &ctx.data, CacheScope::List).await?;
+    let cache = Cache::default();
+    if let Some(response) = cache.get(&cache_key, false).await? {
+        return conditional(&req, response);
+    }
+
+    let url = req.url()?;
+    let filter = match parse_filter(&url) {
+        Ok(filter) => filter,
+        Err(message) => return bad_request(message),
+    };
+
+    let mut plans = match ctx.data.plans.read_all(&filter).await {
+        Ok(plans) => plans,
+        Err(PlanReadError::NotFound) => vec![],
+        Err(err) => {
+            console_error!("failed to read plans: {:?}", err);
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "Internal error occurred."
+            }))?
+            .with_cors(&Cors::new().with_origins(vec!["*"]))?
+            .with_status(500));
+        }
+    };
+    plans.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let icon_ids = match ctx.data.icons.ids().await {
+        Ok(ids) => ids.into_iter().collect(),
+        Err(err) => {
+            console_error!("failed to list icons: {:?}", err);
+            return Ok(Response::from_json(&serde_json::json!({
+                "code": 500,
+                "message": "Internal error occurred."
+            }))?
+            .with_cors(&Cors::new().with_origins(vec!["*"]))?
+            .with_status(500));
+        }
+    };
+
+    let body = geojson::feature_collection(&plans, &icon_ids, &url);
+    let mut response =
+        Response::from_json(&body)?.with_cors(&Cors::new().with_origins(vec!["*"]))?;
+    let headers = response.headers_mut();
+    headers.set("Content-Type", geojson::CONTENT_TYPE)?;
+    headers.set("Cache-Control", "public, max-age=3600, s-maxage=3600")?;
+    headers.set("ETag", &etag(&body))?;
+    set_last_modified(&mut response, modified_at)?;
+    put_cache(&cache, &cache_key, &mut response).await?;
+
+    conditional(&req, response)
+}
+
+/// `GET /v1/plans/now`のキャッシュ期間（現在時刻によって結果が変わるため短くする）
+const NOW_CACHE_CONTROL: &str = "public, max-age=60, s-maxage=60";
+
+/// 現在実施中の企画を取得する（`GET /v1/plans/now`）
+///
+/// 開催日（`FESTIVAL_DATES`）と日本時間の現在時刻から`day`・`at`を求め、`GET /v1/plans`と同じ一覧を返す。
+/// キャッシュは`/now`のURLをキーとして`NOW_CACHE_CONTROL`の期間のみ保持する。開催日でない場合は空の一覧を返す
+pub async fn get_plans_now(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let Some((day, time)) = festival::resolve(&festival::dates(&ctx.env), now()) else {
+        let mut response = Response::from_json(&serde_json::json!({
+            "plans": []
+        }))?
+        .with_cors(&Cors::new().with_origins(vec!["*"]))?;
+        response
+            .headers_mut()
+            .set("Cache-Control", NOW_CACHE_CONTROL)?;
+        return Ok(response);
+    };
+
+    // 時間帯の指定は現在時刻で置き換え、その他のクエリはそのまま引き継ぐ
+    let mut url = req.url()?;
+    let pairs = url
+        .query_pairs()
+        .into_owned()
+        .filter(|(key, _)| !["day", "at", "from", "to"].contains(&key.as_str()))
+        .collect::<Vec<_>>();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("day", &day.to_string())
+        .append_pair("at", &time.to_string());
+
+    list_plans(&req, &url, &ctx.data, NOW_CACHE_CONTROL).await
+}
+
+pub async fn get_plan(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let plan_id = ctx.param("plan_id").map_or("", |v| v);
+
+    // cacheからの復元
+    let (cache_key, modified_at) = cache_key(&req, &ctx.data, CacheScope::Plan(plan_id)).await?;
+    let cache = Cache::default();
+    if let Some(response) = cache.get(&cache_key, false).await? {
+        return conditional(&req, response);
+    }
+
+    let url = req.url()?;
+    let query_params = url.query_pairs();
 
-    let kv = ctx.env.kv(KV_PLANS)?;
+    // クエリパラメータの解析
+    let mut combine_schedule: bool = true;
 
-    let mut response = match PlanRead::read(kv, plan_id).await {
-        Ok(mut plan) => {
-            if combine_schedule {
+    for (key, value) in query_params {
+        if key.as_ref() == "combine_schedule" {
+            combine_schedule = value.parse().ok().unwrap_or(true)
+        }
+    }
+
+    let store = &ctx.data.plans;
+    let mut response = match store.read_with_etag(plan_id).await {
+        Ok((mut plan, etag)) => {
+            // 表現ごとに異なるETagにする（`If-Match`ではどちらも保存されている値のETagとして扱う）
+            let etag = if combine_schedule {
                 plan = PlanRead {
                     schedule: plan.schedule.combine(),
                     ..plan.clone()
-                }
+                };
+                representation_etag(&etag, COMBINED_REPRESENTATION)
             } else {
                 plan = PlanRead {
                     schedule: plan.schedule.uncombine(),
                     ..plan.clone()
-                }
+                };
+                etag
+            };
+            let mut response = Response::from_json(&plan)?;
+            response.headers_mut().set("ETag", &etag)?;
+            response
+        }
+        Err(PlanReadError::NotFound) => {
+            if let Some(response) = redirect_alias(&req, &ctx.data, plan_id).await? {
+                return Ok(response);
             }
-            Response::from_json(&plan)?
+            Response::from_json(&serde_json::json!({
+                "code": 404,
+                "message": "Plan not found."
+            }))?
+            .with_status(404)
         }
-        Err(PlanReadError::NotFound) => Response::from_json(&serde_json::json!({
-            "code": 404,
-            "message": "Plan not found."
-        }))?
-        .with_status(404),
         Err(_) => Response::from_json(&serde_json::json!({
             "code": 500,
             "message": "Internal error occurred."
@@ -192,9 +592,11 @@ pub async fn get_plan(req: Request, ctx: RouteContext<()>) -> Result<Response, E
     if 200 <= response.status_code() && response.status_code() < 300 {
         let headers = response.headers_mut();
         headers.set("Cache-Control", "public, max-age=3600, s-maxage=3600")?;
+        // 企画ごとの更新日時は保持していないため、企画のキャッシュの世代を進めた日時を最終更新日時とする
+        set_last_modified(&mut response, modified_at)?;
 
-        cache.put(&cache_key, response.cloned()?).await?;
+        put_cache(&cache, &cache_key, &mut response).await?;
     }
 
-    Ok(response)
+    conditional(&req, response)
 }
@@ -1,27 +1,40 @@
-use crate::models::details::{PlanDetailsReadError, ReadPlanDetails};
-use crate::KV_PLAN_DETAILS;
-use worker::{Cache, Cors, Error, Method, Request, Response, RouteContext};
+use crate::models::cache::CacheScope;
+use crate::models::details::PlanDetailsReadError;
+use crate::routes::plans::{cache_key, conditional, put_cache, redirect_alias, set_last_modified};
+use crate::storage::Storage;
+use worker::{Cache, Cors, Error, Request, Response, RouteContext};
+
+pub async fn get_details(req: Request, ctx: RouteContext<Storage>) -> Result<Response, Error> {
+    let plan_id = ctx.param("plan_id").map_or("", |v| v);
 
-pub async fn get_details(req: Request, ctx: RouteContext<()>) -> Result<Response, Error> {
     // cacheからの復元
-    let cache_key = Request::new(req.url()?.as_str(), Method::Get)?;
+    let (cache_key, modified_at) = cache_key(&req, &ctx.data, CacheScope::Plan(plan_id)).await?;
     let cache = Cache::default();
     if let Some(response) = cache.get(&cache_key, false).await? {
-        return Ok(response);
+        return conditional(&req, response);
     }
 
-    let plan_id = ctx.param("plan_id").map_or("", |v| v);
-
-    let kv = ctx.env.kv(KV_PLAN_DETAILS)?;
-
-    let mut response = match ReadPlanDetails::read(kv, plan_id).await {
-        Ok(plan_details) => Response::from_json(&plan_details)?,
-        Err(PlanDetailsReadError::NotFound) => Response::from_json(&serde_json::json!({
-            "code": 404,
-            "message": "Plan details not found."
-        }))?
-        .with_cors(&Cors::new().with_origins(vec!["*"]))?
-        .with_status(404),
+    let store = &ctx.data.details;
+
+    let mut response = match store.read_with_etag(plan_id).await {
+        Ok((plan_details, etag)) => {
+            let mut response = Response::from_json(&plan_details)?;
+            response.headers_mut().set("ETag", &etag)?;
+            // 企画詳細の更新日時は保持していないため、企画のキャッシュの世代を進めた日時を最終更新日時とする
+            set_last_modified(&mut response, modified_at)?;
+            response
+        }
+        Err(PlanDetailsReadError::NotFound) => {
+            if let Some(response) = redirect_alias(&req, &ctx.data, plan_id).await? {
+                return Ok(response);
+            }
+            Response::from_json(&serde_json::json!({
+                "code": 404,
+                "message": "Plan details not found."
+            }))?
+            .with_cors(&Cors::new().with_origins(vec!["*"]))?
+            .with_status(404)
+        }
         Err(_) => Response::from_json(&serde_json::json!({
             "code": 500,
             "message": "Internal error occurred."
@@ -32,10 +45,12 @@ pub async fn get_details(req: Request, ctx: RouteContext<()>) -> Result<Response
 
     response = response.with_cors(&Cors::new().with_origins(vec!["*"]))?;
 
-    let headers = response.headers_mut();
-    headers.set("Cache-Control", "public, max-age=600, s-maxage=600")?;
-
-    cache.put(&cache_key, response.cloned()?).await?;
+    if (200..300).contains(&response.status_code()) {
+        response
+            .headers_mut()
+            .set("Cache-Control", "public, max-age=600, s-maxage=600")?;
+        put_cache(&cache, &cache_key, &mut response).await?;
+    }
 
-    Ok(response)
+    conditional(&req, response)
 }
@@ -1,50 +1,47 @@
-use crate::R2_PLAN_IMAGES;
-use wasm_bindgen::JsValue;
-use worker::{Cache, Cors, Headers, Method, Request, Response};
+use crate::models::cache::CacheScope;
+use crate::routes::plans::{cache_key, conditional, put_cache, redirect_alias};
+use crate::storage::Storage;
+use crate::util::http_date;
+use worker::{Cache, Cors, Headers, Request, Response};
 
 pub async fn get_icon(
     req: Request,
-    ctx: worker::RouteContext<()>,
+    ctx: worker::RouteContext<Storage>,
 ) -> Result<Response, worker::Error> {
+    let plan_id = ctx.param("plan_id").unwrap();
+
     // cacheからの復元
-    let cache_key = Request::new(req.url()?.as_str(), Method::Get)?;
+    let (cache_key, _) = cache_key(&req, &ctx.data, CacheScope::Plan(plan_id)).await?;
     let cache = Cache::default();
     if let Some(response) = cache.get(&cache_key, false).await? {
-        return Ok(response);
+        return conditional(&req, response);
     }
-
-    let plan_id = ctx.param("plan_id").unwrap();
-    let bucket = ctx.env.bucket(R2_PLAN_IMAGES)?;
-
-    let object = bucket
-        .get(format!("{}/original", plan_id))
-        .execute()
-        .await?;
-
-    if object.is_none() {
+    let Some(icon) = ctx.data.icons.get(plan_id).await? else {
+        if let Some(response) = redirect_alias(&req, &ctx.data, plan_id).await? {
+            return Ok(response);
+        }
         return Ok(Response::from_json(&serde_json::json!({
             "code": 404,
             "message": "Icon not found."
         }))?
         .with_status(404));
-    }
+    };
 
     // レスポンス
-    let object = object.unwrap();
     let headers = Headers::new();
-    object.write_http_metadata(headers.clone())?;
-    headers.set("etag", &*object.http_etag())?;
+    if let Some(content_type) = &icon.content_type {
+        headers.set("content-type", content_type)?;
+    }
+    headers.set("etag", &icon.etag)?;
+    headers.set("Last-Modified", &http_date(icon.uploaded))?;
     headers.set("Cache-Control", "public, max-age=3600, s-maxage=3600")?;
-    let Some(body) = object.body() else {
-        return Err(worker::Error::Internal(JsValue::from_str("body is none")));
-    };
 
-    let mut response = Response::from_bytes(body.bytes().await?)?
+    let mut response = Response::from_bytes(icon.bytes)?
         .with_headers(headers)
         .with_cors(&Cors::new().with_origins(vec!["*"]))?
         .with_status(200);
 
-    cache.put(&cache_key, response.cloned()?).await?;
+    put_cache(&cache, &cache_key, &mut response).await?;
 
-    Ok(response)
+    conditional(&req, response)
 }
@@ -41,7 +41,7 @@ impl Discord {
             .unwrap_or_default()
     }
 
-    fn combine_range(day: &Vec<DaySchedule>) -> Option<(Time, Time)> {
+    fn combine_range(day: &[DaySchedule]) -> Option<(Time, Time)> {
         if day.is_empty() {
             return None;
         }
@@ -58,7 +58,7 @@ impl Discord {
         Some((start, end))
     }
 
-    fn format_range(day: &Vec<DaySchedule>) -> String {
+    fn format_range(day: &[DaySchedule]) -> String {
         match Self::combine_range(day) {
             Some((start, end)) => format!(
                 "{} - {}",
@@ -115,17 +115,15 @@ impl Discord {
             // Handle rate limiting (429 status)
             if status == 429 {
                 // Try to get Retry-After header
-                if let Ok(retry_after_str) = response.headers().get("Retry-After") {
-                    if let Some(retry_after_str) = retry_after_str {
-                        if let Ok(retry_seconds) = retry_after_str.parse::<u64>() {
-                            // Sleep for the specified number of seconds
-                            worker::console_log!(
-                                "Rate limited. Retrying after {} seconds",
-                                retry_seconds
-                            );
-                            self.sleep_ms(retry_seconds * 1000).await;
-                            continue; // Retry the request
-                        }
+                if let Ok(Some(retry_after_str)) = response.headers().get("Retry-After") {
+                    if let Ok(retry_seconds) = retry_after_str.parse::<u64>() {
+                        // Sleep for the specified number of seconds
+                        worker::console_log!(
+                            "Rate limited. Retrying after {} seconds",
+                            retry_seconds
+                        );
+                        self.sleep_ms(retry_seconds * 1000).await;
+                        continue; // Retry the request
                     }
                 }
                 // Fallback: sleep for 1 second if no valid Retry-After header
@@ -659,17 +657,15 @@ impl Discord {
             // Handle rate limiting (429 status)
             if status == 429 {
                 // Try to get Retry-After header
-                if let Ok(retry_after_str) = response.headers().get("Retry-After") {
-                    if let Some(retry_after_str) = retry_after_str {
-                        if let Ok(retry_seconds) = retry_after_str.parse::<u64>() {
-                            // Sleep for the specified number of seconds
-                            worker::console_log!(
-                                "Rate limited (multipart). Retrying after {} seconds",
-                                retry_seconds
-                            );
-                            self.sleep_ms(retry_seconds * 1000).await;
-                            continue; // Retry the request
-                        }
+                if let Ok(Some(retry_after_str)) = response.headers().get("Retry-After") {
+                    if let Ok(retry_seconds) = retry_after_str.parse::<u64>() {
+                        // Sleep for the specified number of seconds
+                        worker::console_log!(
+                            "Rate limited (multipart). Retrying after {} seconds",
+                            retry_seconds
+                        );
+                        self.sleep_ms(retry_seconds * 1000).await;
+                        continue; // Retry the request
                     }
                 }
                 // Fallback: sleep for 1 second if no valid Retry-After header
@@ -0,0 +1,831 @@
+//! 企画・企画詳細・アイコンのスナップショット（エクスポート・インポート）
+//!
+//! スナップショットはJSON Linesで、1行目にヘッダー、以降は企画ごとに1行とする
+//!
+//! ```text
+//! {"type":"header","format":"koudaisai-plans-snapshot","version":1,...}
+//! {"type":"plan","id":"...","plan":{...},"details":{...},"icon":{...}}
+//! ```
+
+use crate::auth::Principal;
+use crate::models::details::{PlanDetailsReadError, ReadPlanDetails};
+use crate::models::keys::is_valid_plan_id;
+use crate::models::plan::{PlanFilter, PlanRead};
+use crate::models::schema::{unstamp, Document};
+use crate::models::trash::move_to_trash;
+use crate::storage::Storage;
+use crate::util::{now, sha256_hex};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
+use jwt_simple::reexports::ct_codecs::{Base64, Decoder, Encoder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::pin::pin;
+use thiserror::Error;
+
+pub const SNAPSHOT_FORMAT: &str = "koudaisai-plans-snapshot";
+/// スナップショットの形式のバージョン。読み込めるのはこのバージョン以下のもの
+pub const SNAPSHOT_VERSION: u32 = 1;
+pub const SNAPSHOT_CONTENT_TYPE: &str = "application/x-ndjson";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotHeader {
+    pub format: String,
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    /// 企画の数
+    pub plans: usize,
+    /// アイコンの画像を含むかどうか
+    pub icon_bytes: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotIcon {
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub etag: String,
+    pub uploaded: DateTime<Utc>,
+    pub size: usize,
+    /// 画像のSHA-256（16進数）
+    pub sha256: String,
+    /// Base64でエンコードした画像（`icon_bytes`が`true`の場合のみ）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotEntry {
+    pub id: String,
+    pub plan: Value,
+    #[serde(default)]
+    pub details: Option<Value>,
+    #[serde(default)]
+    pub icon: Option<SnapshotIcon>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SnapshotLine {
+    Header(SnapshotHeader),
+    Plan(SnapshotEntry),
+}
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("スナップショットの1行目にヘッダーがありません")]
+    MissingHeader,
+    #[error("対応していない形式のスナップショットです（{0} バージョン{1}）")]
+    UnsupportedFormat(String, u32),
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+    #[error("{0}")]
+    StoreError(String),
+}
+
+/// エクスポートの進み具合
+enum ExportState {
+    Header(Storage),
+    Plans(Storage, std::vec::IntoIter<PlanRead>),
+    Done,
+}
+
+/// 保存されている全ての企画・企画詳細・アイコンのスナップショットを1行ずつ作成する
+///
+/// アイコンの画像を含めると大きくなるため、全体を文字列にまとめずに企画ごとに読み込んで返す。
+/// 各行は改行で終わる。`icon_bytes`が`false`の場合、アイコンはメタデータのみを含める
+pub fn export(
+    storage: Storage,
+    icon_bytes: bool,
+) -> impl Stream<Item = Result<String, SnapshotError>> {
+    stream::unfold(ExportState::Header(storage), move |state| async move {
+        match state {
+            ExportState::Header(storage) => {
+                match storage.plans.read_all(&PlanFilter::default()).await {
+                    Ok(plans) => {
+                        let header = SnapshotLine::Header(SnapshotHeader {
+                            format: SNAPSHOT_FORMAT.to_string(),
+                            version: SNAPSHOT_VERSION,
+                            created_at: now(),
+                            plans: plans.len(),
+                            icon_bytes,
+                        });
+                        Some((
+                            line(&header),
+                            ExportState::Plans(storage, plans.into_iter()),
+                        ))
+                    }
+                    Err(err) => Some((
+                        Err(SnapshotError::StoreError(format!("{:?}", err))),
+                        ExportState::Done,
+                    )),
+                }
+            }
+            ExportState::Plans(storage, mut plans) => {
+                let plan = plans.next()?;
+                match export_entry(&storage, plan, icon_bytes).await {
+                    Ok(entry) => Some((line(&entry), ExportState::Plans(storage, plans))),
+                    // エラーの後は続けない（受け取った側では企画の数がヘッダーと一致しなくなる）
+                    Err(err) => Some((Err(err), ExportState::Done)),
+                }
+            }
+            ExportState::Done => None,
+        }
+    })
+}
+
+/// スナップショット全体を1つの文字列として作成する
+pub async fn export_to_string(
+    storage: &Storage,
+    icon_bytes: bool,
+) -> Result<String, SnapshotError> {
+    let mut lines = pin!(export(storage.clone(), icon_bytes));
+    let mut snapshot = String::new();
+    while let Some(line) = lines.next().await {
+        snapshot.push_str(&line?);
+    }
+    Ok(snapshot)
+}
+
+fn line(line: &SnapshotLine) -> Result<String, SnapshotError> {
+    Ok(serde_json::to_string(line)? + "\n")
+}
+
+async fn export_entry(
+    storage: &Storage,
+    plan: PlanRead,
+    icon_bytes: bool,
+) -> Result<SnapshotLine, SnapshotError> {
+    let id = plan.id.clone();
+    let details = match storage.details.read_with_etag(&id).await {
+        Ok((details, _)) => Some(serde_json::to_value(details)?),
+        Err(PlanDetailsReadError::NotFound) => None,
+        Err(err) => return Err(SnapshotError::StoreError(err.to_string())),
+    };
+    let icon = storage.icons.get(&id).await?.map(|icon| SnapshotIcon {
+        content_type: icon.content_type,
+        etag: icon.etag,
+        uploaded: icon.uploaded,
+        size: icon.bytes.len(),
+        sha256: sha256_hex(&icon.bytes),
+        bytes: icon_bytes.then(|| Base64::encode_to_string(&icon.bytes).unwrap_or_default()),
+    });
+
+    Ok(SnapshotLine::Plan(SnapshotEntry {
+        id,
+        plan: serde_json::to_value(plan)?,
+        details,
+        icon,
+    }))
+}
+
+/// インポートの方法
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportMode {
+    /// 存在しない企画・企画詳細・アイコンのみを追加する。内容の異なるものは衝突として報告する
+    Merge,
+    /// スナップショットの内容で置き換え、スナップショットに無い企画を削除する
+    Replace,
+    /// 書き込まずに、`merge`で追加される内容と衝突・検証エラーを報告する
+    DryRun,
+}
+
+impl ImportMode {
+    pub fn parse(mode: &str) -> Option<ImportMode> {
+        match mode {
+            "merge" => Some(ImportMode::Merge),
+            "replace" => Some(ImportMode::Replace),
+            "dry-run" => Some(ImportMode::DryRun),
+            _ => None,
+        }
+    }
+}
+
+/// 検証済みのスナップショットの企画
+struct ImportEntry {
+    /// スナップショットの行番号（1始まり）
+    line: usize,
+    id: String,
+    plan: Value,
+    details: Option<Value>,
+    /// (画像, Content-Type, SHA-256)
+    icon: Option<(Vec<u8>, String, String)>,
+    /// 画像を含まないアイコンのETag
+    icon_etag: Option<String>,
+}
+
+/// 企画ごとのエラー。`post_plans_bulk`の207レスポンスと同じ形で返す
+#[derive(Serialize, Clone, Debug)]
+pub struct ImportError {
+    /// スナップショットの行番号（1始まり）
+    pub line: usize,
+    pub plan_id: Option<String>,
+    pub code: u16,
+    pub message: String,
+}
+
+/// 書き込んだ値（変更履歴・監査記録用）
+#[derive(Clone, Debug)]
+pub struct ImportChange {
+    pub id: String,
+    /// 企画の変更前と変更後の値
+    pub plan: Option<(Option<Value>, Value)>,
+    /// 企画詳細の変更前と変更後の値
+    pub details: Option<(Option<Value>, Value)>,
+    pub icon: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ImportReport {
+    pub mode: ImportMode,
+    /// 新しく追加した企画
+    pub created: Vec<String>,
+    /// 企画・企画詳細・アイコンのいずれかを書き換えた企画
+    pub updated: Vec<String>,
+    /// 内容が同じだったため書き込まなかった企画
+    pub unchanged: Vec<String>,
+    /// 削除した企画（`dry-run`の場合は、`replace`で削除される企画）
+    pub deleted: Vec<String>,
+    /// スナップショットに画像が無いため復元できなかったアイコン（現在のアイコンが無い、または異なる企画）
+    ///
+    /// 画像を含まないスナップショットからは、現在のアイコンを書き換えも削除もしない
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub icons_skipped: Vec<String>,
+    pub errors: Vec<ImportError>,
+    #[serde(skip)]
+    pub changes: Vec<ImportChange>,
+}
+
+/// 保存されている値と比較できるよう、企画の値を現在の形に揃える
+fn normalize_plan(id: &str, mut value: Value) -> Result<Value, serde_json::Error> {
+    Document::Plan.upgrade(&mut value);
+    unstamp(&mut value);
+    if let Value::Object(map) = &mut value {
+        map.insert("id".into(), Value::String(id.to_string()));
+    }
+    let plan = serde_json::from_value::<PlanRead>(value)?;
+    serde_json::to_value(PlanRead {
+        schedule: plan.schedule.uncombine(),
+        ..plan
+    })
+}
+
+fn normalize_details(mut value: Value) -> Result<Value, serde_json::Error> {
+    Document::Details.upgrade(&mut value);
+    unstamp(&mut value);
+    serde_json::to_value(serde_json::from_value::<ReadPlanDetails>(value)?)
+}
+
+fn validation_error(line: usize, plan_id: Option<&str>, message: String) -> ImportError {
+    ImportError {
+        line,
+        plan_id: plan_id.map(str::to_string),
+        code: 400,
+        message,
+    }
+}
+
+/// 企画の行を検証する
+///
+/// 企画IDとして使えない値（企画以外のキーの接頭辞など）や、企画の内容が条件を満たさない行は書き込まない
+fn validate_entry(line: usize, entry: SnapshotEntry) -> Result<ImportEntry, ImportError> {
+    let id = entry.id.clone();
+    let invalid = |message: String| validation_error(line, Some(&id), message);
+    if !is_valid_plan_id(&entry.id) {
+        return Err(invalid(format!(
+            "「{}」は企画IDとして使用できません",
+            entry.id
+        )));
+    }
+    let plan = normalize_plan(&entry.id, entry.plan).map_err(|err| invalid(err.to_string()))?;
+    if let Err(message) = serde_json::from_value::<PlanRead>(plan.clone())
+        .map_err(|err| err.to_string())
+        .and_then(|plan| plan.check_rules())
+    {
+        return Err(ImportError {
+            code: 422,
+            ..invalid(message)
+        });
+    }
+    let details = entry
+        .details
+        .map(normalize_details)
+        .transpose()
+        .map_err(|err| invalid(err.to_string()))?;
+    let icon_etag = match &entry.icon {
+        Some(SnapshotIcon {
+            bytes: None, etag, ..
+        }) => Some(etag.clone()),
+        _ => None,
+    };
+    let icon = match entry.icon {
+        Some(SnapshotIcon {
+            bytes: Some(bytes),
+            content_type,
+            sha256,
+            ..
+        }) => {
+            let bytes = Base64::decode_to_vec(&bytes, None)
+                .map_err(|_| invalid("アイコンの画像をデコードできません".into()))?;
+            if sha256_hex(&bytes) != sha256 {
+                return Err(invalid("アイコンの画像がSHA-256と一致しません".into()));
+            }
+            let content_type = content_type
+                .filter(|content_type| content_type.starts_with("image/"))
+                .ok_or_else(|| invalid("アイコンのContent-Typeが画像ではありません".into()))?;
+            Some((bytes, content_type, sha256))
+        }
+        _ => None,
+    };
+
+    Ok(ImportEntry {
+        line,
+        id: entry.id,
+        plan,
+        details,
+        icon,
+        icon_etag,
+    })
+}
+
+/// スナップショットを読み込む
+///
+/// ヘッダーが無い・対応していない形式の場合はエラーを返す。企画の行の検証エラーは行ごとに返す
+fn parse(body: &str) -> Result<(Vec<ImportEntry>, Vec<ImportError>), SnapshotError> {
+    let mut lines = body
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let header = match lines
+        .next()
+        .map(|(_, line)| serde_json::from_str::<SnapshotLine>(line))
+    {
+        Some(Ok(SnapshotLine::Header(header))) => header,
+        _ => return Err(SnapshotError::MissingHeader),
+    };
+    if header.format != SNAPSHOT_FORMAT || header.version > SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedFormat(
+            header.format,
+            header.version,
+        ));
+    }
+
+    let mut entries = vec![];
+    let mut errors = vec![];
+    let mut ids = BTreeSet::new();
+    for (line, text) in lines {
+        let entry = match serde_json::from_str::<SnapshotLine>(text) {
+            Ok(SnapshotLine::Plan(entry)) => entry,
+            Ok(SnapshotLine::Header(_)) => {
+                errors.push(validation_error(
+                    line,
+                    None,
+                    "ヘッダーが重複しています".into(),
+                ));
+                continue;
+            }
+            Err(err) => {
+                errors.push(validation_error(line, None, err.to_string()));
+                continue;
+            }
+        };
+
+        let id = entry.id.clone();
+        if !ids.insert(id.clone()) {
+            errors.push(validation_error(
+                line,
+                Some(&id),
+                format!("ID「{}」の企画が重複しています", id),
+            ));
+            continue;
+        }
+        match validate_entry(line, entry) {
+            Ok(entry) => entries.push(entry),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    Ok((entries, errors))
+}
+
+/// 保存されている値とスナップショットの値の比較
+enum Comparison {
+    Missing,
+    Same,
+    Different(Value),
+}
+
+impl Comparison {
+    /// 書き込む前の値
+    fn into_before(self) -> Option<Value> {
+        match self {
+            Comparison::Different(before) => Some(before),
+            Comparison::Missing | Comparison::Same => None,
+        }
+    }
+}
+
+fn compare(current: Option<Value>, new: &Value) -> Comparison {
+    match current {
+        None => Comparison::Missing,
+        Some(current) if &current == new => Comparison::Same,
+        Some(current) => Comparison::Different(current),
+    }
+}
+
+/// スナップショットを保存先に書き込む
+///
+/// 企画ごとに、衝突や書き込みの失敗があった場合もその他の企画の書き込みは続ける。
+/// ただし検証エラーのある行があった場合は、その行の企画を誤って削除しないよう、
+/// スナップショットに無い企画の削除は行わない。
+/// `replace`で削除する企画は、`DELETE /v1/admin/plans/:plan_id`と同様に企画詳細・所有者・アイコンと一緒にゴミ箱に移動する
+pub async fn import(
+    storage: &Storage,
+    body: &str,
+    mode: ImportMode,
+    principal: &Principal,
+) -> Result<ImportReport, SnapshotError> {
+    let (entries, errors) = parse(body)?;
+    let valid = errors.is_empty();
+    let mut report = ImportReport {
+        mode,
+        created: vec![],
+        updated: vec![],
+        unchanged: vec![],
+        deleted: vec![],
+        icons_skipped: vec![],
+        errors,
+        changes: vec![],
+    };
+
+    let mut imported = BTreeSet::new();
+    for entry in entries {
+        imported.insert(entry.id.clone());
+        if let Err(error) = import_entry(storage, &mut report, mode, entry).await {
+            report.errors.push(error);
+        }
+    }
+
+    // スナップショットに無い企画
+    if mode != ImportMode::Merge && valid {
+        let stored = storage
+            .plans
+            .read_all(&PlanFilter::default())
+            .await
+            .map_err(|err| SnapshotError::StoreError(format!("{:?}", err)))?;
+        for plan in stored
+            .into_iter()
+            .filter(|plan| !imported.contains(&plan.id))
+        {
+            let id = plan.id.clone();
+            if mode == ImportMode::Replace {
+                match move_to_trash(storage, principal, &id, &plan).await {
+                    Ok(_) => {}
+                    Err(err) => {
+                        report.errors.push(ImportError {
+                            line: 0,
+                            plan_id: Some(id.clone()),
+                            code: 500,
+                            message: format!(
+                                "ID「{}」の企画削除中に内部エラーが発生しました: {}",
+                                id, err
+                            ),
+                        });
+                        continue;
+                    }
+                }
+            }
+            report.deleted.push(id);
+        }
+    }
+
+    Ok(report)
+}
+
+async fn import_entry(
+    storage: &Storage,
+    report: &mut ImportReport,
+    mode: ImportMode,
+    entry: ImportEntry,
+) -> Result<(), ImportError> {
+    let line = entry.line;
+    let id = entry.id.clone();
+    let id = id.as_str();
+    let internal_error = |err: String| ImportError {
+        line,
+        plan_id: Some(id.to_string()),
+        code: 500,
+        message: format!(
+            "ID「{}」の企画のインポート中に内部エラーが発生しました: {}",
+            id, err
+        ),
+    };
+
+    let current_plan = storage
+        .plans
+        .read_value(id)
+        .await
+        .map_err(|err| internal_error(format!("{:?}", err)))?
+        .map(|value| normalize_plan(id, value))
+        .transpose()
+        .map_err(|err| internal_error(err.to_string()))?;
+    let plan = compare(current_plan, &entry.plan);
+
+    let details = match &entry.details {
+        Some(details) => {
+            let current = storage
+                .details
+                .read_value(id)
+                .await
+                .map_err(|err| internal_error(err.to_string()))?
+                .map(normalize_details)
+                .transpose()
+                .map_err(|err| internal_error(err.to_string()))?;
+            Some(compare(current, details))
+        }
+        None => None,
+    };
+
+    let icon = match &entry.icon {
+        Some((_, _, sha256)) => {
+            let current = storage
+                .icons
+                .get(id)
+                .await
+                .map_err(|err| internal_error(err.to_string()))?
+                .map(|icon| Value::String(sha256_hex(&icon.bytes)));
+            Some(compare(current, &Value::String(sha256.clone())))
+        }
+        None => None,
+    };
+
+    if let Some(etag) = &entry.icon_etag {
+        let current = storage
+            .icons
+            .etag(id)
+            .await
+            .map_err(|err| internal_error(err.to_string()))?;
+        if current.as_ref() != Some(etag) {
+            report.icons_skipped.push(id.to_string());
+        }
+    }
+
+    // mergeでは既存の内容を書き換えない
+    let conflicts = [
+        ("企画", Some(&plan)),
+        ("企画詳細", details.as_ref()),
+        ("アイコン", icon.as_ref()),
+    ]
+    .into_iter()
+    .filter(|(_, comparison)| matches!(comparison, Some(Comparison::Different(_))))
+    .map(|(name, _)| name)
+    .collect::<Vec<_>>();
+    if mode != ImportMode::Replace && !conflicts.is_empty() {
+        return Err(ImportError {
+            line,
+            plan_id: Some(id.to_string()),
+            code: 409,
+            message: format!(
+                "ID「{}」の{}は既に存在し、内容が異なります",
+                id,
+                conflicts.join("・")
+            ),
+        });
+    }
+
+    let needs_write = |comparison: &Option<&Comparison>| {
+        matches!(
+            comparison,
+            Some(Comparison::Missing) | Some(Comparison::Different(_))
+        )
+    };
+    let created = matches!(plan, Comparison::Missing);
+    if !needs_write(&Some(&plan)) && !needs_write(&details.as_ref()) && !needs_write(&icon.as_ref())
+    {
+        report.unchanged.push(id.to_string());
+        return Ok(());
+    }
+    if mode == ImportMode::DryRun {
+        if created {
+            report.created.push(id.to_string());
+        } else {
+            report.updated.push(id.to_string());
+        }
+        return Ok(());
+    }
+
+    let mut change = ImportChange {
+        id: id.to_string(),
+        plan: None,
+        details: None,
+        icon: false,
+    };
+    let result = write_entry(storage, &mut change, entry, plan, details, icon).await;
+
+    // 一部を書き込めた場合も、書き込んだ分は変更として記録する
+    if change.plan.is_some() || change.details.is_some() || change.icon {
+        if created {
+            report.created.push(id.to_string());
+        } else {
+            report.updated.push(id.to_string());
+        }
+        report.changes.push(change);
+    }
+    result.map_err(internal_error)
+}
+
+async fn write_entry(
+    storage: &Storage,
+    change: &mut ImportChange,
+    entry: ImportEntry,
+    plan: Comparison,
+    details: Option<Comparison>,
+    icon: Option<Comparison>,
+) -> Result<(), String> {
+    let id = entry.id.as_str();
+    match plan {
+        Comparison::Same => {}
+        Comparison::Missing | Comparison::Different(_) => {
+            storage
+                .plans
+                .put_value(id, &entry.plan)
+                .await
+                .map_err(|err| err.to_string())?;
+            change.plan = Some((plan.into_before(), entry.plan));
+        }
+    }
+    if let (Some(new), Some(comparison)) = (entry.details, details) {
+        if !matches!(comparison, Comparison::Same) {
+            storage
+                .details
+                .put_value(id, &new)
+                .await
+                .map_err(|err| err.to_string())?;
+            change.details = Some((comparison.into_before(), new));
+        }
+    }
+    if let (Some((bytes, content_type, _)), Some(comparison)) = (entry.icon, icon) {
+        if !matches!(comparison, Comparison::Same) {
+            storage
+                .icons
+                .put(id, bytes, &content_type)
+                .await
+                .map_err(|err| err.to_string())?;
+            change.icon = true;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::KeycloakClaims;
+    use crate::storage::memory::{seed, storage};
+    use futures::executor::block_on;
+
+    fn principal() -> Principal {
+        Principal {
+            subject: "admin".into(),
+            claims: KeycloakClaims::default(),
+            permissions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn snapshot_with_icon_bytes_restores_icons() {
+        let source = storage();
+        let target = storage();
+        block_on(async {
+            seed(&source, "plan-1").await;
+            let snapshot = export_to_string(&source, true).await.unwrap();
+
+            let report = import(&target, &snapshot, ImportMode::Merge, &principal())
+                .await
+                .unwrap();
+            assert_eq!(report.created, ["plan-1"]);
+            assert!(report.icons_skipped.is_empty());
+            assert_eq!(
+                target.icons.etag("plan-1").await.unwrap(),
+                source.icons.etag("plan-1").await.unwrap()
+            );
+        });
+    }
+
+    #[test]
+    fn snapshot_without_icon_bytes_keeps_current_icons() {
+        let storage = storage();
+        block_on(async {
+            seed(&storage, "plan-1").await;
+            seed(&storage, "plan-2").await;
+            let snapshot = export_to_string(&storage, false).await.unwrap();
+            storage.icons.delete("plan-1").await.unwrap();
+            storage
+                .icons
+                .put("plan-2", vec![4, 5, 6], "image/png")
+                .await
+                .unwrap();
+            let etag = storage.icons.etag("plan-2").await.unwrap();
+
+            let report = import(&storage, &snapshot, ImportMode::Replace, &principal())
+                .await
+                .unwrap();
+            assert_eq!(report.icons_skipped, ["plan-1", "plan-2"]);
+            assert!(report.changes.is_empty());
+            assert!(storage.icons.etag("plan-1").await.unwrap().is_none());
+            assert_eq!(storage.icons.etag("plan-2").await.unwrap(), etag);
+        });
+    }
+
+    #[test]
+    fn replace_moves_plans_missing_from_snapshot_to_trash() {
+        let storage = storage();
+        block_on(async {
+            seed(&storage, "plan-1").await;
+            let snapshot = export_to_string(&storage, false).await.unwrap();
+            assert_eq!(snapshot.lines().count(), 2);
+            seed(&storage, "plan-2").await;
+
+            let report = import(&storage, &snapshot, ImportMode::Replace, &principal())
+                .await
+                .unwrap();
+            assert_eq!(report.deleted, ["plan-2"]);
+            assert!(storage.plans.read_value("plan-2").await.unwrap().is_none());
+            assert!(storage
+                .details
+                .read_value("plan-2")
+                .await
+                .unwrap()
+                .is_none());
+            assert!(storage.icons.etag("plan-2").await.unwrap().is_none());
+
+            let entry = storage.trash.read("plan-2").await.unwrap().unwrap();
+            assert!(entry.icon);
+            assert!(entry.details.is_some());
+            assert_eq!(entry.deleted_by, "admin");
+        });
+    }
+
+    /// `plan-1`のスナップショットの企画の行を、企画IDと企画の値を書き換えて複製する
+    fn with_entries(snapshot: &str, entries: &[(&str, Value)]) -> String {
+        let mut lines = snapshot.lines().map(str::to_string).collect::<Vec<_>>();
+        let entry = serde_json::from_str::<Value>(&lines[1]).unwrap();
+        for (id, plan) in entries {
+            let mut entry = entry.clone();
+            entry["id"] = Value::String(id.to_string());
+            if let (Value::Object(to), Value::Object(from)) = (&mut entry["plan"], plan) {
+                to.extend(from.clone());
+            }
+            lines.push(entry.to_string());
+        }
+        lines.join("\n")
+    }
+
+    #[test]
+    fn import_rejects_reserved_ids_and_rule_violations() {
+        let source = storage();
+        let target = storage();
+        block_on(async {
+            seed(&source, "plan-1").await;
+            let snapshot = export_to_string(&source, false).await.unwrap();
+            let snapshot = with_entries(
+                &snapshot,
+                &[
+                    ("keys:all", serde_json::json!({})),
+                    ("apikeys:forged", serde_json::json!({})),
+                    ("now", serde_json::json!({})),
+                    ("a/b", serde_json::json!({})),
+                    ("plan-2", serde_json::json!({"plan_name": " "})),
+                ],
+            );
+
+            let report = import(&target, &snapshot, ImportMode::Merge, &principal())
+                .await
+                .unwrap();
+            assert_eq!(report.created, ["plan-1"]);
+            let errors = report
+                .errors
+                .iter()
+                .map(|error| (error.line, error.plan_id.as_deref().unwrap(), error.code))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                errors,
+                [
+                    (3, "keys:all", 400),
+                    (4, "apikeys:forged", 400),
+                    (5, "now", 400),
+                    (6, "a/b", 400),
+                    (7, "plan-2", 422),
+                ]
+            );
+            for id in ["keys:all", "apikeys:forged", "now", "a/b", "plan-2"] {
+                assert!(target.plans.read_value(id).await.unwrap().is_none());
+            }
+        });
+    }
+}
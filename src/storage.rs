@@ -0,0 +1,318 @@
+//! 企画・企画詳細・アイコンの保存先
+//!
+//! ルートは`Storage`を通してのみ保存先にアクセスし、KV/D1/R2を直接扱わない
+
+pub mod d1;
+pub mod kv;
+#[cfg(test)]
+pub mod memory;
+pub mod migration;
+pub mod orphans;
+pub mod r2;
+
+use crate::models::alias::PlanAlias;
+use crate::models::api_key::{ApiKeyError, StoredApiKey};
+use crate::models::audit::{AuditError, AuditPage, AuditQuery, AuditRecord};
+use crate::models::cache::CacheScope;
+use crate::models::details::{
+    CreatePlanDetails, PlanDetailsCreateError, PlanDetailsReadError, ReadPlanDetails,
+};
+use crate::models::keys::{GetKeysError, KeysCheck, PutKeysError};
+use crate::models::migration::{MigrationState, MigrationStateError};
+use crate::models::owners::{PlanOwners, PlanOwnersError};
+use crate::models::plan::{
+    PlanCreate, PlanCreateError, PlanFilter, PlanRead, PlanReadError, PlanUpdate, PlanUpdateError,
+};
+use crate::models::revision::{RevisionError, RevisionHistory};
+use crate::models::schema::{Document, VersionCounts};
+use crate::models::trash::{TrashEntry, TrashError};
+use crate::{D1_PLANS, KV_PLANS, KV_PLAN_DETAILS, KV_PLAN_HISTORY, R2_PLAN_IMAGES};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+use std::rc::Rc;
+use worker::kv::KvError;
+use worker::{Env, Error};
+
+/// 企画・企画詳細の保存先
+///
+/// 環境変数`PLANS_STORAGE`に`kv`（既定）または`d1`を指定する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Kv,
+    D1,
+}
+
+impl Backend {
+    pub fn from_env(env: &Env) -> Self {
+        match env.var("PLANS_STORAGE").map(|var| var.to_string()) {
+            Ok(var) if var.eq_ignore_ascii_case("d1") => Backend::D1,
+            _ => Backend::Kv,
+        }
+    }
+}
+
+/// 企画の保存先
+#[async_trait(?Send)]
+pub trait PlanRepository {
+    /// 企画と、保存されている値のETagを取得する
+    async fn read_with_etag(&self, id: &str) -> Result<(PlanRead, String), PlanReadError>;
+
+    async fn read(&self, id: &str) -> Result<PlanRead, PlanReadError> {
+        Ok(self.read_with_etag(id).await?.0)
+    }
+
+    /// 保存されている値をそのまま取得する（変更履歴との比較・復元用）
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanReadError>;
+
+    /// 条件に合う企画をID順に取得する
+    async fn read_all(&self, filter: &PlanFilter) -> Result<Vec<PlanRead>, PlanReadError>;
+
+    async fn create(&self, id: &str, plan: PlanCreate) -> Result<PlanRead, PlanCreateError>;
+
+    /// 企画を更新し、更新前と更新後の値を返す
+    ///
+    /// `expected_etag`を指定した場合は、現在の値のETagと一致しなければ更新しない
+    async fn update(
+        &self,
+        id: &str,
+        update: PlanUpdate,
+        expected_etag: Option<&str>,
+    ) -> Result<(Value, Value), PlanUpdateError>;
+
+    /// 値をそのまま保存する（変更履歴からの復元用）
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanUpdateError>;
+
+    async fn delete(&self, id: &str) -> Result<(), Error>;
+
+    /// 保存されている全ての企画IDを、インデックスを使わずに取得する
+    async fn ids(&self) -> Result<Vec<String>, Error>;
+
+    /// 一覧取得用の企画IDのインデックスに企画IDを追加・削除する
+    async fn update_index(&self, added: &[String], removed: &[String]) -> Result<(), PutKeysError>;
+
+    /// インデックスを作り直し、その内容を返す
+    async fn rebuild_index(&self) -> Result<Vec<String>, PutKeysError>;
+
+    /// インデックスと保存されている企画が一致しているか確認する
+    async fn check_index(&self) -> Result<KeysCheck, GetKeysError>;
+
+    /// 保存されている企画のスキーマのバージョンごとの数
+    async fn schema_versions(&self) -> Result<VersionCounts, Error>;
+}
+
+/// 企画詳細の保存先
+#[async_trait(?Send)]
+pub trait DetailsRepository {
+    /// 企画詳細と、保存されている値のETagを取得する
+    async fn read_with_etag(
+        &self,
+        id: &str,
+    ) -> Result<(ReadPlanDetails, String), PlanDetailsReadError>;
+
+    /// 保存されている値をそのまま取得する（変更履歴との比較・復元用）
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanDetailsReadError>;
+
+    /// 企画詳細を作成または置き換える
+    async fn put(
+        &self,
+        id: &str,
+        details: CreatePlanDetails,
+    ) -> Result<ReadPlanDetails, PlanDetailsCreateError>;
+
+    /// 値をそのまま保存する（変更履歴からの復元用）
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanDetailsCreateError>;
+
+    async fn delete(&self, id: &str) -> Result<(), Error>;
+
+    /// 企画詳細が保存されている全ての企画IDを取得する
+    async fn ids(&self) -> Result<Vec<String>, Error>;
+
+    /// 保存されている企画詳細のスキーマのバージョンごとの数
+    async fn schema_versions(&self) -> Result<VersionCounts, Error>;
+}
+
+/// 保存されているアイコン
+#[derive(Clone)]
+pub struct Icon {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+    pub etag: String,
+    pub uploaded: DateTime<Utc>,
+}
+
+/// アイコンの保存先
+#[async_trait(?Send)]
+pub trait IconStore {
+    async fn get(&self, plan_id: &str) -> Result<Option<Icon>, Error>;
+
+    /// 保存されているアイコンのETagを取得する
+    async fn etag(&self, plan_id: &str) -> Result<Option<String>, Error>;
+
+    async fn put(&self, plan_id: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), Error>;
+
+    /// アイコンを完全に削除する（ゴミ箱には移動しない）
+    async fn delete(&self, plan_id: &str) -> Result<(), Error>;
+
+    /// アイコンを別の企画IDに移動する。アイコンが無い場合は`false`を返す
+    async fn rename(&self, from: &str, to: &str) -> Result<bool, Error>;
+
+    /// アイコンが保存されている全ての企画IDを取得する（ゴミ箱のアイコンは含めない）
+    async fn ids(&self) -> Result<Vec<String>, Error>;
+
+    /// アイコンをゴミ箱の領域に移動する。アイコンが無い場合は`false`を返す
+    async fn move_to_trash(&self, plan_id: &str) -> Result<bool, Error>;
+
+    /// ゴミ箱の領域からアイコンを戻す。ゴミ箱にアイコンが無い場合は`false`を返す
+    async fn restore_from_trash(&self, plan_id: &str) -> Result<bool, Error>;
+
+    /// ゴミ箱の領域のアイコンを完全に削除する
+    async fn purge_trash(&self, plan_id: &str) -> Result<(), Error>;
+}
+
+/// 企画の所有者の保存先
+#[async_trait(?Send)]
+pub trait OwnersRepository {
+    /// 所有者を取得する。未設定の場合は空の所有者を返す
+    async fn read(&self, plan_id: &str) -> Result<PlanOwners, PlanOwnersError>;
+
+    async fn write(&self, plan_id: &str, owners: &PlanOwners) -> Result<(), PlanOwnersError>;
+
+    async fn delete(&self, plan_id: &str) -> Result<(), PlanOwnersError>;
+
+    /// 所有者が設定されている全ての企画IDを取得する
+    async fn ids(&self) -> Result<Vec<String>, PlanOwnersError>;
+}
+
+/// 変更前の企画IDから変更後の企画IDへの転送の保存先
+#[async_trait(?Send)]
+pub trait AliasRepository {
+    async fn read(&self, old_id: &str) -> Result<Option<PlanAlias>, KvError>;
+
+    /// 転送を保存する。`ttl`を過ぎると自動で削除される
+    async fn write(&self, old_id: &str, alias: &PlanAlias, ttl: Duration) -> Result<(), KvError>;
+
+    async fn delete(&self, old_id: &str) -> Result<(), KvError>;
+}
+
+/// 公開APIのレスポンスキャッシュの世代の保存先
+#[async_trait(?Send)]
+pub trait CacheGenerations {
+    /// 現在の世代を取得する。一度も進めていない場合は`0`
+    async fn generation(&self, scope: CacheScope<'_>) -> Result<String, KvError>;
+
+    /// 世代を進め、それまでのキャッシュを参照できなくする
+    async fn bump(&self, scope: CacheScope<'_>) -> Result<(), KvError>;
+}
+
+/// ゴミ箱の企画の保存先
+#[async_trait(?Send)]
+pub trait TrashRepository {
+    async fn read(&self, plan_id: &str) -> Result<Option<TrashEntry>, TrashError>;
+
+    async fn write(&self, entry: &TrashEntry) -> Result<(), TrashError>;
+
+    async fn delete(&self, plan_id: &str) -> Result<(), TrashError>;
+
+    /// ゴミ箱の企画を削除日時の新しい順に取得する
+    async fn list(&self) -> Result<Vec<TrashEntry>, TrashError>;
+}
+
+/// 企画・企画詳細の変更履歴の保存先
+#[async_trait(?Send)]
+pub trait RevisionRepository {
+    /// 変更履歴を取得する。未記録の場合は空の履歴を返す
+    async fn read(
+        &self,
+        document: Document,
+        plan_id: &str,
+    ) -> Result<RevisionHistory, RevisionError>;
+
+    async fn write(
+        &self,
+        document: Document,
+        plan_id: &str,
+        history: &RevisionHistory,
+    ) -> Result<(), RevisionError>;
+
+    async fn delete(&self, document: Document, plan_id: &str) -> Result<(), RevisionError>;
+}
+
+/// 監査記録の保存先
+#[async_trait(?Send)]
+pub trait AuditLog {
+    async fn append(&self, record: &AuditRecord) -> Result<(), AuditError>;
+
+    /// 条件に合う監査記録を新しい順に取得する
+    async fn list(&self, query: &AuditQuery) -> Result<AuditPage, AuditError>;
+}
+
+/// APIキーの保存先
+#[async_trait(?Send)]
+pub trait ApiKeyRepository {
+    async fn get(&self, id: &str) -> Result<Option<StoredApiKey>, ApiKeyError>;
+
+    async fn put(&self, stored: &StoredApiKey) -> Result<(), ApiKeyError>;
+
+    async fn list(&self) -> Result<Vec<StoredApiKey>, ApiKeyError>;
+}
+
+/// KVからD1への移行の進捗の保存先
+#[async_trait(?Send)]
+pub trait MigrationStateStore {
+    /// 進捗を取得する。開始していない場合は`None`を返す
+    async fn read(&self) -> Result<Option<MigrationState>, MigrationStateError>;
+
+    async fn write(&self, state: &MigrationState) -> Result<(), MigrationStateError>;
+}
+
+/// ルートが使う保存先一式。`Router::with_data`で各ハンドラーに渡す
+#[derive(Clone)]
+pub struct Storage {
+    pub plans: Rc<dyn PlanRepository>,
+    pub details: Rc<dyn DetailsRepository>,
+    pub icons: Rc<dyn IconStore>,
+    pub owners: Rc<dyn OwnersRepository>,
+    pub aliases: Rc<dyn AliasRepository>,
+    pub cache: Rc<dyn CacheGenerations>,
+    pub trash: Rc<dyn TrashRepository>,
+    pub revisions: Rc<dyn RevisionRepository>,
+    pub audit: Rc<dyn AuditLog>,
+    pub api_keys: Rc<dyn ApiKeyRepository>,
+    pub migration: Rc<dyn MigrationStateStore>,
+}
+
+impl Storage {
+    /// 企画・企画詳細は`PLANS_STORAGE`の保存先、それ以外はバックエンドによらずKVに保存する
+    ///
+    /// `PLAN_HISTORY`が設定されていない場合は、監査記録・変更履歴の読み書きのみがエラーになる
+    pub fn from_env(env: &Env) -> Result<Self, Error> {
+        let (plans, details): (Rc<dyn PlanRepository>, Rc<dyn DetailsRepository>) =
+            match Backend::from_env(env) {
+                Backend::Kv => (
+                    Rc::new(kv::KvPlans(env.kv(KV_PLANS)?)),
+                    Rc::new(kv::KvDetails(env.kv(KV_PLAN_DETAILS)?)),
+                ),
+                Backend::D1 => {
+                    let db = Rc::new(env.d1(D1_PLANS)?);
+                    (Rc::new(d1::D1Plans(db.clone())), Rc::new(d1::D1Details(db)))
+                }
+            };
+        let kv = env.kv(KV_PLANS)?;
+        // 監査記録・変更履歴のnamespaceが無くても、公開APIや定期バックアップは動かす
+        let history = env.kv(KV_PLAN_HISTORY).ok();
+        Ok(Storage {
+            plans,
+            details,
+            icons: Rc::new(r2::R2Icons(env.bucket(R2_PLAN_IMAGES)?)),
+            owners: Rc::new(kv::KvOwners(kv.clone())),
+            aliases: Rc::new(kv::KvAliases(kv.clone())),
+            cache: Rc::new(kv::KvCacheGenerations(kv.clone())),
+            trash: Rc::new(kv::KvTrash(kv.clone())),
+            revisions: Rc::new(kv::KvRevisions(history.clone())),
+            audit: Rc::new(kv::KvAuditLog(history)),
+            api_keys: Rc::new(kv::KvApiKeys(kv.clone())),
+            migration: Rc::new(kv::KvMigrationState(kv)),
+        })
+    }
+}
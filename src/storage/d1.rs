@@ -0,0 +1,1270 @@
+//! D1（SQLite）による企画・企画詳細の保存
+//!
+//! スキーマは`migrations/`以下を参照。行から`PlanRead`/`ReadPlanDetails`を組み立てる際は、
+//! KVに保存していたものと同じ形のJSONを作ってからデシリアライズする
+//!
+//! SQLは`SqlDatabase`を通して実行する（本番ではD1、テストではSQLite）
+
+use super::{DetailsRepository, PlanRepository};
+use crate::models::details::{
+    CreatePlanDetails, PlanDetailsCreateError, PlanDetailsReadError, ReadPlanDetails,
+};
+use crate::models::keys::{GetKeysError, KeysCheck, PutKeysError};
+use crate::models::plan::{
+    PlanCreate, PlanCreateError, PlanFilter, PlanRead, PlanReadError, PlanUpdate, PlanUpdateError,
+};
+use crate::models::schema::{Document, VersionCounts};
+use crate::util::{etag, if_match};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::JsValue;
+use worker::{D1Database, Error};
+
+/// SQLにバインドする値
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl SqlValue {
+    fn to_js(&self) -> JsValue {
+        match self {
+            SqlValue::Null => JsValue::NULL,
+            SqlValue::Integer(value) => JsValue::from_f64(*value as f64),
+            SqlValue::Real(value) => JsValue::from_f64(*value),
+            SqlValue::Text(value) => JsValue::from_str(value),
+        }
+    }
+}
+
+/// バインドする値を含めたSQLのステートメント
+#[derive(Clone, Debug)]
+pub struct Statement {
+    pub sql: String,
+    pub binds: Vec<SqlValue>,
+}
+
+impl Statement {
+    fn new(sql: impl Into<String>, binds: Vec<SqlValue>) -> Self {
+        Self {
+            sql: sql.into(),
+            binds,
+        }
+    }
+}
+
+/// SQLの実行先
+#[async_trait(?Send)]
+pub trait SqlDatabase {
+    /// ステートメントを1つのトランザクションとして実行し、ステートメントごとの結果の行を返す
+    ///
+    /// 行は列名をキーとしたJSONのオブジェクトで返す
+    async fn batch(&self, statements: Vec<Statement>) -> Result<Vec<Vec<Value>>, Error>;
+}
+
+#[async_trait(?Send)]
+impl SqlDatabase for D1Database {
+    async fn batch(&self, statements: Vec<Statement>) -> Result<Vec<Vec<Value>>, Error> {
+        let statements = statements
+            .into_iter()
+            .map(|statement| {
+                let binds = statement
+                    .binds
+                    .iter()
+                    .map(SqlValue::to_js)
+                    .collect::<Vec<_>>();
+                self.prepare(statement.sql).bind(&binds)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        D1Database::batch(self, statements)
+            .await?
+            .iter()
+            .map(|result| result.results::<Value>())
+            .collect()
+    }
+}
+
+/// 1つのステートメントを実行し、結果の行を返す
+async fn query(db: &dyn SqlDatabase, statement: Statement) -> Result<Vec<Value>, Error> {
+    Ok(db.batch(vec![statement]).await?.pop().unwrap_or_default())
+}
+
+/// 結果の行を列名をフィールド名として変換する
+fn rows<T: DeserializeOwned>(rows: Vec<Value>) -> Result<Vec<T>, serde_json::Error> {
+    rows.into_iter().map(serde_json::from_value).collect()
+}
+
+#[derive(Deserialize)]
+struct PlanRow {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    organization_name: String,
+    plan_name: String,
+    description: String,
+    is_child_friendly: i64,
+    is_recommended: i64,
+    is_lab_tour: Option<i64>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct CategoryRow {
+    plan_id: String,
+    category: String,
+}
+
+#[derive(Deserialize)]
+struct ScheduleRow {
+    plan_id: String,
+    day: i64,
+    start_time: String,
+    end_time: String,
+    location: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LocationRow {
+    plan_id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    building: Option<String>,
+    room: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DetailsRow {
+    #[serde(default)]
+    plan_id: String,
+    has_product: i64,
+    product_description: Option<String>,
+    additional_info: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProductRow {
+    #[serde(default)]
+    plan_id: String,
+    name: String,
+    price: Option<f64>,
+    options: String,
+}
+
+fn text(value: &str) -> SqlValue {
+    SqlValue::Text(value.to_string())
+}
+
+fn optional_text(value: Option<&str>) -> SqlValue {
+    value.map_or(SqlValue::Null, text)
+}
+
+fn integer(value: i64) -> SqlValue {
+    SqlValue::Integer(value)
+}
+
+fn real(value: Option<f64>) -> SqlValue {
+    value.map_or(SqlValue::Null, SqlValue::Real)
+}
+
+fn group_by_plan<T>(rows: Vec<T>, plan_id: impl Fn(&T) -> &str) -> HashMap<String, Vec<T>> {
+    let mut grouped: HashMap<String, Vec<T>> = HashMap::new();
+    for row in rows {
+        grouped
+            .entry(plan_id(&row).to_string())
+            .or_default()
+            .push(row);
+    }
+    grouped
+}
+
+/// `condition`に一致する企画を、子テーブルと合わせて1回のバッチで取得する
+async fn select_plans(
+    db: &dyn SqlDatabase,
+    condition: &str,
+    binds: &[SqlValue],
+) -> Result<Vec<PlanRead>, PlanReadError> {
+    let subquery = format!("SELECT id FROM plans WHERE {}", condition);
+    let statements = vec![
+        Statement::new(
+            format!("SELECT * FROM plans WHERE {} ORDER BY id", condition),
+            binds.to_vec(),
+        ),
+        Statement::new(
+            format!(
+                "SELECT plan_id, category FROM plan_categories WHERE plan_id IN ({}) ORDER BY plan_id, position",
+                subquery
+            ),
+            binds.to_vec(),
+        ),
+        Statement::new(
+            format!(
+                "SELECT plan_id, day, start_time, end_time, location FROM plan_schedules WHERE plan_id IN ({}) ORDER BY plan_id, day, position",
+                subquery
+            ),
+            binds.to_vec(),
+        ),
+        Statement::new(
+            format!(
+                "SELECT plan_id, type, building, room, name FROM plan_locations WHERE plan_id IN ({}) ORDER BY plan_id, position",
+                subquery
+            ),
+            binds.to_vec(),
+        ),
+    ];
+    let Ok([plans, categories, schedules, locations]) =
+        <[Vec<Value>; 4]>::try_from(db.batch(statements).await?)
+    else {
+        return Err(Error::RustError("unexpected D1 batch result".into()).into());
+    };
+
+    let mut categories = group_by_plan(rows::<CategoryRow>(categories)?, |row| &row.plan_id);
+    let mut schedules = group_by_plan(rows::<ScheduleRow>(schedules)?, |row| &row.plan_id);
+    let mut locations = group_by_plan(rows::<LocationRow>(locations)?, |row| &row.plan_id);
+
+    rows::<PlanRow>(plans)?
+        .into_iter()
+        .map(|row| {
+            let categories = categories.remove(&row.id).unwrap_or_default();
+            let schedules = schedules.remove(&row.id).unwrap_or_default();
+            let locations = locations.remove(&row.id).unwrap_or_default();
+            Ok(serde_json::from_value(plan_value(
+                row, categories, schedules, locations,
+            ))?)
+        })
+        .collect()
+}
+
+fn plan_value(
+    row: PlanRow,
+    categories: Vec<CategoryRow>,
+    schedules: Vec<ScheduleRow>,
+    locations: Vec<LocationRow>,
+) -> Value {
+    let mut day1 = vec![];
+    let mut day2 = vec![];
+    for schedule in schedules {
+        let mut entry = json!({
+            "start_time": schedule.start_time,
+            "end_time": schedule.end_time,
+        });
+        if let Some(location) = schedule
+            .location
+            .and_then(|location| serde_json::from_str::<Value>(&location).ok())
+        {
+            entry["location"] = location;
+        }
+        match schedule.day {
+            1 => day1.push(entry),
+            _ => day2.push(entry),
+        }
+    }
+
+    let locations = locations
+        .into_iter()
+        .map(|location| match location.kind.as_str() {
+            "outdoor" => json!({ "type": "outdoor", "name": location.name }),
+            _ => json!({
+                "type": "indoor",
+                "building": location.building,
+                "room": location.room,
+            }),
+        })
+        .collect::<Vec<_>>();
+
+    let coordinates = match (row.latitude, row.longitude) {
+        (Some(latitude), Some(longitude)) => json!({
+            "latitude": latitude,
+            "longitude": longitude,
+        }),
+        _ => Value::Null,
+    };
+
+    let mut value = json!({
+        "id": row.id,
+        "type": row.kind,
+        "organization_name": row.organization_name,
+        "plan_name": row.plan_name,
+        "description": row.description,
+        "is_child_friendly": row.is_child_friendly != 0,
+        "is_recommended": row.is_recommended != 0,
+        "schedule": { "day1": day1, "day2": day2 },
+        "location": locations,
+        "coordinates": coordinates,
+    });
+    match row.kind.as_str() {
+        "booth" | "general" => {
+            value["categories"] = categories
+                .into_iter()
+                .map(|category| Value::String(category.category))
+                .collect();
+        }
+        "labo" => value["is_lab_tour"] = Value::Bool(row.is_lab_tour.unwrap_or(0) != 0),
+        _ => {}
+    }
+    value
+}
+
+/// 絞り込み条件をSQLのWHERE句とバインドする値に変換する
+fn filter_condition(filter: &PlanFilter) -> (String, Vec<SqlValue>) {
+    let mut clauses = vec!["1 = 1".to_string()];
+    let mut binds = vec![];
+
+    if let Some(types) = &filter.types {
+        if types.is_empty() {
+            clauses.push("0 = 1".into());
+        } else {
+            clauses.push(format!("type IN ({})", vec!["?"; types.len()].join(", ")));
+            binds.extend(types.iter().map(|t| text(t)));
+        }
+    }
+    if let Some(recommended) = filter.recommended {
+        clauses.push("is_recommended = ?".into());
+        binds.push(integer(recommended as i64));
+    }
+    if let Some(child_friendly) = filter.child_friendly {
+        clauses.push("is_child_friendly = ?".into());
+        binds.push(integer(child_friendly as i64));
+    }
+    if let Some(lab_tour) = filter.lab_tour {
+        clauses.push("(type <> 'labo' OR is_lab_tour = ?)".into());
+        binds.push(integer(lab_tour as i64));
+    }
+    if let Some(categories) = &filter.categories {
+        clauses.push(format!(
+            "id IN (SELECT plan_id FROM plan_categories WHERE {})",
+            in_list("category", categories.len())
+        ));
+        binds.extend(categories.iter().map(|c| text(c)));
+    }
+    if let Some(condition) = location_condition(filter, &mut binds) {
+        clauses.push(condition);
+    }
+    if let Some(window) = &filter.schedule {
+        // 時刻は`HH:mm`の文字列で保存しているため、文字列として比較する
+        clauses.push(
+            "id IN (SELECT plan_id FROM plan_schedules WHERE day = ? AND start_time < ? AND end_time > ?)"
+                .into(),
+        );
+        binds.push(integer(window.day as i64));
+        binds.push(text(&hhmm(window.end)));
+        binds.push(text(&hhmm(window.start)));
+    }
+    if let Some(bbox) = &filter.bbox {
+        clauses.push("longitude BETWEEN ? AND ? AND latitude BETWEEN ? AND ?".into());
+        binds.extend([bbox.min_lng, bbox.max_lng, bbox.min_lat, bbox.max_lat].map(SqlValue::Real));
+    }
+
+    (clauses.join(" AND "), binds)
+}
+
+/// 0時からの分数を`HH:mm`にする（時間帯の終わりの`24:00`を含む）
+fn hhmm(minutes: u16) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// `column IN (?, ...)`。値が無い場合は常に偽とする
+fn in_list(column: &str, len: usize) -> String {
+    if len == 0 {
+        return "0 = 1".into();
+    }
+    format!("{} IN ({})", column, vec!["?"; len].join(", "))
+}
+
+/// 場所の絞り込み条件。企画の場所（`plan_locations`）と実施日時ごとの場所（`plan_schedules`のJSON）のいずれかが合う企画に絞り込む
+fn location_condition(filter: &PlanFilter, binds: &mut Vec<SqlValue>) -> Option<String> {
+    if !filter.has_location() {
+        return None;
+    }
+
+    let sources = [
+        ("plan_locations", "type", "building", "room", "name"),
+        (
+            "plan_schedules",
+            "json_extract(location, '$.type')",
+            "json_extract(location, '$.building')",
+            "json_extract(location, '$.room')",
+            "json_extract(location, '$.name')",
+        ),
+    ];
+    let mut selects = vec![];
+    for (table, kind, building, room, name) in sources {
+        if filter.buildings.is_some() || filter.rooms.is_some() {
+            let mut conditions = vec![format!("{} = 'indoor'", kind)];
+            for (column, values) in [(building, &filter.buildings), (room, &filter.rooms)] {
+                if let Some(values) = values {
+                    conditions.push(in_list(column, values.len()));
+                    binds.extend(values.iter().map(|v| text(v)));
+                }
+            }
+            selects.push(format!(
+                "SELECT plan_id FROM {} WHERE {}",
+                table,
+                conditions.join(" AND ")
+            ));
+        }
+        if let Some(names) = &filter.outdoor {
+            selects.push(format!(
+                "SELECT plan_id FROM {} WHERE {} = 'outdoor' AND {}",
+                table,
+                kind,
+                in_list(name, names.len())
+            ));
+            binds.extend(names.iter().map(|n| text(n)));
+        }
+    }
+    Some(format!("id IN ({})", selects.join(" UNION ")))
+}
+
+async fn read_plans(
+    db: &dyn SqlDatabase,
+    filter: &PlanFilter,
+) -> Result<Vec<PlanRead>, PlanReadError> {
+    let (condition, binds) = filter_condition(filter);
+    select_plans(db, &condition, &binds).await
+}
+
+async fn read_plan_with_etag(
+    db: &dyn SqlDatabase,
+    id: &str,
+) -> Result<(PlanRead, String), PlanReadError> {
+    let Some(plan) = select_plans(db, "id = ?", &[text(id)]).await?.pop() else {
+        return Err(PlanReadError::NotFound);
+    };
+    let etag = etag(&serde_json::to_value(&plan)?);
+    Ok((plan, etag))
+}
+
+/// 企画の子テーブルの行を削除するステートメント
+fn delete_children_statements(id: &SqlValue) -> Vec<Statement> {
+    ["plan_categories", "plan_schedules", "plan_locations"]
+        .into_iter()
+        .map(|table| {
+            Statement::new(
+                format!("DELETE FROM {} WHERE plan_id = ?", table),
+                vec![id.clone()],
+            )
+        })
+        .collect()
+}
+
+/// 企画と子テーブルの行を置き換えるステートメント
+///
+/// `INSERT OR REPLACE`は行を削除してから挿入するため、子テーブルから参照される`plans`はUPSERTで更新する
+fn write_plan_statements(plan: &PlanRead) -> Result<Vec<Statement>, Error> {
+    let value = serde_json::to_value(PlanRead {
+        schedule: plan.schedule.uncombine(),
+        ..plan.clone()
+    })?;
+    let id = text(&plan.id);
+    let str_field =
+        |value: &Value, key: &str| value.get(key).and_then(Value::as_str).map(String::from);
+
+    let mut statements = delete_children_statements(&id);
+    statements.push(Statement::new(
+            "INSERT INTO plans (id, type, organization_name, plan_name, description, is_child_friendly, is_recommended, is_lab_tour, latitude, longitude)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(id) DO UPDATE SET
+                type = excluded.type,
+                organization_name = excluded.organization_name,
+                plan_name = excluded.plan_name,
+                description = excluded.description,
+                is_child_friendly = excluded.is_child_friendly,
+                is_recommended = excluded.is_recommended,
+                is_lab_tour = excluded.is_lab_tour,
+                latitude = excluded.latitude,
+                longitude = excluded.longitude",
+        vec![
+            id.clone(),
+            text(plan.r#type.name()),
+            text(&plan.organization_name),
+            text(&plan.plan_name),
+            text(&plan.description),
+            integer(plan.is_child_friendly as i64),
+            integer(plan.is_recommended as i64),
+            value
+                .get("is_lab_tour")
+                .and_then(Value::as_bool)
+                .map_or(SqlValue::Null, |is_lab_tour| integer(is_lab_tour as i64)),
+            real(plan.coordinates.as_ref().map(|c| c.latitude)),
+            real(plan.coordinates.as_ref().map(|c| c.longitude)),
+        ],
+    ));
+
+    let categories = value
+        .get("categories")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    for (position, category) in categories.iter().enumerate() {
+        statements.push(Statement::new(
+            "INSERT INTO plan_categories (plan_id, position, category) VALUES (?, ?, ?)",
+            vec![
+                id.clone(),
+                integer(position as i64),
+                optional_text(category.as_str()),
+            ],
+        ));
+    }
+
+    for (day, key) in [(1, "day1"), (2, "day2")] {
+        let entries = value["schedule"]
+            .get(key)
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        for (position, entry) in entries.iter().enumerate() {
+            let location = entry
+                .get("location")
+                .filter(|location| !location.is_null())
+                .map(Value::to_string);
+            statements.push(Statement::new(
+                "INSERT INTO plan_schedules (plan_id, day, position, start_time, end_time, location) VALUES (?, ?, ?, ?, ?, ?)",
+                vec![
+                    id.clone(),
+                    integer(day),
+                    integer(position as i64),
+                    optional_text(str_field(entry, "start_time").as_deref()),
+                    optional_text(str_field(entry, "end_time").as_deref()),
+                    optional_text(location.as_deref()),
+                ],
+            ));
+        }
+    }
+
+    let locations = value
+        .get("location")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    for (position, location) in locations.iter().enumerate() {
+        statements.push(Statement::new(
+            "INSERT INTO plan_locations (plan_id, position, type, building, room, name) VALUES (?, ?, ?, ?, ?, ?)",
+            vec![
+                id.clone(),
+                integer(position as i64),
+                optional_text(str_field(location, "type").as_deref()),
+                optional_text(str_field(location, "building").as_deref()),
+                optional_text(str_field(location, "room").as_deref()),
+                optional_text(str_field(location, "name").as_deref()),
+            ],
+        ));
+    }
+
+    Ok(statements)
+}
+
+/// 企画を保存する。バッチは1つのトランザクションとして実行される
+async fn write_plan(db: &dyn SqlDatabase, plan: &PlanRead) -> Result<(), Error> {
+    db.batch(write_plan_statements(plan)?).await?;
+    Ok(())
+}
+
+async fn create_plan(
+    db: &dyn SqlDatabase,
+    id: &str,
+    plan: PlanCreate,
+) -> Result<PlanRead, PlanCreateError> {
+    // conflict check
+    let exists = query(
+        db,
+        Statement::new("SELECT 1 AS found FROM plans WHERE id = ?", vec![text(id)]),
+    )
+    .await?;
+    if !exists.is_empty() {
+        return Err(PlanCreateError::Conflict);
+    }
+
+    let plan = plan.into_read(id);
+    write_plan(db, &plan).await?;
+
+    Ok(plan)
+}
+
+/// 企画を更新し、更新前と更新後の値を返す（KVと同じくJSONをマージする）
+async fn update_plan(
+    db: &dyn SqlDatabase,
+    id: &str,
+    update: PlanUpdate,
+    expected_etag: Option<&str>,
+) -> Result<(Value, Value), PlanUpdateError> {
+    let plan = match read_plan_with_etag(db, id).await {
+        Ok((plan, current_etag)) => {
+            if !if_match(expected_etag, Some(&current_etag)) {
+                return Err(PlanUpdateError::PreconditionFailed(current_etag));
+            }
+            plan
+        }
+        Err(PlanReadError::NotFound) => return Err(PlanUpdateError::NotFound),
+        Err(PlanReadError::SerdeError(err)) => return Err(err.into()),
+        Err(PlanReadError::WorkerError(err)) => return Err(err.into()),
+        Err(PlanReadError::KvError(err)) => return Err(err.into()),
+        Err(PlanReadError::GetKeysError(err)) => {
+            return Err(Error::RustError(err.to_string()).into())
+        }
+    };
+
+    let before = serde_json::to_value(&plan)?;
+    let mut after = before.clone();
+    update.apply(&mut after)?;
+    let updated = serde_json::from_value::<PlanRead>(after)?;
+    write_plan(db, &updated).await?;
+
+    Ok((before, serde_json::to_value(&updated)?))
+}
+
+async fn delete_plan(db: &dyn SqlDatabase, id: &str) -> Result<(), Error> {
+    let id = text(id);
+    let mut statements = delete_children_statements(&id);
+    statements.push(Statement::new("DELETE FROM plans WHERE id = ?", vec![id]));
+    db.batch(statements).await?;
+    Ok(())
+}
+
+async fn read_details_with_etag(
+    db: &dyn SqlDatabase,
+    id: &str,
+) -> Result<(ReadPlanDetails, String), PlanDetailsReadError> {
+    let results = db
+        .batch(vec![
+            Statement::new(
+                "SELECT has_product, product_description, additional_info FROM plan_details WHERE plan_id = ?",
+                vec![text(id)],
+            ),
+            Statement::new(
+                "SELECT name, price, options FROM plan_products WHERE plan_id = ? ORDER BY position",
+                vec![text(id)],
+            ),
+        ])
+        .await?;
+    let Ok([details, products]) = <[Vec<Value>; 2]>::try_from(results) else {
+        return Err(Error::RustError("unexpected D1 batch result".into()).into());
+    };
+    let Some(details) = rows::<DetailsRow>(details)?.pop() else {
+        return Err(PlanDetailsReadError::NotFound);
+    };
+
+    let value = details_value(details, rows::<ProductRow>(products)?)?;
+    let etag = etag(&value);
+    Ok((serde_json::from_value(value)?, etag))
+}
+
+/// 全ての企画詳細を企画ID順に取得する
+async fn read_all_details(
+    db: &dyn SqlDatabase,
+) -> Result<Vec<(String, ReadPlanDetails)>, PlanDetailsReadError> {
+    let results = db
+        .batch(vec![
+            Statement::new(
+                "SELECT plan_id, has_product, product_description, additional_info FROM plan_details ORDER BY plan_id",
+                vec![],
+            ),
+            Statement::new(
+                "SELECT plan_id, name, price, options FROM plan_products ORDER BY plan_id, position",
+                vec![],
+            ),
+        ])
+        .await?;
+    let Ok([details, products]) = <[Vec<Value>; 2]>::try_from(results) else {
+        return Err(Error::RustError("unexpected D1 batch result".into()).into());
+    };
+
+    let mut products = group_by_plan(rows::<ProductRow>(products)?, |row| &row.plan_id);
+    rows::<DetailsRow>(details)?
+        .into_iter()
+        .map(|row| {
+            let id = row.plan_id.clone();
+            let products = products.remove(&id).unwrap_or_default();
+            let value = details_value(row, products)?;
+            Ok((id, serde_json::from_value(value)?))
+        })
+        .collect()
+}
+
+fn details_value(
+    details: DetailsRow,
+    products: Vec<ProductRow>,
+) -> Result<Value, serde_json::Error> {
+    let mut value = Map::new();
+    if details.has_product != 0 {
+        let items = products
+            .into_iter()
+            .map(|product| {
+                Ok(json!({
+                    "name": product.name,
+                    "price": product.price,
+                    "options": serde_json::from_str::<Value>(&product.options)?,
+                }))
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        value.insert(
+            "product".into(),
+            json!({
+                "items": items,
+                "description": details.product_description.unwrap_or_default(),
+            }),
+        );
+    }
+    if let Some(additional_info) = details.additional_info {
+        value.insert("additional_info".into(), Value::String(additional_info));
+    }
+    Ok(Value::Object(value))
+}
+
+async fn write_details(
+    db: &dyn SqlDatabase,
+    id: &str,
+    details: &ReadPlanDetails,
+) -> Result<(), Error> {
+    let plan_id = text(id);
+    let mut statements = vec![
+        Statement::new(
+            "DELETE FROM plan_products WHERE plan_id = ?",
+            vec![plan_id.clone()],
+        ),
+        Statement::new(
+            "INSERT INTO plan_details (plan_id, has_product, product_description, additional_info)
+             VALUES (?, ?, ?, ?)
+             ON CONFLICT(plan_id) DO UPDATE SET
+                has_product = excluded.has_product,
+                product_description = excluded.product_description,
+                additional_info = excluded.additional_info",
+            vec![
+                plan_id.clone(),
+                integer(details.product.is_some() as i64),
+                optional_text(details.product.as_ref().map(|p| p.description.as_str())),
+                optional_text(details.additional_info.as_deref()),
+            ],
+        ),
+    ];
+    for (position, item) in details
+        .product
+        .iter()
+        .flat_map(|product| product.items.iter())
+        .enumerate()
+    {
+        statements.push(Statement::new(
+            "INSERT INTO plan_products (plan_id, position, name, price, options) VALUES (?, ?, ?, ?, ?)",
+            vec![
+                plan_id.clone(),
+                integer(position as i64),
+                text(&item.name),
+                real(item.price),
+                text(&serde_json::to_string(&item.options)?),
+            ],
+        ));
+    }
+
+    db.batch(statements).await?;
+    Ok(())
+}
+
+async fn delete_details(db: &dyn SqlDatabase, id: &str) -> Result<(), Error> {
+    let id = text(id);
+    db.batch(vec![
+        Statement::new(
+            "DELETE FROM plan_products WHERE plan_id = ?",
+            vec![id.clone()],
+        ),
+        Statement::new("DELETE FROM plan_details WHERE plan_id = ?", vec![id]),
+    ])
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct IdRow {
+    id: String,
+}
+
+async fn list_ids(db: &dyn SqlDatabase, sql: &str) -> Result<Vec<String>, Error> {
+    Ok(
+        rows::<IdRow>(query(db, Statement::new(sql, vec![])).await?)?
+            .into_iter()
+            .map(|row| row.id)
+            .collect(),
+    )
+}
+
+async fn list_plan_ids(db: &dyn SqlDatabase) -> Result<Vec<String>, Error> {
+    list_ids(db, "SELECT id FROM plans ORDER BY id").await
+}
+
+async fn list_details_ids(db: &dyn SqlDatabase) -> Result<Vec<String>, Error> {
+    list_ids(
+        db,
+        "SELECT plan_id AS id FROM plan_details ORDER BY plan_id",
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct CountRow {
+    count: usize,
+}
+
+/// D1の値は常に現在のバージョンの形で組み立てるため、全ての行を現在のバージョンとして数える
+async fn count_versions(
+    db: &dyn SqlDatabase,
+    table: &str,
+    document: Document,
+) -> Result<VersionCounts, Error> {
+    let count = rows::<CountRow>(
+        query(
+            db,
+            Statement::new(format!("SELECT COUNT(*) AS count FROM {}", table), vec![]),
+        )
+        .await?,
+    )?
+    .pop()
+    .map_or(0, |row| row.count);
+    Ok(VersionCounts::from([(document.version(), count)]))
+}
+
+/// `plans`とその子テーブルに保存する
+pub struct D1Plans(pub Rc<dyn SqlDatabase>);
+
+#[async_trait(?Send)]
+impl PlanRepository for D1Plans {
+    async fn read_with_etag(&self, id: &str) -> Result<(PlanRead, String), PlanReadError> {
+        read_plan_with_etag(&*self.0, id).await
+    }
+
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanReadError> {
+        match read_plan_with_etag(&*self.0, id).await {
+            Ok((plan, _)) => Ok(Some(serde_json::to_value(plan)?)),
+            Err(PlanReadError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read_all(&self, filter: &PlanFilter) -> Result<Vec<PlanRead>, PlanReadError> {
+        read_plans(&*self.0, filter).await
+    }
+
+    async fn create(&self, id: &str, plan: PlanCreate) -> Result<PlanRead, PlanCreateError> {
+        create_plan(&*self.0, id, plan).await
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        update: PlanUpdate,
+        expected_etag: Option<&str>,
+    ) -> Result<(Value, Value), PlanUpdateError> {
+        update_plan(&*self.0, id, update, expected_etag).await
+    }
+
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanUpdateError> {
+        let plan = PlanRead {
+            id: id.to_string(),
+            ..serde_json::from_value(value.clone())?
+        };
+        Ok(write_plan(&*self.0, &plan).await?)
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        delete_plan(&*self.0, id).await
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        list_plan_ids(&*self.0).await
+    }
+
+    /// D1では一覧をSQLで取得するため、インデックスは使わない
+    async fn update_index(
+        &self,
+        _added: &[String],
+        _removed: &[String],
+    ) -> Result<(), PutKeysError> {
+        Ok(())
+    }
+
+    async fn rebuild_index(&self) -> Result<Vec<String>, PutKeysError> {
+        Ok(list_plan_ids(&*self.0).await?)
+    }
+
+    async fn check_index(&self) -> Result<KeysCheck, GetKeysError> {
+        let ids = list_plan_ids(&*self.0).await.map_err(PutKeysError::from)?;
+        Ok(KeysCheck {
+            consistent: true,
+            indexed: ids.len(),
+            stored: ids.len(),
+            missing_from_index: vec![],
+            missing_from_kv: vec![],
+        })
+    }
+
+    async fn schema_versions(&self) -> Result<VersionCounts, Error> {
+        count_versions(&*self.0, "plans", Document::Plan).await
+    }
+}
+
+/// `plan_details`と`plan_products`に保存する
+pub struct D1Details(pub Rc<dyn SqlDatabase>);
+
+impl D1Details {
+    /// 全ての企画詳細を企画ID順に取得する（移行の検証用）
+    pub async fn read_all(&self) -> Result<Vec<(String, ReadPlanDetails)>, PlanDetailsReadError> {
+        read_all_details(&*self.0).await
+    }
+}
+
+#[async_trait(?Send)]
+impl DetailsRepository for D1Details {
+    async fn read_with_etag(
+        &self,
+        id: &str,
+    ) -> Result<(ReadPlanDetails, String), PlanDetailsReadError> {
+        read_details_with_etag(&*self.0, id).await
+    }
+
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanDetailsReadError> {
+        match read_details_with_etag(&*self.0, id).await {
+            Ok((details, _)) => Ok(Some(serde_json::to_value(details)?)),
+            Err(PlanDetailsReadError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn put(
+        &self,
+        id: &str,
+        details: CreatePlanDetails,
+    ) -> Result<ReadPlanDetails, PlanDetailsCreateError> {
+        let details = ReadPlanDetails::from(details);
+        write_details(&*self.0, id, &details).await?;
+        Ok(details)
+    }
+
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanDetailsCreateError> {
+        let details = serde_json::from_value::<ReadPlanDetails>(value.clone())?;
+        Ok(write_details(&*self.0, id, &details).await?)
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        delete_details(&*self.0, id).await
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        list_details_ids(&*self.0).await
+    }
+
+    async fn schema_versions(&self) -> Result<VersionCounts, Error> {
+        count_versions(&*self.0, "plan_details", Document::Details).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::details::CreatePlanDetails;
+    use crate::models::schedule::ScheduleWindow;
+    use crate::storage::memory::Sqlite;
+    use futures::executor::block_on;
+
+    fn plans() -> D1Plans {
+        let plans = D1Plans(Rc::new(Sqlite::new()));
+        let samples = [
+            (
+                "booth-1",
+                json!({
+                    "type": "booth",
+                    "categories": ["main_rice", "sweet_cold"],
+                    "organization_name": "模擬店の団体",
+                    "plan_name": "おにぎり",
+                    "description": "",
+                    "is_child_friendly": false,
+                    "is_recommended": true,
+                    "schedule": {
+                        "day1": [{"start_time": "10:00", "end_time": "12:00"}],
+                        "day2": []
+                    },
+                    "location": [{"type": "indoor", "building": "A", "room": "101"}],
+                    "coordinates": {"latitude": 35.6, "longitude": 139.6}
+                }),
+            ),
+            (
+                "general-1",
+                json!({
+                    "type": "general",
+                    "categories": ["play"],
+                    "organization_name": "一般企画の団体",
+                    "plan_name": "ゲーム",
+                    "description": "",
+                    "is_child_friendly": true,
+                    "is_recommended": false,
+                    "schedule": {
+                        "day1": [{"start_time": "13:00", "end_time": "15:00"}],
+                        "day2": [{"start_time": "10:00", "end_time": "11:00"}]
+                    },
+                    "location": [{"type": "indoor", "building": "B", "room": "201"}]
+                }),
+            ),
+            (
+                "stage-1",
+                json!({
+                    "type": "stage",
+                    "organization_name": "ステージの団体",
+                    "plan_name": "ライブ",
+                    "description": "",
+                    "is_child_friendly": true,
+                    "is_recommended": true,
+                    "schedule": {
+                        "day1": [{
+                            "start_time": "10:00",
+                            "end_time": "10:30",
+                            "location": {"type": "outdoor", "name": "サブステージ"}
+                        }],
+                        "day2": [{"start_time": "14:00", "end_time": "16:00"}]
+                    },
+                    "location": [{"type": "outdoor", "name": "メインステージ"}],
+                    "coordinates": {"latitude": 35.7, "longitude": 139.7}
+                }),
+            ),
+            (
+                "labo-1",
+                json!({
+                    "type": "labo",
+                    "is_lab_tour": true,
+                    "organization_name": "研究室",
+                    "plan_name": "研究室見学",
+                    "description": "",
+                    "is_child_friendly": false,
+                    "is_recommended": false,
+                    "schedule": {
+                        "day1": [],
+                        "day2": [{"start_time": "09:00", "end_time": "17:00"}]
+                    },
+                    "location": [{"type": "indoor", "building": "A", "room": "301"}]
+                }),
+            ),
+            (
+                "labo-2",
+                json!({
+                    "type": "labo",
+                    "is_lab_tour": false,
+                    "organization_name": "研究室",
+                    "plan_name": "研究紹介",
+                    "description": "",
+                    "is_child_friendly": true,
+                    "is_recommended": false,
+                    "schedule": {
+                        "day1": [{
+                            "start_time": "11:00",
+                            "end_time": "12:00",
+                            "location": {"type": "indoor", "building": "A", "room": "101"}
+                        }],
+                        "day2": []
+                    },
+                    "location": [{"type": "indoor", "building": "C", "room": "101"}]
+                }),
+            ),
+        ];
+        for (id, plan) in samples {
+            block_on(plans.create(id, serde_json::from_value(plan).unwrap())).unwrap();
+        }
+        plans
+    }
+
+    fn filter(params: &[(&str, &str)]) -> PlanFilter {
+        let mut filter = PlanFilter::default();
+        for (key, value) in params {
+            assert!(filter.parse_param(key, value).unwrap());
+        }
+        filter
+    }
+
+    fn ids(plans: &[PlanRead]) -> Vec<&str> {
+        plans.iter().map(|plan| plan.id.as_str()).collect()
+    }
+
+    #[test]
+    fn read_all_returns_plans_as_created() {
+        let plans = plans();
+        let all = block_on(plans.read_all(&PlanFilter::default())).unwrap();
+        assert_eq!(
+            ids(&all),
+            ["booth-1", "general-1", "labo-1", "labo-2", "stage-1"]
+        );
+
+        let stage = block_on(plans.read("stage-1")).unwrap();
+        let value = serde_json::to_value(&stage).unwrap();
+        assert_eq!(value["location"][0]["name"], "メインステージ");
+        assert_eq!(
+            value["schedule"]["day1"][0]["location"]["name"],
+            "サブステージ"
+        );
+        assert_eq!(value["coordinates"]["latitude"], 35.7);
+
+        let booth = serde_json::to_value(block_on(plans.read("booth-1")).unwrap()).unwrap();
+        assert_eq!(booth["categories"], json!(["main_rice", "sweet_cold"]));
+    }
+
+    #[test]
+    fn filter_pushdown_matches_plan_filter() {
+        let plans = plans();
+        let all = block_on(plans.read_all(&PlanFilter::default())).unwrap();
+
+        let mut filters = [
+            vec![("type", "booth,stage")],
+            vec![("recommended", "true")],
+            vec![("recommended", "false"), ("child_friendly", "true")],
+            vec![("lab_tour", "true")],
+            vec![("lab_tour", "false"), ("type", "labo")],
+            vec![("category", "sweet_cold,play")],
+            vec![("category", "performance")],
+            vec![("building", "A")],
+            vec![("building", "A"), ("room", "101")],
+            vec![("room", "101")],
+            vec![("outdoor", "サブステージ")],
+            vec![("outdoor", "メインステージ"), ("building", "B")],
+            vec![("bbox", "139.5,35.5,139.65,35.65")],
+            vec![("bbox", "139,35,140,36"), ("type", "stage")],
+            // 値を検証しない条件
+            vec![("type", "labo,unknown")],
+            vec![("type", "")],
+            vec![("recommended", "yes"), ("lab_tour", "1")],
+        ]
+        .map(|params| filter(&params))
+        .to_vec();
+        for (day, at, from, to) in [
+            ("1", Some("10:15"), None, None),
+            ("1", Some("12:00"), None, None),
+            ("1", None, Some("11:30"), None),
+            ("2", None, None, Some("10:00")),
+            ("2", None, Some("15:00"), Some("16:00")),
+        ] {
+            filters.push(PlanFilter {
+                schedule: ScheduleWindow::parse(Some(day), at, from, to).unwrap(),
+                ..Default::default()
+            });
+        }
+
+        for filter in filters {
+            let expected = all
+                .iter()
+                .filter(|plan| filter.matches(plan))
+                .map(|plan| plan.id.as_str())
+                .collect::<Vec<_>>();
+            let actual = block_on(plans.read_all(&filter)).unwrap();
+            assert_eq!(ids(&actual), expected, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn update_and_delete_plan() {
+        let plans = plans();
+        let (_, etag) = block_on(plans.read_with_etag("general-1")).unwrap();
+
+        let update = serde_json::from_value(json!({
+            "type": "general",
+            "categories": ["cafe"],
+            "location": [{"type": "outdoor", "name": "中庭"}],
+            "coordinates": {"latitude": 35.65, "longitude": 139.65}
+        }))
+        .unwrap();
+        let (before, after) = block_on(plans.update("general-1", update, Some(&etag))).unwrap();
+        assert_eq!(before["categories"], json!(["play"]));
+        assert_eq!(after["categories"], json!(["cafe"]));
+
+        let stored = serde_json::to_value(block_on(plans.read("general-1")).unwrap()).unwrap();
+        assert_eq!(stored, after);
+        assert_eq!(
+            ids(&block_on(plans.read_all(&filter(&[("category", "play")]))).unwrap()),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            ids(&block_on(plans.read_all(&filter(&[("outdoor", "中庭")]))).unwrap()),
+            ["general-1"]
+        );
+
+        // 古いETagでは更新しない
+        let update = serde_json::from_value(json!({"plan_name": "更新"})).unwrap();
+        assert!(matches!(
+            block_on(plans.update("general-1", update, Some(&etag))),
+            Err(PlanUpdateError::PreconditionFailed(_))
+        ));
+
+        let plan = serde_json::from_value(json!({
+            "type": "stage",
+            "organization_name": "",
+            "plan_name": "",
+            "description": "",
+            "is_child_friendly": false,
+            "is_recommended": false,
+            "schedule": {"day1": [], "day2": []},
+            "location": []
+        }))
+        .unwrap();
+        assert!(matches!(
+            block_on(plans.create("stage-1", plan)),
+            Err(PlanCreateError::Conflict)
+        ));
+
+        block_on(plans.delete("general-1")).unwrap();
+        assert!(matches!(
+            block_on(plans.read("general-1")),
+            Err(PlanReadError::NotFound)
+        ));
+        assert_eq!(
+            block_on(plans.ids()).unwrap(),
+            ["booth-1", "labo-1", "labo-2", "stage-1"]
+        );
+    }
+
+    #[test]
+    fn put_and_delete_details() {
+        let details = D1Details(Rc::new(Sqlite::new()));
+        let create = |value: Value| serde_json::from_value::<CreatePlanDetails>(value).unwrap();
+
+        block_on(details.put(
+            "booth-1",
+            create(json!({
+                "product": {
+                    "description": "販売物",
+                    "items": [
+                        {"name": "おにぎり", "price": 150, "options": [{"name": "大盛り", "price": 50}]},
+                        {"name": "お茶", "price": null, "options": []}
+                    ]
+                }
+            })),
+        ))
+        .unwrap();
+        block_on(details.put("labo-1", create(json!({"additional_info": "予約制"})))).unwrap();
+
+        let (read, _) = block_on(details.read_with_etag("booth-1")).unwrap();
+        let product = read.product.unwrap();
+        assert_eq!(product.description, "販売物");
+        assert_eq!(product.items[0].price, Some(150.0));
+        assert_eq!(product.items[0].options[0].name, "大盛り");
+        assert_eq!(product.items[1].price, None);
+
+        // 置き換えると前の販売物は残らない
+        block_on(details.put("booth-1", create(json!({"additional_info": "完売"})))).unwrap();
+        let (read, _) = block_on(details.read_with_etag("booth-1")).unwrap();
+        assert!(read.product.is_none());
+        assert_eq!(read.additional_info.as_deref(), Some("完売"));
+
+        let all = block_on(details.read_all()).unwrap();
+        assert_eq!(
+            all.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+            ["booth-1", "labo-1"]
+        );
+
+        block_on(details.delete("booth-1")).unwrap();
+        assert!(matches!(
+            block_on(details.read_with_etag("booth-1")),
+            Err(PlanDetailsReadError::NotFound)
+        ));
+        assert_eq!(block_on(details.ids()).unwrap(), ["labo-1"]);
+    }
+}
@@ -0,0 +1,312 @@
+//! Workers KVによる企画・企画詳細と、所有者・ゴミ箱・変更履歴などの保存
+
+use super::{
+    AliasRepository, ApiKeyRepository, AuditLog, CacheGenerations, DetailsRepository,
+    MigrationStateStore, OwnersRepository, PlanRepository, RevisionRepository, TrashRepository,
+};
+use crate::models::alias::PlanAlias;
+use crate::models::api_key::{ApiKeyError, StoredApiKey};
+use crate::models::audit::{AuditError, AuditPage, AuditQuery, AuditRecord};
+use crate::models::cache::CacheScope;
+use crate::models::details::{
+    CreatePlanDetails, PlanDetailsCreateError, PlanDetailsReadError, ReadPlanDetails,
+};
+use crate::models::keys::{
+    check_keys, list_plan_keys, put_keys, update_keys, GetKeysError, KeysCheck, PutKeysError,
+};
+use crate::models::migration::{MigrationState, MigrationStateError};
+use crate::models::owners::{PlanOwners, PlanOwnersError};
+use crate::models::plan::{
+    PlanCreate, PlanCreateError, PlanFilter, PlanRead, PlanReadError, PlanUpdate, PlanUpdateError,
+};
+use crate::models::revision::{RevisionError, RevisionHistory};
+use crate::models::schema::{count_kv_versions, read_kv, write_kv, Document, VersionCounts};
+use crate::models::trash::{TrashEntry, TrashError};
+use async_trait::async_trait;
+use chrono::Duration;
+use serde_json::Value;
+use worker::kv::{KvError, KvStore};
+use worker::Error;
+
+/// `PLANS` namespaceに企画IDをキーとして保存する
+pub struct KvPlans(pub KvStore);
+
+#[async_trait(?Send)]
+impl PlanRepository for KvPlans {
+    async fn read_with_etag(&self, id: &str) -> Result<(PlanRead, String), PlanReadError> {
+        PlanRead::read_with_etag(self.0.clone(), id).await
+    }
+
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanReadError> {
+        Ok(read_kv(&self.0, Document::Plan, id).await?)
+    }
+
+    async fn read_all(&self, filter: &PlanFilter) -> Result<Vec<PlanRead>, PlanReadError> {
+        let mut plans = PlanRead::read_all(&self.0).await?;
+        plans.retain(|plan| filter.matches(plan));
+        Ok(plans)
+    }
+
+    async fn create(&self, id: &str, plan: PlanCreate) -> Result<PlanRead, PlanCreateError> {
+        plan.create(self.0.clone(), id).await
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        update: PlanUpdate,
+        expected_etag: Option<&str>,
+    ) -> Result<(Value, Value), PlanUpdateError> {
+        update.update(self.0.clone(), id, expected_etag).await
+    }
+
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanUpdateError> {
+        Ok(write_kv(&self.0, Document::Plan, id, value).await?)
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        Ok(self.0.delete(id).await?)
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        Ok(list_plan_keys(&self.0).await?)
+    }
+
+    async fn update_index(&self, added: &[String], removed: &[String]) -> Result<(), PutKeysError> {
+        update_keys(&self.0, added, removed).await
+    }
+
+    async fn rebuild_index(&self) -> Result<Vec<String>, PutKeysError> {
+        put_keys(&self.0).await
+    }
+
+    async fn check_index(&self) -> Result<KeysCheck, GetKeysError> {
+        check_keys(&self.0).await
+    }
+
+    async fn schema_versions(&self) -> Result<VersionCounts, Error> {
+        count_kv_versions(&self.0).await
+    }
+}
+
+/// `PLAN_DETAILS` namespaceに企画IDをキーとして保存する
+pub struct KvDetails(pub KvStore);
+
+#[async_trait(?Send)]
+impl DetailsRepository for KvDetails {
+    async fn read_with_etag(
+        &self,
+        id: &str,
+    ) -> Result<(ReadPlanDetails, String), PlanDetailsReadError> {
+        ReadPlanDetails::read_with_etag(self.0.clone(), id).await
+    }
+
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanDetailsReadError> {
+        Ok(read_kv(&self.0, Document::Details, id).await?)
+    }
+
+    async fn put(
+        &self,
+        id: &str,
+        details: CreatePlanDetails,
+    ) -> Result<ReadPlanDetails, PlanDetailsCreateError> {
+        details.create(self.0.clone(), id).await
+    }
+
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanDetailsCreateError> {
+        Ok(write_kv(&self.0, Document::Details, id, value).await?)
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        Ok(self.0.delete(id).await?)
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        Ok(list_plan_keys(&self.0).await?)
+    }
+
+    async fn schema_versions(&self) -> Result<VersionCounts, Error> {
+        count_kv_versions(&self.0).await
+    }
+}
+
+/// `PLANS` namespaceに`owners:<plan_id>`のキーで保存する
+pub struct KvOwners(pub KvStore);
+
+#[async_trait(?Send)]
+impl OwnersRepository for KvOwners {
+    async fn read(&self, plan_id: &str) -> Result<PlanOwners, PlanOwnersError> {
+        PlanOwners::read(&self.0, plan_id).await
+    }
+
+    async fn write(&self, plan_id: &str, owners: &PlanOwners) -> Result<(), PlanOwnersError> {
+        owners.write(&self.0, plan_id).await
+    }
+
+    async fn delete(&self, plan_id: &str) -> Result<(), PlanOwnersError> {
+        PlanOwners::delete(&self.0, plan_id).await
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, PlanOwnersError> {
+        PlanOwners::ids(&self.0).await
+    }
+}
+
+/// `PLANS` namespaceに`aliases:<変更前の企画ID>`のキーで保存する
+pub struct KvAliases(pub KvStore);
+
+#[async_trait(?Send)]
+impl AliasRepository for KvAliases {
+    async fn read(&self, old_id: &str) -> Result<Option<PlanAlias>, KvError> {
+        PlanAlias::read(&self.0, old_id).await
+    }
+
+    async fn write(&self, old_id: &str, alias: &PlanAlias, ttl: Duration) -> Result<(), KvError> {
+        alias.write(&self.0, old_id, ttl).await
+    }
+
+    async fn delete(&self, old_id: &str) -> Result<(), KvError> {
+        PlanAlias::delete(&self.0, old_id).await
+    }
+}
+
+/// `PLANS` namespaceに`cache:<スコープ>`のキーで保存する
+pub struct KvCacheGenerations(pub KvStore);
+
+#[async_trait(?Send)]
+impl CacheGenerations for KvCacheGenerations {
+    async fn generation(&self, scope: CacheScope<'_>) -> Result<String, KvError> {
+        scope.generation(&self.0).await
+    }
+
+    async fn bump(&self, scope: CacheScope<'_>) -> Result<(), KvError> {
+        scope.bump(&self.0).await
+    }
+}
+
+/// `PLANS` namespaceに`trash:<plan_id>`のキーで保存する
+pub struct KvTrash(pub KvStore);
+
+#[async_trait(?Send)]
+impl TrashRepository for KvTrash {
+    async fn read(&self, plan_id: &str) -> Result<Option<TrashEntry>, TrashError> {
+        TrashEntry::read(&self.0, plan_id).await
+    }
+
+    async fn write(&self, entry: &TrashEntry) -> Result<(), TrashError> {
+        entry.write(&self.0).await
+    }
+
+    async fn delete(&self, plan_id: &str) -> Result<(), TrashError> {
+        TrashEntry::delete(&self.0, plan_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<TrashEntry>, TrashError> {
+        TrashEntry::list(&self.0).await
+    }
+}
+
+/// `PLAN_HISTORY` namespaceが設定されていない場合に返すエラー
+fn history(kv: &Option<KvStore>) -> Result<&KvStore, KvError> {
+    kv.as_ref().ok_or_else(|| {
+        KvError::InvalidKvStore(format!("{} namespace is not bound", crate::KV_PLAN_HISTORY))
+    })
+}
+
+/// `PLAN_HISTORY` namespaceに`revisions:<plan|details>:<plan_id>`のキーで保存する
+///
+/// namespaceが設定されていない場合は、読み書きのたびにエラーを返す
+pub struct KvRevisions(pub Option<KvStore>);
+
+#[async_trait(?Send)]
+impl RevisionRepository for KvRevisions {
+    async fn read(
+        &self,
+        document: Document,
+        plan_id: &str,
+    ) -> Result<RevisionHistory, RevisionError> {
+        RevisionHistory::read(history(&self.0)?, document, plan_id).await
+    }
+
+    async fn write(
+        &self,
+        document: Document,
+        plan_id: &str,
+        revisions: &RevisionHistory,
+    ) -> Result<(), RevisionError> {
+        revisions.write(history(&self.0)?, document, plan_id).await
+    }
+
+    async fn delete(&self, document: Document, plan_id: &str) -> Result<(), RevisionError> {
+        RevisionHistory::delete(history(&self.0)?, document, plan_id).await
+    }
+}
+
+/// `PLAN_HISTORY` namespaceに新しい順に並ぶキーで保存する
+///
+/// namespaceが設定されていない場合は、読み書きのたびにエラーを返す
+pub struct KvAuditLog(pub Option<KvStore>);
+
+#[async_trait(?Send)]
+impl AuditLog for KvAuditLog {
+    async fn append(&self, record: &AuditRecord) -> Result<(), AuditError> {
+        record.append(history(&self.0)?).await
+    }
+
+    async fn list(&self, query: &AuditQuery) -> Result<AuditPage, AuditError> {
+        AuditRecord::list(history(&self.0)?, query).await
+    }
+}
+
+/// `PLANS` namespaceに`apikeys:<id>`のキーで保存する
+pub struct KvApiKeys(pub KvStore);
+
+#[async_trait(?Send)]
+impl ApiKeyRepository for KvApiKeys {
+    async fn get(&self, id: &str) -> Result<Option<StoredApiKey>, ApiKeyError> {
+        StoredApiKey::read(&self.0, id).await
+    }
+
+    async fn put(&self, stored: &StoredApiKey) -> Result<(), ApiKeyError> {
+        stored.write(&self.0).await
+    }
+
+    async fn list(&self) -> Result<Vec<StoredApiKey>, ApiKeyError> {
+        StoredApiKey::list(&self.0).await
+    }
+}
+
+/// `PLANS` namespaceに`migrations:d1`のキーで保存する
+pub struct KvMigrationState(pub KvStore);
+
+#[async_trait(?Send)]
+impl MigrationStateStore for KvMigrationState {
+    async fn read(&self) -> Result<Option<MigrationState>, MigrationStateError> {
+        MigrationState::read(&self.0).await
+    }
+
+    async fn write(&self, state: &MigrationState) -> Result<(), MigrationStateError> {
+        state.write(&self.0).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn missing_history_namespace_fails_only_history_access() {
+        block_on(async {
+            let revisions = KvRevisions(None);
+            assert!(matches!(
+                revisions.read(Document::Plan, "plan-1").await,
+                Err(RevisionError::KvError(KvError::InvalidKvStore(_)))
+            ));
+            assert!(matches!(
+                KvAuditLog(None).list(&AuditQuery::default()).await,
+                Err(AuditError::KvError(KvError::InvalidKvStore(_)))
+            ));
+        });
+    }
+}
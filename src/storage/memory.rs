@@ -0,0 +1,652 @@
+//! メモリ上への保存（テスト用）
+//!
+//! 企画・企画詳細はKVと同じ形のJSONで保持する
+
+use super::d1::{SqlDatabase, SqlValue, Statement};
+use super::{
+    AliasRepository, ApiKeyRepository, AuditLog, CacheGenerations, DetailsRepository, Icon,
+    IconStore, MigrationStateStore, OwnersRepository, PlanRepository, RevisionRepository, Storage,
+    TrashRepository,
+};
+use crate::models::alias::PlanAlias;
+use crate::models::api_key::{ApiKeyError, StoredApiKey};
+use crate::models::audit::{AuditError, AuditPage, AuditQuery, AuditRecord};
+use crate::models::cache::CacheScope;
+use crate::models::details::{
+    CreatePlanDetails, PlanDetailsCreateError, PlanDetailsReadError, ReadPlanDetails,
+};
+use crate::models::keys::{GetKeysError, KeysCheck, PutKeysError};
+use crate::models::migration::{MigrationState, MigrationStateError};
+use crate::models::owners::{PlanOwners, PlanOwnersError};
+use crate::models::plan::{
+    PlanCreate, PlanCreateError, PlanFilter, PlanRead, PlanReadError, PlanUpdate, PlanUpdateError,
+};
+use crate::models::revision::{RevisionError, RevisionHistory};
+use crate::models::schema::{unstamp, Document, VersionCounts};
+use crate::models::trash::{TrashEntry, TrashError};
+use crate::util::{etag, if_match, now, sha256_hex};
+use async_trait::async_trait;
+use chrono::Duration;
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::{params_from_iter, Connection, ToSql};
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use worker::kv::KvError;
+use worker::Error;
+
+/// 全てメモリ上に保存する保存先一式
+pub fn storage() -> Storage {
+    Storage {
+        plans: Rc::new(MemoryPlans::default()),
+        details: Rc::new(MemoryDetails::default()),
+        icons: Rc::new(MemoryIcons::default()),
+        owners: Rc::new(MemoryOwners::default()),
+        aliases: Rc::new(MemoryAliases::default()),
+        cache: Rc::new(MemoryCacheGenerations::default()),
+        trash: Rc::new(MemoryTrash::default()),
+        revisions: Rc::new(MemoryRevisions::default()),
+        audit: Rc::new(MemoryAuditLog::default()),
+        api_keys: Rc::new(MemoryApiKeys::default()),
+        migration: Rc::new(MemoryMigrationState::default()),
+    }
+}
+
+/// テスト用の一般企画の値
+pub fn plan_value() -> Value {
+    serde_json::json!({
+        "type": "general",
+        "categories": ["play"],
+        "organization_name": "テスト団体",
+        "plan_name": "テスト企画",
+        "description": "",
+        "is_child_friendly": false,
+        "is_recommended": false,
+        "schedule": {"day1": [], "day2": []},
+        "location": [{"type": "indoor", "building": "A", "room": "101"}]
+    })
+}
+
+/// 保存せずに企画を作る。`fields`で一般企画の既定の値を書き換える
+pub fn plan(plan_id: &str, fields: Value) -> PlanRead {
+    let mut value = plan_value();
+    value["id"] = Value::String(plan_id.to_string());
+    if let (Value::Object(value), Value::Object(fields)) = (&mut value, fields) {
+        value.extend(fields);
+    }
+    serde_json::from_value(value).unwrap()
+}
+
+/// 企画詳細・所有者・アイコンを持つ企画を作成する
+pub async fn seed(storage: &Storage, plan_id: &str) -> PlanRead {
+    let plan = serde_json::from_value::<PlanCreate>(plan_value()).unwrap();
+    let plan = storage.plans.create(plan_id, plan).await.unwrap();
+    let details = serde_json::from_value::<CreatePlanDetails>(serde_json::json!({
+        "additional_info": "予約制"
+    }))
+    .unwrap();
+    storage.details.put(plan_id, details).await.unwrap();
+    let owners = PlanOwners {
+        subjects: vec!["owner".into()],
+        groups: vec![],
+    };
+    storage.owners.write(plan_id, &owners).await.unwrap();
+    storage
+        .icons
+        .put(plan_id, vec![1, 2, 3], "image/png")
+        .await
+        .unwrap();
+    plan
+}
+
+/// 変更履歴から復元する値を現在のバージョンの形にする（メモリ上ではバージョンを保持しない）
+fn current(document: Document, value: &Value) -> Value {
+    let mut value = value.clone();
+    document.upgrade(&mut value);
+    unstamp(&mut value);
+    value
+}
+
+#[derive(Default)]
+pub struct MemoryPlans {
+    plans: RefCell<BTreeMap<String, Value>>,
+}
+
+#[async_trait(?Send)]
+impl PlanRepository for MemoryPlans {
+    async fn read_with_etag(&self, id: &str) -> Result<(PlanRead, String), PlanReadError> {
+        let Some(value) = self.plans.borrow().get(id).cloned() else {
+            return Err(PlanReadError::NotFound);
+        };
+        let etag = etag(&value);
+        Ok((serde_json::from_value(value)?, etag))
+    }
+
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanReadError> {
+        Ok(self.plans.borrow().get(id).cloned())
+    }
+
+    async fn read_all(&self, filter: &PlanFilter) -> Result<Vec<PlanRead>, PlanReadError> {
+        let mut plans = vec![];
+        for value in self.plans.borrow().values() {
+            let plan = serde_json::from_value::<PlanRead>(value.clone())?;
+            if filter.matches(&plan) {
+                plans.push(plan);
+            }
+        }
+        Ok(plans)
+    }
+
+    async fn create(&self, id: &str, plan: PlanCreate) -> Result<PlanRead, PlanCreateError> {
+        if self.plans.borrow().contains_key(id) {
+            return Err(PlanCreateError::Conflict);
+        }
+        let plan = plan.into_read(id);
+        let value = serde_json::to_value(&plan).map_err(Error::from)?;
+        self.plans.borrow_mut().insert(id.to_string(), value);
+        Ok(plan)
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        update: PlanUpdate,
+        expected_etag: Option<&str>,
+    ) -> Result<(Value, Value), PlanUpdateError> {
+        let Some(mut plan) = self.plans.borrow().get(id).cloned() else {
+            return Err(PlanUpdateError::NotFound);
+        };
+        let current_etag = etag(&plan);
+        if !if_match(expected_etag, Some(&current_etag)) {
+            return Err(PlanUpdateError::PreconditionFailed(current_etag));
+        }
+        let before = plan.clone();
+        update.apply(&mut plan)?;
+
+        self.plans.borrow_mut().insert(id.to_string(), plan.clone());
+        Ok((before, plan))
+    }
+
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanUpdateError> {
+        self.plans
+            .borrow_mut()
+            .insert(id.to_string(), current(Document::Plan, value));
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        self.plans.borrow_mut().remove(id);
+        Ok(())
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        Ok(self.plans.borrow().keys().cloned().collect())
+    }
+
+    /// 一覧はメモリ上の内容から直接作るため、インデックスは使わない
+    async fn update_index(
+        &self,
+        _added: &[String],
+        _removed: &[String],
+    ) -> Result<(), PutKeysError> {
+        Ok(())
+    }
+
+    async fn rebuild_index(&self) -> Result<Vec<String>, PutKeysError> {
+        Ok(self.plans.borrow().keys().cloned().collect())
+    }
+
+    async fn check_index(&self) -> Result<KeysCheck, GetKeysError> {
+        let stored = self.plans.borrow().len();
+        Ok(KeysCheck {
+            consistent: true,
+            indexed: stored,
+            stored,
+            missing_from_index: vec![],
+            missing_from_kv: vec![],
+        })
+    }
+
+    async fn schema_versions(&self) -> Result<VersionCounts, Error> {
+        let count = self.plans.borrow().len();
+        Ok(VersionCounts::from([(Document::Plan.version(), count)]))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryDetails {
+    details: RefCell<BTreeMap<String, Value>>,
+}
+
+#[async_trait(?Send)]
+impl DetailsRepository for MemoryDetails {
+    async fn read_with_etag(
+        &self,
+        id: &str,
+    ) -> Result<(ReadPlanDetails, String), PlanDetailsReadError> {
+        let Some(value) = self.details.borrow().get(id).cloned() else {
+            return Err(PlanDetailsReadError::NotFound);
+        };
+        let etag = etag(&value);
+        Ok((serde_json::from_value(value)?, etag))
+    }
+
+    async fn read_value(&self, id: &str) -> Result<Option<Value>, PlanDetailsReadError> {
+        Ok(self.details.borrow().get(id).cloned())
+    }
+
+    async fn put(
+        &self,
+        id: &str,
+        details: CreatePlanDetails,
+    ) -> Result<ReadPlanDetails, PlanDetailsCreateError> {
+        let details = ReadPlanDetails::from(details);
+        self.details
+            .borrow_mut()
+            .insert(id.to_string(), serde_json::to_value(&details)?);
+        Ok(details)
+    }
+
+    async fn put_value(&self, id: &str, value: &Value) -> Result<(), PlanDetailsCreateError> {
+        self.details
+            .borrow_mut()
+            .insert(id.to_string(), current(Document::Details, value));
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        self.details.borrow_mut().remove(id);
+        Ok(())
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        Ok(self.details.borrow().keys().cloned().collect())
+    }
+
+    async fn schema_versions(&self) -> Result<VersionCounts, Error> {
+        let count = self.details.borrow().len();
+        Ok(VersionCounts::from([(Document::Details.version(), count)]))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryIcons {
+    icons: RefCell<BTreeMap<String, Icon>>,
+    /// ゴミ箱に移動したアイコン
+    trash: RefCell<BTreeMap<String, Icon>>,
+}
+
+#[async_trait(?Send)]
+impl IconStore for MemoryIcons {
+    async fn get(&self, plan_id: &str) -> Result<Option<Icon>, Error> {
+        Ok(self.icons.borrow().get(plan_id).cloned())
+    }
+
+    async fn etag(&self, plan_id: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .icons
+            .borrow()
+            .get(plan_id)
+            .map(|icon| icon.etag.clone()))
+    }
+
+    async fn put(&self, plan_id: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), Error> {
+        let icon = Icon {
+            etag: format!("\"{}\"", sha256_hex(&bytes)),
+            bytes,
+            content_type: Some(content_type.to_string()),
+            uploaded: now(),
+        };
+        self.icons.borrow_mut().insert(plan_id.to_string(), icon);
+        Ok(())
+    }
+
+    async fn delete(&self, plan_id: &str) -> Result<(), Error> {
+        self.icons.borrow_mut().remove(plan_id);
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<bool, Error> {
+        let Some(icon) = self.icons.borrow_mut().remove(from) else {
+            return Ok(false);
+        };
+        self.icons.borrow_mut().insert(to.to_string(), icon);
+        Ok(true)
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        Ok(self.icons.borrow().keys().cloned().collect())
+    }
+
+    async fn move_to_trash(&self, plan_id: &str) -> Result<bool, Error> {
+        let Some(icon) = self.icons.borrow_mut().remove(plan_id) else {
+            return Ok(false);
+        };
+        self.trash.borrow_mut().insert(plan_id.to_string(), icon);
+        Ok(true)
+    }
+
+    async fn restore_from_trash(&self, plan_id: &str) -> Result<bool, Error> {
+        let Some(icon) = self.trash.borrow_mut().remove(plan_id) else {
+            return Ok(false);
+        };
+        self.icons.borrow_mut().insert(plan_id.to_string(), icon);
+        Ok(true)
+    }
+
+    async fn purge_trash(&self, plan_id: &str) -> Result<(), Error> {
+        self.trash.borrow_mut().remove(plan_id);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryOwners {
+    owners: RefCell<BTreeMap<String, PlanOwners>>,
+}
+
+#[async_trait(?Send)]
+impl OwnersRepository for MemoryOwners {
+    async fn read(&self, plan_id: &str) -> Result<PlanOwners, PlanOwnersError> {
+        Ok(self
+            .owners
+            .borrow()
+            .get(plan_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn write(&self, plan_id: &str, owners: &PlanOwners) -> Result<(), PlanOwnersError> {
+        self.owners
+            .borrow_mut()
+            .insert(plan_id.to_string(), owners.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, plan_id: &str) -> Result<(), PlanOwnersError> {
+        self.owners.borrow_mut().remove(plan_id);
+        Ok(())
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, PlanOwnersError> {
+        Ok(self.owners.borrow().keys().cloned().collect())
+    }
+}
+
+/// 保持期間は扱わず、削除するまで残す
+#[derive(Default)]
+pub struct MemoryAliases {
+    aliases: RefCell<BTreeMap<String, PlanAlias>>,
+}
+
+#[async_trait(?Send)]
+impl AliasRepository for MemoryAliases {
+    async fn read(&self, old_id: &str) -> Result<Option<PlanAlias>, KvError> {
+        Ok(self.aliases.borrow().get(old_id).cloned())
+    }
+
+    async fn write(&self, old_id: &str, alias: &PlanAlias, _ttl: Duration) -> Result<(), KvError> {
+        self.aliases
+            .borrow_mut()
+            .insert(old_id.to_string(), alias.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, old_id: &str) -> Result<(), KvError> {
+        self.aliases.borrow_mut().remove(old_id);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryCacheGenerations {
+    generations: RefCell<BTreeMap<String, String>>,
+}
+
+#[async_trait(?Send)]
+impl CacheGenerations for MemoryCacheGenerations {
+    async fn generation(&self, scope: CacheScope<'_>) -> Result<String, KvError> {
+        Ok(self
+            .generations
+            .borrow()
+            .get(&scope.key())
+            .cloned()
+            .unwrap_or_else(|| "0".to_string()))
+    }
+
+    async fn bump(&self, scope: CacheScope<'_>) -> Result<(), KvError> {
+        self.generations
+            .borrow_mut()
+            .insert(scope.key(), now().timestamp_millis().to_string());
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryTrash {
+    entries: RefCell<BTreeMap<String, TrashEntry>>,
+}
+
+#[async_trait(?Send)]
+impl TrashRepository for MemoryTrash {
+    async fn read(&self, plan_id: &str) -> Result<Option<TrashEntry>, TrashError> {
+        Ok(self.entries.borrow().get(plan_id).cloned())
+    }
+
+    async fn write(&self, entry: &TrashEntry) -> Result<(), TrashError> {
+        self.entries
+            .borrow_mut()
+            .insert(entry.plan_id.clone(), entry.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, plan_id: &str) -> Result<(), TrashError> {
+        self.entries.borrow_mut().remove(plan_id);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<TrashEntry>, TrashError> {
+        let mut entries = self.entries.borrow().values().cloned().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| Reverse(entry.deleted_at));
+        Ok(entries)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryRevisions {
+    histories: RefCell<BTreeMap<(String, String), RevisionHistory>>,
+}
+
+fn revisions_key(document: Document, plan_id: &str) -> (String, String) {
+    (format!("{:?}", document), plan_id.to_string())
+}
+
+#[async_trait(?Send)]
+impl RevisionRepository for MemoryRevisions {
+    async fn read(
+        &self,
+        document: Document,
+        plan_id: &str,
+    ) -> Result<RevisionHistory, RevisionError> {
+        Ok(self
+            .histories
+            .borrow()
+            .get(&revisions_key(document, plan_id))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn write(
+        &self,
+        document: Document,
+        plan_id: &str,
+        history: &RevisionHistory,
+    ) -> Result<(), RevisionError> {
+        self.histories
+            .borrow_mut()
+            .insert(revisions_key(document, plan_id), history.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, document: Document, plan_id: &str) -> Result<(), RevisionError> {
+        self.histories
+            .borrow_mut()
+            .remove(&revisions_key(document, plan_id));
+        Ok(())
+    }
+}
+
+/// 追記した順に保持する。カーソルは新しい順に数えた次の位置
+#[derive(Default)]
+pub struct MemoryAuditLog {
+    records: RefCell<Vec<AuditRecord>>,
+}
+
+#[async_trait(?Send)]
+impl AuditLog for MemoryAuditLog {
+    async fn append(&self, record: &AuditRecord) -> Result<(), AuditError> {
+        self.records.borrow_mut().push(record.clone());
+        Ok(())
+    }
+
+    async fn list(&self, query: &AuditQuery) -> Result<AuditPage, AuditError> {
+        let offset = match &query.cursor {
+            None => 0,
+            Some(cursor) => cursor.parse().map_err(|_| AuditError::InvalidCursor)?,
+        };
+        let records = self.records.borrow();
+        let mut matched = records
+            .iter()
+            .rev()
+            .enumerate()
+            .skip(offset)
+            .filter(|(_, record)| {
+                query.from.is_none_or(|from| record.timestamp >= from)
+                    && query.to.is_none_or(|to| record.timestamp <= to)
+                    && query
+                        .actor
+                        .as_ref()
+                        .is_none_or(|actor| actor == &record.actor)
+                    && query
+                        .plan_id
+                        .as_ref()
+                        .is_none_or(|plan_id| Some(plan_id) == record.plan_id.as_ref())
+            });
+        let page = matched
+            .by_ref()
+            .take(query.limit)
+            .map(|(_, record)| record.clone())
+            .collect();
+        Ok(AuditPage {
+            records: page,
+            next_cursor: matched.next().map(|(index, _)| index.to_string()),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryApiKeys {
+    keys: RefCell<BTreeMap<String, StoredApiKey>>,
+}
+
+#[async_trait(?Send)]
+impl ApiKeyRepository for MemoryApiKeys {
+    async fn get(&self, id: &str) -> Result<Option<StoredApiKey>, ApiKeyError> {
+        Ok(self.keys.borrow().get(id).cloned())
+    }
+
+    async fn put(&self, stored: &StoredApiKey) -> Result<(), ApiKeyError> {
+        self.keys
+            .borrow_mut()
+            .insert(stored.key.id.clone(), stored.clone());
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<StoredApiKey>, ApiKeyError> {
+        Ok(self.keys.borrow().values().cloned().collect())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryMigrationState {
+    state: RefCell<Option<MigrationState>>,
+}
+
+#[async_trait(?Send)]
+impl MigrationStateStore for MemoryMigrationState {
+    async fn read(&self) -> Result<Option<MigrationState>, MigrationStateError> {
+        Ok(self.state.borrow().clone())
+    }
+
+    async fn write(&self, state: &MigrationState) -> Result<(), MigrationStateError> {
+        *self.state.borrow_mut() = Some(state.clone());
+        Ok(())
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            SqlValue::Null => ToSqlOutput::from(rusqlite::types::Null),
+            SqlValue::Integer(value) => ToSqlOutput::from(*value),
+            SqlValue::Real(value) => ToSqlOutput::from(*value),
+            SqlValue::Text(value) => ToSqlOutput::from(value.as_str()),
+        })
+    }
+}
+
+/// マイグレーションを適用したメモリ上のSQLite
+pub struct Sqlite(Connection);
+
+impl Sqlite {
+    pub fn new() -> Self {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(include_str!("../../migrations/0001_create_plans.sql"))
+            .unwrap();
+        Sqlite(connection)
+    }
+}
+
+fn sqlite_error(err: rusqlite::Error) -> Error {
+    Error::RustError(err.to_string())
+}
+
+#[async_trait(?Send)]
+impl SqlDatabase for Sqlite {
+    async fn batch(&self, statements: Vec<Statement>) -> Result<Vec<Vec<Value>>, Error> {
+        let transaction = self.0.unchecked_transaction().map_err(sqlite_error)?;
+        let mut results = vec![];
+        for statement in statements {
+            let mut prepared = transaction.prepare(&statement.sql).map_err(sqlite_error)?;
+            let columns = prepared
+                .column_names()
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            let mut rows = prepared
+                .query(params_from_iter(statement.binds.iter()))
+                .map_err(sqlite_error)?;
+            let mut values = vec![];
+            while let Some(row) = rows.next().map_err(sqlite_error)? {
+                let mut object = Map::new();
+                for (index, column) in columns.iter().enumerate() {
+                    let value = match row.get_ref(index).map_err(sqlite_error)? {
+                        ValueRef::Null => Value::Null,
+                        ValueRef::Integer(value) => json!(value),
+                        ValueRef::Real(value) => json!(value),
+                        ValueRef::Text(value) => json!(String::from_utf8_lossy(value)),
+                        ValueRef::Blob(_) => unreachable!("the schema has no blob column"),
+                    };
+                    object.insert(column.clone(), value);
+                }
+                values.push(Value::Object(object));
+            }
+            results.push(values);
+        }
+        transaction.commit().map_err(sqlite_error)?;
+        Ok(results)
+    }
+}
@@ -0,0 +1,538 @@
+//! KVからD1への企画・企画詳細の移行
+//!
+//! D1への書き込みは同じIDの行を置き換えるため、同じ値を何度移行しても結果は変わらない
+
+use super::d1::{D1Details, D1Plans};
+use super::{DetailsRepository, PlanRepository};
+use crate::models::details::ReadPlanDetails;
+use crate::models::keys::list_plan_keys;
+use crate::models::migration::{MigrationFailure, MigrationPhase, MigrationState};
+use crate::models::plan::{PlanFilter, PlanRead};
+use crate::models::schema::{unstamp, Document};
+use crate::util::{etag, kv_bulk_get_values, now, sha256_hex};
+use crate::{D1_PLANS, KV_PLANS, KV_PLAN_DETAILS};
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use thiserror::Error;
+use worker::kv::{KvError, KvStore};
+use worker::Env;
+
+/// `kv_bulk_get_values`で一度に取得できるキーの数
+const BULK_GET_LIMIT: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error(transparent)]
+    KvError(#[from] KvError),
+    #[error(transparent)]
+    WorkerError(#[from] worker::Error),
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+    #[error("{0}")]
+    StoreError(String),
+}
+
+/// 移行元のnamespace
+#[async_trait(?Send)]
+pub trait SourceStore {
+    /// 企画IDをID順に全て返す
+    async fn keys(&self) -> Result<Vec<String>, MigrationError>;
+    /// 値を取得する。存在しないキーは結果に含めない
+    async fn values(&self, keys: &[String]) -> Result<Vec<(String, Value)>, MigrationError>;
+}
+
+#[async_trait(?Send)]
+impl SourceStore for KvStore {
+    async fn keys(&self) -> Result<Vec<String>, MigrationError> {
+        Ok(list_plan_keys(self).await?)
+    }
+
+    /// `BULK_GET_LIMIT`件ずつ取得する
+    async fn values(&self, keys: &[String]) -> Result<Vec<(String, Value)>, MigrationError> {
+        let mut values = vec![];
+        for chunk in keys.chunks(BULK_GET_LIMIT) {
+            let mut chunk_values = kv_bulk_get_values::<Value>(self, chunk, "json").await?;
+            for key in chunk {
+                if let Some(Some(value)) = chunk_values.remove(key) {
+                    values.push((key.clone(), value));
+                }
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// 移行元のKV
+pub struct Source {
+    pub plans: Rc<dyn SourceStore>,
+    pub details: Rc<dyn SourceStore>,
+}
+
+/// 移行先のD1
+pub struct Target {
+    pub plans: D1Plans,
+    pub details: D1Details,
+}
+
+/// 移行せずに値を読み込めるか確認した結果
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct DryRunReport {
+    pub plans: usize,
+    pub details: usize,
+    pub failures: Vec<MigrationFailure>,
+}
+
+/// 移行元のKVと、D1が設定されている場合は移行先のD1を取得する
+pub fn source_and_target(env: &Env) -> Result<(Source, Option<Target>), worker::Error> {
+    let source = Source {
+        plans: Rc::new(env.kv(KV_PLANS)?),
+        details: Rc::new(env.kv(KV_PLAN_DETAILS)?),
+    };
+    let target = env.d1(D1_PLANS).ok().map(|db| {
+        let db = Rc::new(db);
+        Target {
+            plans: D1Plans(db.clone()),
+            details: D1Details(db),
+        }
+    });
+    Ok((source, target))
+}
+
+/// 移行元と移行先の内容の比較
+#[derive(Serialize, Clone, Debug)]
+pub struct TableVerification {
+    /// 移行元で読み込めた値の数
+    pub source: usize,
+    pub target: usize,
+    /// 値をID順に並べた`<id>:<ETag>`の一覧のSHA-256
+    pub source_checksum: String,
+    pub target_checksum: String,
+    /// 移行元にあるが移行先に無いID
+    pub missing: Vec<String>,
+    /// 移行元と移行先で値が異なるID
+    pub mismatched: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Verification {
+    pub consistent: bool,
+    pub plans: TableVerification,
+    pub details: TableVerification,
+}
+
+impl MigrationPhase {
+    fn store(self, source: &Source) -> Option<&dyn SourceStore> {
+        match self {
+            MigrationPhase::Plans => Some(&*source.plans),
+            MigrationPhase::Details => Some(&*source.details),
+            MigrationPhase::Completed => None,
+        }
+    }
+
+    fn next(self) -> MigrationPhase {
+        match self {
+            MigrationPhase::Plans => MigrationPhase::Details,
+            MigrationPhase::Details | MigrationPhase::Completed => MigrationPhase::Completed,
+        }
+    }
+}
+
+/// 企画の値をD1に保存する形に揃える
+///
+/// キーをIDとし、古いバージョンの値は現在のバージョンに変換する
+fn normalize_plan(id: &str, mut value: Value) -> Result<PlanRead, serde_json::Error> {
+    Document::Plan.upgrade(&mut value);
+    unstamp(&mut value);
+    if let Value::Object(map) = &mut value {
+        map.insert("id".into(), Value::String(id.to_string()));
+    }
+    serde_json::from_value::<PlanRead>(value)
+}
+
+/// 企画詳細の値を現在のバージョンに変換する
+fn normalize_details(mut value: Value) -> Result<ReadPlanDetails, serde_json::Error> {
+    Document::Details.upgrade(&mut value);
+    unstamp(&mut value);
+    serde_json::from_value::<ReadPlanDetails>(value)
+}
+
+/// 移行元の値を読み込み、移行先に保存する形に変換する
+async fn read_source(
+    source: &Source,
+) -> Result<
+    (
+        BTreeMap<String, PlanRead>,
+        BTreeMap<String, ReadPlanDetails>,
+        Vec<MigrationFailure>,
+    ),
+    MigrationError,
+> {
+    let mut failures = vec![];
+
+    let mut plans = BTreeMap::new();
+    let keys = source.plans.keys().await?;
+    for (id, value) in source.plans.values(&keys).await? {
+        match normalize_plan(&id, value) {
+            Ok(plan) => {
+                plans.insert(id, plan);
+            }
+            Err(err) => failures.push(MigrationFailure {
+                phase: MigrationPhase::Plans,
+                id,
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    let mut details = BTreeMap::new();
+    let keys = source.details.keys().await?;
+    for (id, value) in source.details.values(&keys).await? {
+        match normalize_details(value) {
+            Ok(value) => {
+                details.insert(id, value);
+            }
+            Err(err) => failures.push(MigrationFailure {
+                phase: MigrationPhase::Details,
+                id,
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    Ok((plans, details, failures))
+}
+
+/// 移行元の全ての値を読み込み、移行できない値を報告する（書き込みは行わない）
+pub async fn dry_run(source: &Source) -> Result<DryRunReport, MigrationError> {
+    let (plans, details, failures) = read_source(source).await?;
+    Ok(DryRunReport {
+        plans: plans.len(),
+        details: details.len(),
+        failures,
+    })
+}
+
+/// 前回の続きから最大`limit`件の値を移行し、進捗を更新する
+pub async fn run_batch(
+    source: &Source,
+    target: &Target,
+    state: &mut MigrationState,
+    limit: usize,
+) -> Result<(), MigrationError> {
+    let mut remaining = limit;
+    while remaining > 0 {
+        let Some(store) = state.phase.store(source) else {
+            break;
+        };
+
+        let keys = store
+            .keys()
+            .await?
+            .into_iter()
+            .filter(|key| state.cursor.as_ref().is_none_or(|cursor| key > cursor))
+            .take(remaining)
+            .collect::<Vec<_>>();
+        if keys.is_empty() {
+            state.phase = state.phase.next();
+            state.cursor = None;
+            continue;
+        }
+
+        for (id, value) in store.values(&keys).await? {
+            match migrate_value(target, state.phase, &id, value).await {
+                Ok(()) => {
+                    state.clear_failure(state.phase, &id);
+                    match state.phase {
+                        MigrationPhase::Plans => state.plans_migrated += 1,
+                        _ => state.details_migrated += 1,
+                    }
+                }
+                Err(message) => state.record_failure(MigrationFailure {
+                    phase: state.phase,
+                    id,
+                    message,
+                }),
+            }
+        }
+
+        remaining -= keys.len();
+        state.cursor = keys.last().cloned();
+    }
+
+    state.updated_at = now();
+    Ok(())
+}
+
+async fn migrate_value(
+    target: &Target,
+    phase: MigrationPhase,
+    id: &str,
+    value: Value,
+) -> Result<(), String> {
+    match phase {
+        MigrationPhase::Plans => {
+            let plan = normalize_plan(id, value).map_err(|err| err.to_string())?;
+            let value = serde_json::to_value(plan).map_err(|err| err.to_string())?;
+            target
+                .plans
+                .put_value(id, &value)
+                .await
+                .map_err(|err| err.to_string())
+        }
+        MigrationPhase::Details => {
+            let details = normalize_details(value).map_err(|err| err.to_string())?;
+            let value = serde_json::to_value(details).map_err(|err| err.to_string())?;
+            target
+                .details
+                .put_value(id, &value)
+                .await
+                .map_err(|err| err.to_string())
+        }
+        MigrationPhase::Completed => Ok(()),
+    }
+}
+
+fn verify_table(
+    source: BTreeMap<String, String>,
+    target: BTreeMap<String, String>,
+) -> TableVerification {
+    let checksum = |etags: &BTreeMap<String, String>| {
+        sha256_hex(
+            etags
+                .iter()
+                .map(|(id, etag)| format!("{}:{}\n", id, etag))
+                .collect::<String>(),
+        )
+    };
+
+    let missing = source
+        .keys()
+        .filter(|id| !target.contains_key(*id))
+        .cloned()
+        .collect();
+    let mismatched = source
+        .iter()
+        .filter(|(id, etag)| target.get(*id).is_some_and(|target| target != *etag))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    TableVerification {
+        source: source.len(),
+        target: target.len(),
+        source_checksum: checksum(&source),
+        target_checksum: checksum(&target),
+        missing,
+        mismatched,
+    }
+}
+
+/// 移行元と移行先の件数とチェックサムを比較する
+///
+/// 移行元で読み込めない値は比較の対象にしない
+pub async fn verify(source: &Source, target: &Target) -> Result<Verification, MigrationError> {
+    let (source_plans, source_details, _) = read_source(source).await?;
+
+    let etags = |values: Vec<(String, Value)>| {
+        values
+            .into_iter()
+            .map(|(id, value)| (id, etag(&value)))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    let source_plans = etags(
+        source_plans
+            .into_iter()
+            .map(|(id, plan)| Ok((id, serde_json::to_value(plan)?)))
+            .collect::<Result<_, serde_json::Error>>()?,
+    );
+    let target_plans = etags(
+        target
+            .plans
+            .read_all(&PlanFilter::default())
+            .await
+            .map_err(|err| MigrationError::StoreError(format!("{:?}", err)))?
+            .into_iter()
+            .map(|plan| Ok((plan.id.clone(), serde_json::to_value(plan)?)))
+            .collect::<Result<_, serde_json::Error>>()?,
+    );
+
+    let source_details = etags(
+        source_details
+            .into_iter()
+            .map(|(id, details)| Ok((id, serde_json::to_value(details)?)))
+            .collect::<Result<_, serde_json::Error>>()?,
+    );
+    let target_details = etags(
+        target
+            .details
+            .read_all()
+            .await
+            .map_err(|err| MigrationError::StoreError(err.to_string()))?
+            .into_iter()
+            .map(|(id, details)| Ok((id, serde_json::to_value(details)?)))
+            .collect::<Result<_, serde_json::Error>>()?,
+    );
+
+    let plans = verify_table(source_plans, target_plans);
+    let details = verify_table(source_details, target_details);
+    Ok(Verification {
+        consistent: plans.source_checksum == plans.target_checksum
+            && details.source_checksum == details.target_checksum,
+        plans,
+        details,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::{plan_value, Sqlite};
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    /// メモリ上の移行元
+    struct MemorySource(BTreeMap<String, Value>);
+
+    #[async_trait(?Send)]
+    impl SourceStore for MemorySource {
+        async fn keys(&self) -> Result<Vec<String>, MigrationError> {
+            Ok(self.0.keys().cloned().collect())
+        }
+
+        async fn values(&self, keys: &[String]) -> Result<Vec<(String, Value)>, MigrationError> {
+            Ok(keys
+                .iter()
+                .filter_map(|key| Some((key.clone(), self.0.get(key)?.clone())))
+                .collect())
+        }
+    }
+
+    fn source(plans: &[(&str, Value)], details: &[(&str, Value)]) -> Source {
+        let store = |values: &[(&str, Value)]| {
+            Rc::new(MemorySource(
+                values
+                    .iter()
+                    .map(|(id, value)| (id.to_string(), value.clone()))
+                    .collect(),
+            ))
+        };
+        Source {
+            plans: store(plans),
+            details: store(details),
+        }
+    }
+
+    fn target() -> Target {
+        let db = Rc::new(Sqlite::new());
+        Target {
+            plans: D1Plans(db.clone()),
+            details: D1Details(db),
+        }
+    }
+
+    fn target_plan_ids(target: &Target) -> Vec<String> {
+        block_on(target.plans.read_all(&PlanFilter::default()))
+            .unwrap()
+            .into_iter()
+            .map(|plan| plan.id)
+            .collect()
+    }
+
+    #[test]
+    fn batches_resume_from_saved_state() {
+        let source = source(
+            &[
+                ("a", plan_value()),
+                ("b", plan_value()),
+                ("c", plan_value()),
+            ],
+            &[("a", json!({"additional_info": "予約制"}))],
+        );
+        let target = target();
+
+        let mut state = MigrationState::start();
+        block_on(run_batch(&source, &target, &mut state, 2)).unwrap();
+        assert_eq!(state.phase, MigrationPhase::Plans);
+        assert_eq!(state.cursor.as_deref(), Some("b"));
+        assert_eq!(state.plans_migrated, 2);
+        assert_eq!(target_plan_ids(&target), ["a", "b"]);
+
+        // 保存した進捗から再開する
+        let mut state: MigrationState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        block_on(run_batch(&source, &target, &mut state, 2)).unwrap();
+        assert_eq!(state.phase, MigrationPhase::Details);
+        assert_eq!(state.cursor.as_deref(), Some("a"));
+        assert_eq!((state.plans_migrated, state.details_migrated), (3, 1));
+        assert_eq!(target_plan_ids(&target), ["a", "b", "c"]);
+
+        block_on(run_batch(&source, &target, &mut state, 2)).unwrap();
+        assert_eq!(state.phase, MigrationPhase::Completed);
+        assert_eq!((state.plans_migrated, state.details_migrated), (3, 1));
+        assert!(state.failures.is_empty());
+
+        let details = block_on(target.details.read_all()).unwrap();
+        assert_eq!(details.len(), 1);
+        assert_eq!(details[0].1.additional_info.as_deref(), Some("予約制"));
+    }
+
+    #[test]
+    fn failed_values_are_recorded_and_skipped() {
+        let source = source(&[("a", plan_value()), ("bad", json!({"type": 1}))], &[]);
+        let target = target();
+
+        let mut state = MigrationState::start();
+        block_on(run_batch(&source, &target, &mut state, 10)).unwrap();
+        assert_eq!(state.phase, MigrationPhase::Completed);
+        assert_eq!(state.plans_migrated, 1);
+        assert_eq!(state.failures.len(), 1);
+        assert_eq!(state.failures[0].phase, MigrationPhase::Plans);
+        assert_eq!(state.failures[0].id, "bad");
+        assert_eq!(target_plan_ids(&target), ["a"]);
+
+        let report = block_on(dry_run(&source)).unwrap();
+        assert_eq!((report.plans, report.details), (1, 0));
+        assert_eq!(report.failures.len(), 1);
+    }
+
+    #[test]
+    fn verify_reports_missing_and_mismatched_values() {
+        let migrated = source(&[("a", plan_value()), ("b", plan_value())], &[]);
+        let target = target();
+        let mut state = MigrationState::start();
+        block_on(run_batch(&migrated, &target, &mut state, 10)).unwrap();
+
+        let verification = block_on(verify(&migrated, &target)).unwrap();
+        assert!(verification.consistent);
+        assert_eq!(
+            verification.plans.source_checksum,
+            verification.plans.target_checksum
+        );
+
+        let mut changed = plan_value();
+        changed["id"] = json!("a");
+        changed["plan_name"] = json!("書き換えた企画");
+        block_on(target.plans.put_value("a", &changed)).unwrap();
+        let source = source(
+            &[
+                ("a", plan_value()),
+                ("b", plan_value()),
+                ("c", plan_value()),
+            ],
+            &[],
+        );
+
+        let verification = block_on(verify(&source, &target)).unwrap();
+        assert!(!verification.consistent);
+        assert_eq!(
+            (verification.plans.source, verification.plans.target),
+            (3, 2)
+        );
+        assert_eq!(verification.plans.missing, ["c"]);
+        assert_eq!(verification.plans.mismatched, ["a"]);
+        assert!(verification.details.missing.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! 企画が存在しない企画詳細・アイコン・所有者（孤立したデータ）の検出と削除
+
+use super::Storage;
+use crate::models::owners::PlanOwnersError;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use worker::Error;
+
+/// 企画が存在しない企画詳細・アイコン・所有者の企画ID
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Orphans {
+    pub details: Vec<String>,
+    pub icons: Vec<String>,
+    pub owners: Vec<String>,
+}
+
+impl Orphans {
+    pub fn is_empty(&self) -> bool {
+        self.details.is_empty() && self.icons.is_empty() && self.owners.is_empty()
+    }
+}
+
+/// 企画が存在しない企画詳細・アイコン・所有者を探す
+///
+/// 企画IDはインデックスではなく保存先から直接取得する
+pub async fn scan(storage: &Storage) -> Result<Orphans, Error> {
+    let plans = storage
+        .plans
+        .ids()
+        .await?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+    let orphaned = |ids: Vec<String>| {
+        let mut ids = ids
+            .into_iter()
+            .filter(|id| !plans.contains(id))
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+
+    Ok(Orphans {
+        details: orphaned(storage.details.ids().await?),
+        icons: orphaned(storage.icons.ids().await?),
+        owners: orphaned(storage.owners.ids().await.map_err(owners_error)?),
+    })
+}
+
+/// 企画が存在しない企画詳細・アイコン・所有者を削除し、削除したものを返す
+///
+/// 検出後に同じIDで企画が作成された場合に備え、削除する直前に企画が存在しないことを確認する
+pub async fn clean(storage: &Storage) -> Result<Orphans, Error> {
+    let orphans = scan(storage).await?;
+    let mut cleaned = Orphans::default();
+
+    for id in orphans.details {
+        if exists(storage, &id).await? {
+            continue;
+        }
+        storage.details.delete(&id).await?;
+        cleaned.details.push(id);
+    }
+    for id in orphans.icons {
+        if exists(storage, &id).await? {
+            continue;
+        }
+        storage.icons.delete(&id).await?;
+        cleaned.icons.push(id);
+    }
+    for id in orphans.owners {
+        if exists(storage, &id).await? {
+            continue;
+        }
+        storage.owners.delete(&id).await.map_err(owners_error)?;
+        cleaned.owners.push(id);
+    }
+
+    Ok(cleaned)
+}
+
+fn owners_error(err: PlanOwnersError) -> Error {
+    Error::RustError(err.to_string())
+}
+
+async fn exists(storage: &Storage, id: &str) -> Result<bool, Error> {
+    Ok(storage
+        .plans
+        .read_value(id)
+        .await
+        .map_err(|err| Error::RustError(format!("{:?}", err)))?
+        .is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::owners::PlanOwners;
+    use crate::storage::memory::{seed, storage};
+    use futures::executor::block_on;
+
+    /// 企画だけを削除し、企画詳細・アイコン・所有者を残す
+    fn orphan(storage: &Storage, plan_id: &str) {
+        block_on(seed(storage, plan_id));
+        block_on(storage.plans.delete(plan_id)).unwrap();
+    }
+
+    #[test]
+    fn scan_finds_data_without_plans() {
+        let storage = storage();
+        block_on(seed(&storage, "alive"));
+        orphan(&storage, "gone");
+        block_on(storage.icons.put("icon-only", vec![1], "image/png")).unwrap();
+        block_on(storage.owners.write("owners-only", &PlanOwners::default())).unwrap();
+
+        let orphans = block_on(scan(&storage)).unwrap();
+        assert_eq!(orphans.details, ["gone"]);
+        assert_eq!(orphans.icons, ["gone", "icon-only"]);
+        assert_eq!(orphans.owners, ["gone", "owners-only"]);
+    }
+
+    #[test]
+    fn scan_without_orphans_is_empty() {
+        let storage = storage();
+        block_on(seed(&storage, "alive"));
+        assert!(block_on(scan(&storage)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn clean_deletes_only_orphans() {
+        let storage = storage();
+        block_on(seed(&storage, "alive"));
+        orphan(&storage, "gone");
+
+        let cleaned = block_on(clean(&storage)).unwrap();
+        assert_eq!(cleaned.details, ["gone"]);
+        assert_eq!(cleaned.icons, ["gone"]);
+        assert_eq!(cleaned.owners, ["gone"]);
+        assert!(block_on(scan(&storage)).unwrap().is_empty());
+
+        assert!(block_on(storage.details.read_value("alive"))
+            .unwrap()
+            .is_some());
+        assert!(block_on(storage.icons.get("alive")).unwrap().is_some());
+        assert_eq!(
+            block_on(storage.owners.read("alive")).unwrap().subjects,
+            ["owner"]
+        );
+    }
+}
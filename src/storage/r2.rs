@@ -0,0 +1,124 @@
+//! R2によるアイコンの保存
+
+use super::{Icon, IconStore};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use worker::{Bucket, Error, HttpMetadata};
+
+/// `<plan_id>/original`のキーで元の画像を保存する。ゴミ箱に移動した画像は`trash/<plan_id>/original`
+pub struct R2Icons(pub Bucket);
+
+const ORIGINAL_KEY_SUFFIX: &str = "/original";
+
+fn original_key(plan_id: &str) -> String {
+    format!("{}{}", plan_id, ORIGINAL_KEY_SUFFIX)
+}
+
+fn trash_key(plan_id: &str) -> String {
+    format!("trash/{}", original_key(plan_id))
+}
+
+impl R2Icons {
+    /// オブジェクトを別のキーにコピーしてから元のオブジェクトを削除する
+    async fn move_object(&self, from: String, to: String) -> Result<bool, Error> {
+        let Some(object) = self.0.get(&from).execute().await? else {
+            return Ok(false);
+        };
+        let Some(body) = object.body() else {
+            return Err(Error::RustError("body is none".into()));
+        };
+        self.0
+            .put(to, body.bytes().await?)
+            .http_metadata(object.http_metadata())
+            .execute()
+            .await?;
+        self.0.delete(from).await?;
+        Ok(true)
+    }
+}
+
+#[async_trait(?Send)]
+impl IconStore for R2Icons {
+    async fn get(&self, plan_id: &str) -> Result<Option<Icon>, Error> {
+        let Some(object) = self.0.get(original_key(plan_id)).execute().await? else {
+            return Ok(None);
+        };
+        let Some(body) = object.body() else {
+            return Err(Error::RustError("body is none".into()));
+        };
+
+        Ok(Some(Icon {
+            bytes: body.bytes().await?,
+            content_type: object.http_metadata().content_type,
+            etag: object.http_etag(),
+            uploaded: DateTime::from_timestamp_millis(object.uploaded().as_millis() as i64)
+                .unwrap_or(DateTime::<Utc>::UNIX_EPOCH),
+        }))
+    }
+
+    async fn etag(&self, plan_id: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .0
+            .head(original_key(plan_id))
+            .await?
+            .map(|object| object.http_etag()))
+    }
+
+    async fn put(&self, plan_id: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), Error> {
+        self.0
+            .put(original_key(plan_id), bytes)
+            .http_metadata(HttpMetadata {
+                content_type: Some(content_type.to_string()),
+                ..Default::default()
+            })
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, plan_id: &str) -> Result<(), Error> {
+        self.0.delete(original_key(plan_id)).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<bool, Error> {
+        self.move_object(original_key(from), original_key(to)).await
+    }
+
+    async fn ids(&self) -> Result<Vec<String>, Error> {
+        let mut ids = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = self.0.list();
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let objects = list.execute().await?;
+            ids.extend(objects.objects().iter().filter_map(|object| {
+                object
+                    .key()
+                    .strip_suffix(ORIGINAL_KEY_SUFFIX)
+                    .filter(|id| !id.contains('/'))
+                    .map(str::to_string)
+            }));
+            match objects.cursor() {
+                Some(next) if objects.truncated() => cursor = Some(next),
+                _ => break,
+            }
+        }
+        Ok(ids)
+    }
+
+    async fn move_to_trash(&self, plan_id: &str) -> Result<bool, Error> {
+        self.move_object(original_key(plan_id), trash_key(plan_id))
+            .await
+    }
+
+    async fn restore_from_trash(&self, plan_id: &str) -> Result<bool, Error> {
+        self.move_object(trash_key(plan_id), original_key(plan_id))
+            .await
+    }
+
+    async fn purge_trash(&self, plan_id: &str) -> Result<(), Error> {
+        self.0.delete(trash_key(plan_id)).await
+    }
+}
@@ -1,3 +1,6 @@
+use chrono::{DateTime, Utc};
+use jwt_simple::prelude::Clock;
+use jwt_simple::reexports::ct_codecs::{Encoder, Hex};
 use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::collections::HashMap;
@@ -57,3 +60,108 @@ pub fn extension_from_content_type(ct: &str) -> &'static str {
         _ => "bin",
     }
 }
+
+/// 現在時刻をUNIX時間（秒）で返す
+pub fn now_secs() -> u64 {
+    Clock::now_since_epoch().as_secs()
+}
+
+/// 現在時刻を返す
+pub fn now() -> DateTime<Utc> {
+    let millis = Clock::now_since_epoch().as_millis();
+    DateTime::from_timestamp_millis(millis as i64).unwrap_or_default()
+}
+
+/// SHA-256ハッシュを16進文字列で返す
+pub fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    Hex::encode_to_string(hmac_sha256::Hash::hash(data.as_ref())).unwrap_or_default()
+}
+
+/// JSONの値から強いETagを求める
+///
+/// `Value`のオブジェクトはキー順に並ぶため、KVに保存した際の書式によらず内容が同じなら同じETagになる
+pub fn etag(value: &Value) -> String {
+    format!("\"{}\"", &sha256_hex(value.to_string())[..32])
+}
+
+/// 企画の`schedule`を1つにまとめた表現（`combine_schedule=true`）
+pub const COMBINED_REPRESENTATION: &str = "combined";
+
+/// `representation_etag`で付ける表現の種類
+const REPRESENTATIONS: [&str; 1] = [COMBINED_REPRESENTATION];
+
+/// 保存されている値のETagに表現の種類を付け、表現ごとに異なるETagにする
+///
+/// 例えば`"abc"`と`combined`から`"abc-combined"`を作る
+pub fn representation_etag(etag: &str, representation: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(tag) => format!("{}-{}\"", tag, representation),
+        None => format!("{}-{}", etag, representation),
+    }
+}
+
+/// `If-Match`ヘッダーを満たすかを強い比較で判定する
+///
+/// ヘッダーが無い場合は常に満たし、`*`は現在の値が存在する場合にのみ満たす。
+/// `representation_etag`で作ったETagは、元の値のETagとして比較する
+pub fn if_match(header: Option<&str>, current: Option<&str>) -> bool {
+    let Some(header) = header else {
+        return true;
+    };
+    let Some(current) = current else {
+        return false;
+    };
+    header.split(',').map(str::trim).any(|tag| {
+        tag == "*"
+            || (!tag.starts_with("W/")
+                && (tag == current
+                    || REPRESENTATIONS
+                        .iter()
+                        .any(|representation| representation_etag(current, representation) == tag)))
+    })
+}
+
+/// `If-None-Match`ヘッダーのいずれかのETagが一致するかを弱い比較で判定する
+pub fn if_none_match(header: &str, current: &str) -> bool {
+    let current = current.trim_start_matches("W/");
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == current)
+}
+
+/// HTTPの日付形式（IMF-fixdate）に変換する
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// HTTPの日付形式の文字列を解析する
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn representation_etag_differs_from_stored_etag() {
+        assert_eq!(
+            representation_etag("\"abc\"", COMBINED_REPRESENTATION),
+            "\"abc-combined\""
+        );
+    }
+
+    #[test]
+    fn if_match_accepts_etag_of_any_representation() {
+        let current = "\"abc\"";
+        assert!(if_match(Some("\"abc\""), Some(current)));
+        assert!(if_match(Some("\"abc-combined\""), Some(current)));
+        assert!(if_match(Some("*"), Some(current)));
+        assert!(!if_match(Some("\"abd-combined\""), Some(current)));
+        assert!(!if_match(Some("W/\"abc\""), Some(current)));
+        assert!(!if_match(Some("\"abc\""), None));
+    }
+}